@@ -0,0 +1,104 @@
+//! Measures how many instructions per second the emulator core runs.
+//!
+//! Compare `cargo bench` against `cargo bench --no-default-features` to see
+//! what the per-access logging costs.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use rust6502lib::clock::FreeRunning;
+use rust6502lib::CPU;
+
+/// Instructions run per benchmark iteration.
+const STEPS: u64 = 100_000;
+
+/// Where the programs are loaded and started.
+const START: u16 = 0x0200;
+
+/// start: LDX #$00
+/// loop:  INX
+///        BNE loop
+///        JMP start
+const TIGHT_LOOP: [u8; 8] = [0xA2, 0x00, 0xE8, 0xD0, 0xFD, 0x4C, 0x00, 0x02];
+
+/// Fills $0300-$033F with descending values, bubble sorts them, calls a
+/// subroutine and starts over. Uses most addressing modes, the stack and
+/// both ways of every branch.
+const BUBBLE_SORT: [u8; 56] = [
+  0xA2, 0x3F, // start: LDX #$3F
+  0x8A, // fill: TXA
+  0x49, 0x3F, // EOR #$3F
+  0x9D, 0x00, 0x03, // STA $0300,X
+  0xCA, // DEX
+  0x10, 0xF7, // BPL fill
+  0xA0, 0x00, // sort: LDY #$00
+  0x84, 0x10, // STY $10
+  0xA2, 0x00, // LDX #$00
+  0xBD, 0x00, 0x03, // inner: LDA $0300,X
+  0xDD, 0x01, 0x03, // CMP $0301,X
+  0x90, 0x0F, // BCC noswap
+  0xF0, 0x0D, // BEQ noswap
+  0x48, // PHA
+  0xBD, 0x01, 0x03, // LDA $0301,X
+  0x9D, 0x00, 0x03, // STA $0300,X
+  0x68, // PLA
+  0x9D, 0x01, 0x03, // STA $0301,X
+  0xE6, 0x10, // INC $10
+  0xE8, // noswap: INX
+  0xE0, 0x3F, // CPX #$3F
+  0xD0, 0xE4, // BNE inner
+  0xA5, 0x10, // LDA $10
+  0xD0, 0xDA, // BNE sort
+  0x20, 0x37, 0x02, // JSR done
+  0x4C, 0x00, 0x02, // JMP start
+  0x60, // done: RTS
+];
+
+/// The address of `done` in the bubble sort, reached once the data is sorted.
+const SORTED: u16 = 0x0237;
+
+fn cpu(program: &[u8]) -> CPU {
+  let mut cpu = CPU::new(FreeRunning);
+  for (i, byte) in program.iter().enumerate() {
+    cpu.get_memory_mut().poke(START + i as u16, *byte);
+  }
+  let mut registers = cpu.get_registers();
+  registers.program_counter = START;
+  cpu.set_registers(registers);
+  cpu
+}
+
+/// Runs the bubble sort once to make sure the benchmark measures a working
+/// program rather than one stuck in a loop.
+fn check_bubble_sort() {
+  let mut cpu = cpu(&BUBBLE_SORT);
+  while cpu.get_registers().program_counter != SORTED {
+    cpu.step();
+  }
+  let memory = cpu.get_memory();
+  for i in 0..0x3F {
+    assert!(memory.peek(0x0300 + i) <= memory.peek(0x0301 + i));
+  }
+}
+
+fn throughput(c: &mut Criterion) {
+  check_bubble_sort();
+  let mut group = c.benchmark_group("instructions");
+  group.throughput(Throughput::Elements(STEPS));
+  for (name, program) in [
+    ("tight loop", &TIGHT_LOOP[..]),
+    ("bubble sort", &BUBBLE_SORT[..]),
+  ] {
+    let mut cpu = cpu(program);
+    group.bench_function(name, |b| {
+      b.iter(|| {
+        for _ in 0..STEPS {
+          cpu.step();
+        }
+        cpu.get_cycles()
+      })
+    });
+  }
+  group.finish();
+}
+
+criterion_group!(benches, throughput);
+criterion_main!(benches);
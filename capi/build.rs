@@ -0,0 +1,19 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Writes the C header for the bindings to `rust6502.h` in the build's output
+/// directory. The committed copy in `include` is checked against it by
+/// `tests/header.rs`.
+fn main() {
+  let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+  let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+  let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+  println!("cargo:rerun-if-changed=src/lib.rs");
+  println!("cargo:rerun-if-changed=cbindgen.toml");
+  cbindgen::Builder::new()
+    .with_crate(&crate_dir)
+    .with_config(config)
+    .generate()
+    .expect("Unable to generate C header")
+    .write_to_file(out_dir.join("rust6502.h"));
+}
@@ -0,0 +1,85 @@
+/*
+ * A tiny machine built on the C bindings: 64K of RAM with a character output
+ * port at $F001 and an interrupt acknowledge register at $F002.
+ *
+ * The program prints a message through the output port. An IRQ is raised
+ * before it starts, and the handler prints a '*' and acknowledges it by
+ * reading $F002, which releases the line.
+ */
+#include <stdio.h>
+
+#include "rust6502.h"
+
+struct machine {
+  bool irq_acknowledged;
+};
+
+static bool bus_read(void *user_data, uint16_t address, uint8_t *value) {
+  struct machine *machine = user_data;
+  if (address != 0xF002) {
+    return false;
+  }
+  machine->irq_acknowledged = true;
+  *value = 0;
+  return true;
+}
+
+static bool bus_write(void *user_data, uint16_t address, uint8_t value) {
+  (void)user_data;
+  if (address != 0xF001) {
+    return false;
+  }
+  putchar(value);
+  return true;
+}
+
+static const uint8_t PROGRAM[] = {
+    0xA2, 0x00,       /* $0200       LDX #$00      */
+    0xBD, 0x20, 0x02, /* $0202 loop: LDA $0220,X   */
+    0xF0, 0x06,       /* $0205       BEQ done      */
+    0x8D, 0x01, 0xF0, /* $0207       STA $F001     */
+    0xE8,             /* $020A       INX           */
+    0xD0, 0xF5,       /* $020B       BNE loop      */
+    0x02,             /* $020D done: KIL           */
+};
+
+static const uint8_t HANDLER[] = {
+    0xAD, 0x02, 0xF0, /* $0300       LDA $F002     */
+    0xA9, 0x2A,       /* $0303       LDA #'*'      */
+    0x8D, 0x01, 0xF0, /* $0305       STA $F001     */
+    0x40,             /* $0308       RTI           */
+};
+
+static const char MESSAGE[] = "Hello, world!\n";
+
+int main(void) {
+  struct machine machine = {false};
+  Rust6502Cpu *cpu = rust6502_cpu_new();
+  if (cpu == NULL) {
+    return 1;
+  }
+  rust6502_cpu_set_bus(cpu, bus_read, bus_write, &machine);
+  rust6502_cpu_load(cpu, PROGRAM, sizeof PROGRAM, 0x0200);
+  rust6502_cpu_load(cpu, HANDLER, sizeof HANDLER, 0x0300);
+  rust6502_cpu_load(cpu, (const uint8_t *)MESSAGE, sizeof MESSAGE, 0x0220);
+  rust6502_cpu_write(cpu, 0xFFFE, 0x00);
+  rust6502_cpu_write(cpu, 0xFFFF, 0x03);
+
+  Rust6502Registers registers = rust6502_cpu_get_registers(cpu);
+  registers.program_counter = 0x0200;
+  rust6502_cpu_set_registers(cpu, registers);
+
+  rust6502_cpu_set_irq(cpu, true);
+  while (rust6502_cpu_step(cpu) > 0) {
+    if (machine.irq_acknowledged) {
+      rust6502_cpu_set_irq(cpu, false);
+      machine.irq_acknowledged = false;
+    }
+  }
+
+  registers = rust6502_cpu_get_registers(cpu);
+  printf("PC=%04X X=%02X cycles=%llu\n", registers.program_counter, registers.x,
+         (unsigned long long)rust6502_cpu_get_cycles(cpu));
+  rust6502_cpu_free(cpu);
+  return 0;
+}
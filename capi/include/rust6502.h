@@ -0,0 +1,105 @@
+#ifndef RUST6502_H
+#define RUST6502_H
+
+/* Generated by cbindgen from capi/src/lib.rs. Do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// A CPU and its memory. Opaque to C code.
+typedef struct Rust6502Cpu Rust6502Cpu;
+
+// The programmer visible registers.
+typedef struct Rust6502Registers {
+  uint8_t accumulator;
+  uint8_t x;
+  uint8_t y;
+  uint8_t stack_pointer;
+  uint8_t status;
+  uint16_t program_counter;
+} Rust6502Registers;
+
+// Called for every read the CPU makes. Store the value and return true to
+// claim the address, or return false to let the read fall through to RAM.
+typedef bool (*Rust6502BusRead)(void *user_data, uint16_t address, uint8_t *value);
+
+// Called for every write the CPU makes. Return true to claim the address, or
+// false to let the write fall through to RAM.
+typedef bool (*Rust6502BusWrite)(void *user_data, uint16_t address, uint8_t value);
+
+// Creates a CPU with empty memory and the program counter at $8000. Free it
+// with `rust6502_cpu_free`.
+struct Rust6502Cpu *rust6502_cpu_new(void);
+
+// Frees a CPU made by `rust6502_cpu_new`.
+//
+// # Safety
+//
+// `cpu` must be NULL or a pointer returned by `rust6502_cpu_new` that has not
+// been freed yet.
+void rust6502_cpu_free(struct Rust6502Cpu *cpu);
+
+// Copies `length` bytes into memory starting at an address, wrapping around
+// at the top of the address space. The program counter is left alone.
+//
+// # Safety
+//
+// `data` must point to at least `length` readable bytes.
+void rust6502_cpu_load(struct Rust6502Cpu *cpu,
+                       const uint8_t *data,
+                       size_t length,
+                       uint16_t address);
+
+// Reads a byte of memory without costing cycles. The read still goes to the
+// bus callbacks.
+uint8_t rust6502_cpu_read(const struct Rust6502Cpu *cpu, uint16_t address);
+
+// Writes a byte of memory without costing cycles. The write still goes to
+// the bus callbacks.
+void rust6502_cpu_write(struct Rust6502Cpu *cpu, uint16_t address, uint8_t value);
+
+// Executes a single instruction, taking any pending interrupt first. Returns
+// the number of cycles it took, or zero if the CPU is halted.
+uint32_t rust6502_cpu_step(struct Rust6502Cpu *cpu);
+
+// Executes instructions until at least a number of cycles have passed or the
+// CPU halts. Returns the number of cycles that passed, which can run over by
+// part of an instruction.
+uint64_t rust6502_cpu_run_cycles(struct Rust6502Cpu *cpu, uint64_t cycles);
+
+// Returns true if the next instruction is a KIL opcode, or if the emulator
+// hit an error and stopped the CPU.
+bool rust6502_cpu_halted(const struct Rust6502Cpu *cpu);
+
+// Gets the number of cycles run since the CPU was created.
+uint64_t rust6502_cpu_get_cycles(const struct Rust6502Cpu *cpu);
+
+// Gets a copy of the registers.
+struct Rust6502Registers rust6502_cpu_get_registers(const struct Rust6502Cpu *cpu);
+
+// Sets every register at once.
+void rust6502_cpu_set_registers(struct Rust6502Cpu *cpu, struct Rust6502Registers registers);
+
+// Puts a pair of callbacks in front of memory, replacing any set before.
+// Either callback may be NULL to leave that direction to RAM. `user_data` is
+// passed to both untouched.
+//
+// The callbacks must not call back into the CPU. To raise or release an
+// interrupt from a device, note it in `user_data` and call
+// `rust6502_cpu_set_irq` once the step returns. The callbacks run on
+// whichever thread steps the CPU.
+void rust6502_cpu_set_bus(struct Rust6502Cpu *cpu,
+                          Rust6502BusRead read,
+                          Rust6502BusWrite write,
+                          void *user_data);
+
+// Holds or releases the IRQ line. The interrupt is taken before the next
+// instruction whenever the line is held and interrupts are enabled.
+void rust6502_cpu_set_irq(struct Rust6502Cpu *cpu, bool asserted);
+
+// Signals a non-maskable interrupt, which is taken once before the next
+// instruction.
+void rust6502_cpu_set_nmi(struct Rust6502Cpu *cpu);
+
+#endif  /* RUST6502_H */
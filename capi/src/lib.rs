@@ -0,0 +1,394 @@
+//! C bindings for the emulator.
+//!
+//! Builds as a shared and a static library named `rust6502`. The matching
+//! header is `include/rust6502.h`, which a test keeps in step with the one the
+//! build script generates, and `examples/hello.c` shows a small machine built
+//! on top of it.
+//!
+//! The CPU is free running: C code decides how fast to run it by calling
+//! `rust6502_cpu_step` or `rust6502_cpu_run_cycles`. Every function accepts a
+//! NULL CPU and does nothing with it.
+
+use rust6502lib::clock::FreeRunning;
+use rust6502lib::mappers::Mapper;
+use rust6502lib::CPU;
+use std::os::raw::c_void;
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+
+/// A CPU and its memory. Opaque to C code.
+pub struct Cpu {
+  cpu: CPU,
+  /// Set once the emulator has panicked, after which the CPU state can't be
+  /// trusted and it refuses to run.
+  crashed: bool,
+}
+
+/// The programmer visible registers.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+  pub accumulator: u8,
+  pub x: u8,
+  pub y: u8,
+  pub stack_pointer: u8,
+  pub status: u8,
+  pub program_counter: u16,
+}
+
+impl From<rust6502lib::Registers> for Registers {
+  fn from(r: rust6502lib::Registers) -> Self {
+    Registers {
+      accumulator: r.accumulator,
+      x: r.x,
+      y: r.y,
+      stack_pointer: r.stack_pointer,
+      status: r.status,
+      program_counter: r.program_counter,
+    }
+  }
+}
+
+impl From<Registers> for rust6502lib::Registers {
+  fn from(r: Registers) -> Self {
+    rust6502lib::Registers {
+      accumulator: r.accumulator,
+      x: r.x,
+      y: r.y,
+      stack_pointer: r.stack_pointer,
+      status: r.status,
+      program_counter: r.program_counter,
+    }
+  }
+}
+
+/// Called for every read the CPU makes. Store the value and return true to
+/// claim the address, or return false to let the read fall through to RAM.
+pub type BusRead =
+  Option<extern "C" fn(user_data: *mut c_void, address: u16, value: *mut u8) -> bool>;
+
+/// Called for every write the CPU makes. Return true to claim the address, or
+/// false to let the write fall through to RAM.
+pub type BusWrite = Option<extern "C" fn(user_data: *mut c_void, address: u16, value: u8) -> bool>;
+
+/// Offers every access to a pair of C callbacks before it reaches RAM.
+#[derive(Clone, Copy)]
+struct CallbackBus {
+  read: BusRead,
+  write: BusWrite,
+  user_data: *mut c_void,
+}
+
+// The callbacks and `user_data` belong to the C caller, who is told they
+// run on whichever thread steps the CPU.
+unsafe impl Send for CallbackBus {}
+
+impl Mapper for CallbackBus {
+  fn name(&self) -> &str {
+    "C bus"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    let read = self.read?;
+    let mut value = 0;
+    match read(self.user_data, index, &mut value) {
+      true => Some(value),
+      false => None,
+    }
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    match self.write {
+      Some(write) => write(self.user_data, index, value),
+      None => false,
+    }
+  }
+
+  fn reset(&mut self) {}
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(*self)
+  }
+}
+
+impl Cpu {
+  fn halted(&self) -> bool {
+    self.crashed || self.cpu.is_halted()
+  }
+
+  fn step(&mut self) -> u32 {
+    if self.halted() {
+      return 0;
+    }
+    let start = self.cpu.get_cycles();
+    let cpu = &mut self.cpu;
+    // Unwinding into C is undefined, so a panic stops the CPU instead.
+    if panic::catch_unwind(AssertUnwindSafe(|| cpu.step())).is_err() {
+      self.crashed = true;
+      return 0;
+    }
+    (self.cpu.get_cycles() - start) as u32
+  }
+}
+
+/// Creates a CPU with empty memory and the program counter at $8000. Free it
+/// with `rust6502_cpu_free`.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_new() -> *mut Cpu {
+  Box::into_raw(Box::new(Cpu {
+    cpu: CPU::new(FreeRunning),
+    crashed: false,
+  }))
+}
+
+/// Frees a CPU made by `rust6502_cpu_new`.
+///
+/// # Safety
+///
+/// `cpu` must be NULL or a pointer returned by `rust6502_cpu_new` that has not
+/// been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn rust6502_cpu_free(cpu: *mut Cpu) {
+  if !cpu.is_null() {
+    drop(Box::from_raw(cpu));
+  }
+}
+
+/// Copies `length` bytes into memory starting at an address, wrapping around
+/// at the top of the address space. The program counter is left alone.
+///
+/// # Safety
+///
+/// `data` must point to at least `length` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn rust6502_cpu_load(
+  cpu: Option<&mut Cpu>,
+  data: *const u8,
+  length: usize,
+  address: u16,
+) {
+  if let (Some(cpu), false) = (cpu, data.is_null()) {
+    let memory = cpu.cpu.get_memory_mut();
+    for (offset, byte) in slice::from_raw_parts(data, length).iter().enumerate() {
+      memory.poke(address.wrapping_add(offset as u16), *byte);
+    }
+  }
+}
+
+/// Reads a byte of memory without costing cycles. The read still goes to the
+/// bus callbacks.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_read(cpu: Option<&Cpu>, address: u16) -> u8 {
+  cpu.map_or(0, |cpu| cpu.cpu.get_memory().peek(address))
+}
+
+/// Writes a byte of memory without costing cycles. The write still goes to
+/// the bus callbacks.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_write(cpu: Option<&mut Cpu>, address: u16, value: u8) {
+  if let Some(cpu) = cpu {
+    cpu.cpu.get_memory_mut().poke(address, value);
+  }
+}
+
+/// Executes a single instruction, taking any pending interrupt first. Returns
+/// the number of cycles it took, or zero if the CPU is halted.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_step(cpu: Option<&mut Cpu>) -> u32 {
+  cpu.map_or(0, |cpu| cpu.step())
+}
+
+/// Executes instructions until at least a number of cycles have passed or the
+/// CPU halts. Returns the number of cycles that passed, which can run over by
+/// part of an instruction.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_run_cycles(cpu: Option<&mut Cpu>, cycles: u64) -> u64 {
+  let cpu = match cpu {
+    Some(cpu) => cpu,
+    None => return 0,
+  };
+  let mut elapsed = 0;
+  while elapsed < cycles {
+    match cpu.step() {
+      0 => break,
+      taken => elapsed += taken as u64,
+    }
+  }
+  elapsed
+}
+
+/// Returns true if the next instruction is a KIL opcode, or if the emulator
+/// hit an error and stopped the CPU.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_halted(cpu: Option<&Cpu>) -> bool {
+  cpu.is_none_or(|cpu| cpu.halted())
+}
+
+/// Gets the number of cycles run since the CPU was created.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_get_cycles(cpu: Option<&Cpu>) -> u64 {
+  cpu.map_or(0, |cpu| cpu.cpu.get_cycles())
+}
+
+/// Gets a copy of the registers.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_get_registers(cpu: Option<&Cpu>) -> Registers {
+  cpu.map_or_else(Registers::default, |cpu| cpu.cpu.get_registers().into())
+}
+
+/// Sets every register at once.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_set_registers(cpu: Option<&mut Cpu>, registers: Registers) {
+  if let Some(cpu) = cpu {
+    cpu.cpu.set_registers(registers.into());
+  }
+}
+
+/// Puts a pair of callbacks in front of memory, replacing any set before.
+/// Either callback may be NULL to leave that direction to RAM. `user_data` is
+/// passed to both untouched.
+///
+/// The callbacks must not call back into the CPU. To raise or release an
+/// interrupt from a device, note it in `user_data` and call
+/// `rust6502_cpu_set_irq` once the step returns. The callbacks run on
+/// whichever thread steps the CPU.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_set_bus(
+  cpu: Option<&mut Cpu>,
+  read: BusRead,
+  write: BusWrite,
+  user_data: *mut c_void,
+) {
+  if let Some(cpu) = cpu {
+    cpu.cpu.set_mapper(Box::new(CallbackBus {
+      read,
+      write,
+      user_data,
+    }));
+  }
+}
+
+/// Holds or releases the IRQ line. The interrupt is taken before the next
+/// instruction whenever the line is held and interrupts are enabled.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_set_irq(cpu: Option<&mut Cpu>, asserted: bool) {
+  if let Some(cpu) = cpu {
+    match asserted {
+      true => cpu.cpu.set_irq(),
+      false => cpu.cpu.clear_irq(),
+    }
+  }
+}
+
+/// Signals a non-maskable interrupt, which is taken once before the next
+/// instruction.
+#[no_mangle]
+pub extern "C" fn rust6502_cpu_set_nmi(cpu: Option<&mut Cpu>) {
+  if let Some(cpu) = cpu {
+    cpu.cpu.set_nmi();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  struct Device {
+    output: Vec<u8>,
+    reads: usize,
+  }
+
+  extern "C" fn device_read(user_data: *mut c_void, address: u16, value: *mut u8) -> bool {
+    let device = unsafe { &mut *(user_data as *mut Device) };
+    if address != 0xF000 {
+      return false;
+    }
+    device.reads += 1;
+    unsafe { *value = 0x5A };
+    true
+  }
+
+  extern "C" fn device_write(user_data: *mut c_void, address: u16, value: u8) -> bool {
+    let device = unsafe { &mut *(user_data as *mut Device) };
+    if address != 0xF001 {
+      return false;
+    }
+    device.output.push(value);
+    true
+  }
+
+  fn new_cpu() -> Box<Cpu> {
+    unsafe { Box::from_raw(rust6502_cpu_new()) }
+  }
+
+  #[test]
+  fn bus_callbacks() {
+    let mut cpu = new_cpu();
+    let mut device = Device {
+      output: vec![],
+      reads: 0,
+    };
+    let user_data = &mut device as *mut Device as *mut c_void;
+    rust6502_cpu_set_bus(
+      Some(&mut cpu),
+      Some(device_read),
+      Some(device_write),
+      user_data,
+    );
+    // LDA $F000; STA $F001; STA $10; KIL
+    let program = [0xAD, 0x00, 0xF0, 0x8D, 0x01, 0xF0, 0x85, 0x10, 0x02];
+    unsafe { rust6502_cpu_load(Some(&mut cpu), program.as_ptr(), program.len(), 0x0200) };
+    let mut registers = rust6502_cpu_get_registers(Some(&cpu));
+    registers.program_counter = 0x0200;
+    rust6502_cpu_set_registers(Some(&mut cpu), registers);
+    let elapsed = rust6502_cpu_run_cycles(Some(&mut cpu), 1000);
+    assert_eq!(elapsed, 11);
+    assert!(rust6502_cpu_halted(Some(&cpu)));
+    assert_eq!(rust6502_cpu_step(Some(&mut cpu)), 0);
+    assert_eq!(device.output, vec![0x5A]);
+    assert_eq!(device.reads, 1);
+    assert_eq!(rust6502_cpu_read(Some(&cpu), 0x10), 0x5A);
+    assert_eq!(rust6502_cpu_get_cycles(Some(&cpu)), 11);
+  }
+
+  #[test]
+  fn interrupts() {
+    let mut cpu = new_cpu();
+    // main: NOP; NOP; handler: INX; RTI
+    rust6502_cpu_write(Some(&mut cpu), 0x8000, 0xEA);
+    rust6502_cpu_write(Some(&mut cpu), 0x8001, 0xEA);
+    rust6502_cpu_write(Some(&mut cpu), 0x9000, 0xE8);
+    rust6502_cpu_write(Some(&mut cpu), 0x9001, 0x40);
+    rust6502_cpu_write(Some(&mut cpu), 0xFFFB, 0x90);
+    rust6502_cpu_write(Some(&mut cpu), 0xFFFF, 0x90);
+    rust6502_cpu_set_irq(Some(&mut cpu), true);
+    rust6502_cpu_step(Some(&mut cpu));
+    rust6502_cpu_set_irq(Some(&mut cpu), false);
+    rust6502_cpu_step(Some(&mut cpu));
+    rust6502_cpu_set_nmi(Some(&mut cpu));
+    rust6502_cpu_step(Some(&mut cpu));
+    rust6502_cpu_step(Some(&mut cpu));
+    rust6502_cpu_step(Some(&mut cpu));
+    let registers = rust6502_cpu_get_registers(Some(&cpu));
+    assert_eq!(registers.x, 2);
+    assert_eq!(registers.program_counter, 0x8001);
+  }
+
+  #[test]
+  fn null_cpu() {
+    assert_eq!(rust6502_cpu_step(None), 0);
+    assert_eq!(rust6502_cpu_run_cycles(None, 10), 0);
+    assert!(rust6502_cpu_halted(None));
+    assert_eq!(rust6502_cpu_get_registers(None), Registers::default());
+    unsafe { rust6502_cpu_free(std::ptr::null_mut()) };
+  }
+
+  #[test]
+  fn panics_halt_the_cpu() {
+    let mut cpu = new_cpu();
+    // XAA panics inside the emulator.
+    rust6502_cpu_write(Some(&mut cpu), 0x8000, 0x8B);
+    assert_eq!(rust6502_cpu_step(Some(&mut cpu)), 0);
+    assert!(rust6502_cpu_halted(Some(&cpu)));
+  }
+}
@@ -0,0 +1,41 @@
+//! Builds `examples/hello.c` against the shared library and runs it.
+#![cfg(unix)]
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The directory cargo puts the library in, which is the parent of the
+/// `deps` directory holding this test.
+fn library_dir() -> PathBuf {
+  let exe = env::current_exe().unwrap();
+  exe.parent().unwrap().parent().unwrap().to_path_buf()
+}
+
+#[test]
+fn hello() {
+  let manifest = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+  let output = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("hello");
+  let library = library_dir();
+  let compiler = env::var("CC").unwrap_or_else(|_| "cc".to_string());
+  let status = Command::new(compiler)
+    .arg("-std=c99")
+    .arg("-Wall")
+    .arg("-Werror")
+    .arg("-I")
+    .arg(manifest.join("include"))
+    .arg(manifest.join("examples").join("hello.c"))
+    .arg("-o")
+    .arg(&output)
+    .arg("-L")
+    .arg(&library)
+    .arg(format!("-Wl,-rpath,{}", library.display()))
+    .arg("-lrust6502")
+    .status()
+    .expect("Unable to run the C compiler");
+  assert!(status.success());
+  let run = Command::new(&output).output().unwrap();
+  assert!(run.status.success());
+  let stdout = String::from_utf8(run.stdout).unwrap();
+  assert!(stdout.starts_with("*Hello, world!\nPC=020D X=0E cycles="));
+}
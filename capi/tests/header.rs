@@ -0,0 +1,24 @@
+//! Checks the committed header matches the one the build script generates.
+//! Run with `RUST6502_UPDATE_HEADER=1` to refresh the committed copy.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn header_is_current() {
+  let generated = PathBuf::from(env!("OUT_DIR")).join("rust6502.h");
+  let committed = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+    .join("include")
+    .join("rust6502.h");
+  let expected = fs::read_to_string(&generated).unwrap();
+  if env::var_os("RUST6502_UPDATE_HEADER").is_some() {
+    fs::write(&committed, &expected).unwrap();
+  }
+  let actual = fs::read_to_string(&committed).unwrap();
+  assert!(
+    actual == expected,
+    "{} is out of date, run the tests with RUST6502_UPDATE_HEADER=1",
+    committed.display()
+  );
+}
@@ -0,0 +1,422 @@
+//! Python bindings for testing 6502 code.
+//!
+//! Build and install the `rust6502` module into a virtualenv with
+//! `maturin develop -m python/Cargo.toml`, then drive it from pytest:
+//!
+//! ```python
+//! from rust6502 import CPU
+//!
+//! def test_double():
+//!     cpu = CPU()
+//!     cpu.assemble("double: ASL\n RTS")
+//!     cpu.accumulator = 21
+//!     cpu.call(cpu.symbol("double"))
+//!     assert cpu.accumulator == 42
+//! ```
+
+use pyo3::exceptions::{PyKeyError, PyValueError};
+use pyo3::prelude::*;
+use rust6502lib::assembler::{self, Assembly};
+use rust6502lib::clock::FreeRunning;
+use rust6502lib::disassembler::{self, OPCODES};
+use rust6502lib::loader::{Format, Image};
+use rust6502lib::symbols::SymbolTable;
+use rust6502lib::{Memory, Registers, CPU};
+use std::collections::BTreeSet;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Why `run` or `call` stopped.
+#[pyclass(eq, eq_int, from_py_object)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+  /// The program counter reached a breakpoint.
+  Breakpoint,
+  /// The next instruction is a KIL opcode.
+  Halted,
+  /// The cycle budget ran out.
+  Cycles,
+  /// The called subroutine returned.
+  Returned,
+}
+
+/// A free running CPU with 64K of memory.
+#[pyclass(name = "CPU")]
+pub struct Cpu {
+  /// Behind a mutex so the class is Sync, as Python objects can be shared
+  /// between threads. Methods taking `&mut self` skip the lock.
+  cpu: Mutex<CPU>,
+  breakpoints: BTreeSet<u16>,
+}
+
+#[pymethods]
+impl Cpu {
+  /// Creates a CPU with empty memory and the program counter at $8000.
+  #[new]
+  fn new() -> Cpu {
+    Cpu {
+      cpu: Mutex::new(CPU::new(FreeRunning)),
+      breakpoints: BTreeSet::new(),
+    }
+  }
+
+  #[getter]
+  fn get_accumulator(&self) -> u8 {
+    self.cpu().get_registers().accumulator
+  }
+
+  #[setter]
+  fn set_accumulator(&mut self, value: u8) {
+    self.update(|r| r.accumulator = value);
+  }
+
+  #[getter]
+  fn get_x(&self) -> u8 {
+    self.cpu().get_registers().x
+  }
+
+  #[setter]
+  fn set_x(&mut self, value: u8) {
+    self.update(|r| r.x = value);
+  }
+
+  #[getter]
+  fn get_y(&self) -> u8 {
+    self.cpu().get_registers().y
+  }
+
+  #[setter]
+  fn set_y(&mut self, value: u8) {
+    self.update(|r| r.y = value);
+  }
+
+  #[getter]
+  fn get_stack_pointer(&self) -> u8 {
+    self.cpu().get_registers().stack_pointer
+  }
+
+  #[setter]
+  fn set_stack_pointer(&mut self, value: u8) {
+    self.update(|r| r.stack_pointer = value);
+  }
+
+  #[getter]
+  fn get_status(&self) -> u8 {
+    self.cpu().get_registers().status
+  }
+
+  #[setter]
+  fn set_status(&mut self, value: u8) {
+    self.update(|r| r.status = value);
+  }
+
+  #[getter]
+  fn get_program_counter(&self) -> u16 {
+    self.cpu().get_registers().program_counter
+  }
+
+  #[setter]
+  fn set_program_counter(&mut self, value: u16) {
+    self.update(|r| r.program_counter = value);
+  }
+
+  /// The number of cycles run since the CPU was created.
+  #[getter]
+  fn get_cycles(&self) -> u64 {
+    self.cpu().get_cycles()
+  }
+
+  /// True if the next instruction is a KIL opcode, or the program has exited
+  /// through a trap.
+  #[getter]
+  fn get_halted(&self) -> bool {
+    self.cpu().is_halted()
+  }
+
+  /// Reads a byte of memory without costing cycles.
+  fn read(&self, address: u16) -> u8 {
+    self.cpu().get_memory().peek(address)
+  }
+
+  /// Reads a range of memory, wrapping around at the top of the address
+  /// space.
+  fn read_range(&self, start: u16, length: usize) -> Vec<u8> {
+    let cpu = self.cpu();
+    let memory = cpu.get_memory();
+    (0..length.min(0x10000))
+      .map(|i| memory.peek(start.wrapping_add(i as u16)))
+      .collect()
+  }
+
+  /// Reads a little endian word.
+  fn read_word(&self, address: u16) -> u16 {
+    u16::from_le_bytes([self.read(address), self.read(address.wrapping_add(1))])
+  }
+
+  /// Writes a byte of memory without costing cycles.
+  fn write(&mut self, address: u16, value: u8) {
+    self.cpu_mut().get_memory_mut().poke(address, value);
+  }
+
+  /// Writes bytes into memory starting at an address, wrapping around at the
+  /// top of the address space.
+  fn write_range(&mut self, start: u16, data: &[u8]) {
+    let memory = self.cpu_mut().get_memory_mut();
+    for (offset, byte) in data.iter().enumerate() {
+      memory.poke(start.wrapping_add(offset as u16), *byte);
+    }
+  }
+
+  /// Writes a little endian word.
+  fn write_word(&mut self, address: u16, value: u16) {
+    let [lo, hi] = value.to_le_bytes();
+    self.write(address, lo);
+    self.write(address.wrapping_add(1), hi);
+  }
+
+  /// Copies a program into memory at an address and points the program
+  /// counter at it.
+  fn load(&mut self, program: &[u8], address: u16) {
+    self.write_range(address, program);
+    self.set_program_counter(address);
+  }
+
+  /// Parses an image in the format named by a file extension, such as "hex"
+  /// or "prg", loads it and points the program counter at its entry point.
+  /// Raw binaries are loaded at `address`. Returns the entry point.
+  #[pyo3(signature = (data, extension, address = 0x8000))]
+  fn load_image(&mut self, data: &[u8], extension: &str, address: u16) -> PyResult<u16> {
+    let format = Format::from_extension(extension);
+    let image = Image::parse(data, format, Some(address)).map_err(value_error)?;
+    Ok(self.cpu_mut().load_image(&image))
+  }
+
+  /// Loads symbols in the format named by a file extension, such as "sym",
+  /// "lbl" or "dbg", for use in disassembly and `symbol`.
+  fn load_symbols(&mut self, text: &str, extension: &str) -> PyResult<()> {
+    let symbols = SymbolTable::parse(text, extension).map_err(value_error)?;
+    self.cpu_mut().set_symbols(symbols);
+    Ok(())
+  }
+
+  /// Assembles source text, loads it and points the program counter at the
+  /// start. Its labels replace any symbols loaded before. Raises ValueError
+  /// with the line number if the source doesn't assemble.
+  #[pyo3(signature = (source, origin = 0x8000, file = "<string>"))]
+  fn assemble(&mut self, source: &str, origin: u16, file: &str) -> PyResult<u16> {
+    let Assembly { image, symbols } =
+      assembler::assemble(source, file, origin).map_err(value_error)?;
+    let entry = self.cpu_mut().load_image(&image);
+    self.cpu_mut().set_symbols(symbols);
+    Ok(entry)
+  }
+
+  /// Looks up the address of a symbol. Raises KeyError if there is none.
+  fn symbol(&self, name: &str) -> PyResult<u16> {
+    self
+      .cpu()
+      .get_symbols()
+      .and_then(|symbols| symbols.get_address(name))
+      .ok_or_else(|| PyKeyError::new_err(name.to_string()))
+  }
+
+  /// Disassembles a number of instructions starting at an address, one per
+  /// line.
+  #[pyo3(signature = (start, count = 1))]
+  fn disassemble(&self, start: u16, count: usize) -> String {
+    self.cpu().disassemble(start, count)
+  }
+
+  /// Executes a single instruction. Returns the number of cycles it took, or
+  /// zero if the CPU is halted.
+  fn step(&mut self) -> u32 {
+    if self.get_halted() {
+      return 0;
+    }
+    let start = self.cpu_mut().get_cycles();
+    self.cpu_mut().step();
+    (self.cpu_mut().get_cycles() - start) as u32
+  }
+
+  /// Stops `run` and `call` before the instruction at an address.
+  fn add_breakpoint(&mut self, address: u16) {
+    self.breakpoints.insert(address);
+  }
+
+  /// Removes a breakpoint. Returns False if there was none at the address.
+  fn remove_breakpoint(&mut self, address: u16) -> bool {
+    self.breakpoints.remove(&address)
+  }
+
+  /// The addresses with a breakpoint, in order.
+  #[getter]
+  fn get_breakpoints(&self) -> Vec<u16> {
+    self.breakpoints.iter().copied().collect()
+  }
+
+  /// Runs until a breakpoint, a KIL opcode, or until at least `max_cycles`
+  /// have passed. A breakpoint at the starting address is stepped over.
+  #[pyo3(signature = (max_cycles = 1_000_000))]
+  fn run(&mut self, max_cycles: u64) -> StopReason {
+    self.run_until(max_cycles, |_| false)
+  }
+
+  /// Calls a subroutine as if with JSR and runs it until it returns. Stops
+  /// early at a breakpoint, a KIL opcode, or once `max_cycles` have passed.
+  #[pyo3(signature = (address, max_cycles = 1_000_000))]
+  fn call(&mut self, address: u16, max_cycles: u64) -> StopReason {
+    let return_address = self.get_program_counter();
+    let stack_pointer = self.get_stack_pointer();
+    self.cpu_mut().call_subroutine(address);
+    self.run_until(max_cycles, |cpu| {
+      let registers = cpu.cpu().get_registers();
+      registers.program_counter == return_address && registers.stack_pointer == stack_pointer
+    })
+  }
+
+  /// Holds or releases the IRQ line.
+  fn set_irq(&mut self, asserted: bool) {
+    match asserted {
+      true => self.cpu_mut().set_irq(),
+      false => self.cpu_mut().clear_irq(),
+    }
+  }
+
+  /// Signals a non-maskable interrupt, taken before the next instruction.
+  fn nmi(&mut self) {
+    self.cpu_mut().set_nmi();
+  }
+
+  fn __repr__(&self) -> String {
+    let r = self.cpu().get_registers();
+    format!(
+      "CPU(pc=${:04X}, a=${:02X}, x=${:02X}, y=${:02X}, sp=${:02X}, status=${:02X})",
+      r.program_counter, r.accumulator, r.x, r.y, r.stack_pointer, r.status
+    )
+  }
+}
+
+impl Cpu {
+  /// Locks the CPU for reading. A panic while it was held leaves it usable.
+  fn cpu(&self) -> MutexGuard<'_, CPU> {
+    self.cpu.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Borrows the CPU without locking.
+  fn cpu_mut(&mut self) -> &mut CPU {
+    self.cpu.get_mut().unwrap_or_else(PoisonError::into_inner)
+  }
+
+  /// Changes a single register.
+  fn update(&mut self, change: impl FnOnce(&mut Registers)) {
+    let mut registers = self.cpu_mut().get_registers();
+    change(&mut registers);
+    self.cpu_mut().set_registers(registers);
+  }
+
+  /// Steps until a stop condition is met or the cycle budget runs out.
+  fn run_until(&mut self, max_cycles: u64, done: impl Fn(&Cpu) -> bool) -> StopReason {
+    let mut elapsed = 0;
+    let mut first = true;
+    loop {
+      if done(self) {
+        return StopReason::Returned;
+      }
+      if !first && self.breakpoints.contains(&self.get_program_counter()) {
+        return StopReason::Breakpoint;
+      }
+      if elapsed >= max_cycles {
+        return StopReason::Cycles;
+      }
+      match self.step() {
+        0 => return StopReason::Halted,
+        taken => elapsed += taken as u64,
+      }
+      first = false;
+    }
+  }
+}
+
+/// Assembles source text without a CPU. Returns a list of `(address, bytes)`
+/// segments. Raises ValueError with the line number if the source doesn't
+/// assemble.
+#[pyfunction]
+#[pyo3(signature = (source, origin = 0x8000))]
+fn assemble(source: &str, origin: u16) -> PyResult<Vec<(u16, Vec<u8>)>> {
+  let assembly = assembler::assemble(source, "<string>", origin).map_err(value_error)?;
+  Ok(
+    assembly
+      .image
+      .get_segments()
+      .iter()
+      .map(|segment| (segment.address, segment.data.clone()))
+      .collect(),
+  )
+}
+
+/// Disassembles machine code loaded at an address into a listing, one
+/// instruction per line.
+#[pyfunction]
+#[pyo3(signature = (code, address = 0x8000))]
+fn disassemble(code: &[u8], address: u16) -> String {
+  let mut memory = Memory::new();
+  for (offset, byte) in code.iter().enumerate() {
+    memory.poke(address.wrapping_add(offset as u16), *byte);
+  }
+  let mut count = 0;
+  let mut offset = 0;
+  while offset < code.len() {
+    let opcode = code[offset];
+    offset += OPCODES[opcode as usize].1.size() as usize;
+    count += 1;
+  }
+  disassembler::listing(&memory, address, count, None)
+}
+
+fn value_error(error: impl ToString) -> PyErr {
+  PyValueError::new_err(error.to_string())
+}
+
+/// The `rust6502` Python module.
+#[pymodule]
+fn rust6502(m: &Bound<'_, PyModule>) -> PyResult<()> {
+  m.add_class::<Cpu>()?;
+  m.add_class::<StopReason>()?;
+  m.add_function(wrap_pyfunction!(assemble, m)?)?;
+  m.add_function(wrap_pyfunction!(disassemble, m)?)?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use pyo3::types::PyDict;
+  use std::ffi::CString;
+  use std::sync::Once;
+
+  /// Runs every `test_` function in the pytest file against an embedded
+  /// interpreter, so the bindings are tested without pytest installed.
+  #[test]
+  fn python_tests() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+      pyo3::append_to_inittab!(rust6502);
+      Python::initialize();
+    });
+    let source = CString::new(include_str!("../tests/test_rust6502.py")).unwrap();
+    Python::attach(|py| {
+      let globals = PyDict::new(py);
+      py.run(&source, Some(&globals), None).unwrap();
+      let mut ran = 0;
+      for (name, test) in globals.iter() {
+        let name: String = name.extract().unwrap();
+        if name.starts_with("test_") {
+          if let Err(error) = test.call0() {
+            panic!("{} failed: {}", name, error);
+          }
+          ran += 1;
+        }
+      }
+      assert_eq!(ran, 7);
+    });
+  }
+}
@@ -0,0 +1,134 @@
+"""Tests for the Python bindings. Run with pytest once the module is built
+with `maturin develop -m python/Cargo.toml`."""
+
+from rust6502 import CPU, StopReason, assemble, disassemble
+
+MULTIPLY = """
+; Multiplies the numbers at num1 and num2, leaving the 16 bit product in
+; the accumulator (high byte) and result (low byte).
+num1 = $10
+num2 = $11
+result = $12
+
+multiply:
+        LDA #0
+        STA result
+        LDX #8
+loop:   LSR num2
+        BCC skip
+        CLC
+        ADC num1
+skip:   ROR
+        ROR result
+        DEX
+        BNE loop
+        RTS
+"""
+
+
+def test_registers_and_memory():
+    cpu = CPU()
+    cpu.accumulator = 1
+    cpu.x = 2
+    cpu.y = 3
+    cpu.stack_pointer = 0xF0
+    cpu.status = 0x81
+    cpu.program_counter = 0x1234
+    assert (cpu.accumulator, cpu.x, cpu.y) == (1, 2, 3)
+    assert (cpu.stack_pointer, cpu.status, cpu.program_counter) == (0xF0, 0x81, 0x1234)
+    cpu.write_word(0xFFFF, 0xBBAA)
+    assert cpu.read(0x0000) == 0xBB
+    assert cpu.read_range(0xFFFF, 2) == b"\xaa\xbb"
+    cpu.write_range(0x0200, b"\x01\x02")
+    assert cpu.read_word(0x0200) == 0x0201
+    assert repr(cpu).startswith("CPU(pc=$1234")
+
+
+def test_call_routine():
+    cpu = CPU()
+    cpu.assemble(MULTIPLY, origin=0x0300)
+    for a, b in [(0, 0), (3, 7), (255, 255), (16, 16)]:
+        cpu.write(cpu.symbol("num1"), a)
+        cpu.write(cpu.symbol("num2"), b)
+        assert cpu.call(cpu.symbol("multiply")) == StopReason.Returned
+        assert cpu.accumulator * 256 + cpu.read(cpu.symbol("result")) == a * b
+
+
+def test_step_and_halt():
+    cpu = CPU()
+    cpu.load(bytes([0xA9, 0x42, 0x02]), 0x0200)
+    assert cpu.step() == 2
+    assert cpu.accumulator == 0x42
+    assert cpu.halted
+    assert cpu.step() == 0
+    assert cpu.run() == StopReason.Halted
+    assert cpu.cycles == 2
+
+
+def test_breakpoints():
+    cpu = CPU()
+    cpu.assemble("start: INX\nhere: INX\n JMP start")
+    cpu.add_breakpoint(cpu.symbol("here"))
+    assert cpu.breakpoints == [0x8001]
+    assert cpu.run() == StopReason.Breakpoint
+    assert cpu.program_counter == 0x8001
+    assert cpu.x == 1
+    assert cpu.run() == StopReason.Breakpoint
+    assert cpu.x == 3
+    assert cpu.remove_breakpoint(0x8001)
+    assert not cpu.remove_breakpoint(0x8001)
+    assert cpu.run(max_cycles=100) == StopReason.Cycles
+
+
+def test_interrupts():
+    cpu = CPU()
+    cpu.assemble(
+        """
+        main:    NOP
+                 NOP
+                 .org $9000
+        handler: INX
+                 RTI
+                 .org $FFFA
+                 .word handler, main, handler
+        """
+    )
+    assert cpu.program_counter == 0x8000
+    cpu.set_irq(True)
+    cpu.step()
+    cpu.set_irq(False)
+    cpu.step()
+    cpu.nmi()
+    cpu.step()
+    cpu.step()
+    assert cpu.program_counter == 0x8000
+    assert cpu.x == 2
+
+
+def test_assembler_and_disassembler():
+    segments = assemble("LDA #$10\nSTA $0200\n.org $9000\nRTS", origin=0x8000)
+    assert segments == [(0x8000, b"\xa9\x10\x8d\x00\x02"), (0x9000, b"\x60")]
+    listing = disassemble(segments[0][1], 0x8000)
+    assert listing == "8000  A9 10     LDA #$10\n8002  8D 00 02  STA $0200\n"
+    cpu = CPU()
+    cpu.assemble("start: JMP start", file="loop.s")
+    listing = cpu.disassemble(0x8000).splitlines()
+    assert listing[0] == "start:"
+    assert listing[1].startswith("8000  4C 00 80  JMP start")
+    assert listing[1].endswith("; loop.s:1")
+
+
+def test_assembler_errors():
+    cpu = CPU()
+    try:
+        cpu.assemble("NOP\nJMP nowhere")
+    except ValueError as error:
+        assert str(error) == "undefined symbol nowhere on line 2"
+    else:
+        assert False, "expected a ValueError"
+    try:
+        cpu.symbol("nowhere")
+    except KeyError:
+        pass
+    else:
+        assert False, "expected a KeyError"
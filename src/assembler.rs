@@ -0,0 +1,703 @@
+use crate::disassembler::{Mode, OPCODES};
+use crate::loader::Image;
+use crate::prelude::*;
+use crate::symbols::SymbolTable;
+use alloc::collections::BTreeMap;
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// Things that can go wrong while assembling.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AssembleError {
+  /// A line could not be parsed. Holds the line number and what was wrong.
+  Syntax(usize, String),
+  /// A symbol was used but never defined. Holds the line number and name.
+  UndefinedSymbol(usize, String),
+  /// A symbol was defined twice. Holds the line number and name.
+  DuplicateSymbol(usize, String),
+  /// A value does not fit where it is used, such as a zero page operand over
+  /// $FF or a branch too far away. Holds the line number and the value.
+  OutOfRange(usize, i32),
+}
+
+impl Display for AssembleError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      AssembleError::Syntax(line, message) => {
+        write!(f, "syntax error on line {}: {}", line, message)
+      }
+      AssembleError::UndefinedSymbol(line, name) => {
+        write!(f, "undefined symbol {} on line {}", name, line)
+      }
+      AssembleError::DuplicateSymbol(line, name) => {
+        write!(f, "symbol {} defined again on line {}", name, line)
+      }
+      AssembleError::OutOfRange(line, value) => {
+        write!(f, "value {} is out of range on line {}", value, line)
+      }
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for AssembleError {}
+
+/// The output of the assembler: the bytes to load and the labels and source
+/// lines that produced them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Assembly {
+  pub image: Image,
+  pub symbols: SymbolTable,
+}
+
+/// Assembles source text into an image, starting at an origin.
+///
+/// The syntax is the one the disassembler prints, so a listing can be fed
+/// straight back in. On top of instructions it understands:
+///
+/// - `label:` on its own or in front of a statement
+/// - `name = expression` to define a constant
+/// - `.org expression` or `*= expression` to move the origin, which starts a
+///   new segment
+/// - `.byte` or `.db` with numbers and "strings", and `.word` or `.dw`
+/// - `;` comments
+///
+/// Numbers are decimal, `$hex`, `%binary` or a `'c'` character. Expressions
+/// add and subtract numbers, symbols and `*`, the current address, and can be
+/// prefixed with `<` or `>` to take the low or high byte. Every mnemonic the
+/// disassembler knows is accepted, undocumented ones included. Zero page
+/// addressing is picked whenever the operand is known to fit by the time the
+/// line is reached.
+pub fn assemble(source: &str, file: &str, origin: u16) -> Result<Assembly, AssembleError> {
+  let lines = source
+    .lines()
+    .enumerate()
+    .map(|(i, text)| Line::parse(i + 1, text))
+    .collect::<Result<Vec<_>, _>>()?;
+  let mut symbols = BTreeMap::new();
+  let modes = first_pass(&lines, origin, &mut symbols)?;
+  let assembly = second_pass(&lines, &modes, file, origin, &symbols)?;
+  debug!(
+    "Assembled {} lines into {} segments",
+    lines.len(),
+    assembly.image.get_segments().len()
+  );
+  Ok(assembly)
+}
+
+/// A statement on a line of source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Statement<'a> {
+  Empty,
+  Origin(&'a str),
+  Constant(&'a str, &'a str),
+  Bytes(Vec<&'a str>),
+  Words(Vec<&'a str>),
+  Instruction(String, String),
+}
+
+/// A parsed line of source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Line<'a> {
+  number: usize,
+  label: Option<&'a str>,
+  statement: Statement<'a>,
+}
+
+impl<'a> Line<'a> {
+  fn parse(number: usize, text: &'a str) -> Result<Line<'a>, AssembleError> {
+    let syntax = |message: String| AssembleError::Syntax(number, message);
+    let mut text = strip_comment(text).trim();
+    let mut label = None;
+    if let Some(colon) = text.find(':') {
+      let name = text[..colon].trim();
+      if is_identifier(name) {
+        label = Some(name);
+        text = text[colon + 1..].trim();
+      }
+    }
+    let (word, rest) = match text.find(char::is_whitespace) {
+      Some(i) => (&text[..i], text[i..].trim()),
+      None => (text, ""),
+    };
+    let statement = if text.is_empty() {
+      Statement::Empty
+    } else if let Some(expression) = text.strip_prefix("*=") {
+      Statement::Origin(expression.trim())
+    } else if let Some((name, expression)) = constant(text) {
+      Statement::Constant(name, expression)
+    } else if word.starts_with('.') {
+      match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Origin(rest),
+        ".byte" | ".db" => Statement::Bytes(split_list(rest)),
+        ".word" | ".dw" => Statement::Words(split_list(rest)),
+        _ => return Err(syntax(format!("unknown directive {}", word))),
+      }
+    } else {
+      let mnemonic = word.to_ascii_uppercase();
+      if !OPCODES.iter().any(|(m, _)| *m == mnemonic) {
+        return Err(syntax(format!("unknown mnemonic {}", word)));
+      }
+      Statement::Instruction(mnemonic, squeeze(rest))
+    };
+    Ok(Line {
+      number,
+      label,
+      statement,
+    })
+  }
+}
+
+/// Splits a `name = expression` line.
+fn constant(text: &str) -> Option<(&str, &str)> {
+  let (name, expression) = text.split_once('=')?;
+  match is_identifier(name.trim()) {
+    true => Some((name.trim(), expression.trim())),
+    false => None,
+  }
+}
+
+/// Works out the address of every label and the addressing mode of every
+/// instruction. Returns the mode picked for each line.
+fn first_pass(
+  lines: &[Line],
+  origin: u16,
+  symbols: &mut BTreeMap<String, u16>,
+) -> Result<Vec<Option<Mode>>, AssembleError> {
+  let mut address = origin as u32;
+  let mut modes = Vec::with_capacity(lines.len());
+  for line in lines {
+    let number = line.number;
+    if let Some(label) = line.label {
+      define(symbols, label, address as u16, number)?;
+    }
+    let mut mode = None;
+    let size = match &line.statement {
+      Statement::Empty => 0,
+      Statement::Origin(expression) => {
+        address = required(expression, symbols, address, number)? as u32;
+        0
+      }
+      Statement::Constant(name, expression) => {
+        let value = required(expression, symbols, address, number)?;
+        define(symbols, name, value as u16, number)?;
+        0
+      }
+      Statement::Bytes(items) => items.iter().map(|item| byte_items(item).len() as u32).sum(),
+      Statement::Words(items) => 2 * items.len() as u32,
+      Statement::Instruction(mnemonic, operand) => {
+        let picked = pick_mode(mnemonic, operand, symbols, address, number)?;
+        mode = Some(picked);
+        picked.size() as u32
+      }
+    };
+    if address + size > 0x10000 {
+      return Err(AssembleError::OutOfRange(number, (address + size) as i32));
+    }
+    address += size;
+    modes.push(mode);
+  }
+  Ok(modes)
+}
+
+/// Emits the bytes for every line using the modes from the first pass.
+fn second_pass(
+  lines: &[Line],
+  modes: &[Option<Mode>],
+  file: &str,
+  origin: u16,
+  symbols: &BTreeMap<String, u16>,
+) -> Result<Assembly, AssembleError> {
+  let mut assembly = Assembly::default();
+  for (name, address) in symbols.iter() {
+    assembly.symbols.add(name, *address, None);
+  }
+  let mut start = origin as u32;
+  let mut data = vec![];
+  for (line, mode) in lines.iter().zip(modes.iter()) {
+    let number = line.number;
+    let address = start + data.len() as u32;
+    let before = data.len();
+    match &line.statement {
+      Statement::Empty | Statement::Constant(..) => (),
+      Statement::Origin(expression) => {
+        let image = &mut assembly.image;
+        let segment = core::mem::take(&mut data);
+        image
+          .add_segment(start, segment)
+          .map_err(|_| AssembleError::OutOfRange(number, start as i32))?;
+        start = required(expression, symbols, address, number)? as u32;
+      }
+      Statement::Bytes(items) => {
+        for item in items {
+          match byte_items(item) {
+            ByteItem::Text(text) => data.extend_from_slice(text),
+            ByteItem::Value(expression) => {
+              let value = required(expression, symbols, address, number)?;
+              data.push(fit_byte(value, number)?);
+            }
+          }
+        }
+      }
+      Statement::Words(items) => {
+        for item in items {
+          let value = required(item, symbols, address, number)?;
+          if !(-0x8000..=0xFFFF).contains(&value) {
+            return Err(AssembleError::OutOfRange(number, value));
+          }
+          data.extend_from_slice(&(value as u16).to_le_bytes());
+        }
+      }
+      Statement::Instruction(mnemonic, operand) => {
+        let mode = mode.expect("every instruction has a mode after the first pass");
+        let opcode = find_opcode(mnemonic, mode).expect("the first pass checked the opcode");
+        data.push(opcode);
+        let expression = operand_expression(operand, mode);
+        match mode {
+          Mode::Implied | Mode::Accumulator => (),
+          Mode::Relative => {
+            let target = required(expression, symbols, address, number)?;
+            let offset = target - (address as i32 + 2);
+            if !(-128..=127).contains(&offset) {
+              return Err(AssembleError::OutOfRange(number, offset));
+            }
+            data.push(offset as u8);
+          }
+          Mode::Immediate => {
+            let value = required(expression, symbols, address, number)?;
+            data.push(fit_byte(value, number)?);
+          }
+          _ if mode.size() == 2 => {
+            let value = required(expression, symbols, address, number)?;
+            if !(0..=0xFF).contains(&value) {
+              return Err(AssembleError::OutOfRange(number, value));
+            }
+            data.push(value as u8);
+          }
+          _ => {
+            let value = required(expression, symbols, address, number)?;
+            if !(0..=0xFFFF).contains(&value) {
+              return Err(AssembleError::OutOfRange(number, value));
+            }
+            data.extend_from_slice(&(value as u16).to_le_bytes());
+          }
+        }
+      }
+    }
+    if data.len() > before {
+      let size = (data.len() - before) as u16;
+      assembly
+        .symbols
+        .add_line(file, number, address as u16, size);
+    }
+  }
+  assembly
+    .image
+    .add_segment(start, data)
+    .map_err(|_| AssembleError::OutOfRange(lines.len(), start as i32))?;
+  Ok(assembly)
+}
+
+/// Picks the addressing mode for an instruction from the shape of its
+/// operand, preferring zero page when the value is already known to fit.
+fn pick_mode(
+  mnemonic: &str,
+  operand: &str,
+  symbols: &BTreeMap<String, u16>,
+  address: u32,
+  number: usize,
+) -> Result<Mode, AssembleError> {
+  let upper = operand.to_ascii_uppercase();
+  let candidates: &[Mode] = if operand.is_empty() {
+    &[Mode::Implied, Mode::Accumulator]
+  } else if upper == "A" {
+    &[Mode::Accumulator]
+  } else if operand.starts_with('#') {
+    &[Mode::Immediate]
+  } else if operand.starts_with('(') && upper.ends_with(",X)") {
+    &[Mode::IndexedX]
+  } else if operand.starts_with('(') && upper.ends_with("),Y") {
+    &[Mode::IndexedY]
+  } else if operand.starts_with('(') && operand.ends_with(')') {
+    &[Mode::Indirect]
+  } else if upper.ends_with(",X") {
+    &[Mode::ZeroPageX, Mode::AbsoluteX]
+  } else if upper.ends_with(",Y") {
+    &[Mode::ZeroPageY, Mode::AbsoluteY]
+  } else {
+    &[Mode::Relative, Mode::ZeroPage, Mode::Absolute]
+  };
+  let available: Vec<Mode> = candidates
+    .iter()
+    .copied()
+    .filter(|mode| find_opcode(mnemonic, *mode).is_some())
+    .collect();
+  let mode = match available.as_slice() {
+    [] => {
+      return Err(AssembleError::Syntax(
+        number,
+        format!("{} can't take the operand {}", mnemonic, operand),
+      ))
+    }
+    [mode] => *mode,
+    [zero_page, absolute, ..] => {
+      let expression = operand_expression(operand, *zero_page);
+      match evaluate(expression, symbols, address) {
+        Ok(value) if (0..=0xFF).contains(&value) => *zero_page,
+        Ok(_) | Err(Undefined::Symbol(_)) => *absolute,
+        Err(Undefined::Syntax(message)) => return Err(AssembleError::Syntax(number, message)),
+      }
+    }
+  };
+  Ok(mode)
+}
+
+/// Finds the opcode for a mnemonic in an addressing mode. Where the
+/// undocumented opcodes repeat a documented one, the documented one wins.
+fn find_opcode(mnemonic: &str, mode: Mode) -> Option<u8> {
+  if mnemonic == "NOP" && mode == Mode::Implied {
+    return Some(0xEA);
+  }
+  OPCODES
+    .iter()
+    .position(|(m, o)| *m == mnemonic && *o == mode)
+    .map(|opcode| opcode as u8)
+}
+
+/// Strips the addressing mode syntax off an operand, leaving the expression.
+fn operand_expression(operand: &str, mode: Mode) -> &str {
+  match mode {
+    Mode::Immediate => &operand[1..],
+    Mode::IndexedX => &operand[1..operand.len() - 3],
+    Mode::IndexedY => &operand[1..operand.len() - 3],
+    Mode::Indirect => &operand[1..operand.len() - 1],
+    Mode::ZeroPageX | Mode::ZeroPageY | Mode::AbsoluteX | Mode::AbsoluteY => {
+      &operand[..operand.len() - 2]
+    }
+    _ => operand,
+  }
+}
+
+/// Why an expression has no value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Undefined {
+  /// It names a symbol that isn't defined yet.
+  Symbol(String),
+  /// It could not be parsed.
+  Syntax(String),
+}
+
+/// Evaluates an expression. `*` is the address of the current line.
+fn evaluate(
+  expression: &str,
+  symbols: &BTreeMap<String, u16>,
+  address: u32,
+) -> Result<i32, Undefined> {
+  let expression = expression.trim();
+  if let Some(rest) = expression.strip_prefix('<') {
+    return evaluate(rest, symbols, address).map(|value| value & 0xFF);
+  }
+  if let Some(rest) = expression.strip_prefix('>') {
+    return evaluate(rest, symbols, address).map(|value| (value >> 8) & 0xFF);
+  }
+  let mut total = 0;
+  let mut sign = 1;
+  let mut rest = expression;
+  loop {
+    let rest_trimmed = rest.trim_start();
+    let (negative, term_text) = match rest_trimmed.strip_prefix('-') {
+      Some(after) => (true, after.trim_start()),
+      None => (false, rest_trimmed),
+    };
+    let end = term_end(term_text);
+    let value = term(&term_text[..end], symbols, address)?;
+    total += sign * if negative { -value } else { value };
+    rest = term_text[end..].trim_start();
+    match rest.chars().next() {
+      None => return Ok(total),
+      Some('+') => sign = 1,
+      Some('-') => sign = -1,
+      Some(c) => {
+        return Err(Undefined::Syntax(format!(
+          "unexpected {} in {}",
+          c, expression
+        )))
+      }
+    }
+    rest = &rest[1..];
+  }
+}
+
+/// Finds where the term at the start of some text ends.
+fn term_end(text: &str) -> usize {
+  if text.starts_with('\'') {
+    return text.char_indices().nth(3).map_or(text.len(), |(i, _)| i);
+  }
+  if text.starts_with('*') {
+    return 1;
+  }
+  let skip = match text.starts_with('$') || text.starts_with('%') {
+    true => 1,
+    false => 0,
+  };
+  text[skip..]
+    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+    .map_or(text.len(), |i| i + skip)
+}
+
+/// Evaluates a single number, character or symbol.
+fn term(text: &str, symbols: &BTreeMap<String, u16>, address: u32) -> Result<i32, Undefined> {
+  let bad = || Undefined::Syntax(format!("bad value {}", text));
+  let radix = |digits: &str, radix: u32| i32::from_str_radix(digits, radix).map_err(|_| bad());
+  if let Some(hex) = text.strip_prefix('$') {
+    radix(hex, 16)
+  } else if let Some(binary) = text.strip_prefix('%') {
+    radix(binary, 2)
+  } else if text == "*" {
+    Ok(address as i32)
+  } else if text.len() == 3 && text.starts_with('\'') && text.ends_with('\'') {
+    Ok(text.as_bytes()[1] as i32)
+  } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+    radix(text, 10)
+  } else if is_identifier(text) {
+    match symbols.get(text) {
+      Some(value) => Ok(*value as i32),
+      None => Err(Undefined::Symbol(text.to_string())),
+    }
+  } else {
+    Err(bad())
+  }
+}
+
+/// Evaluates an expression that must have a value by now.
+fn required(
+  expression: &str,
+  symbols: &BTreeMap<String, u16>,
+  address: u32,
+  number: usize,
+) -> Result<i32, AssembleError> {
+  evaluate(expression, symbols, address).map_err(|e| match e {
+    Undefined::Symbol(name) => AssembleError::UndefinedSymbol(number, name),
+    Undefined::Syntax(message) => AssembleError::Syntax(number, message),
+  })
+}
+
+/// Adds a symbol, refusing to redefine one.
+fn define(
+  symbols: &mut BTreeMap<String, u16>,
+  name: &str,
+  value: u16,
+  number: usize,
+) -> Result<(), AssembleError> {
+  if symbols.insert(name.to_string(), value).is_some() {
+    return Err(AssembleError::DuplicateSymbol(number, name.to_string()));
+  }
+  Ok(())
+}
+
+/// Checks a value fits in a byte, allowing negative numbers down to -128.
+fn fit_byte(value: i32, number: usize) -> Result<u8, AssembleError> {
+  match (-128..=0xFF).contains(&value) {
+    true => Ok(value as u8),
+    false => Err(AssembleError::OutOfRange(number, value)),
+  }
+}
+
+/// An item in a `.byte` list.
+enum ByteItem<'a> {
+  Text(&'a [u8]),
+  Value(&'a str),
+}
+
+impl ByteItem<'_> {
+  fn len(&self) -> usize {
+    match self {
+      ByteItem::Text(text) => text.len(),
+      ByteItem::Value(_) => 1,
+    }
+  }
+}
+
+fn byte_items(item: &str) -> ByteItem<'_> {
+  match item.len() >= 2 && item.starts_with('"') && item.ends_with('"') {
+    true => ByteItem::Text(&item.as_bytes()[1..item.len() - 1]),
+    false => ByteItem::Value(item),
+  }
+}
+
+/// Returns true if some text can be used as a symbol name.
+fn is_identifier(text: &str) -> bool {
+  let mut chars = text.chars();
+  match chars.next() {
+    Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+      chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+    }
+    _ => false,
+  }
+}
+
+/// Cuts a comment off a line, leaving semicolons inside quotes alone.
+fn strip_comment(text: &str) -> &str {
+  let mut quote = None;
+  for (i, c) in text.char_indices() {
+    match (quote, c) {
+      (None, ';') => return &text[..i],
+      (None, '"') | (None, '\'') => quote = Some(c),
+      (Some(q), c) if q == c => quote = None,
+      _ => (),
+    }
+  }
+  text
+}
+
+/// Splits a comma separated list, leaving commas inside quotes alone.
+fn split_list(text: &str) -> Vec<&str> {
+  let mut items = vec![];
+  let mut quote = None;
+  let mut start = 0;
+  for (i, c) in text.char_indices() {
+    match (quote, c) {
+      (None, ',') => {
+        items.push(text[start..i].trim());
+        start = i + 1;
+      }
+      (None, '"') | (None, '\'') => quote = Some(c),
+      (Some(q), c) if q == c => quote = None,
+      _ => (),
+    }
+  }
+  items.push(text[start..].trim());
+  items.retain(|item| !item.is_empty());
+  items
+}
+
+/// Removes whitespace from an operand, except inside a character literal.
+fn squeeze(text: &str) -> String {
+  let mut squeezed = String::with_capacity(text.len());
+  let mut quoted = false;
+  for c in text.chars() {
+    if c == '\'' {
+      quoted = !quoted;
+    }
+    if quoted || !c.is_whitespace() {
+      squeezed.push(c);
+    }
+  }
+  squeezed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::disassembler;
+  use crate::Memory;
+  use test_case::test_case;
+
+  fn bytes(source: &str) -> Vec<u8> {
+    let assembly = assemble(source, "test.s", 0x8000).unwrap();
+    assembly.image.get_segments()[0].data.clone()
+  }
+
+  #[test_case("NOP", &[0xEA]; "Implied")]
+  #[test_case("ASL", &[0x0A]; "Accumulator implied")]
+  #[test_case("lsr a", &[0x4A]; "Accumulator")]
+  #[test_case("LDA #$10", &[0xA9, 0x10]; "Immediate")]
+  #[test_case("LDA #'A'", &[0xA9, 0x41]; "Character")]
+  #[test_case("LDA #-1", &[0xA9, 0xFF]; "Negative")]
+  #[test_case("LDA $10", &[0xA5, 0x10]; "Zero page")]
+  #[test_case("LDX $10, Y", &[0xB6, 0x10]; "Zero page y")]
+  #[test_case("LDA $0010", &[0xA5, 0x10]; "Zero page by value")]
+  #[test_case("LDA $1234", &[0xAD, 0x34, 0x12]; "Absolute")]
+  #[test_case("ADC $1234,X", &[0x7D, 0x34, 0x12]; "Absolute x")]
+  #[test_case("STA $12,Y", &[0x99, 0x12, 0x00]; "No zero page y")]
+  #[test_case("JMP ($1234)", &[0x6C, 0x34, 0x12]; "Indirect")]
+  #[test_case("LDA ($10,X)", &[0xA1, 0x10]; "Indexed x")]
+  #[test_case("LDA ($10),Y", &[0xB1, 0x10]; "Indexed y")]
+  #[test_case("BNE *", &[0xD0, 0xFE]; "Branch to self")]
+  #[test_case("JMP *+3", &[0x4C, 0x03, 0x80]; "Current address")]
+  #[test_case("SBC #%1010", &[0xE9, 0x0A]; "Binary")]
+  #[test_case("LAX $10", &[0xA7, 0x10]; "Undocumented")]
+  fn instruction(source: &str, expected: &[u8]) {
+    assert_eq!(bytes(source), expected);
+  }
+
+  #[test]
+  fn labels_and_directives() {
+    let source = "
+      screen = $0400
+      start:  LDX #0        ; forward and backward labels
+      loop:   LDA message,X
+              BEQ done
+              STA screen,X
+              INX
+              BNE loop
+      done:   RTS
+      message: .byte \"HI; there\", 0
+              .word start, >screen
+    ";
+    let assembly = assemble(source, "test.s", 0x8000).unwrap();
+    let data = &assembly.image.get_segments()[0].data;
+    assert_eq!(
+      data[..15],
+      [0xA2, 0x00, 0xBD, 0x0E, 0x80, 0xF0, 0x06, 0x9D, 0x00, 0x04, 0xE8, 0xD0, 0xF5, 0x60, b'H']
+    );
+    assert_eq!(data[23..], [0x00, 0x00, 0x80, 0x04, 0x00]);
+    assert_eq!(assembly.symbols.get_address("done"), Some(0x800D));
+    assert_eq!(assembly.symbols.get_address("screen"), Some(0x0400));
+    let line = assembly.symbols.get_line(0x8002).unwrap();
+    assert_eq!(line.to_string(), "test.s:4");
+  }
+
+  #[test]
+  fn forward_zero_page_reference_stays_absolute() {
+    assert_eq!(bytes("LDA later\nlater = $10"), [0xAD, 0x10, 0x00]);
+  }
+
+  #[test]
+  fn origin_starts_segment() {
+    let source = "NOP\n.org $FFFC\n.word $8000\n*= $9000\nRTS";
+    let assembly = assemble(source, "test.s", 0x8000).unwrap();
+    let segments: Vec<(u16, Vec<u8>)> = assembly
+      .image
+      .get_segments()
+      .iter()
+      .map(|s| (s.address, s.data.clone()))
+      .collect();
+    assert_eq!(
+      segments,
+      vec![
+        (0x8000, vec![0xEA]),
+        (0xFFFC, vec![0x00, 0x80]),
+        (0x9000, vec![0x60])
+      ]
+    );
+  }
+
+  #[test]
+  fn round_trips_through_disassembler() {
+    let program = [
+      0xA9, 0x10, 0x8D, 0x00, 0x02, 0x6C, 0x34, 0x12, 0xB1, 0x10, 0xD0, 0xF4,
+    ];
+    let mut memory = Memory::new();
+    for (i, byte) in program.iter().enumerate() {
+      memory.set(0x8000 + i as u16, *byte);
+    }
+    let source: String = disassembler::disassemble(&memory, 0x8000, 5)
+      .iter()
+      .map(|i| format!("{}\n", i))
+      .collect();
+    assert_eq!(bytes(&source), program);
+  }
+
+  #[test_case("FOO #1", AssembleError::Syntax(1, "unknown mnemonic FOO".to_string()); "Mnemonic")]
+  #[test_case("JMP nowhere", AssembleError::UndefinedSymbol(1, "nowhere".to_string()); "Undefined")]
+  #[test_case("a: NOP\na: NOP", AssembleError::DuplicateSymbol(2, "a".to_string()); "Duplicate")]
+  #[test_case("LDA #$100", AssembleError::OutOfRange(1, 0x100); "Immediate too large")]
+  #[test_case("BNE *+200", AssembleError::OutOfRange(1, 198); "Branch too far")]
+  #[test_case("STX $1234,X", AssembleError::Syntax(1, "STX can't take the operand $1234,X".to_string()); "Bad mode")]
+  #[test_case(".org $FFFF\n.word 1", AssembleError::OutOfRange(2, 0x10001); "Past the end")]
+  fn errors(source: &str, expected: AssembleError) {
+    assert_eq!(assemble(source, "test.s", 0x8000), Err(expected));
+  }
+}
@@ -0,0 +1,342 @@
+use crate::prelude::*;
+use crate::symbols::SymbolTable;
+use core::fmt::{Display, Formatter};
+
+/// The hardware stack holds at most 128 return addresses, so a deeper shadow
+/// stack means frames were abandoned without returning.
+const MAX_FRAMES: usize = 128;
+/// How many detected problems are kept.
+const MAX_ERRORS: usize = 64;
+
+/// How a frame was entered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+  /// Entered with JSR.
+  Subroutine,
+  /// Entered through the IRQ pin.
+  Irq,
+  /// Entered through the NMI pin.
+  Nmi,
+  /// Entered with BRK.
+  Break,
+}
+
+impl FrameKind {
+  /// Returns true for frames left with RTI rather than RTS.
+  pub fn is_interrupt(self) -> bool {
+    self != FrameKind::Subroutine
+  }
+}
+
+/// A single entry on the shadow call stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Frame {
+  pub kind: FrameKind,
+  /// The address of the routine or handler that was entered.
+  pub target: u16,
+  /// The address of the JSR or BRK, or of the instruction an interrupt
+  /// arrived before.
+  pub call_site: u16,
+  /// Where execution should carry on once the frame returns.
+  pub return_address: u16,
+  /// The stack pointer before the return address was pushed.
+  pub stack_pointer: u8,
+}
+
+/// Something the shadow call stack noticed that a well behaved program would
+/// not do. Each variant holds the address of the instruction responsible.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackError {
+  /// A return with no call to return from.
+  UnmatchedReturn { address: u16, target: u16 },
+  /// A return went somewhere other than where the call would return to,
+  /// usually because the return address on the stack was changed.
+  WrongReturnAddress {
+    address: u16,
+    expected: u16,
+    actual: u16,
+  },
+  /// RTS out of an interrupt handler, or RTI out of a subroutine.
+  WrongReturnKind { address: u16, frame: FrameKind },
+  /// Frames were dropped without returning, by moving the stack pointer
+  /// past them or by returning from an outer frame.
+  FramesDiscarded { address: u16, count: usize },
+  /// A push wrapped the stack pointer below $0100.
+  Overflow { address: u16 },
+  /// A pull wrapped the stack pointer above $01FF.
+  Underflow { address: u16 },
+}
+
+impl Display for StackError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      StackError::UnmatchedReturn { address, target } => write!(
+        f,
+        "${:04X}: return to ${:04X} without a matching call",
+        address, target
+      ),
+      StackError::WrongReturnAddress {
+        address,
+        expected,
+        actual,
+      } => write!(
+        f,
+        "${:04X}: returned to ${:04X} instead of ${:04X}",
+        address, actual, expected
+      ),
+      StackError::WrongReturnKind { address, frame } => {
+        write!(f, "${:04X}: wrong return out of {:?} frame", address, frame)
+      }
+      StackError::FramesDiscarded { address, count } => {
+        write!(
+          f,
+          "${:04X}: {} frames discarded without returning",
+          address, count
+        )
+      }
+      StackError::Overflow { address } => write!(f, "${:04X}: stack overflow", address),
+      StackError::Underflow { address } => write!(f, "${:04X}: stack underflow", address),
+    }
+  }
+}
+
+/// Tracks the logical call stack alongside the hardware stack.
+///
+/// The CPU pushes a frame for every JSR, BRK and interrupt, and pops one for
+/// every RTS and RTI. Returns are matched against the recorded return
+/// address, so changes to the hardware stack that a debugger would otherwise
+/// have to guess at show up as errors instead.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallStack {
+  frames: Vec<Frame>,
+  errors: Vec<StackError>,
+}
+
+impl CallStack {
+  /// Creates an empty call stack.
+  pub fn new() -> CallStack {
+    CallStack::default()
+  }
+
+  /// Forgets every frame and error.
+  pub fn clear(&mut self) {
+    self.frames.clear();
+    self.errors.clear();
+  }
+
+  /// Gets the frames, outermost first.
+  pub fn get_frames(&self) -> &[Frame] {
+    &self.frames
+  }
+
+  /// Gets the problems detected so far, oldest first.
+  pub fn get_errors(&self) -> &[StackError] {
+    &self.errors
+  }
+
+  /// Takes the problems detected so far, leaving none.
+  pub fn take_errors(&mut self) -> Vec<StackError> {
+    core::mem::take(&mut self.errors)
+  }
+
+  /// Records a problem, dropping the oldest if there are too many.
+  pub fn report(&mut self, error: StackError) {
+    warn!("Call stack: {}", error);
+    if self.errors.len() == MAX_ERRORS {
+      self.errors.remove(0);
+    }
+    self.errors.push(error);
+  }
+
+  /// Records entry into a frame.
+  pub fn enter(&mut self, frame: Frame) {
+    debug!("Entering {:?} frame at {:X}", frame.kind, frame.target);
+    if self.frames.len() == MAX_FRAMES {
+      self.frames.remove(0);
+    }
+    self.frames.push(frame);
+  }
+
+  /// Records a return from the instruction at `address` to `actual`. The
+  /// innermost frame that would return there is popped, along with any
+  /// frames inside it.
+  pub fn leave(&mut self, address: u16, actual: u16, interrupt: bool) {
+    let found = self
+      .frames
+      .iter()
+      .rposition(|f| f.return_address == actual && f.kind.is_interrupt() == interrupt);
+    match (found, self.frames.last().copied()) {
+      (_, None) => self.report(StackError::UnmatchedReturn {
+        address,
+        target: actual,
+      }),
+      (Some(index), _) => {
+        let discarded = self.frames.len() - index - 1;
+        if discarded > 0 {
+          self.report(StackError::FramesDiscarded {
+            address,
+            count: discarded,
+          });
+        }
+        self.frames.truncate(index);
+      }
+      (None, Some(top)) if top.kind.is_interrupt() != interrupt => {
+        self.report(StackError::WrongReturnKind {
+          address,
+          frame: top.kind,
+        });
+        self.frames.pop();
+      }
+      (None, Some(top)) => {
+        self.report(StackError::WrongReturnAddress {
+          address,
+          expected: top.return_address,
+          actual,
+        });
+        self.frames.pop();
+      }
+    }
+  }
+
+  /// Drops frames whose return address is no longer on the stack after the
+  /// stack pointer was set directly.
+  pub fn stack_pointer_set(&mut self, address: u16, stack_pointer: u8) {
+    let kept = self
+      .frames
+      .iter()
+      .position(|f| f.stack_pointer <= stack_pointer)
+      .unwrap_or(self.frames.len());
+    let discarded = self.frames.len() - kept;
+    if discarded > 0 {
+      self.report(StackError::FramesDiscarded {
+        address,
+        count: discarded,
+      });
+      self.frames.truncate(kept);
+    }
+  }
+
+  /// Builds a backtrace, innermost frame first. The first line is the current
+  /// program counter; each line after it is the call site of the frame
+  /// inside it.
+  pub fn backtrace(&self, program_counter: u16, symbols: Option<&SymbolTable>) -> String {
+    let name = |address: u16| match symbols {
+      Some(symbols) => symbols.format_address(address),
+      None => format!("${:04X}", address),
+    };
+    let label = |address: u16| {
+      symbols
+        .map(|s| format!(" {}", s.format_address(address)))
+        .unwrap_or_default()
+    };
+    let mut text = format!("#0  ${:04X}{}\n", program_counter, label(program_counter));
+    for (depth, frame) in self.frames.iter().rev().enumerate() {
+      let entry = match frame.kind {
+        FrameKind::Subroutine => format!("called {}", name(frame.target)),
+        kind => format!("interrupted by {:?}, handler {}", kind, name(frame.target)),
+      };
+      text.push_str(&format!(
+        "#{:<2} ${:04X}{} ({})\n",
+        depth + 1,
+        frame.call_site,
+        label(frame.call_site),
+        entry
+      ));
+    }
+    text
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn frame(kind: FrameKind, call_site: u16, stack_pointer: u8) -> Frame {
+    Frame {
+      kind,
+      target: 0x9000,
+      call_site,
+      return_address: call_site + 2,
+      stack_pointer,
+    }
+  }
+
+  #[test]
+  fn balanced() {
+    let mut stack = CallStack::new();
+    stack.enter(frame(FrameKind::Subroutine, 0x8000, 0xFF));
+    stack.enter(frame(FrameKind::Irq, 0x9000, 0xFD));
+    stack.leave(0x9100, 0x9002, true);
+    stack.leave(0x9010, 0x8002, false);
+    assert!(stack.get_frames().is_empty());
+    assert!(stack.get_errors().is_empty());
+  }
+
+  #[test]
+  fn unmatched_return() {
+    let mut stack = CallStack::new();
+    stack.leave(0x8000, 0x1234, false);
+    assert_eq!(
+      stack.take_errors(),
+      vec![StackError::UnmatchedReturn {
+        address: 0x8000,
+        target: 0x1234
+      }]
+    );
+    assert!(stack.get_errors().is_empty());
+  }
+
+  #[test]
+  fn wrong_return() {
+    let mut stack = CallStack::new();
+    stack.enter(frame(FrameKind::Subroutine, 0x8000, 0xFF));
+    stack.enter(frame(FrameKind::Nmi, 0x8100, 0xFD));
+    stack.leave(0x9000, 0x4000, false);
+    stack.leave(0x9000, 0x4000, false);
+    assert_eq!(
+      stack.get_errors(),
+      &[
+        StackError::WrongReturnKind {
+          address: 0x9000,
+          frame: FrameKind::Nmi
+        },
+        StackError::WrongReturnAddress {
+          address: 0x9000,
+          expected: 0x8002,
+          actual: 0x4000
+        }
+      ]
+    );
+  }
+
+  #[test]
+  fn unwinding() {
+    let mut stack = CallStack::new();
+    stack.enter(frame(FrameKind::Subroutine, 0x8000, 0xFF));
+    stack.enter(frame(FrameKind::Subroutine, 0x8100, 0xFD));
+    stack.enter(frame(FrameKind::Subroutine, 0x8200, 0xFB));
+    stack.leave(0x9000, 0x8002, false);
+    assert!(stack.get_frames().is_empty());
+    stack.enter(frame(FrameKind::Subroutine, 0x8000, 0xFF));
+    stack.enter(frame(FrameKind::Subroutine, 0x8100, 0xFD));
+    stack.stack_pointer_set(0x9000, 0xFD);
+    assert_eq!(stack.get_frames().len(), 1);
+    assert_eq!(
+      stack.get_errors()[1],
+      StackError::FramesDiscarded {
+        address: 0x9000,
+        count: 1
+      }
+    );
+  }
+
+  #[test]
+  fn bounded() {
+    let mut stack = CallStack::new();
+    for i in 0..200 {
+      stack.enter(frame(FrameKind::Subroutine, i, 0xFF));
+    }
+    assert_eq!(stack.get_frames().len(), MAX_FRAMES);
+    assert_eq!(stack.get_frames()[0].call_site, 200 - MAX_FRAMES as u16);
+  }
+}
@@ -0,0 +1,527 @@
+use rust6502lib::loader::Format;
+use rust6502lib::runner::Limit;
+use std::collections::BTreeMap;
+
+pub const USAGE: &str = "usage: rust6502lib <command> [options]
+
+commands:
+  run <program>       run a program until it stops and print its registers
+  trace <program>     run a program, printing each instruction before it runs
+  test <program>      run a test program and check it stops at --success
+  asm <source>        assemble a source file, writing it to --output
+  disasm <program>    disassemble a program
+  debug <program>     serve a program to GDB
+  machine <profile>   boot the machine set up in a profile
+  ben-eater <rom>     boot Ben Eater's breadboard computer
+  apple1 <rom>        boot an Apple-1 with the Woz Monitor
+  kim1 <rom> [tty]    boot a KIM-1, with the TTY jumper fitted if asked
+  c64 <basic> <kernal> <chargen> [program]
+                      boot a headless Commodore 64
+  help                print this message
+
+loading programs:
+  --format <bin|hex|srec|prg>  file format, from the extension by default
+  --load <address>             where to load a raw binary
+  --start <address|reset>      where to start, the entry point by default
+  --host                       install the host console and file traps
+
+running programs:
+  --cycles <count>             stop after this many cycles
+  --instructions <count>       stop after this many instructions
+  --stop <address>             stop on reaching an address, repeatable
+  --success <address>          where a test passes
+  --dump <start>[-<end>]       print memory once stopped, repeatable
+  --cpu <6502|6502-documented> the CPU to emulate, the NMOS 6502 by default,
+                               or one that jams on undocumented opcodes
+  --clock <hz>                 run at a clock speed such as 1M or 1.79MHz,
+                               as fast as possible by default
+
+other options:
+  -o, --output <file>          where asm writes to, the source with the
+                               format's extension by default
+  --origin <address>           where asm starts, 0x8000 by default
+  --count <count>              how many instructions disasm lists
+  --port <port>                the port debug listens on, 1234 by default
+  --log-level <level>          off, error, warn, info, debug or trace, or
+                               RUST_LOG, warn by default
+  --log-dir <directory>        log to a file there instead of stderr
+  --config <file>              where profiles are read from, rust6502.conf
+                               by default
+  --profile <name>             take default options from a profile
+
+Addresses are hex, with or without a $ or 0x in front.";
+
+/// The config file profiles are read from when no other is given.
+pub const CONFIG_FILE: &str = "rust6502.conf";
+
+/// The CPUs that can be emulated: the NMOS 6502, with and without its
+/// undocumented opcodes.
+const CPUS: &[&str] = &["6502", "6502-documented"];
+
+/// Commands that run a program file.
+const PROGRAM_COMMANDS: &[&str] = &["run", "trace", "test", "asm", "disasm", "debug"];
+
+/// Machines that can be booted, with the ROMs each takes in order.
+const MACHINES: &[(&str, &[&str])] = &[
+  ("ben-eater", &["rom"]),
+  ("apple1", &["rom"]),
+  ("kim1", &["rom"]),
+  ("c64", &["basic", "kernal", "characters"]),
+];
+
+/// Where execution starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Start {
+  Address(u16),
+  /// The address in the reset vector.
+  Reset,
+}
+
+/// The options for a command, from the command line with any profile's
+/// settings underneath.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Options {
+  pub command: String,
+  /// The program or source file the command works on.
+  pub program: Option<String>,
+  pub format: Option<Format>,
+  pub load: Option<u16>,
+  pub start: Option<Start>,
+  pub host: bool,
+  pub limit: Option<Limit>,
+  pub stops: Vec<u16>,
+  pub success: Option<u16>,
+  pub dumps: Vec<(u16, u16)>,
+  /// Jam on undocumented opcodes, as asked for with --cpu 6502-documented.
+  pub documented_only: bool,
+  /// The clock speed in Hz, or free running without one.
+  pub clock: Option<f64>,
+  pub output: Option<String>,
+  pub origin: Option<u16>,
+  pub count: Option<usize>,
+  pub port: Option<u16>,
+  pub log_level: Option<String>,
+  pub log_dir: Option<String>,
+  pub config: Option<String>,
+  pub profile: Option<String>,
+  /// The machine to boot, and the files its ROMs are read from by name.
+  pub machine: Option<String>,
+  pub roms: BTreeMap<String, String>,
+  pub tty: bool,
+}
+
+impl Options {
+  /// Parses the command line, less the program name, reading the config
+  /// file if a profile is asked for.
+  pub fn parse(args: &[String]) -> Result<Options, String> {
+    let options = Options::parse_with_profile(args, &[])?;
+    let profile = match &options.profile {
+      Some(profile) => profile.clone(),
+      None => return Ok(options),
+    };
+    let path = options.config.as_deref().unwrap_or(CONFIG_FILE);
+    let text = std::fs::read_to_string(path)
+      .map_err(|error| format!("could not read {}: {}", path, error))?;
+    let config = Config::parse(&text).map_err(|error| format!("{}: {}", path, error))?;
+    let settings = config
+      .get_profile(&profile)
+      .ok_or_else(|| format!("no profile {} in {}", profile, path))?;
+    Options::parse_with_profile(args, settings)
+  }
+
+  /// Parses the command line over a profile's settings, so the command
+  /// line wins.
+  pub fn parse_with_profile(
+    args: &[String],
+    settings: &[(String, String)],
+  ) -> Result<Options, String> {
+    let mut options = Options::default();
+    for (key, value) in settings {
+      match value.as_str() {
+        "true" => options.set(key, None)?,
+        "false" => (),
+        value => options.set(key, Some(value))?,
+      }
+    }
+    let mut args = args.iter();
+    options.command = match args.next().map(String::as_str) {
+      None => return Err("no command given".to_string()),
+      Some("--help") | Some("-h") => "help".to_string(),
+      Some(command) => command.to_string(),
+    };
+    let mut positional = vec![];
+    options.apply(&mut args, &mut positional)?;
+    options.set_positional(&positional)?;
+    Ok(options)
+  }
+
+  /// Applies flags, collecting the arguments that aren't flags.
+  fn apply<'a, I: Iterator<Item = &'a String>>(
+    &mut self,
+    args: &mut I,
+    positional: &mut Vec<String>,
+  ) -> Result<(), String> {
+    while let Some(arg) = args.next() {
+      let name = match arg.strip_prefix("--") {
+        Some(name) => name,
+        None if arg == "-o" => "output",
+        None => {
+          positional.push(arg.clone());
+          continue;
+        }
+      };
+      match name.split_once('=') {
+        Some((name, value)) => self.set(name, Some(value))?,
+        None if Options::is_switch(name) => self.set(name, None)?,
+        None => {
+          let value = args
+            .next()
+            .ok_or_else(|| format!("--{} needs a value", name))?;
+          self.set(name, Some(value))?
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Returns true for flags that take no value.
+  fn is_switch(name: &str) -> bool {
+    matches!(name, "host" | "tty" | "help")
+  }
+
+  /// Sets an option by its flag name, as given on the command line or as a
+  /// key in a profile.
+  fn set(&mut self, name: &str, value: Option<&str>) -> Result<(), String> {
+    let value = || value.ok_or_else(|| format!("--{} needs a value", name));
+    match name {
+      "host" => self.host = true,
+      "tty" => self.tty = true,
+      "help" => self.command = "help".to_string(),
+      "program" => self.program = Some(value()?.to_string()),
+      "format" => self.format = Some(parse_format(value()?)?),
+      "load" => self.load = Some(parse_address(value()?)?),
+      "start" => {
+        self.start = Some(match value()? {
+          "reset" => Start::Reset,
+          address => Start::Address(parse_address(address)?),
+        })
+      }
+      "cycles" => self.limit = Some(Limit::Cycles(parse_count(value()?)?)),
+      "instructions" => self.limit = Some(Limit::Instructions(parse_count(value()?)?)),
+      "stop" => self.stops.push(parse_address(value()?)?),
+      "success" => self.success = Some(parse_address(value()?)?),
+      "dump" => {
+        let range = value()?;
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        self
+          .dumps
+          .push((parse_address(first)?, parse_address(last)?));
+      }
+      "cpu" if CPUS.contains(&value()?) => self.documented_only = value()? == "6502-documented",
+      "cpu" => {
+        return Err(format!(
+          "unsupported CPU {}, choose from {}",
+          value()?,
+          CPUS.join(", ")
+        ))
+      }
+      "clock" => self.clock = Some(parse_frequency(value()?)?),
+      "output" => self.output = Some(value()?.to_string()),
+      "origin" => self.origin = Some(parse_address(value()?)?),
+      "count" => self.count = Some(parse_count(value()?)? as usize),
+      "port" => {
+        let port = value()?;
+        self.port = Some(port.parse().map_err(|_| format!("bad port {}", port))?)
+      }
+      "log-level" => self.log_level = Some(value()?.to_string()),
+      "log-dir" => self.log_dir = Some(value()?.to_string()),
+      "config" => self.config = Some(value()?.to_string()),
+      "profile" => self.profile = Some(value()?.to_string()),
+      "machine" => self.machine = Some(value()?.to_string()),
+      "rom" | "basic" | "kernal" | "characters" => {
+        self.roms.insert(name.to_string(), value()?.to_string());
+      }
+      _ => return Err(format!("unknown option --{}", name)),
+    }
+    Ok(())
+  }
+
+  /// Gives the arguments that aren't flags their meaning for the command.
+  fn set_positional(&mut self, positional: &[String]) -> Result<(), String> {
+    let command = self.command.as_str();
+    let machine = MACHINES.iter().find(|(name, _)| *name == command);
+    let extra = if PROGRAM_COMMANDS.contains(&command) {
+      let mut positional = positional.iter();
+      if let Some(program) = positional.next() {
+        self.program = Some(program.clone());
+      }
+      positional.next()
+    } else if command == "machine" {
+      let mut positional = positional.iter();
+      match positional.next() {
+        Some(profile) => self.profile = Some(profile.clone()),
+        None if self.machine.is_some() || self.profile.is_some() => (),
+        None => return Err("machine needs a profile".to_string()),
+      }
+      positional.next()
+    } else if let Some((name, roms)) = machine {
+      self.machine = Some(name.to_string());
+      let mut positional = positional.iter();
+      for (rom, path) in roms.iter().zip(positional.by_ref()) {
+        self.roms.insert(rom.to_string(), path.clone());
+      }
+      match (command, positional.next()) {
+        ("kim1", Some(tty)) if tty == "tty" => {
+          self.tty = true;
+          positional.next()
+        }
+        ("c64", Some(program)) => {
+          self.program = Some(program.clone());
+          positional.next()
+        }
+        (_, extra) => extra,
+      }
+    } else if command == "help" {
+      None
+    } else {
+      return Err(format!("unknown command {}", command));
+    };
+    match extra {
+      Some(extra) => Err(format!("unexpected argument {}", extra)),
+      None => Ok(()),
+    }
+  }
+
+  /// Gets the ROM file for a machine, by name.
+  pub fn get_rom(&self, name: &str) -> Result<&str, String> {
+    self
+      .roms
+      .get(name)
+      .map(String::as_str)
+      .ok_or_else(|| format!("no {} ROM given", name))
+  }
+}
+
+/// Named profiles of default options, read from a config file.
+///
+/// Each profile starts with its name in square brackets, followed by
+/// `option = value` lines using the names of the command line flags, with
+/// `true` for switches such as `host`. Lines starting with `#` are comments.
+///
+/// ```text
+/// [apple1]
+/// machine = apple1
+/// rom = roms/wozmon.bin
+///
+/// [hello]
+/// program = hello.bin
+/// load = 0200
+/// host = true
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+  profiles: BTreeMap<String, Vec<(String, String)>>,
+}
+
+impl Config {
+  /// Parses a config file.
+  pub fn parse(text: &str) -> Result<Config, String> {
+    let mut config = Config::default();
+    let mut profile = None;
+    for (number, line) in text.lines().enumerate() {
+      let line = line.trim();
+      if line.is_empty() || line.starts_with('#') {
+        continue;
+      }
+      if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+        let name = name.trim().to_string();
+        config.profiles.entry(name.clone()).or_default();
+        profile = Some(name);
+        continue;
+      }
+      let (key, value) = line
+        .split_once('=')
+        .ok_or_else(|| format!("line {}: expected option = value", number + 1))?;
+      let settings = profile
+        .as_ref()
+        .and_then(|name| config.profiles.get_mut(name))
+        .ok_or_else(|| format!("line {}: option outside a profile", number + 1))?;
+      settings.push((key.trim().to_string(), value.trim().to_string()));
+    }
+    Ok(config)
+  }
+
+  /// Gets a profile's settings, in the order they were written.
+  pub fn get_profile(&self, name: &str) -> Option<&[(String, String)]> {
+    self.profiles.get(name).map(Vec::as_slice)
+  }
+}
+
+/// Parses a hex address, with or without a `$` or `0x` in front.
+pub fn parse_address(text: &str) -> Result<u16, String> {
+  let digits = text.trim_start_matches('$').trim_start_matches("0x");
+  u16::from_str_radix(digits, 16).map_err(|_| format!("bad address {}", text))
+}
+
+/// Parses a decimal count.
+fn parse_count(text: &str) -> Result<u64, String> {
+  text.parse().map_err(|_| format!("bad count {}", text))
+}
+
+/// Parses a file format by name or extension.
+fn parse_format(text: &str) -> Result<Format, String> {
+  match text {
+    "bin" | "binary" | "raw" => Ok(Format::Binary),
+    _ => match Format::from_extension(text) {
+      Format::Binary => Err(format!("unknown format {}", text)),
+      format => Ok(format),
+    },
+  }
+}
+
+/// Parses a frequency in Hz, with an optional k or M multiplier and Hz.
+fn parse_frequency(text: &str) -> Result<f64, String> {
+  let number = text.trim_end_matches("Hz").trim_end_matches("hz");
+  let (number, multiplier) = match number.chars().last() {
+    Some('k') | Some('K') => (&number[..number.len() - 1], 1e3),
+    Some('M') | Some('m') => (&number[..number.len() - 1], 1e6),
+    _ => (number, 1.0),
+  };
+  match number.parse::<f64>() {
+    Ok(hz) if hz > 0.0 => Ok(hz * multiplier),
+    _ => Err(format!("bad clock speed {}", text)),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn args(line: &str) -> Vec<String> {
+    line.split_whitespace().map(String::from).collect()
+  }
+
+  #[test_case("help"; "Command")]
+  #[test_case("--help"; "Flag")]
+  #[test_case("run a.bin --help"; "After command")]
+  fn help(line: &str) {
+    assert_eq!(Options::parse(&args(line)).unwrap().command, "help");
+  }
+
+  #[test]
+  fn run_options() {
+    let options = Options::parse(&args(
+      "run hello.bin --load $0200 --start=reset --host --cycles 1000 --stop 0x300 \
+       --stop 400 --dump 10-1F --dump 20 --cpu 6502-documented --clock 1.5M --log-level debug",
+    ))
+    .unwrap();
+    assert_eq!(options.command, "run");
+    assert_eq!(options.program.as_deref(), Some("hello.bin"));
+    assert_eq!(options.load, Some(0x0200));
+    assert_eq!(options.start, Some(Start::Reset));
+    assert!(options.host);
+    assert_eq!(options.limit, Some(Limit::Cycles(1000)));
+    assert_eq!(options.stops, vec![0x0300, 0x0400]);
+    assert_eq!(options.dumps, vec![(0x10, 0x1F), (0x20, 0x20)]);
+    assert!(options.documented_only);
+    assert_eq!(options.clock, Some(1_500_000.0));
+    assert_eq!(options.log_level.as_deref(), Some("debug"));
+  }
+
+  #[test_case(""; "Nothing")]
+  #[test_case("frobnicate"; "Command")]
+  #[test_case("run a.bin --bogus"; "Option")]
+  #[test_case("run a.bin b.bin"; "Extra argument")]
+  #[test_case("run a.bin --load"; "Missing value")]
+  #[test_case("run a.bin --load zz"; "Address")]
+  #[test_case("run a.bin --cpu 65816"; "CPU")]
+  #[test_case("run a.bin --clock fast"; "Clock")]
+  #[test_case("asm a.s --format exe"; "Format")]
+  #[test_case("machine"; "Machine without profile")]
+  fn rejects(line: &str) {
+    assert!(Options::parse(&args(line)).is_err());
+  }
+
+  #[test_case("kim1 kim.bin tty", "kim1", &[("rom", "kim.bin")], true, None; "KIM-1")]
+  #[test_case(
+    "c64 b.bin k.bin c.bin game.prg",
+    "c64",
+    &[("basic", "b.bin"), ("characters", "c.bin"), ("kernal", "k.bin")],
+    false,
+    Some("game.prg");
+    "C64"
+  )]
+  fn machine_commands(
+    line: &str,
+    machine: &str,
+    roms: &[(&str, &str)],
+    tty: bool,
+    program: Option<&str>,
+  ) {
+    let options = Options::parse(&args(line)).unwrap();
+    assert_eq!(options.machine.as_deref(), Some(machine));
+    let expected: Vec<(String, String)> = roms
+      .iter()
+      .map(|(k, v)| (k.to_string(), v.to_string()))
+      .collect();
+    assert_eq!(options.roms.into_iter().collect::<Vec<_>>(), expected);
+    assert_eq!(options.tty, tty);
+    assert_eq!(options.program.as_deref(), program);
+  }
+
+  const CONFIG: &str = "
+# Profiles for testing
+[apple1]
+machine = apple1
+rom = roms/wozmon.bin
+
+[hello]
+program = hello.bin
+load = 0200
+host = true
+cycles = 1000
+";
+
+  #[test]
+  fn config() {
+    let config = Config::parse(CONFIG).unwrap();
+    let profile = config.get_profile("apple1").unwrap();
+    assert_eq!(
+      profile[1],
+      ("rom".to_string(), "roms/wozmon.bin".to_string())
+    );
+    assert!(config.get_profile("c64").is_none());
+    assert!(Config::parse("rom = a.bin").is_err());
+    assert!(Config::parse("[a]\nrom").is_err());
+  }
+
+  #[test]
+  fn command_line_overrides_profile() {
+    let config = Config::parse(CONFIG).unwrap();
+    let settings = config.get_profile("hello").unwrap();
+    let options =
+      Options::parse_with_profile(&args("run --profile hello --cycles 5"), settings).unwrap();
+    assert_eq!(options.program.as_deref(), Some("hello.bin"));
+    assert_eq!(options.load, Some(0x0200));
+    assert!(options.host);
+    assert_eq!(options.limit, Some(Limit::Cycles(5)));
+  }
+
+  #[test]
+  fn machine_profile() {
+    let config = Config::parse(CONFIG).unwrap();
+    let settings = config.get_profile("apple1").unwrap();
+    let options = Options::parse_with_profile(&args("machine apple1"), settings).unwrap();
+    assert_eq!(options.command, "machine");
+    assert_eq!(options.machine.as_deref(), Some("apple1"));
+    assert_eq!(options.get_rom("rom"), Ok("roms/wozmon.bin"));
+  }
+
+  #[test_case("1000", 1000.0)]
+  #[test_case("1.79MHz", 1_790_000.0)]
+  #[test_case("500k", 500_000.0)]
+  fn frequency(text: &str, expected: f64) {
+    assert_eq!(parse_frequency(text), Ok(expected));
+  }
+}
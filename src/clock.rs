@@ -0,0 +1,42 @@
+#[cfg(any(feature = "std", test))]
+use std::sync::mpsc::Receiver;
+
+/// A source of clock ticks for the CPU.
+///
+/// The CPU waits for a tick at the end of every machine cycle.
+pub trait Clock {
+  /// Takes a tick if one is available. Returns false if the CPU should keep
+  /// waiting.
+  fn tick(&mut self) -> bool;
+
+  /// Returns true if this clock always ticks, so the CPU can skip waiting on
+  /// it altogether.
+  fn is_free_running(&self) -> bool {
+    false
+  }
+}
+
+/// Ticks whenever a value arrives on the channel, so another thread can drive
+/// the CPU at a set speed.
+#[cfg(any(feature = "std", test))]
+impl Clock for Receiver<bool> {
+  fn tick(&mut self) -> bool {
+    self.try_recv().is_ok()
+  }
+}
+
+/// A clock that always ticks, so the CPU runs as fast as the host allows.
+/// This needs no threads, which makes it the clock to use on hosts without
+/// them such as WebAssembly.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FreeRunning;
+
+impl Clock for FreeRunning {
+  fn tick(&mut self) -> bool {
+    true
+  }
+
+  fn is_free_running(&self) -> bool {
+    true
+  }
+}
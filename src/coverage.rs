@@ -0,0 +1,322 @@
+use crate::disassembler::{Instruction, Mode};
+use crate::prelude::*;
+use crate::symbols::SymbolTable;
+use crate::Memory;
+use alloc::collections::BTreeMap;
+
+/// Number of addresses in the 6502 address space.
+const ADDRESSES: usize = 0x10000;
+
+/// How often a branch went each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+  pub taken: u64,
+  pub not_taken: u64,
+}
+
+/// Records which instructions ran and which way branches went.
+///
+/// The CPU feeds this from its fetch path and its branch helper when coverage
+/// tracking is turned on. Every fetched byte is marked, instruction starts
+/// are counted, and branches count how often they were and were not taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+  fetched: Vec<bool>,
+  instructions: Vec<u64>,
+  branches: BTreeMap<u16, BranchCoverage>,
+}
+
+impl Coverage {
+  /// Creates empty coverage.
+  pub fn new() -> Coverage {
+    Coverage {
+      fetched: vec![false; ADDRESSES],
+      instructions: vec![0; ADDRESSES],
+      branches: BTreeMap::new(),
+    }
+  }
+
+  /// Records a byte fetched by the CPU, either an opcode or an operand.
+  pub fn record_fetch(&mut self, address: u16) {
+    self.fetched[address as usize] = true;
+  }
+
+  /// Records an instruction starting at an address.
+  pub fn record_instruction(&mut self, address: u16) {
+    self.instructions[address as usize] += 1;
+  }
+
+  /// Records a branch at an address going one way or the other.
+  pub fn record_branch(&mut self, address: u16, taken: bool) {
+    let branch = self.branches.entry(address).or_default();
+    match taken {
+      true => branch.taken += 1,
+      false => branch.not_taken += 1,
+    }
+  }
+
+  /// Adds the coverage from another run to this one.
+  pub fn merge(&mut self, other: &Coverage) {
+    for i in 0..ADDRESSES {
+      self.fetched[i] |= other.fetched[i];
+      self.instructions[i] += other.instructions[i];
+    }
+    for (address, branch) in &other.branches {
+      let entry = self.branches.entry(*address).or_default();
+      entry.taken += branch.taken;
+      entry.not_taken += branch.not_taken;
+    }
+  }
+
+  /// Returns true if the CPU fetched the byte at an address.
+  pub fn is_fetched(&self, address: u16) -> bool {
+    self.fetched[address as usize]
+  }
+
+  /// Gets how many times an instruction starting at an address ran.
+  pub fn get_count(&self, address: u16) -> u64 {
+    self.instructions[address as usize]
+  }
+
+  /// Gets which way a branch at an address went, if it ran.
+  pub fn get_branch(&self, address: u16) -> Option<&BranchCoverage> {
+    self.branches.get(&address)
+  }
+
+  /// Gets the addresses of every instruction that ran, lowest first.
+  pub fn get_instructions(&self) -> Vec<u16> {
+    (0..ADDRESSES)
+      .filter(|i| self.instructions[*i] > 0)
+      .map(|i| i as u16)
+      .collect()
+  }
+
+  /// Builds a disassembly of a range of memory marking what ran. Each line is
+  /// prefixed with the number of times the instruction ran, or dashes if it
+  /// never did. Branches note which ways they went. Disassembly restarts at
+  /// every instruction that ran, so bytes that are only ever skipped over are
+  /// shown as data.
+  pub fn annotated(
+    &self,
+    memory: &Memory,
+    start: u16,
+    end: u16,
+    symbols: Option<&SymbolTable>,
+  ) -> String {
+    let mut text = String::new();
+    let mut address = start as u32;
+    while address <= end as u32 {
+      let instruction = Instruction::read(memory, address as u16);
+      let size = instruction.size() as u32;
+      let overlaps =
+        (1..size).any(|i| address + i <= 0xFFFF && self.instructions[(address + i) as usize] > 0);
+      let count = self.instructions[address as usize];
+      if let Some(symbol) = symbols.and_then(|s| s.get_symbol(address as u16)) {
+        text.push_str(&format!("{}:\n", symbol.name));
+      }
+      let (body, size) = match overlaps && count == 0 {
+        true => (
+          format!(
+            "{:02X}        .byte ${:02X}",
+            instruction.opcode, instruction.opcode
+          ),
+          1,
+        ),
+        false => {
+          let bytes: Vec<String> = instruction
+            .get_bytes()
+            .iter()
+            .map(|b| format!("{:02X}", b))
+            .collect();
+          (
+            format!("{:<8}  {}", bytes.join(" "), instruction.format(symbols)),
+            size,
+          )
+        }
+      };
+      let hits = match count {
+        0 => "-----".to_string(),
+        count => format!("{:>5}", count),
+      };
+      let mut line = format!("{} {:04X}  {}", hits, address, body);
+      if instruction.mode == Mode::Relative && size == 2 {
+        let branch = self.get_branch(address as u16).copied().unwrap_or_default();
+        let way = |count: u64| match count {
+          0 => "never",
+          _ => "yes",
+        };
+        line = format!(
+          "{:<44}; taken: {}, not taken: {}",
+          line,
+          way(branch.taken),
+          way(branch.not_taken)
+        );
+      }
+      text.push_str(line.trim_end());
+      text.push('\n');
+      address += size;
+    }
+    text
+  }
+
+  /// Builds an lcov tracefile from the source lines in a symbol table. A line
+  /// is hit as often as the instruction at its first address ran, and branch
+  /// instructions on a line report how often they went each way. Returns None
+  /// if the symbol table holds no source lines.
+  pub fn lcov(&self, memory: &Memory, symbols: &SymbolTable, test_name: &str) -> Option<String> {
+    if symbols.get_lines().is_empty() {
+      return None;
+    }
+    let mut files: BTreeMap<&str, BTreeMap<usize, u64>> = BTreeMap::new();
+    let mut branches: BTreeMap<&str, BTreeMap<(usize, u16), Option<BranchCoverage>>> =
+      BTreeMap::new();
+    for line in symbols.get_lines() {
+      *files
+        .entry(&line.file)
+        .or_default()
+        .entry(line.line)
+        .or_default() += self.instructions[line.start as usize];
+      let end = line.start as u32 + line.size as u32;
+      let mut address = line.start as u32;
+      while address < end.min(ADDRESSES as u32) {
+        let instruction = Instruction::read(memory, address as u16);
+        if instruction.mode == Mode::Relative {
+          let coverage = match self.instructions[address as usize] {
+            0 => None,
+            _ => Some(self.get_branch(address as u16).copied().unwrap_or_default()),
+          };
+          branches
+            .entry(&line.file)
+            .or_default()
+            .insert((line.line, address as u16), coverage);
+        }
+        address += instruction.size() as u32;
+      }
+    }
+    let mut text = String::new();
+    for (file, lines) in files {
+      text.push_str(&format!("TN:{}\nSF:{}\n", test_name, file));
+      let mut found = 0;
+      let mut hit = 0;
+      if let Some(branches) = branches.get(file) {
+        for ((line, address), coverage) in branches {
+          let (taken, not_taken) = match coverage {
+            Some(b) => (b.taken.to_string(), b.not_taken.to_string()),
+            None => ("-".to_string(), "-".to_string()),
+          };
+          text.push_str(&format!("BRDA:{},{},0,{}\n", line, address, taken));
+          text.push_str(&format!("BRDA:{},{},1,{}\n", line, address, not_taken));
+          found += 2;
+          hit += coverage
+            .map(|b| (b.taken > 0) as usize + (b.not_taken > 0) as usize)
+            .unwrap_or(0);
+        }
+      }
+      text.push_str(&format!("BRF:{}\nBRH:{}\n", found, hit));
+      for (line, count) in &lines {
+        text.push_str(&format!("DA:{},{}\n", line, count));
+      }
+      let lines_hit = lines.values().filter(|c| **c > 0).count();
+      text.push_str(&format!(
+        "LF:{}\nLH:{}\nend_of_record\n",
+        lines.len(),
+        lines_hit
+      ));
+    }
+    Some(text)
+  }
+}
+
+impl Default for Coverage {
+  fn default() -> Self {
+    Coverage::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::CPU;
+  use std::sync::mpsc;
+
+  // LDX #$03; loop: DEX; BNE loop; BEQ done; NOP; done: NOP
+  const PROGRAM: [u8; 9] = [0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0xF0, 0x01, 0xEA, 0xEA];
+
+  fn run(steps: usize) -> CPU {
+    let (_, rx) = mpsc::channel();
+    let mut cpu = CPU::new(rx);
+    cpu.set_free_running(true);
+    cpu.set_coverage(true);
+    for (i, byte) in PROGRAM.iter().enumerate() {
+      cpu.get_memory_mut().poke(0x0200 + i as u16, *byte);
+    }
+    let mut registers = cpu.get_registers();
+    registers.program_counter = 0x0200;
+    cpu.set_registers(registers);
+    for _ in 0..steps {
+      cpu.step();
+    }
+    cpu
+  }
+
+  #[test]
+  fn instructions_and_branches() {
+    let cpu = run(9);
+    let coverage = cpu.get_coverage().unwrap();
+    assert_eq!(
+      coverage.get_instructions(),
+      vec![0x0200, 0x0202, 0x0203, 0x0205, 0x0208]
+    );
+    assert_eq!(coverage.get_count(0x0202), 3);
+    assert!(coverage.is_fetched(0x0204));
+    assert!(!coverage.is_fetched(0x0207));
+    let bne = coverage.get_branch(0x0203).unwrap();
+    assert_eq!((bne.taken, bne.not_taken), (2, 1));
+    let beq = coverage.get_branch(0x0205).unwrap();
+    assert_eq!((beq.taken, beq.not_taken), (1, 0));
+  }
+
+  #[test]
+  fn merge() {
+    let mut coverage = run(9).get_coverage().unwrap().clone();
+    coverage.merge(&run(1).get_coverage().unwrap().clone());
+    assert_eq!(coverage.get_count(0x0200), 2);
+    assert_eq!(coverage.get_count(0x0202), 3);
+  }
+
+  #[test]
+  fn annotated() {
+    let cpu = run(9);
+    let text = cpu
+      .get_coverage()
+      .unwrap()
+      .annotated(cpu.get_memory(), 0x0200, 0x0208, None);
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "    1 0200  A2 03     LDX #$03");
+    assert_eq!(lines[1], "    3 0202  CA        DEX");
+    assert!(lines[2].ends_with("; taken: yes, not taken: yes"));
+    assert!(lines[3].ends_with("; taken: yes, not taken: never"));
+    assert_eq!(lines[4], "----- 0207  EA        NOP");
+  }
+
+  #[test]
+  fn lcov() {
+    let cpu = run(9);
+    let coverage = cpu.get_coverage().unwrap();
+    assert_eq!(
+      coverage.lcov(cpu.get_memory(), &SymbolTable::new(), "test"),
+      None
+    );
+    let mut symbols = SymbolTable::new();
+    symbols.add_line("loop.s", 1, 0x0200, 2);
+    symbols.add_line("loop.s", 2, 0x0202, 1);
+    symbols.add_line("loop.s", 3, 0x0203, 2);
+    symbols.add_line("loop.s", 4, 0x0205, 2);
+    symbols.add_line("loop.s", 5, 0x0207, 1);
+    let text = coverage.lcov(cpu.get_memory(), &symbols, "test").unwrap();
+    let expected = "TN:test\nSF:loop.s\n\
+      BRDA:3,515,0,2\nBRDA:3,515,1,1\nBRDA:4,517,0,1\nBRDA:4,517,1,0\nBRF:4\nBRH:3\n\
+      DA:1,1\nDA:2,3\nDA:3,3\nDA:4,1\nDA:5,0\nLF:5\nLH:4\nend_of_record\n";
+    assert_eq!(text, expected);
+  }
+}
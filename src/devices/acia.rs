@@ -0,0 +1,387 @@
+use crate::devices::serial::Serial;
+use crate::devices::Device;
+use crate::prelude::*;
+use alloc::sync::Arc;
+use core::any::Any;
+use spin::Mutex;
+
+/// Transmit data when written, receive data when read.
+const DATA: u16 = 0x0;
+/// Status when read, a programmed reset when written.
+const STATUS: u16 = 0x1;
+/// Command register: DTR, interrupt enables, echo and parity.
+const COMMAND: u16 = 0x2;
+/// Control register: baud rate, word length and stop bits.
+const CONTROL: u16 = 0x3;
+
+/// Status bit set when the ACIA has interrupted. Cleared by reading status.
+const STATUS_IRQ: u8 = 0x80;
+/// Status bit set when the transmit data register is empty.
+const STATUS_TDRE: u8 = 0x10;
+/// Status bit set when the receive data register is full.
+const STATUS_RDRF: u8 = 0x08;
+/// Status bit set when a received byte was lost.
+const STATUS_OVERRUN: u8 = 0x04;
+
+/// Baud rates selected by the low nibble of the control register, in
+/// hundredths of a baud. Rate 0 runs from the external clock, taken to be
+/// the usual 1.8432 MHz crystal divided by 16.
+const BAUD_RATES: [u64; 16] = [
+  11_520_000, 5_000, 7_500, 10_992, 13_458, 15_000, 30_000, 60_000, 120_000, 180_000, 240_000,
+  360_000, 480_000, 720_000, 960_000, 1_920_000,
+];
+
+/// The MOS 6551 Asynchronous Communications Interface Adapter.
+///
+/// Four registers, mirrored through whatever range the ACIA is mapped to.
+/// Bytes go to and from the host through a `Serial` line, at the pace the
+/// baud rate and frame format set: a byte written to the data register
+/// leaves a frame later, and the receiver takes at most one byte per frame.
+/// The line is only polled while the receive register is empty, so no host
+/// input is ever overrun.
+///
+/// The receiver runs while DTR is set in the command register. Receive
+/// interrupts are on unless command bit 1 is set, and transmit interrupts
+/// are on when command bits 2-3 are 01. Clones share the serial line.
+#[derive(Clone)]
+pub struct Acia {
+  serial: Arc<Mutex<dyn Serial>>,
+  clock_rate: u64,
+  status: u8,
+  command: u8,
+  control: u8,
+  received: u8,
+  transmitting: Option<u8>,
+  transmit_timer: u64,
+  receive_timer: u64,
+}
+
+impl Acia {
+  /// Creates an ACIA talking to a serial line, for a CPU clocked at 1 MHz.
+  pub fn new<S: Serial + 'static>(serial: S) -> Acia {
+    debug!("Initializing 6551 ACIA");
+    Acia {
+      serial: Arc::new(Mutex::new(serial)),
+      clock_rate: 1_000_000,
+      status: STATUS_TDRE,
+      command: 0,
+      control: 0,
+      received: 0,
+      transmitting: None,
+      transmit_timer: 0,
+      receive_timer: 0,
+    }
+  }
+
+  /// Sets the rate the CPU is clocked at, in Hz, so the baud rate can be
+  /// turned into cycles.
+  pub fn set_clock_rate(&mut self, hz: u32) {
+    self.clock_rate = hz as u64;
+  }
+
+  /// Gets the status register.
+  pub fn get_status(&self) -> u8 {
+    self.status
+  }
+
+  /// The number of data bits in a frame.
+  fn word_length(&self) -> u8 {
+    8 - ((self.control >> 5) & 0x03)
+  }
+
+  /// The number of cycles one frame takes on the line: a start bit, the
+  /// data bits, the parity bit if enabled and the stop bits.
+  fn frame_cycles(&self) -> u64 {
+    let parity = (self.command >> 5) & 0x01;
+    let stop = 1 + (self.control >> 7);
+    let bits = (1 + self.word_length() + parity + stop) as u64;
+    let baud = BAUD_RATES[(self.control & 0x0F) as usize];
+    (self.clock_rate * bits * 100 / baud).max(1)
+  }
+
+  /// Transmit interrupts are enabled when command bits 2-3 are 01.
+  fn transmit_irq_enabled(&self) -> bool {
+    (self.command >> 2) & 0x03 == 0x01
+  }
+
+  /// Sends the byte in flight once its frame is done.
+  fn tick_transmitter(&mut self) {
+    let byte = match self.transmitting {
+      Some(byte) => byte,
+      None => return,
+    };
+    self.transmit_timer = self.transmit_timer.saturating_sub(1);
+    if self.transmit_timer > 0 {
+      return;
+    }
+    trace!("ACIA transmitting {:X}", byte);
+    self.serial.lock().transmit(byte);
+    self.transmitting = None;
+    self.status |= STATUS_TDRE;
+    if self.transmit_irq_enabled() {
+      self.status |= STATUS_IRQ;
+    }
+  }
+
+  /// Takes a byte from the line once per frame while DTR is set and the
+  /// receive register is empty.
+  fn tick_receiver(&mut self) {
+    if self.command & 0x01 == 0 {
+      return;
+    }
+    self.receive_timer = self.receive_timer.saturating_sub(1);
+    if self.receive_timer > 0 || self.status & STATUS_RDRF != 0 {
+      return;
+    }
+    self.receive_timer = self.frame_cycles();
+    let byte = match self.serial.lock().receive() {
+      Some(byte) => byte,
+      None => return,
+    };
+    trace!("ACIA received {:X}", byte);
+    self.received = byte & (0xFF >> (8 - self.word_length()));
+    self.status |= STATUS_RDRF;
+    if self.command & 0x02 == 0 {
+      self.status |= STATUS_IRQ;
+    }
+    if self.command & 0x1C == 0x10 {
+      self.serial.lock().transmit(self.received);
+    }
+  }
+}
+
+impl Device for Acia {
+  fn name(&self) -> &str {
+    "6551 ACIA"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    let value = self.peek(register);
+    match register & 0x03 {
+      DATA => self.status &= !(STATUS_RDRF | STATUS_OVERRUN),
+      STATUS => self.status &= !STATUS_IRQ,
+      _ => (),
+    }
+    value
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    match register & 0x03 {
+      DATA => self.received,
+      STATUS => self.status,
+      COMMAND => self.command,
+      CONTROL => self.control,
+      _ => unreachable!(),
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    match register & 0x03 {
+      DATA => {
+        self.transmitting = Some(value);
+        self.transmit_timer = self.frame_cycles();
+        self.status &= !STATUS_TDRE;
+      }
+      STATUS => {
+        debug!("ACIA programmed reset");
+        self.command &= 0xE0;
+        self.status &= !STATUS_OVERRUN;
+      }
+      COMMAND => {
+        debug!("ACIA command set to {:X}", value);
+        self.command = value;
+        if self.transmit_irq_enabled() && self.status & STATUS_TDRE != 0 {
+          self.status |= STATUS_IRQ;
+        }
+      }
+      CONTROL => {
+        debug!("ACIA control set to {:X}", value);
+        self.control = value;
+      }
+      _ => unreachable!(),
+    }
+  }
+
+  fn tick(&mut self) {
+    self.tick_transmitter();
+    self.tick_receiver();
+  }
+
+  fn irq(&self) -> bool {
+    self.status & STATUS_IRQ != 0
+  }
+
+  fn reset(&mut self) {
+    debug!("Resetting 6551 ACIA");
+    self.status = STATUS_TDRE;
+    self.command = 0;
+    self.control = 0;
+    self.transmitting = None;
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+  use crate::clock::FreeRunning;
+  use crate::devices::serial::Buffer;
+  use crate::CPU;
+  use test_case::test_case;
+
+  /// 19200 baud, 8 data bits and 1 stop bit.
+  const CONTROL_19200_8N1: u8 = 0x1F;
+  /// Cycles for a 10 bit frame at 19200 baud.
+  const FRAME: usize = 520;
+
+  fn acia(command: u8) -> (Acia, Buffer) {
+    let buffer = Buffer::new();
+    let mut acia = Acia::new(buffer.clone());
+    acia.write(CONTROL, CONTROL_19200_8N1);
+    acia.write(COMMAND, command);
+    (acia, buffer)
+  }
+
+  fn ticks(acia: &mut Acia, count: usize) {
+    for _ in 0..count {
+      acia.tick();
+    }
+  }
+
+  #[test_case(0x1F, 0x00, 520; "8N1 at 19200")]
+  #[test_case(0x9F, 0x20, 625; "8 bits, parity and 2 stop bits")]
+  #[test_case(0x7E, 0x00, 729; "5N1 at 9600")]
+  #[test_case(0x10, 0x00, 86; "External clock")]
+  fn frame_cycles(control: u8, command: u8, expected: u64) {
+    let mut acia = Acia::new(Buffer::new());
+    acia.write(CONTROL, control);
+    acia.write(COMMAND, command);
+    assert_eq!(acia.frame_cycles(), expected);
+  }
+
+  #[test]
+  fn transmits_after_a_frame() {
+    let (mut acia, buffer) = acia(0x0B);
+    acia.write(DATA, b'A');
+    assert_eq!(acia.read(STATUS) & STATUS_TDRE, 0);
+    ticks(&mut acia, FRAME - 1);
+    assert!(buffer.take_output().is_empty());
+    acia.tick();
+    assert_eq!(buffer.take_output(), b"A");
+    assert_eq!(acia.read(STATUS), STATUS_TDRE);
+    assert!(!acia.irq());
+  }
+
+  #[test]
+  fn receive_interrupts() {
+    let (mut acia, buffer) = acia(0x09);
+    buffer.push_input(b"Z");
+    ticks(&mut acia, FRAME);
+    assert!(acia.irq());
+    assert_eq!(acia.read(STATUS), STATUS_IRQ | STATUS_TDRE | STATUS_RDRF);
+    assert!(!acia.irq());
+    assert_eq!(acia.read(DATA), b'Z');
+    assert_eq!(acia.read(STATUS), STATUS_TDRE);
+  }
+
+  #[test]
+  fn receive_interrupts_can_be_disabled() {
+    let (mut acia, buffer) = acia(0x0B);
+    buffer.push_input(b"Z");
+    ticks(&mut acia, FRAME);
+    assert!(!acia.irq());
+    assert_eq!(acia.peek(STATUS), STATUS_TDRE | STATUS_RDRF);
+  }
+
+  #[test]
+  fn receiver_needs_dtr() {
+    let (mut acia, buffer) = acia(0x02);
+    buffer.push_input(b"Z");
+    ticks(&mut acia, FRAME * 2);
+    assert_eq!(acia.peek(STATUS), STATUS_TDRE);
+  }
+
+  #[test]
+  fn receiver_waits_for_register_to_empty() {
+    let (mut acia, buffer) = acia(0x0B);
+    buffer.push_input(b"AB");
+    ticks(&mut acia, FRAME * 3);
+    assert_eq!(acia.read(DATA), b'A');
+    ticks(&mut acia, FRAME);
+    assert_eq!(acia.read(DATA), b'B');
+  }
+
+  #[test]
+  fn short_words_are_masked() {
+    let buffer = Buffer::new();
+    let mut acia = Acia::new(buffer.clone());
+    acia.write(CONTROL, 0x3F);
+    acia.write(COMMAND, 0x0B);
+    buffer.push_input(&[0xC1]);
+    ticks(&mut acia, FRAME);
+    assert_eq!(acia.read(DATA), 0x41);
+  }
+
+  #[test]
+  fn transmit_interrupts() {
+    let (mut acia, _) = acia(0x07);
+    assert!(acia.irq());
+    acia.read(STATUS);
+    acia.write(DATA, b'A');
+    ticks(&mut acia, FRAME);
+    assert!(acia.irq());
+  }
+
+  #[test]
+  fn echo_mode() {
+    let (mut acia, buffer) = acia(0x13);
+    buffer.push_input(b"E");
+    ticks(&mut acia, FRAME);
+    assert_eq!(buffer.take_output(), b"E");
+  }
+
+  #[test]
+  fn programmed_reset() {
+    let (mut acia, _) = acia(0xEB);
+    acia.write(STATUS, 0);
+    assert_eq!(acia.peek(COMMAND), 0xE0);
+    assert_eq!(acia.peek(CONTROL), CONTROL_19200_8N1);
+  }
+
+  #[test]
+  fn echoes_from_the_cpu() {
+    let mut cpu = CPU::new(FreeRunning);
+    let buffer = Buffer::new();
+    cpu.map_device(0x5000, 0x5003, Box::new(Acia::new(buffer.clone())));
+    let source = "
+            LDA #$1F
+            STA $5003
+            LDA #$0B
+            STA $5002
+    wait:   LDA $5001
+            AND #$08
+            BEQ wait
+            LDA $5000
+            STA $5000
+    loop:   JMP loop";
+    let assembly = assemble(source, "echo.s", 0x8000).unwrap();
+    cpu.load_image(&assembly.image);
+    buffer.push_input(b"!");
+    for _ in 0..1000 {
+      cpu.step();
+    }
+    assert_eq!(buffer.take_output(), b"!");
+  }
+}
@@ -0,0 +1,621 @@
+use crate::devices::{Device, Peripheral, Port, Ports};
+use crate::prelude::*;
+use core::any::Any;
+
+/// Port A data register.
+const PRA: u16 = 0x0;
+/// Port B data register.
+const PRB: u16 = 0x1;
+/// Port A data direction register.
+const DDRA: u16 = 0x2;
+/// Port B data direction register.
+const DDRB: u16 = 0x3;
+/// Timer A, low byte.
+const TA_LO: u16 = 0x4;
+/// Timer A, high byte.
+const TA_HI: u16 = 0x5;
+/// Timer B, low byte.
+const TB_LO: u16 = 0x6;
+/// Timer B, high byte.
+const TB_HI: u16 = 0x7;
+/// Time of day tenths of a second, in BCD.
+const TOD_TENTHS: u16 = 0x8;
+/// Time of day seconds, in BCD.
+const TOD_SECONDS: u16 = 0x9;
+/// Time of day minutes, in BCD.
+const TOD_MINUTES: u16 = 0xA;
+/// Time of day hours, in BCD from 1 to 12, with PM in bit 7.
+const TOD_HOURS: u16 = 0xB;
+/// Serial data register.
+const SDR: u16 = 0xC;
+/// Interrupt control register.
+const ICR: u16 = 0xD;
+/// Control register A.
+const CRA: u16 = 0xE;
+/// Control register B.
+const CRB: u16 = 0xF;
+
+/// Interrupt flag for timer A underflowing.
+const IRQ_TA: u8 = 0x01;
+/// Interrupt flag for timer B underflowing.
+const IRQ_TB: u8 = 0x02;
+/// Interrupt flag for the time of day matching the alarm.
+const IRQ_ALARM: u8 = 0x04;
+/// Interrupt flag for the serial port finishing a byte.
+const IRQ_SDR: u8 = 0x08;
+/// Interrupt flag for a falling edge on the FLAG pin.
+const IRQ_FLAG: u8 = 0x10;
+
+/// Control register bit that starts a timer.
+const START: u8 = 0x01;
+/// Control register bit that stops a timer when it underflows.
+const ONE_SHOT: u8 = 0x08;
+/// Control register strobe that loads a timer from its latch.
+const FORCE_LOAD: u8 = 0x10;
+/// Control register A bit that makes the serial port an output.
+const SERIAL_OUT: u8 = 0x40;
+/// Control register A bit that expects a 50 Hz time of day input.
+const TOD_50HZ: u8 = 0x80;
+/// Control register B bit that makes time of day writes set the alarm.
+const ALARM: u8 = 0x80;
+
+/// One of the two 16 bit interval timers.
+#[derive(Clone)]
+struct Timer {
+  counter: u16,
+  latch: u16,
+  control: u8,
+}
+
+impl Timer {
+  fn new() -> Timer {
+    Timer {
+      counter: 0xFFFF,
+      latch: 0xFFFF,
+      control: 0,
+    }
+  }
+
+  /// Counts down once. Returns true on underflow, when the counter reloads
+  /// from the latch and a one shot timer stops.
+  fn count(&mut self) -> bool {
+    if self.counter > 0 {
+      self.counter -= 1;
+      return false;
+    }
+    self.counter = self.latch;
+    if self.control & ONE_SHOT != 0 {
+      self.control &= !START;
+    }
+    true
+  }
+
+  fn is_running(&self) -> bool {
+    self.control & START != 0
+  }
+
+  fn write_low(&mut self, value: u8) {
+    self.latch = (self.latch & 0xFF00) | value as u16;
+  }
+
+  /// Writes the high byte of the latch, which also loads a stopped timer.
+  fn write_high(&mut self, value: u8) {
+    self.latch = (self.latch & 0x00FF) | ((value as u16) << 8);
+    if !self.is_running() {
+      self.counter = self.latch;
+    }
+  }
+
+  fn write_control(&mut self, value: u8) {
+    if value & FORCE_LOAD != 0 {
+      self.counter = self.latch;
+    }
+    self.control = value & !FORCE_LOAD;
+  }
+}
+
+/// A time of day in BCD, as the registers hold it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Time {
+  tenths: u8,
+  seconds: u8,
+  minutes: u8,
+  hours: u8,
+}
+
+impl Time {
+  fn new() -> Time {
+    Time {
+      tenths: 0,
+      seconds: 0,
+      minutes: 0,
+      hours: 0x01,
+    }
+  }
+
+  fn get(&self, register: u16) -> u8 {
+    match register {
+      TOD_TENTHS => self.tenths,
+      TOD_SECONDS => self.seconds,
+      TOD_MINUTES => self.minutes,
+      _ => self.hours,
+    }
+  }
+
+  fn set(&mut self, register: u16, value: u8) {
+    match register {
+      TOD_TENTHS => self.tenths = value & 0x0F,
+      TOD_SECONDS => self.seconds = value & 0x7F,
+      TOD_MINUTES => self.minutes = value & 0x7F,
+      _ => self.hours = value & 0x9F,
+    }
+  }
+
+  /// Advances a tenth of a second, rolling over from 11:59:59.9 to 12:00
+  /// with AM and PM swapped, and from 12:59:59.9 to 1:00.
+  fn advance(&mut self) {
+    self.tenths = (self.tenths + 1) % 10;
+    if self.tenths != 0 {
+      return;
+    }
+    self.seconds = bcd_increment(self.seconds, 0x60);
+    if self.seconds != 0 {
+      return;
+    }
+    self.minutes = bcd_increment(self.minutes, 0x60);
+    if self.minutes != 0 {
+      return;
+    }
+    let pm = self.hours & 0x80;
+    self.hours = match self.hours & 0x1F {
+      0x11 => 0x12 | (pm ^ 0x80),
+      0x12 => 0x01 | pm,
+      hours => bcd_increment(hours, 0x13) | pm,
+    };
+  }
+}
+
+/// Adds one to a BCD value, wrapping to zero at the limit.
+fn bcd_increment(value: u8, limit: u8) -> u8 {
+  let value = match value & 0x0F {
+    0x09 => (value & 0xF0) + 0x10,
+    _ => value + 1,
+  };
+  if value >= limit {
+    0
+  } else {
+    value
+  }
+}
+
+/// The MOS 6526 Complex Interface Adapter.
+///
+/// Sixteen registers, mirrored through whatever range the CIA is mapped to:
+/// - two 8 bit ports with data direction registers
+/// - timers A and B, counting down every cycle, one shot or continuous, with
+///   timer B also able to count timer A's underflows
+/// - a time of day clock in BCD with an alarm, driven by the 50 or 60 Hz
+///   mains input
+/// - a serial data register that, as an output, flags each byte sent after
+///   16 underflows of timer A
+/// - the interrupt control register, which drives the IRQ line
+///
+/// Reading the hours latches the time of day until the tenths are read, and
+/// writing the hours stops it until the tenths are written, so the time can
+/// be read and set consistently. The CNT pin isn't emulated, so timers
+/// counting CNT edges stand still, and timers don't drive PB6 or PB7.
+#[derive(Clone)]
+pub struct Cia {
+  ports: Ports,
+  timer_a: Timer,
+  timer_b: Timer,
+  time: Time,
+  latched: Option<Time>,
+  alarm: Time,
+  tod_running: bool,
+  tod_cycles: u64,
+  tod_pulses: u8,
+  clock_rate: u64,
+  mains_frequency: u64,
+  shift: u8,
+  shift_count: u8,
+  flags: u8,
+  mask: u8,
+}
+
+impl Cia {
+  /// Creates a CIA in its reset state, with every port pin an input. The
+  /// time of day assumes a 1 MHz clock and 60 Hz mains until told
+  /// otherwise.
+  pub fn new() -> Cia {
+    debug!("Initializing 6526 CIA");
+    Cia {
+      ports: Ports::new(),
+      timer_a: Timer::new(),
+      timer_b: Timer::new(),
+      time: Time::new(),
+      latched: None,
+      alarm: Time::new(),
+      tod_running: true,
+      tod_cycles: 0,
+      tod_pulses: 0,
+      clock_rate: 1_000_000,
+      mains_frequency: 60,
+      shift: 0,
+      shift_count: 0,
+      flags: 0,
+      mask: 0,
+    }
+  }
+
+  /// Sets the rate of the clock the CIA is ticked at, in Hz.
+  pub fn set_clock_rate(&mut self, hz: u64) {
+    self.clock_rate = hz;
+  }
+
+  /// Sets the frequency of the mains input that drives the time of day, in
+  /// Hz. Control register A must be set to match for the clock to keep time.
+  pub fn set_mains_frequency(&mut self, hz: u64) {
+    self.mains_frequency = hz;
+  }
+
+  /// Gets the ports, to read what the CIA is driving.
+  pub fn get_ports(&self) -> &Ports {
+    &self.ports
+  }
+
+  /// Gets the ports, to drive inputs or attach a peripheral.
+  pub fn get_ports_mut(&mut self) -> &mut Ports {
+    &mut self.ports
+  }
+
+  /// Attaches a peripheral to the ports.
+  pub fn set_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+    self.ports.set_peripheral(peripheral);
+  }
+
+  /// Gets the interrupt flags, without the IRQ bit.
+  pub fn get_interrupt_flags(&self) -> u8 {
+    self.flags
+  }
+
+  /// Signals a falling edge on the FLAG pin.
+  pub fn pulse_flag(&mut self) {
+    trace!("CIA FLAG edge");
+    self.flags |= IRQ_FLAG;
+  }
+
+  /// Counts a pulse of the mains input, advancing the time of day every 5
+  /// or 6 pulses as control register A expects 50 or 60 Hz.
+  fn tick_tod(&mut self) {
+    self.tod_cycles += self.mains_frequency;
+    if self.tod_cycles < self.clock_rate {
+      return;
+    }
+    self.tod_cycles -= self.clock_rate;
+    self.tod_pulses += 1;
+    let divider = if self.timer_a.control & TOD_50HZ != 0 {
+      5
+    } else {
+      6
+    };
+    if self.tod_pulses < divider {
+      return;
+    }
+    self.tod_pulses = 0;
+    if self.tod_running {
+      self.time.advance();
+      if self.time == self.alarm {
+        trace!("CIA alarm");
+        self.flags |= IRQ_ALARM;
+      }
+    }
+  }
+
+  /// Shifts out half a bit of the serial register on a timer A underflow.
+  fn tick_serial(&mut self) {
+    if self.timer_a.control & SERIAL_OUT == 0 || self.shift_count == 0 {
+      return;
+    }
+    self.shift_count -= 1;
+    if self.shift_count == 0 {
+      trace!("CIA sent {:X}", self.shift);
+      self.flags |= IRQ_SDR;
+    }
+  }
+
+  fn write_time(&mut self, register: u16, value: u8) {
+    if self.timer_b.control & ALARM != 0 {
+      self.alarm.set(register, value);
+      return;
+    }
+    self.time.set(register, value);
+    match register {
+      TOD_HOURS => self.tod_running = false,
+      TOD_TENTHS => self.tod_running = true,
+      _ => (),
+    }
+  }
+}
+
+impl Device for Cia {
+  fn name(&self) -> &str {
+    "6526 CIA"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    let value = self.peek(register);
+    match register & 0x0F {
+      TOD_HOURS => self.latched = Some(self.time),
+      TOD_TENTHS => self.latched = None,
+      ICR => self.flags = 0,
+      _ => (),
+    }
+    value
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    let register = register & 0x0F;
+    match register {
+      PRA => self.ports.get_pins(Port::A),
+      PRB => self.ports.get_pins(Port::B),
+      DDRA => self.ports.get_direction(Port::A),
+      DDRB => self.ports.get_direction(Port::B),
+      TA_LO => self.timer_a.counter as u8,
+      TA_HI => (self.timer_a.counter >> 8) as u8,
+      TB_LO => self.timer_b.counter as u8,
+      TB_HI => (self.timer_b.counter >> 8) as u8,
+      TOD_TENTHS..=TOD_HOURS => self.latched.unwrap_or(self.time).get(register),
+      SDR => self.shift,
+      ICR => self.flags | ((self.irq() as u8) << 7),
+      CRA => self.timer_a.control,
+      CRB => self.timer_b.control,
+      _ => unreachable!(),
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    let register = register & 0x0F;
+    match register {
+      PRA => self.ports.set_output(Port::A, value),
+      PRB => self.ports.set_output(Port::B, value),
+      DDRA => self.ports.set_direction(Port::A, value),
+      DDRB => self.ports.set_direction(Port::B, value),
+      TA_LO => self.timer_a.write_low(value),
+      TA_HI => self.timer_a.write_high(value),
+      TB_LO => self.timer_b.write_low(value),
+      TB_HI => self.timer_b.write_high(value),
+      TOD_TENTHS..=TOD_HOURS => self.write_time(register, value),
+      SDR => {
+        self.shift = value;
+        self.shift_count = 16;
+      }
+      ICR => match value & 0x80 != 0 {
+        true => self.mask |= value & 0x1F,
+        false => self.mask &= !value,
+      },
+      CRA => self.timer_a.write_control(value),
+      CRB => self.timer_b.write_control(value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn tick(&mut self) {
+    let mut underflow_a = false;
+    if self.timer_a.is_running() && self.timer_a.control & 0x20 == 0 {
+      underflow_a = self.timer_a.count();
+      if underflow_a {
+        trace!("CIA timer A underflow");
+        self.flags |= IRQ_TA;
+        self.tick_serial();
+      }
+    }
+    let count_b = match (self.timer_b.control >> 5) & 0x03 {
+      0 => true,
+      1 => false,
+      _ => underflow_a,
+    };
+    if self.timer_b.is_running() && count_b && self.timer_b.count() {
+      trace!("CIA timer B underflow");
+      self.flags |= IRQ_TB;
+    }
+    self.tick_tod();
+  }
+
+  fn irq(&self) -> bool {
+    self.flags & self.mask != 0
+  }
+
+  /// Stops the timers, masks every interrupt and clears the ports. The time
+  /// of day carries on.
+  fn reset(&mut self) {
+    debug!("Resetting 6526 CIA");
+    self.ports.reset();
+    self.timer_a = Timer::new();
+    self.timer_b = Timer::new();
+    self.shift_count = 0;
+    self.flags = 0;
+    self.mask = 0;
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+impl Default for Cia {
+  fn default() -> Self {
+    Cia::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn ticks(cia: &mut Cia, count: usize) {
+    for _ in 0..count {
+      cia.tick();
+    }
+  }
+
+  #[test]
+  fn ports() {
+    let mut cia = Cia::new();
+    cia.get_ports_mut().set_input(Port::B, 0x0F);
+    cia.write(DDRB, 0xF0);
+    cia.write(PRB, 0xA5);
+    assert_eq!(cia.read(PRB), 0xAF);
+    assert_eq!(cia.read(DDRB), 0xF0);
+  }
+
+  #[test_case(0x01, 2; "Continuous")]
+  #[test_case(0x09, 1; "One shot")]
+  fn timer_a(control: u8, underflows: usize) {
+    let mut cia = Cia::new();
+    cia.write(ICR, 0x80 | IRQ_TA);
+    cia.write(TA_LO, 0x03);
+    cia.write(TA_HI, 0x00);
+    cia.write(CRA, control);
+    ticks(&mut cia, 3);
+    assert!(!cia.irq());
+    cia.tick();
+    assert!(cia.irq());
+    assert_eq!(cia.read(ICR), 0x80 | IRQ_TA);
+    assert!(!cia.irq());
+    ticks(&mut cia, 4);
+    assert_eq!(cia.read(ICR) & IRQ_TA != 0, underflows == 2);
+  }
+
+  #[test]
+  fn masked_flags_dont_interrupt() {
+    let mut cia = Cia::new();
+    cia.write(TA_LO, 0x00);
+    cia.write(TA_HI, 0x00);
+    cia.write(CRA, START);
+    cia.tick();
+    assert!(!cia.irq());
+    assert_eq!(cia.peek(ICR), IRQ_TA);
+    cia.write(ICR, 0x80 | IRQ_TA);
+    assert!(cia.irq());
+    cia.write(ICR, IRQ_TA);
+    assert!(!cia.irq());
+  }
+
+  #[test]
+  fn force_load() {
+    let mut cia = Cia::new();
+    cia.write(TB_LO, 0x34);
+    cia.write(TB_HI, 0x12);
+    cia.write(CRB, START);
+    ticks(&mut cia, 0x10);
+    cia.write(CRB, START | FORCE_LOAD);
+    assert_eq!(cia.peek(TB_LO), 0x34);
+    assert_eq!(cia.peek(TB_HI), 0x12);
+    assert_eq!(cia.peek(CRB), START);
+  }
+
+  #[test]
+  fn timer_b_counts_timer_a() {
+    let mut cia = Cia::new();
+    cia.write(TA_LO, 0x01);
+    cia.write(TA_HI, 0x00);
+    cia.write(TB_LO, 0x02);
+    cia.write(TB_HI, 0x00);
+    cia.write(CRB, 0x40 | START);
+    cia.write(CRA, START);
+    ticks(&mut cia, 5);
+    assert_eq!(cia.peek(TB_LO), 0x00);
+    assert_eq!(cia.peek(ICR) & IRQ_TB, 0);
+    ticks(&mut cia, 2);
+    assert_eq!(cia.peek(ICR) & IRQ_TB, IRQ_TB);
+  }
+
+  #[test]
+  fn serial_output() {
+    let mut cia = Cia::new();
+    cia.write(TA_LO, 0x00);
+    cia.write(TA_HI, 0x00);
+    cia.write(CRA, SERIAL_OUT | START);
+    cia.write(SDR, 0x42);
+    ticks(&mut cia, 15);
+    assert_eq!(cia.peek(ICR) & IRQ_SDR, 0);
+    cia.tick();
+    assert_eq!(cia.peek(ICR) & IRQ_SDR, IRQ_SDR);
+  }
+
+  #[test_case(0x00, 60; "60 Hz")]
+  #[test_case(TOD_50HZ, 50; "50 Hz")]
+  fn time_of_day(control: u8, mains: u64) {
+    let mut cia = Cia::new();
+    cia.set_clock_rate(1000);
+    cia.set_mains_frequency(mains);
+    cia.write(CRA, control);
+    ticks(&mut cia, 100);
+    assert_eq!(cia.read(TOD_TENTHS), 0x01);
+    ticks(&mut cia, 1000);
+    assert_eq!(cia.read(TOD_SECONDS), 0x01);
+    assert_eq!(cia.read(TOD_TENTHS), 0x01);
+  }
+
+  #[test]
+  fn time_of_day_latches() {
+    let mut cia = Cia::new();
+    cia.set_clock_rate(60);
+    assert_eq!(cia.read(TOD_HOURS), 0x01);
+    ticks(&mut cia, 6);
+    assert_eq!(cia.read(TOD_SECONDS), 0x00);
+    assert_eq!(cia.read(TOD_TENTHS), 0x00);
+    assert_eq!(cia.read(TOD_TENTHS), 0x01);
+  }
+
+  #[test]
+  fn setting_time_of_day() {
+    let mut cia = Cia::new();
+    cia.set_clock_rate(60);
+    cia.write(TOD_HOURS, 0x91);
+    cia.write(TOD_MINUTES, 0x59);
+    cia.write(TOD_SECONDS, 0x59);
+    ticks(&mut cia, 6);
+    cia.write(TOD_TENTHS, 0x09);
+    assert_eq!(cia.peek(TOD_TENTHS), 0x09);
+    ticks(&mut cia, 6);
+    assert_eq!(cia.peek(TOD_HOURS), 0x12);
+    assert_eq!(cia.peek(TOD_MINUTES), 0x00);
+    assert_eq!(cia.peek(TOD_TENTHS), 0x00);
+  }
+
+  #[test]
+  fn alarm() {
+    let mut cia = Cia::new();
+    cia.set_clock_rate(60);
+    cia.write(ICR, 0x80 | IRQ_ALARM);
+    cia.write(CRB, ALARM);
+    cia.write(TOD_HOURS, 0x01);
+    cia.write(TOD_MINUTES, 0x00);
+    cia.write(TOD_SECONDS, 0x00);
+    cia.write(TOD_TENTHS, 0x02);
+    assert_eq!(cia.peek(TOD_TENTHS), 0x00);
+    ticks(&mut cia, 6);
+    assert!(!cia.irq());
+    ticks(&mut cia, 6);
+    assert!(cia.irq());
+  }
+
+  #[test]
+  fn flag_pin() {
+    let mut cia = Cia::new();
+    cia.write(ICR, 0x80 | IRQ_FLAG);
+    cia.pulse_flag();
+    assert!(cia.irq());
+  }
+}
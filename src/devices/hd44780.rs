@@ -0,0 +1,410 @@
+use crate::prelude::*;
+
+/// Characters in each line of display data RAM in two line mode. Line 2
+/// starts at address 0x40.
+const LINE_LENGTH: u8 = 0x28;
+/// Characters of display data RAM in one line mode.
+const ONE_LINE_LENGTH: u8 = 0x50;
+
+/// The Hitachi HD44780 character LCD controller.
+///
+/// The controller is driven through its pins: RS selects instructions or
+/// data, RW selects reading or writing, and E strobes a transfer. Writes are
+/// latched as E falls, and reads drive the data bus while E is high. After a
+/// function set clears DL, each transfer is split into two nibbles on D4-D7,
+/// high nibble first.
+///
+/// Instructions complete instantly, so the busy flag always reads clear.
+/// Programs that poll it and programs that wait it out both work.
+#[derive(Clone)]
+pub struct Hd44780 {
+  columns: u8,
+  rows: u8,
+  ddram: [u8; 0x80],
+  cgram: [u8; 0x40],
+  address: u8,
+  cgram_selected: bool,
+  increment: bool,
+  shift_on_write: bool,
+  display_on: bool,
+  cursor_on: bool,
+  blink_on: bool,
+  eight_bit: bool,
+  two_lines: bool,
+  shift: u8,
+  enable: bool,
+  second_nibble: bool,
+  nibble: u8,
+  output: Option<u8>,
+}
+
+impl Hd44780 {
+  /// Creates a controller for a display of the given size, such as the
+  /// common 16x2, in its power on state: 8 bit interface, one line, display
+  /// off.
+  pub fn new(columns: u8, rows: u8) -> Hd44780 {
+    Hd44780 {
+      columns,
+      rows,
+      ddram: [b' '; 0x80],
+      cgram: [0; 0x40],
+      address: 0,
+      cgram_selected: false,
+      increment: true,
+      shift_on_write: false,
+      display_on: false,
+      cursor_on: false,
+      blink_on: false,
+      eight_bit: true,
+      two_lines: false,
+      shift: 0,
+      enable: false,
+      second_nibble: false,
+      nibble: 0,
+      output: None,
+    }
+  }
+
+  /// Sets the levels on the controller's pins.
+  pub fn set_pins(&mut self, rs: bool, rw: bool, enable: bool, data: u8) {
+    let rising = enable && !self.enable;
+    let falling = !enable && self.enable;
+    self.enable = enable;
+    if rising && rw {
+      self.output = Some(self.read_transfer(rs));
+    } else if !enable {
+      self.output = None;
+    }
+    if falling && !rw {
+      self.write_transfer(rs, data);
+    }
+  }
+
+  /// Gets what the controller drives on the data bus, if it is being read.
+  pub fn get_output(&self) -> Option<u8> {
+    self.output
+  }
+
+  /// Gets the address counter.
+  pub fn get_address(&self) -> u8 {
+    self.address
+  }
+
+  /// Returns true if the display is on.
+  pub fn is_display_on(&self) -> bool {
+    self.display_on
+  }
+
+  /// Returns true if the cursor is shown, as an underline or a blinking
+  /// block.
+  pub fn is_cursor_on(&self) -> bool {
+    self.cursor_on || self.blink_on
+  }
+
+  /// Renders the visible part of the display as text, one line per row. A
+  /// display that is off renders as blank lines.
+  pub fn render(&self) -> String {
+    let rows = if self.two_lines { self.rows } else { 1 };
+    let mut lines = vec![];
+    for row in 0..rows {
+      let line: String = (0..self.columns)
+        .map(|column| match self.display_on {
+          true => to_char(self.ddram[self.visible_address(row, column) as usize]),
+          false => ' ',
+        })
+        .collect();
+      lines.push(line);
+    }
+    lines.join("\n")
+  }
+
+  /// The DDRAM address shown at a position, taking the display shift into
+  /// account. Rows past the second continue from the first two lines, as on
+  /// 20x4 displays.
+  fn visible_address(&self, row: u8, column: u8) -> u8 {
+    if !self.two_lines {
+      return (column + self.shift) % ONE_LINE_LENGTH;
+    }
+    let base = if row.is_multiple_of(2) { 0x00 } else { 0x40 };
+    let offset = (row / 2) * self.columns;
+    base + (offset + column + self.shift) % LINE_LENGTH
+  }
+
+  /// Handles a write to the bus, gathering nibbles in 4 bit mode.
+  fn write_transfer(&mut self, rs: bool, data: u8) {
+    if self.eight_bit {
+      return self.write(rs, data);
+    }
+    self.second_nibble = !self.second_nibble;
+    match self.second_nibble {
+      true => self.nibble = data & 0xF0,
+      false => self.write(rs, self.nibble | (data >> 4)),
+    }
+  }
+
+  /// Handles a read from the bus, splitting it into nibbles in 4 bit mode.
+  fn read_transfer(&mut self, rs: bool) -> u8 {
+    if self.eight_bit {
+      return self.read(rs);
+    }
+    let value = match self.second_nibble {
+      false => {
+        let value = self.read(rs);
+        self.nibble = value << 4;
+        value & 0xF0
+      }
+      true => self.nibble,
+    };
+    self.second_nibble = !self.second_nibble;
+    value
+  }
+
+  /// Reads the busy flag and address counter, or data from RAM.
+  fn read(&mut self, rs: bool) -> u8 {
+    if !rs {
+      return self.address;
+    }
+    let value = match self.cgram_selected {
+      true => self.cgram[self.address as usize & 0x3F],
+      false => self.ddram[self.address as usize & 0x7F],
+    };
+    self.advance(self.increment);
+    value
+  }
+
+  /// Runs an instruction or writes data to RAM.
+  fn write(&mut self, rs: bool, value: u8) {
+    if rs {
+      trace!("LCD writing {:X} to {:X}", value, self.address);
+      match self.cgram_selected {
+        true => self.cgram[self.address as usize & 0x3F] = value,
+        false => self.ddram[self.address as usize & 0x7F] = value,
+      }
+      self.advance(self.increment);
+      if self.shift_on_write {
+        self.shift_display(self.increment);
+      }
+      return;
+    }
+    trace!("LCD instruction {:X}", value);
+    match value.leading_zeros() {
+      7 => {
+        self.ddram = [b' '; 0x80];
+        self.address = 0;
+        self.cgram_selected = false;
+        self.increment = true;
+        self.shift = 0;
+      }
+      6 => {
+        self.address = 0;
+        self.cgram_selected = false;
+        self.shift = 0;
+      }
+      5 => {
+        self.increment = value & 0x02 != 0;
+        self.shift_on_write = value & 0x01 != 0;
+      }
+      4 => {
+        self.display_on = value & 0x04 != 0;
+        self.cursor_on = value & 0x02 != 0;
+        self.blink_on = value & 0x01 != 0;
+      }
+      3 => match value & 0x08 != 0 {
+        true => self.shift_display(value & 0x04 == 0),
+        false => self.advance(value & 0x04 != 0),
+      },
+      2 => {
+        self.eight_bit = value & 0x10 != 0;
+        self.two_lines = value & 0x08 != 0;
+      }
+      1 => {
+        self.address = value & 0x3F;
+        self.cgram_selected = true;
+      }
+      0 => {
+        self.address = value & 0x7F;
+        self.cgram_selected = false;
+      }
+      _ => (),
+    }
+  }
+
+  /// Moves the address counter one place, wrapping from the end of one line
+  /// of display data RAM to the start of the next.
+  fn advance(&mut self, forwards: bool) {
+    if self.cgram_selected {
+      self.address = match forwards {
+        true => self.address.wrapping_add(1),
+        false => self.address.wrapping_sub(1),
+      } & 0x3F;
+      return;
+    }
+    self.address = match (self.two_lines, forwards, self.address) {
+      (true, true, 0x27) => 0x40,
+      (true, true, 0x67) => 0x00,
+      (true, false, 0x00) => 0x67,
+      (true, false, 0x40) => 0x27,
+      (false, true, 0x4F) => 0x00,
+      (false, false, 0x00) => 0x4F,
+      (_, true, address) => address + 1,
+      (_, false, address) => address - 1,
+    };
+  }
+
+  /// Shifts the whole display one place, left or right.
+  fn shift_display(&mut self, left: bool) {
+    let length = if self.two_lines {
+      LINE_LENGTH
+    } else {
+      ONE_LINE_LENGTH
+    };
+    self.shift = match left {
+      true => (self.shift + 1) % length,
+      false => (self.shift + length - 1) % length,
+    };
+  }
+}
+
+/// Maps a character code from the A00 character ROM to the nearest
+/// character on the host. Custom characters and the Japanese half of the
+/// ROM render as a block.
+fn to_char(code: u8) -> char {
+  match code {
+    0x5C => '¥',
+    0x7E => '→',
+    0x7F => '←',
+    0x20..=0x7D => code as char,
+    _ => '█',
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Writes a byte the way a program does, strobing E.
+  fn write(lcd: &mut Hd44780, rs: bool, data: u8) {
+    lcd.set_pins(rs, false, true, data);
+    lcd.set_pins(rs, false, false, data);
+  }
+
+  /// Reads a byte the way a program does, strobing E.
+  fn read(lcd: &mut Hd44780, rs: bool) -> u8 {
+    lcd.set_pins(rs, true, true, 0);
+    let value = lcd.get_output().unwrap();
+    lcd.set_pins(rs, true, false, 0);
+    value
+  }
+
+  fn print(lcd: &mut Hd44780, text: &str) {
+    for byte in text.bytes() {
+      write(lcd, true, byte);
+    }
+  }
+
+  /// A 16x2 display set up as most programs do: 8 bit, two lines, display
+  /// on, incrementing and cleared.
+  fn lcd() -> Hd44780 {
+    let mut lcd = Hd44780::new(16, 2);
+    for instruction in [0x38, 0x0E, 0x06, 0x01] {
+      write(&mut lcd, false, instruction);
+    }
+    lcd
+  }
+
+  #[test]
+  fn power_on_display_is_blank() {
+    let lcd = Hd44780::new(16, 2);
+    assert_eq!(lcd.render(), " ".repeat(16));
+  }
+
+  #[test]
+  fn prints_text() {
+    let mut lcd = lcd();
+    print(&mut lcd, "Hello, world!");
+    assert_eq!(lcd.render(), "Hello, world!   \n                ");
+    assert_eq!(lcd.get_address(), 13);
+  }
+
+  #[test]
+  fn second_line() {
+    let mut lcd = lcd();
+    write(&mut lcd, false, 0xC0);
+    print(&mut lcd, "Line 2");
+    assert_eq!(
+      lcd.render(),
+      format!("{}\nLine 2          ", " ".repeat(16))
+    );
+  }
+
+  #[test]
+  fn first_line_wraps_to_second() {
+    let mut lcd = lcd();
+    write(&mut lcd, false, 0x80 | 0x27);
+    print(&mut lcd, "ab");
+    assert_eq!(lcd.get_address(), 0x41);
+  }
+
+  #[test]
+  fn four_bit_interface() {
+    let mut lcd = Hd44780::new(16, 2);
+    write(&mut lcd, false, 0x20);
+    for instruction in [0x28, 0x0C, 0x06, 0x01] {
+      write(&mut lcd, false, instruction & 0xF0);
+      write(&mut lcd, false, instruction << 4);
+    }
+    write(&mut lcd, true, b'H' & 0xF0);
+    write(&mut lcd, true, b'H' << 4);
+    assert_eq!(&lcd.render()[..2], "H ");
+    assert_eq!(read(&mut lcd, false), 0x00);
+    assert_eq!(read(&mut lcd, false), 0x10);
+  }
+
+  #[test]
+  fn reads_address_and_data() {
+    let mut lcd = lcd();
+    print(&mut lcd, "xy");
+    assert_eq!(read(&mut lcd, false), 0x02);
+    write(&mut lcd, false, 0x80);
+    assert_eq!(read(&mut lcd, true), b'x');
+    assert_eq!(read(&mut lcd, true), b'y');
+  }
+
+  #[test]
+  fn shifts_display() {
+    let mut lcd = lcd();
+    print(&mut lcd, "abc");
+    write(&mut lcd, false, 0x18);
+    assert_eq!(&lcd.render()[..3], "bc ");
+    write(&mut lcd, false, 0x1C);
+    write(&mut lcd, false, 0x1C);
+    assert_eq!(&lcd.render()[..4], " abc");
+  }
+
+  #[test]
+  fn display_off_is_blank() {
+    let mut lcd = lcd();
+    print(&mut lcd, "abc");
+    write(&mut lcd, false, 0x08);
+    assert!(lcd.render().chars().all(|c| c == ' ' || c == '\n'));
+  }
+
+  #[test]
+  fn clear_and_home() {
+    let mut lcd = lcd();
+    print(&mut lcd, "abc");
+    write(&mut lcd, false, 0x02);
+    print(&mut lcd, "x");
+    assert_eq!(&lcd.render()[..3], "xbc");
+    write(&mut lcd, false, 0x01);
+    assert_eq!(lcd.get_address(), 0);
+    assert_eq!(&lcd.render()[..3], "   ");
+  }
+
+  #[test]
+  fn character_rom() {
+    assert_eq!(to_char(b'A'), 'A');
+    assert_eq!(to_char(0x5C), '¥');
+    assert_eq!(to_char(0x00), '█');
+  }
+}
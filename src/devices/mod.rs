@@ -0,0 +1,296 @@
+mod acia;
+mod cia;
+mod hd44780;
+mod pia;
+mod riot;
+mod rom;
+mod rriot;
+pub mod serial;
+mod sid;
+mod via;
+mod vic;
+
+use crate::prelude::*;
+use core::any::Any;
+
+pub use acia::Acia;
+pub use cia::Cia;
+pub use hd44780::Hd44780;
+pub use pia::Pia;
+pub use riot::Riot;
+pub use rom::Rom;
+pub use rriot::Rriot;
+pub use sid::Sid;
+pub use via::Via;
+pub use vic::VicII;
+
+/// A peripheral chip mapped into a range of memory.
+///
+/// Where a mapper only decides which bytes an address refers to, a device
+/// treats every access in its range as a register access. Reads go through
+/// `read` rather than `peek` as reading a register often has side effects,
+/// such as clearing an interrupt flag. Devices are clocked once per CPU cycle
+/// and can hold the CPU's IRQ line low. Devices are `Send` so the CPU that owns
+/// them can be moved to another thread.
+pub trait Device: Send {
+  /// A short name for the chip, used for logging.
+  fn name(&self) -> &str;
+
+  /// Reads a register. The register is the offset of the access from the
+  /// start of the device's range, so chips that leave address lines undecoded
+  /// mask it themselves.
+  fn read(&mut self, register: u16) -> u8;
+
+  /// Reads a register without any side effects, for debuggers.
+  fn peek(&self, register: u16) -> u8;
+
+  /// Writes a register.
+  fn write(&mut self, register: u16, value: u8);
+
+  /// Advances the device by one CPU cycle.
+  fn tick(&mut self) {}
+
+  /// Returns true while the device is holding the IRQ line.
+  fn irq(&self) -> bool {
+    false
+  }
+
+  /// Returns the device to its power on state, as the reset line does.
+  fn reset(&mut self);
+
+  /// Clones the device along with its state. Used to snapshot devices
+  /// together with memory.
+  fn box_clone(&self) -> Box<dyn Device>;
+
+  /// Gets the device as `Any`, so it can be downcast to the concrete chip.
+  fn as_any(&self) -> &dyn Any;
+
+  /// Gets the device as `Any`, so it can be downcast to the concrete chip.
+  fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl Clone for Box<dyn Device> {
+  fn clone(&self) -> Self {
+    self.box_clone()
+  }
+}
+
+/// Identifies a device mapped into memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceId(pub(crate) usize);
+
+/// One of the two 8 bit I/O ports on a peripheral chip.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Port {
+  A = 0,
+  B = 1,
+}
+
+/// Something wired to a chip's I/O ports, such as a display or a keypad.
+pub trait Peripheral: Send {
+  /// Called whenever the chip changes what it drives on a port. Bits set in
+  /// `driven` are outputs, the rest of `value` should be ignored.
+  fn output(&mut self, port: Port, value: u8, driven: u8);
+
+  /// Gets the levels the peripheral drives on a port. Pins that nothing
+  /// drives are pulled high.
+  fn input(&self, _port: Port) -> u8 {
+    0xFF
+  }
+
+  /// Clones the peripheral along with its state.
+  fn box_clone(&self) -> Box<dyn Peripheral>;
+
+  /// Gets the peripheral as `Any`, so it can be downcast.
+  fn as_any(&self) -> &dyn Any;
+
+  /// Gets the peripheral as `Any`, so it can be downcast.
+  fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+impl Clone for Box<dyn Peripheral> {
+  fn clone(&self) -> Self {
+    self.box_clone()
+  }
+}
+
+/// A pair of I/O ports with data direction registers, as found on most of
+/// the 65xx peripheral chips.
+///
+/// Each pin whose direction bit is set drives the output register onto the
+/// pin. The other pins are inputs, and read whatever the attached peripheral
+/// drives. With no peripheral attached the inputs read levels set with
+/// `set_input`, which start out pulled high.
+#[derive(Clone)]
+pub struct Ports {
+  output: [u8; 2],
+  direction: [u8; 2],
+  input: [u8; 2],
+  peripheral: Option<Box<dyn Peripheral>>,
+}
+
+impl Ports {
+  /// Creates a pair of ports with every pin an input.
+  pub fn new() -> Ports {
+    Ports {
+      output: [0; 2],
+      direction: [0; 2],
+      input: [0xFF; 2],
+      peripheral: None,
+    }
+  }
+
+  /// Makes every pin an input and clears the output registers. The input
+  /// levels and any peripheral are left alone.
+  pub fn reset(&mut self) {
+    self.output = [0; 2];
+    self.direction = [0; 2];
+    self.notify(Port::A);
+    self.notify(Port::B);
+  }
+
+  /// Gets a port's output register.
+  pub fn get_output(&self, port: Port) -> u8 {
+    self.output[port as usize]
+  }
+
+  /// Sets a port's output register.
+  pub fn set_output(&mut self, port: Port, value: u8) {
+    self.output[port as usize] = value;
+    self.notify(port);
+  }
+
+  /// Gets a port's data direction register. Set bits are outputs.
+  pub fn get_direction(&self, port: Port) -> u8 {
+    self.direction[port as usize]
+  }
+
+  /// Sets a port's data direction register. Set bits are outputs.
+  pub fn set_direction(&mut self, port: Port, value: u8) {
+    self.direction[port as usize] = value;
+    self.notify(port);
+  }
+
+  /// Sets the levels driven onto a port from outside, used when no
+  /// peripheral is attached.
+  pub fn set_input(&mut self, port: Port, value: u8) {
+    self.input[port as usize] = value;
+  }
+
+  /// Gets the levels on a port's pins: outputs as the chip drives them and
+  /// inputs as the outside world does.
+  pub fn get_pins(&self, port: Port) -> u8 {
+    let input = match self.peripheral.as_ref() {
+      Some(peripheral) => peripheral.input(port),
+      None => self.input[port as usize],
+    };
+    let direction = self.direction[port as usize];
+    (self.output[port as usize] & direction) | (input & !direction)
+  }
+
+  /// Attaches a peripheral to the ports, replacing any existing one.
+  pub fn set_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+    self.peripheral = Some(peripheral);
+    self.notify(Port::A);
+    self.notify(Port::B);
+  }
+
+  /// Gets the attached peripheral if it is a `T`.
+  pub fn get_peripheral<T: Peripheral + 'static>(&self) -> Option<&T> {
+    self.peripheral.as_ref()?.as_any().downcast_ref()
+  }
+
+  /// Gets the attached peripheral if it is a `T`.
+  pub fn get_peripheral_mut<T: Peripheral + 'static>(&mut self) -> Option<&mut T> {
+    self.peripheral.as_mut()?.as_any_mut().downcast_mut()
+  }
+
+  /// Tells the peripheral what is now being driven on a port.
+  fn notify(&mut self, port: Port) {
+    if let Some(peripheral) = self.peripheral.as_mut() {
+      let index = port as usize;
+      peripheral.output(port, self.output[index], self.direction[index]);
+    }
+  }
+}
+
+impl Default for Ports {
+  fn default() -> Self {
+    Ports::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+
+  /// Records the last thing driven on port B, and drives port A.
+  #[derive(Clone, Default)]
+  struct Recorder {
+    port_b: (u8, u8),
+  }
+
+  impl Peripheral for Recorder {
+    fn output(&mut self, port: Port, value: u8, driven: u8) {
+      if port == Port::B {
+        self.port_b = (value, driven);
+      }
+    }
+
+    fn input(&self, port: Port) -> u8 {
+      match port {
+        Port::A => 0x5A,
+        Port::B => 0xFF,
+      }
+    }
+
+    fn box_clone(&self) -> Box<dyn Peripheral> {
+      Box::new(self.clone())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+      self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+      self
+    }
+  }
+
+  #[test]
+  fn devices_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Box<dyn Device>>();
+    assert_send::<Box<dyn Peripheral>>();
+    assert_send::<Box<dyn serial::Serial>>();
+    assert_send::<crate::Memory>();
+  }
+
+  #[test]
+  fn inputs_are_pulled_high() {
+    let mut ports = Ports::new();
+    ports.set_output(Port::A, random());
+    assert_eq!(ports.get_pins(Port::A), 0xFF);
+  }
+
+  #[test]
+  fn pins_mix_outputs_and_inputs() {
+    let mut ports = Ports::new();
+    ports.set_input(Port::B, 0x0F);
+    ports.set_output(Port::B, 0xA0);
+    ports.set_direction(Port::B, 0xF0);
+    assert_eq!(ports.get_pins(Port::B), 0xAF);
+  }
+
+  #[test]
+  fn peripherals_see_outputs_and_drive_inputs() {
+    let mut ports = Ports::new();
+    ports.set_peripheral(Box::new(Recorder::default()));
+    ports.set_direction(Port::B, 0xFF);
+    ports.set_output(Port::B, 0x42);
+    let recorder: &Recorder = ports.get_peripheral().unwrap();
+    assert_eq!(recorder.port_b, (0x42, 0xFF));
+    assert_eq!(ports.get_pins(Port::A), 0x5A);
+  }
+}
@@ -0,0 +1,370 @@
+use crate::devices::{Device, Peripheral, Port, Ports};
+use crate::prelude::*;
+use core::any::Any;
+
+/// Control register bit enabling the C1 interrupt.
+const C1_IRQ: u8 = 0x01;
+/// Control register bit making the rising edge of C1 active.
+const C1_RISING: u8 = 0x02;
+/// Control register bit selecting the data register instead of the data
+/// direction register.
+const DATA_SELECT: u8 = 0x04;
+/// Control register bit enabling the C2 interrupt while C2 is an input.
+const C2_IRQ: u8 = 0x08;
+/// Control register bit making the rising edge of C2 active while C2 is an
+/// input.
+const C2_RISING: u8 = 0x10;
+/// Control register flag for an active edge on C1.
+const IRQ_1: u8 = 0x80;
+/// Control register flag for an active edge on C2.
+const IRQ_2: u8 = 0x40;
+
+/// The control register and lines for one side of the PIA.
+#[derive(Clone)]
+struct Side {
+  control: u8,
+  c1: bool,
+  c2: bool,
+  pulse: bool,
+}
+
+impl Side {
+  fn new() -> Side {
+    Side {
+      control: 0,
+      c1: true,
+      c2: true,
+      pulse: false,
+    }
+  }
+
+  /// The C2 mode from bits 3-5 of the control register. Modes 4 to 7 make
+  /// C2 an output.
+  fn c2_mode(&self) -> u8 {
+    (self.control >> 3) & 0x07
+  }
+
+  /// Drops C2 for a handshake or a pulse, as reading port A or writing
+  /// port B does.
+  fn strobe(&mut self) {
+    match self.c2_mode() {
+      4 => self.c2 = false,
+      5 => {
+        self.c2 = false;
+        self.pulse = true;
+      }
+      _ => (),
+    }
+  }
+
+  fn irq(&self) -> bool {
+    let c1 = self.control & IRQ_1 != 0 && self.control & C1_IRQ != 0;
+    let c2 = self.control & IRQ_2 != 0 && self.control & C2_IRQ != 0;
+    c1 || (c2 && self.c2_mode() < 4)
+  }
+}
+
+/// The Motorola 6820/6821 Peripheral Interface Adapter, also sold as the
+/// MOS 6520.
+///
+/// Four registers, mirrored through whatever range the PIA is mapped to:
+/// - 0: port A data, or its data direction register when bit 2 of control
+///   register A is clear
+/// - 1: control register A
+/// - 2: port B data, or its data direction register when bit 2 of control
+///   register B is clear
+/// - 3: control register B
+///
+/// Bits 0-1 of a control register make C1 an interrupt input, with bit 1
+/// choosing the rising edge. Bits 3-5 set up C2: as an input like C1, or as
+/// an output driven low by a read of port A or a write to port B and
+/// raised again by the next active C1 edge (mode 4) or a cycle later
+/// (mode 5), or held low or high (modes 6 and 7). Bits 6-7 flag C2 and C1
+/// edges, and are cleared by reading that side's data register.
+#[derive(Clone)]
+pub struct Pia {
+  ports: Ports,
+  sides: [Side; 2],
+}
+
+impl Pia {
+  /// Creates a PIA in its reset state, with every port pin an input.
+  pub fn new() -> Pia {
+    debug!("Initializing 6821 PIA");
+    Pia {
+      ports: Ports::new(),
+      sides: [Side::new(), Side::new()],
+    }
+  }
+
+  /// Gets the ports, to read what the PIA is driving.
+  pub fn get_ports(&self) -> &Ports {
+    &self.ports
+  }
+
+  /// Gets the ports, to drive inputs or attach a peripheral.
+  pub fn get_ports_mut(&mut self) -> &mut Ports {
+    &mut self.ports
+  }
+
+  /// Attaches a peripheral to the ports.
+  pub fn set_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+    self.ports.set_peripheral(peripheral);
+  }
+
+  /// Gets the level of CA2, which is an output in control modes 4 to 7.
+  pub fn get_ca2(&self) -> bool {
+    self.sides[Port::A as usize].c2
+  }
+
+  /// Gets the level of CB2, which is an output in control modes 4 to 7.
+  pub fn get_cb2(&self) -> bool {
+    self.sides[Port::B as usize].c2
+  }
+
+  /// Drives the CA1 line.
+  pub fn set_ca1(&mut self, level: bool) {
+    self.set_c1(Port::A, level);
+  }
+
+  /// Drives the CA2 line. Ignored while CA2 is an output.
+  pub fn set_ca2(&mut self, level: bool) {
+    self.set_c2(Port::A, level);
+  }
+
+  /// Drives the CB1 line.
+  pub fn set_cb1(&mut self, level: bool) {
+    self.set_c1(Port::B, level);
+  }
+
+  /// Drives the CB2 line. Ignored while CB2 is an output.
+  pub fn set_cb2(&mut self, level: bool) {
+    self.set_c2(Port::B, level);
+  }
+
+  fn set_c1(&mut self, port: Port, level: bool) {
+    let side = &mut self.sides[port as usize];
+    if level == side.c1 {
+      return;
+    }
+    side.c1 = level;
+    if level == (side.control & C1_RISING != 0) {
+      trace!("PIA {:?}1 active edge", port);
+      side.control |= IRQ_1;
+      if side.c2_mode() == 4 {
+        side.c2 = true;
+      }
+    }
+  }
+
+  fn set_c2(&mut self, port: Port, level: bool) {
+    let side = &mut self.sides[port as usize];
+    if side.c2_mode() >= 4 || level == side.c2 {
+      return;
+    }
+    side.c2 = level;
+    if level == (side.control & C2_RISING != 0) {
+      trace!("PIA {:?}2 active edge", port);
+      side.control |= IRQ_2;
+    }
+  }
+
+  /// Writes a control register, leaving its flags alone.
+  fn write_control(&mut self, port: Port, value: u8) {
+    let side = &mut self.sides[port as usize];
+    side.control = (side.control & (IRQ_1 | IRQ_2)) | (value & 0x3F);
+    match side.c2_mode() {
+      4 | 5 | 7 => side.c2 = true,
+      6 => side.c2 = false,
+      _ => (),
+    }
+  }
+
+  /// Reads or writes a data register or its data direction register, as the
+  /// control register selects.
+  fn data_selected(&self, port: Port) -> bool {
+    self.sides[port as usize].control & DATA_SELECT != 0
+  }
+}
+
+impl Device for Pia {
+  fn name(&self) -> &str {
+    "6821 PIA"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    let value = self.peek(register);
+    let port = match register & 0x03 {
+      0 => Port::A,
+      2 => Port::B,
+      _ => return value,
+    };
+    if self.data_selected(port) {
+      let side = &mut self.sides[port as usize];
+      side.control &= !(IRQ_1 | IRQ_2);
+      if port == Port::A {
+        side.strobe();
+      }
+    }
+    value
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    match register & 0x03 {
+      0 if self.data_selected(Port::A) => self.ports.get_pins(Port::A),
+      0 => self.ports.get_direction(Port::A),
+      1 => self.sides[Port::A as usize].control,
+      2 if self.data_selected(Port::B) => self.ports.get_pins(Port::B),
+      2 => self.ports.get_direction(Port::B),
+      3 => self.sides[Port::B as usize].control,
+      _ => unreachable!(),
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    match register & 0x03 {
+      0 if self.data_selected(Port::A) => self.ports.set_output(Port::A, value),
+      0 => self.ports.set_direction(Port::A, value),
+      1 => self.write_control(Port::A, value),
+      2 if self.data_selected(Port::B) => {
+        self.ports.set_output(Port::B, value);
+        self.sides[Port::B as usize].strobe();
+      }
+      2 => self.ports.set_direction(Port::B, value),
+      3 => self.write_control(Port::B, value),
+      _ => unreachable!(),
+    }
+  }
+
+  fn tick(&mut self) {
+    for side in self.sides.iter_mut() {
+      if side.pulse {
+        side.pulse = false;
+        side.c2 = true;
+      }
+    }
+  }
+
+  fn irq(&self) -> bool {
+    self.sides.iter().any(Side::irq)
+  }
+
+  fn reset(&mut self) {
+    debug!("Resetting 6821 PIA");
+    self.ports.reset();
+    self.sides = [Side::new(), Side::new()];
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+impl Default for Pia {
+  fn default() -> Self {
+    Pia::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+  use test_case::test_case;
+
+  #[test]
+  fn data_direction_select() {
+    let mut pia = Pia::new();
+    pia.write(0, 0xF0);
+    assert_eq!(pia.read(0), 0xF0);
+    pia.write(1, DATA_SELECT);
+    pia.get_ports_mut().set_input(Port::A, 0x0F);
+    pia.write(0, 0xA5);
+    assert_eq!(pia.read(0), 0xAF);
+    assert_eq!(pia.get_ports().get_direction(Port::A), 0xF0);
+  }
+
+  #[test_case(0x00, false, true; "Falling edge")]
+  #[test_case(C1_RISING, false, false; "Rising edge ignores falling")]
+  #[test_case(C1_RISING, true, true; "Rising edge")]
+  fn c1_edges(control: u8, rising: bool, flagged: bool) {
+    let mut pia = Pia::new();
+    pia.write(3, control | C1_IRQ | DATA_SELECT);
+    pia.set_cb1(false);
+    if rising {
+      pia.set_cb1(true);
+    }
+    assert_eq!(pia.peek(3) & IRQ_1 != 0, flagged);
+    assert_eq!(pia.irq(), flagged);
+    pia.read(2);
+    assert_eq!(pia.peek(3) & IRQ_1, 0);
+    assert!(!pia.irq());
+  }
+
+  #[test]
+  fn c2_input_interrupt() {
+    let mut pia = Pia::new();
+    pia.write(1, C2_IRQ | C2_RISING);
+    pia.set_ca2(false);
+    assert!(!pia.irq());
+    pia.set_ca2(true);
+    assert_eq!(pia.peek(1) & IRQ_2, IRQ_2);
+    assert!(pia.irq());
+  }
+
+  #[test]
+  fn control_flags_are_read_only() {
+    let mut pia = Pia::new();
+    pia.write(1, 0xFF);
+    assert_eq!(pia.peek(1), 0x3F);
+  }
+
+  #[test]
+  fn read_handshake() {
+    let mut pia = Pia::new();
+    pia.write(1, 0x20 | DATA_SELECT);
+    assert!(pia.get_ca2());
+    pia.read(0);
+    assert!(!pia.get_ca2());
+    pia.set_ca1(false);
+    assert!(pia.get_ca2());
+  }
+
+  #[test]
+  fn write_pulse() {
+    let mut pia = Pia::new();
+    pia.write(3, 0x28 | DATA_SELECT);
+    pia.write(2, random());
+    assert!(!pia.get_cb2());
+    pia.tick();
+    assert!(pia.get_cb2());
+  }
+
+  #[test_case(0x30, false; "Low")]
+  #[test_case(0x38, true; "High")]
+  fn manual_output(control: u8, level: bool) {
+    let mut pia = Pia::new();
+    pia.write(1, control);
+    assert_eq!(pia.get_ca2(), level);
+    pia.set_ca2(!level);
+    assert_eq!(pia.get_ca2(), level);
+  }
+
+  #[test]
+  fn reset() {
+    let mut pia = Pia::new();
+    pia.write(1, 0x3F);
+    pia.write(0, 0xFF);
+    pia.reset();
+    assert_eq!(pia.peek(1), 0x00);
+    assert_eq!(pia.peek(0), 0x00);
+  }
+}
@@ -0,0 +1,394 @@
+use crate::devices::{Device, Peripheral, Port, Ports};
+use crate::prelude::*;
+use core::any::Any;
+
+/// Set in a register number to select I/O and the timer rather than RAM,
+/// as the RS pin does.
+const RS: u16 = 0x80;
+/// Bytes of RAM on the chip.
+const RAM_SIZE: usize = 128;
+/// Timer flag in the interrupt flag register.
+pub(super) const FLAG_TIMER: u8 = 0x80;
+/// PA7 edge flag in the interrupt flag register.
+const FLAG_PA7: u8 = 0x40;
+/// Cycles per count for each prescaler selected by A0 and A1.
+const INTERVALS: [u16; 4] = [1, 8, 64, 1024];
+
+/// The interval timer shared by the 6530 and 6532.
+///
+/// Loading the timer picks a prescaler of 1, 8, 64 or 1024 cycles from A0
+/// and A1 and enables its interrupt from A3. It counts down once per
+/// prescaler interval, starting the cycle after it is loaded. On counting
+/// past zero it sets its flag and carries on counting down every cycle
+/// until loaded again. Reading or loading the timer clears its flag.
+#[derive(Clone)]
+pub(super) struct IntervalTimer {
+  value: u8,
+  interval: u16,
+  prescaler: u16,
+  flag: bool,
+  irq_enabled: bool,
+}
+
+impl IntervalTimer {
+  pub(super) fn new() -> IntervalTimer {
+    IntervalTimer {
+      value: 0xFF,
+      interval: INTERVALS[3],
+      prescaler: INTERVALS[3],
+      flag: false,
+      irq_enabled: false,
+    }
+  }
+
+  /// Gets the count without side effects.
+  pub(super) fn get_value(&self) -> u8 {
+    self.value
+  }
+
+  /// Returns true once the timer has counted past zero.
+  pub(super) fn is_flagged(&self) -> bool {
+    self.flag
+  }
+
+  /// Loads the timer and selects its prescaler from the register number.
+  pub(super) fn load(&mut self, register: u16, value: u8) {
+    self.interval = INTERVALS[(register & 0x03) as usize];
+    trace!("Timer loaded with {:X}, {}T", value, self.interval);
+    self.value = value;
+    self.prescaler = 1;
+    self.flag = false;
+    self.irq_enabled = register & 0x08 != 0;
+  }
+
+  /// Reads the count as the CPU does, clearing the flag and enabling the
+  /// interrupt from A3.
+  pub(super) fn read(&mut self, register: u16) -> u8 {
+    self.flag = false;
+    self.irq_enabled = register & 0x08 != 0;
+    self.value
+  }
+
+  pub(super) fn disable_irq(&mut self) {
+    self.irq_enabled = false;
+  }
+
+  pub(super) fn tick(&mut self) {
+    self.prescaler -= 1;
+    if self.prescaler == 0 {
+      self.prescaler = self.interval;
+      self.value = self.value.wrapping_sub(1);
+      if self.value == 0xFF {
+        trace!("Timer counted past zero");
+        self.flag = true;
+        self.interval = 1;
+        self.prescaler = 1;
+      }
+    }
+  }
+
+  pub(super) fn irq(&self) -> bool {
+    self.flag && self.irq_enabled
+  }
+}
+
+/// The MOS 6532 RAM-I/O-Timer.
+///
+/// Registers 0x00-0x7F are the 128 bytes of RAM and 0x80-0xFF are I/O and
+/// the timer, as if RS were wired to A7. Boards that decode RS from another
+/// line map the RAM and then mirror the rest at register 0x80; on an Atari
+/// 2600, for example, RAM sits at 0x0080 and I/O at 0x0280. Within the I/O
+/// half the low address lines pick the function:
+/// - A2 clear: port A data and direction, then port B data and direction
+/// - A2 set, writing with A4 set: load the timer, with A0-A1 choosing the
+///   1, 8, 64 or 1024 cycle prescaler and A3 enabling its interrupt
+/// - A2 set, writing with A4 clear: PA7 edge detection, with A0 choosing a
+///   rising edge and A1 enabling its interrupt
+/// - A2 set, reading with A0 clear: the timer, with A3 enabling its
+///   interrupt
+/// - A2 set, reading with A0 set: the interrupt flags, timer in bit 7 and
+///   PA7 in bit 6
+///
+/// The timer is an `IntervalTimer`. Reading the flags clears the PA7 flag.
+#[derive(Clone)]
+pub struct Riot {
+  ram: [u8; RAM_SIZE],
+  ports: Ports,
+  timer: IntervalTimer,
+  edge_flag: bool,
+  edge_irq: bool,
+  rising_edge: bool,
+  pa7: bool,
+}
+
+impl Riot {
+  /// Creates a RIOT with cleared RAM and every port pin an input.
+  pub fn new() -> Riot {
+    debug!("Initializing 6532 RIOT");
+    Riot {
+      ram: [0; RAM_SIZE],
+      ports: Ports::new(),
+      timer: IntervalTimer::new(),
+      edge_flag: false,
+      edge_irq: false,
+      rising_edge: false,
+      pa7: true,
+    }
+  }
+
+  /// Gets the ports, to read what the RIOT is driving.
+  pub fn get_ports(&self) -> &Ports {
+    &self.ports
+  }
+
+  /// Gets the ports, to drive inputs or attach a peripheral.
+  pub fn get_ports_mut(&mut self) -> &mut Ports {
+    &mut self.ports
+  }
+
+  /// Attaches a peripheral to the ports.
+  pub fn set_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+    self.ports.set_peripheral(peripheral);
+  }
+
+  /// The interrupt flag register.
+  fn flags(&self) -> u8 {
+    let timer = if self.timer.is_flagged() {
+      FLAG_TIMER
+    } else {
+      0
+    };
+    let edge = if self.edge_flag { FLAG_PA7 } else { 0 };
+    timer | edge
+  }
+}
+
+impl Device for Riot {
+  fn name(&self) -> &str {
+    "6532 RIOT"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    let value = self.peek(register);
+    if register & (RS | 0x04) == (RS | 0x04) {
+      match register & 0x01 {
+        0 => {
+          self.timer.read(register);
+        }
+        _ => self.edge_flag = false,
+      }
+    }
+    value
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    if register & RS == 0 {
+      return self.ram[register as usize % RAM_SIZE];
+    }
+    match register & 0x07 {
+      0x0 => self.ports.get_pins(Port::A),
+      0x1 => self.ports.get_direction(Port::A),
+      0x2 => self.ports.get_pins(Port::B),
+      0x3 => self.ports.get_direction(Port::B),
+      0x4 | 0x6 => self.timer.get_value(),
+      _ => self.flags(),
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    if register & RS == 0 {
+      self.ram[register as usize % RAM_SIZE] = value;
+      return;
+    }
+    match register & 0x07 {
+      0x0 => self.ports.set_output(Port::A, value),
+      0x1 => self.ports.set_direction(Port::A, value),
+      0x2 => self.ports.set_output(Port::B, value),
+      0x3 => self.ports.set_direction(Port::B, value),
+      _ if register & 0x10 != 0 => self.timer.load(register, value),
+      _ => {
+        self.rising_edge = register & 0x01 != 0;
+        self.edge_irq = register & 0x02 != 0;
+      }
+    }
+  }
+
+  fn tick(&mut self) {
+    self.timer.tick();
+    let pa7 = self.ports.get_pins(Port::A) & 0x80 != 0;
+    if pa7 != self.pa7 {
+      self.pa7 = pa7;
+      if pa7 == self.rising_edge {
+        trace!("RIOT PA7 edge");
+        self.edge_flag = true;
+      }
+    }
+  }
+
+  fn irq(&self) -> bool {
+    self.timer.irq() || (self.edge_flag && self.edge_irq)
+  }
+
+  /// Clears the ports and disables interrupts. RAM and the timer are left
+  /// alone.
+  fn reset(&mut self) {
+    debug!("Resetting 6532 RIOT");
+    self.ports.reset();
+    self.timer.disable_irq();
+    self.edge_irq = false;
+    self.edge_flag = false;
+    self.rising_edge = false;
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+impl Default for Riot {
+  fn default() -> Self {
+    Riot::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+  use crate::clock::FreeRunning;
+  use crate::CPU;
+  use rand::random;
+  use test_case::test_case;
+
+  /// Reads the timer, leaving its interrupt disabled.
+  const INTIM: u16 = 0x84;
+  /// Reads the interrupt flags.
+  const INSTAT: u16 = 0x85;
+
+  fn ticks(riot: &mut Riot, count: usize) {
+    for _ in 0..count {
+      riot.tick();
+    }
+  }
+
+  #[test]
+  fn ram() {
+    let mut riot = Riot::new();
+    let value = random();
+    riot.write(0x42, value);
+    assert_eq!(riot.read(0x42), value);
+    assert_eq!(riot.read(0x142), value);
+    riot.write(0x83, 0xFF);
+    riot.write(0x82, value);
+    assert_eq!(riot.read(0xC2), value);
+  }
+
+  #[test]
+  fn ports() {
+    let mut riot = Riot::new();
+    riot.get_ports_mut().set_input(Port::B, 0x0F);
+    riot.write(0x83, 0xF0);
+    riot.write(0x82, 0xA5);
+    assert_eq!(riot.read(0x82), 0xAF);
+    assert_eq!(riot.read(0x83), 0xF0);
+    assert_eq!(riot.read(0x80), 0xFF);
+  }
+
+  #[test_case(0x94, 1; "1T")]
+  #[test_case(0x95, 8; "8T")]
+  #[test_case(0x96, 64; "64T")]
+  #[test_case(0x97, 1024; "1024T")]
+  fn timer_prescalers(register: u16, interval: usize) {
+    let mut riot = Riot::new();
+    riot.write(register, 2);
+    riot.tick();
+    assert_eq!(riot.read(INTIM), 1);
+    ticks(&mut riot, 2 * interval - 1);
+    assert_eq!(riot.read(INSTAT), 0);
+    riot.tick();
+    assert_eq!(riot.read(INSTAT), FLAG_TIMER);
+    assert_eq!(riot.peek(INTIM), 0xFF);
+  }
+
+  #[test]
+  fn timer_counts_every_cycle_after_underflow() {
+    let mut riot = Riot::new();
+    riot.write(0x97, 0);
+    riot.tick();
+    ticks(&mut riot, 3);
+    assert_eq!(riot.read(INTIM), 0xFC);
+    assert_eq!(riot.peek(INSTAT), 0);
+  }
+
+  #[test]
+  fn timer_interrupt() {
+    let mut riot = Riot::new();
+    riot.write(0x9C, 1);
+    ticks(&mut riot, 2);
+    assert!(riot.irq());
+    riot.read(INTIM);
+    assert!(!riot.irq());
+    riot.write(0x94, 1);
+    ticks(&mut riot, 2);
+    assert!(!riot.irq());
+  }
+
+  #[test_case(0x86, 0x00, FLAG_PA7; "Falling edge")]
+  #[test_case(0x87, 0x00, 0; "Rising edge ignores falling")]
+  fn pa7_edges(register: u16, level: u8, expected: u8) {
+    let mut riot = Riot::new();
+    riot.write(register, 0);
+    riot.get_ports_mut().set_input(Port::A, level);
+    riot.tick();
+    assert_eq!(riot.peek(INSTAT) & FLAG_PA7, expected);
+    assert_eq!(riot.irq(), expected != 0);
+    riot.read(INSTAT);
+    assert!(!riot.irq());
+  }
+
+  #[test]
+  fn reset_keeps_ram() {
+    let mut riot = Riot::new();
+    riot.write(0x00, 0x42);
+    riot.write(0x81, 0xFF);
+    riot.reset();
+    assert_eq!(riot.peek(0x00), 0x42);
+    assert_eq!(riot.peek(0x81), 0x00);
+  }
+
+  #[test]
+  fn atari_2600_layout() {
+    let mut cpu = CPU::new(FreeRunning);
+    let riot = cpu.map_device(0x0080, 0x00FF, Box::new(Riot::new()));
+    cpu.mirror_device(riot, 0x0280, 0x029F, 0x80);
+    let source = "
+            LDA #$10
+            STA $029E
+            STA $80
+            CLI
+    loop:   JMP loop
+            .org $9000
+    handler:
+            INX
+            LDA $0284
+            RTI
+            .org $FFFE
+            .word handler";
+    let assembly = assemble(source, "riot.s", 0x8000).unwrap();
+    cpu.load_image(&assembly.image);
+    for _ in 0..1000 {
+      cpu.step();
+    }
+    let registers = cpu.get_registers();
+    assert_eq!(registers.x, 1);
+    assert_eq!(cpu.get_memory().peek(0x0080), 0x10);
+  }
+}
@@ -0,0 +1,80 @@
+use crate::devices::Device;
+use crate::prelude::*;
+use core::any::Any;
+
+/// A ROM chip. Writes are ignored, and an image smaller than the range it
+/// is mapped to is mirrored through it.
+#[derive(Clone)]
+pub struct Rom {
+  data: Vec<u8>,
+}
+
+impl Rom {
+  /// Creates a ROM holding an image.
+  ///
+  /// # Panics
+  /// Panics if the image is empty.
+  pub fn new(data: Vec<u8>) -> Rom {
+    if data.is_empty() {
+      panic!("ROM image is empty");
+    }
+    debug!("Initializing {} byte ROM", data.len());
+    Rom { data }
+  }
+
+  /// Gets the image.
+  pub fn get_data(&self) -> &[u8] {
+    &self.data
+  }
+}
+
+impl Device for Rom {
+  fn name(&self) -> &str {
+    "ROM"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    self.peek(register)
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    self.data[register as usize % self.data.len()]
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    trace!("Ignoring write of {:X} to ROM at {:X}", value, register);
+  }
+
+  fn reset(&mut self) {}
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+
+  #[test]
+  fn ignores_writes() {
+    let mut rom = Rom::new(vec![1, 2, 3, 4]);
+    rom.write(1, random());
+    assert_eq!(rom.read(1), 2);
+  }
+
+  #[test]
+  fn mirrors_small_images() {
+    let mut rom = Rom::new(vec![1, 2, 3, 4]);
+    assert_eq!(rom.read(0x1006), 3);
+  }
+}
@@ -0,0 +1,207 @@
+use crate::devices::riot::{IntervalTimer, FLAG_TIMER};
+use crate::devices::{Device, Peripheral, Port, Ports};
+use crate::prelude::*;
+use core::any::Any;
+
+/// Set in a register number to select the ROM.
+const ROM_SELECT: u16 = 0x400;
+/// Set in a register number to select the RAM rather than I/O.
+const RAM_SELECT: u16 = 0x40;
+/// Bytes of mask programmed ROM on the chip.
+const ROM_SIZE: usize = 1024;
+/// Bytes of RAM on the chip.
+const RAM_SIZE: usize = 64;
+
+/// The MOS 6530 ROM-RAM-I/O-Timer.
+///
+/// Each 6530 was mask programmed with its ROM contents and with the
+/// addresses it answered to, so the registers are laid out here to map
+/// easily onto any board:
+/// - 0x400-0x7FF: the 1K ROM
+/// - 0x040-0x07F: the 64 bytes of RAM
+/// - 0x000-0x03F: I/O and the timer, picked by the low address lines
+///
+/// Within I/O, A2 clear selects port A data and direction, then port B data
+/// and direction. With A2 set, writes load the timer, with A0-A1 choosing
+/// the 1, 8, 64 or 1024 cycle prescaler and A3 enabling its interrupt, and
+/// reads return the timer when A0 is clear or the interrupt flag in bit 7
+/// when A0 is set. On the real chip the interrupt shares a pin with PB7.
+#[derive(Clone)]
+pub struct Rriot {
+  rom: Vec<u8>,
+  ram: [u8; RAM_SIZE],
+  ports: Ports,
+  timer: IntervalTimer,
+}
+
+impl Rriot {
+  /// Creates a RRIOT holding a ROM image, with cleared RAM and every port
+  /// pin an input.
+  ///
+  /// # Panics
+  /// Panics if the image is not 1K.
+  pub fn new(rom: Vec<u8>) -> Rriot {
+    if rom.len() != ROM_SIZE {
+      panic!("6530 ROM image must be {} bytes", ROM_SIZE);
+    }
+    debug!("Initializing 6530 RRIOT");
+    Rriot {
+      rom,
+      ram: [0; RAM_SIZE],
+      ports: Ports::new(),
+      timer: IntervalTimer::new(),
+    }
+  }
+
+  /// Gets the ports, to read what the RRIOT is driving.
+  pub fn get_ports(&self) -> &Ports {
+    &self.ports
+  }
+
+  /// Gets the ports, to drive inputs or attach a peripheral.
+  pub fn get_ports_mut(&mut self) -> &mut Ports {
+    &mut self.ports
+  }
+
+  /// Attaches a peripheral to the ports.
+  pub fn set_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+    self.ports.set_peripheral(peripheral);
+  }
+}
+
+impl Device for Rriot {
+  fn name(&self) -> &str {
+    "6530 RRIOT"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    let value = self.peek(register);
+    if register & (ROM_SELECT | RAM_SELECT | 0x05) == 0x04 {
+      self.timer.read(register);
+    }
+    value
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    if register & ROM_SELECT != 0 {
+      return self.rom[register as usize % ROM_SIZE];
+    }
+    if register & RAM_SELECT != 0 {
+      return self.ram[register as usize % RAM_SIZE];
+    }
+    match register & 0x07 {
+      0x0 => self.ports.get_pins(Port::A),
+      0x1 => self.ports.get_direction(Port::A),
+      0x2 => self.ports.get_pins(Port::B),
+      0x3 => self.ports.get_direction(Port::B),
+      0x4 | 0x6 => self.timer.get_value(),
+      _ => match self.timer.is_flagged() {
+        true => FLAG_TIMER,
+        false => 0,
+      },
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    if register & ROM_SELECT != 0 {
+      trace!(
+        "Ignoring write of {:X} to 6530 ROM at {:X}",
+        value,
+        register
+      );
+      return;
+    }
+    if register & RAM_SELECT != 0 {
+      self.ram[register as usize % RAM_SIZE] = value;
+      return;
+    }
+    match register & 0x07 {
+      0x0 => self.ports.set_output(Port::A, value),
+      0x1 => self.ports.set_direction(Port::A, value),
+      0x2 => self.ports.set_output(Port::B, value),
+      0x3 => self.ports.set_direction(Port::B, value),
+      _ => self.timer.load(register, value),
+    }
+  }
+
+  fn tick(&mut self) {
+    self.timer.tick();
+  }
+
+  fn irq(&self) -> bool {
+    self.timer.irq()
+  }
+
+  /// Clears the ports and disables the timer interrupt. RAM and the timer
+  /// are left alone.
+  fn reset(&mut self) {
+    debug!("Resetting 6530 RRIOT");
+    self.ports.reset();
+    self.timer.disable_irq();
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+
+  fn rriot() -> Rriot {
+    Rriot::new((0..ROM_SIZE).map(|i| i as u8).collect())
+  }
+
+  #[test]
+  fn rom_and_ram() {
+    let mut rriot = rriot();
+    let value = random();
+    rriot.write(0x412, value);
+    assert_eq!(rriot.read(0x412), 0x12);
+    rriot.write(0x45, value);
+    assert_eq!(rriot.read(0x45), value);
+    assert_eq!(rriot.read(0x05), 0);
+  }
+
+  #[test]
+  fn ports() {
+    let mut rriot = rriot();
+    rriot.get_ports_mut().set_input(Port::A, 0x0F);
+    rriot.write(0x01, 0xF0);
+    rriot.write(0x00, 0xA5);
+    assert_eq!(rriot.read(0x00), 0xAF);
+    assert_eq!(rriot.read(0x01), 0xF0);
+  }
+
+  #[test]
+  fn timer_interrupt() {
+    let mut rriot = rriot();
+    rriot.write(0x0D, 1);
+    rriot.tick();
+    assert_eq!(rriot.read(0x07), 0);
+    for _ in 0..16 {
+      rriot.tick();
+    }
+    assert_eq!(rriot.read(0x07), FLAG_TIMER);
+    assert!(rriot.irq());
+    rriot.read(0x06);
+    assert!(!rriot.irq());
+    assert_eq!(rriot.peek(0x07), 0);
+  }
+
+  #[test]
+  #[should_panic]
+  fn rejects_wrong_rom_size() {
+    Rriot::new(vec![0; 2048]);
+  }
+}
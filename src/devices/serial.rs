@@ -0,0 +1,264 @@
+use crate::prelude::*;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use spin::Mutex;
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+#[cfg(feature = "std")]
+use std::sync::mpsc::{self, Receiver};
+
+/// The host end of a serial line.
+///
+/// Serial devices poll `receive` for bytes typed on the host and hand each
+/// byte the 6502 sends to `transmit`. Neither should block.
+pub trait Serial: Send {
+  /// Takes the next byte from the host, if one is waiting.
+  fn receive(&mut self) -> Option<u8>;
+
+  /// Sends a byte to the host.
+  fn transmit(&mut self, byte: u8);
+}
+
+/// A serial line to a pair of in memory queues, for tests and for driving a
+/// program from code.
+///
+/// Clones share the same queues, so keep a clone to feed input and collect
+/// output while the device owns the other.
+#[derive(Clone, Default)]
+pub struct Buffer {
+  input: Arc<Mutex<VecDeque<u8>>>,
+  output: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Buffer {
+  /// Creates a line with nothing waiting in either direction.
+  pub fn new() -> Buffer {
+    Buffer::default()
+  }
+
+  /// Queues bytes for the 6502 to receive.
+  pub fn push_input(&self, bytes: &[u8]) {
+    self.input.lock().extend(bytes);
+  }
+
+  /// Takes everything the 6502 has sent so far.
+  pub fn take_output(&self) -> Vec<u8> {
+    core::mem::take(&mut self.output.lock())
+  }
+}
+
+impl Serial for Buffer {
+  fn receive(&mut self) -> Option<u8> {
+    self.input.lock().pop_front()
+  }
+
+  fn transmit(&mut self, byte: u8) {
+    self.output.lock().push(byte);
+  }
+}
+
+/// Translates line endings between a host terminal and the carriage returns
+/// 6502 software expects.
+///
+/// Newlines typed on the host become carriage returns. Carriage returns
+/// sent by the 6502 become newlines, and a line feed straight after one is
+/// dropped so CR LF pairs don't print blank lines.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct LineEndings {
+  after_return: bool,
+}
+
+#[cfg(feature = "std")]
+impl LineEndings {
+  fn input(&self, byte: u8) -> u8 {
+    match byte {
+      b'\n' => b'\r',
+      _ => byte,
+    }
+  }
+
+  fn output(&mut self, byte: u8) -> Option<u8> {
+    let after_return = core::mem::replace(&mut self.after_return, byte == b'\r');
+    match byte {
+      b'\r' => Some(b'\n'),
+      b'\n' if after_return => None,
+      _ => Some(byte),
+    }
+  }
+}
+
+/// A serial line to the host's stdin and stdout.
+///
+/// Stdin is read on a background thread so polling never blocks. The host
+/// terminal stays in line mode, so input arrives a line at a time and is
+/// echoed by the terminal as well as by the 6502 software.
+#[cfg(feature = "std")]
+pub struct Terminal {
+  input: Receiver<u8>,
+  endings: LineEndings,
+}
+
+#[cfg(feature = "std")]
+impl Terminal {
+  /// Starts reading stdin.
+  pub fn new() -> Terminal {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+      let mut buffer = [0; 256];
+      while let Ok(length @ 1..) = io::stdin().read(&mut buffer) {
+        if buffer[..length].iter().any(|b| tx.send(*b).is_err()) {
+          break;
+        }
+      }
+    });
+    Terminal {
+      input: rx,
+      endings: LineEndings::default(),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Default for Terminal {
+  fn default() -> Self {
+    Terminal::new()
+  }
+}
+
+#[cfg(feature = "std")]
+impl Serial for Terminal {
+  fn receive(&mut self) -> Option<u8> {
+    self.input.try_recv().ok().map(|b| self.endings.input(b))
+  }
+
+  fn transmit(&mut self, byte: u8) {
+    if let Some(byte) = self.endings.output(byte) {
+      let mut stdout = io::stdout();
+      // Nowhere to report a closed stdout to, so the byte is dropped.
+      let _ = stdout.write_all(&[byte]).and_then(|_| stdout.flush());
+    }
+  }
+}
+
+/// A serial line to whoever connects to a TCP port, such as `telnet` or
+/// `nc`.
+///
+/// Connections are accepted while polling, one at a time. Bytes sent while
+/// nobody is connected are dropped, and a new connection replaces a closed
+/// one.
+#[cfg(feature = "std")]
+pub struct TcpSerial {
+  listener: TcpListener,
+  stream: Option<TcpStream>,
+}
+
+#[cfg(feature = "std")]
+impl TcpSerial {
+  /// Starts listening on an address.
+  pub fn listen<A: ToSocketAddrs>(address: A) -> io::Result<TcpSerial> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    debug!("Serial line listening on {}", listener.local_addr()?);
+    Ok(TcpSerial {
+      listener,
+      stream: None,
+    })
+  }
+
+  /// Gets the address being listened on.
+  pub fn local_addr(&self) -> io::Result<std::net::SocketAddr> {
+    self.listener.local_addr()
+  }
+
+  /// Gets the open connection, accepting a waiting one if there is none.
+  fn connection(&mut self) -> Option<&mut TcpStream> {
+    if self.stream.is_none() {
+      if let Ok((stream, peer)) = self.listener.accept() {
+        debug!("Serial line connected to {}", peer);
+        self.stream = stream.set_nonblocking(true).ok().map(|_| stream);
+      }
+    }
+    self.stream.as_mut()
+  }
+}
+
+#[cfg(feature = "std")]
+impl Serial for TcpSerial {
+  fn receive(&mut self) -> Option<u8> {
+    let stream = self.connection()?;
+    let mut byte = [0];
+    match stream.read(&mut byte) {
+      Ok(1) => Some(byte[0]),
+      Err(e) if e.kind() == io::ErrorKind::WouldBlock => None,
+      _ => {
+        debug!("Serial line disconnected");
+        self.stream = None;
+        None
+      }
+    }
+  }
+
+  fn transmit(&mut self, byte: u8) {
+    if let Some(stream) = self.connection() {
+      match stream.write_all(&[byte]) {
+        Err(e) if e.kind() != io::ErrorKind::WouldBlock => self.stream = None,
+        _ => (),
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn buffer_clones_share_queues() {
+    let buffer = Buffer::new();
+    let mut line = buffer.clone();
+    buffer.push_input(b"hi");
+    assert_eq!(line.receive(), Some(b'h'));
+    assert_eq!(line.receive(), Some(b'i'));
+    assert_eq!(line.receive(), None);
+    line.transmit(b'!');
+    assert_eq!(buffer.take_output(), b"!");
+    assert!(buffer.take_output().is_empty());
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn line_endings() {
+    let mut endings = LineEndings::default();
+    assert_eq!(endings.input(b'\n'), b'\r');
+    let output: Vec<u8> = b"a\r\nb\rc\nd"
+      .iter()
+      .filter_map(|b| endings.output(*b))
+      .collect();
+    assert_eq!(output, b"a\nb\nc\nd");
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn tcp_serial() {
+    let mut serial = TcpSerial::listen("127.0.0.1:0").unwrap();
+    serial.transmit(b'x');
+    let mut client = TcpStream::connect(serial.local_addr().unwrap()).unwrap();
+    client.write_all(b"A").unwrap();
+    let mut received = None;
+    for _ in 0..1000 {
+      received = serial.receive();
+      if received.is_some() {
+        break;
+      }
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(received, Some(b'A'));
+    serial.transmit(b'B');
+    let mut byte = [0];
+    client.read_exact(&mut byte).unwrap();
+    assert_eq!(&byte, b"B");
+  }
+}
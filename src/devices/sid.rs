@@ -0,0 +1,89 @@
+use crate::devices::Device;
+use crate::prelude::*;
+use core::any::Any;
+
+/// Registers on the chip, mirrored through its range.
+const REGISTERS: usize = 0x20;
+/// The first of the read only registers: the paddles, voice 3's oscillator
+/// and voice 3's envelope.
+const READ_ONLY: usize = 0x19;
+
+/// The register file of a SID, without any sound.
+///
+/// The write only registers read back what was last written, so programs
+/// that keep no copy of their settings still work, and the read only
+/// registers read 0.
+#[derive(Clone)]
+pub struct Sid {
+  registers: [u8; REGISTERS],
+}
+
+impl Sid {
+  /// Creates a SID with cleared registers.
+  pub fn new() -> Sid {
+    debug!("Initializing SID register file");
+    Sid {
+      registers: [0; REGISTERS],
+    }
+  }
+}
+
+impl Device for Sid {
+  fn name(&self) -> &str {
+    "SID"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    self.peek(register)
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    match register as usize % REGISTERS {
+      READ_ONLY..=0x1C => 0,
+      register => self.registers[register],
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    self.registers[register as usize % REGISTERS] = value;
+  }
+
+  fn reset(&mut self) {
+    debug!("Resetting SID register file");
+    self.registers = [0; REGISTERS];
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+impl Default for Sid {
+  fn default() -> Self {
+    Sid::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+
+  #[test]
+  fn registers() {
+    let mut sid = Sid::new();
+    let value = random();
+    sid.write(0x18 + 0x20, value);
+    assert_eq!(sid.read(0x18), value);
+    sid.write(0x1B, value);
+    assert_eq!(sid.read(0x1B), 0);
+  }
+}
@@ -0,0 +1,824 @@
+use crate::devices::{Device, Peripheral, Port, Ports};
+use crate::prelude::*;
+use core::any::Any;
+
+/// Output register B, or input register B when read.
+const ORB: u16 = 0x0;
+/// Output register A with handshaking, or input register A when read.
+const ORA: u16 = 0x1;
+/// Data direction register B.
+const DDRB: u16 = 0x2;
+/// Data direction register A.
+const DDRA: u16 = 0x3;
+/// Timer 1 counter, low byte. Writes go to the low latch.
+const T1C_L: u16 = 0x4;
+/// Timer 1 counter, high byte. Writes load the counter and start the timer.
+const T1C_H: u16 = 0x5;
+/// Timer 1 latch, low byte.
+const T1L_L: u16 = 0x6;
+/// Timer 1 latch, high byte.
+const T1L_H: u16 = 0x7;
+/// Timer 2 counter, low byte. Writes go to the low latch.
+const T2C_L: u16 = 0x8;
+/// Timer 2 counter, high byte. Writes load the counter and start the timer.
+const T2C_H: u16 = 0x9;
+/// Shift register.
+const SR: u16 = 0xA;
+/// Auxiliary control register.
+const ACR: u16 = 0xB;
+/// Peripheral control register.
+const PCR: u16 = 0xC;
+/// Interrupt flag register.
+const IFR: u16 = 0xD;
+/// Interrupt enable register.
+const IER: u16 = 0xE;
+/// Output register A without handshaking.
+const ORA_NO_HANDSHAKE: u16 = 0xF;
+
+/// Interrupt flag for an active edge on CA2.
+const IRQ_CA2: u8 = 0x01;
+/// Interrupt flag for an active edge on CA1.
+const IRQ_CA1: u8 = 0x02;
+/// Interrupt flag for the shift register completing a byte.
+const IRQ_SR: u8 = 0x04;
+/// Interrupt flag for an active edge on CB2.
+const IRQ_CB2: u8 = 0x08;
+/// Interrupt flag for an active edge on CB1.
+const IRQ_CB1: u8 = 0x10;
+/// Interrupt flag for timer 2 timing out.
+const IRQ_T2: u8 = 0x20;
+/// Interrupt flag for timer 1 timing out.
+const IRQ_T1: u8 = 0x40;
+
+/// The MOS 6522 Versatile Interface Adapter.
+///
+/// Sixteen registers, mirrored through whatever range the VIA is mapped to:
+/// - two 8 bit ports with data direction registers, with input latching on
+///   the CA1 and CB1 edges when enabled in the ACR
+/// - timer 1, one shot or free running, optionally driving PB7
+/// - timer 2, one shot or counting falling edges on PB6
+/// - an 8 bit shift register clocked by timer 2, the system clock or CB1
+/// - the CA1, CA2, CB1 and CB2 control lines, as interrupt inputs or as
+///   handshake, pulse or manual outputs depending on the PCR
+/// - the interrupt flag and enable registers, which drive the IRQ line
+///
+/// Timers count down once per CPU cycle. They interrupt on passing zero, so
+/// a timer loaded with N interrupts N + 1 cycles later, and in free running
+/// mode timer 1 then reloads from its latches every N + 2 cycles.
+#[derive(Clone)]
+pub struct Via {
+  ports: Ports,
+  input_a: u8,
+  input_b: u8,
+  t1_counter: u16,
+  t1_latch: u16,
+  t1_armed: bool,
+  t1_reload: bool,
+  pb7: bool,
+  t2_counter: u16,
+  t2_latch: u8,
+  t2_armed: bool,
+  pb6: bool,
+  shift: u8,
+  shift_count: u8,
+  shift_timer: u16,
+  shifting: bool,
+  acr: u8,
+  pcr: u8,
+  ifr: u8,
+  ier: u8,
+  ca1: bool,
+  ca2: bool,
+  cb1: bool,
+  cb2: bool,
+  ca2_pulse: bool,
+  cb2_pulse: bool,
+}
+
+impl Via {
+  /// Creates a VIA in its power on state, with every port pin an input.
+  pub fn new() -> Via {
+    debug!("Initializing 6522 VIA");
+    Via {
+      ports: Ports::new(),
+      input_a: 0,
+      input_b: 0,
+      t1_counter: 0xFFFF,
+      t1_latch: 0xFFFF,
+      t1_armed: false,
+      t1_reload: false,
+      pb7: true,
+      t2_counter: 0xFFFF,
+      t2_latch: 0xFF,
+      t2_armed: false,
+      pb6: true,
+      shift: 0,
+      shift_count: 0,
+      shift_timer: 0,
+      shifting: false,
+      acr: 0,
+      pcr: 0,
+      ifr: 0,
+      ier: 0,
+      ca1: true,
+      ca2: true,
+      cb1: true,
+      cb2: true,
+      ca2_pulse: false,
+      cb2_pulse: false,
+    }
+  }
+
+  /// Gets the ports, to read what the VIA is driving.
+  pub fn get_ports(&self) -> &Ports {
+    &self.ports
+  }
+
+  /// Gets the ports, to drive inputs or attach a peripheral.
+  pub fn get_ports_mut(&mut self) -> &mut Ports {
+    &mut self.ports
+  }
+
+  /// Attaches a peripheral to the ports.
+  pub fn set_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+    self.ports.set_peripheral(peripheral);
+  }
+
+  /// Gets the level of port B's pins, with PB7 driven by timer 1 when the
+  /// ACR hands it over.
+  pub fn get_port_b(&self) -> u8 {
+    let pins = self.ports.get_pins(Port::B);
+    match self.acr & 0x80 != 0 {
+      true => (pins & 0x7F) | ((self.pb7 as u8) << 7),
+      false => pins,
+    }
+  }
+
+  /// Gets the level of port A's pins.
+  pub fn get_port_a(&self) -> u8 {
+    self.ports.get_pins(Port::A)
+  }
+
+  /// Gets the interrupt flags, without the IRQ bit.
+  pub fn get_interrupt_flags(&self) -> u8 {
+    self.ifr
+  }
+
+  /// Gets the level of CA2, which is an output in PCR modes 4 to 7.
+  pub fn get_ca2(&self) -> bool {
+    self.ca2
+  }
+
+  /// Gets the level of CB2, which is an output in PCR modes 4 to 7 and
+  /// carries the shift register's data when shifting out.
+  pub fn get_cb2(&self) -> bool {
+    self.cb2
+  }
+
+  /// Drives the CA1 line.
+  pub fn set_ca1(&mut self, level: bool) {
+    if level == self.ca1 {
+      return;
+    }
+    self.ca1 = level;
+    if level == (self.pcr & 0x01 != 0) {
+      trace!("VIA CA1 active edge");
+      self.ifr |= IRQ_CA1;
+      if self.acr & 0x01 != 0 {
+        self.input_a = self.ports.get_pins(Port::A);
+      }
+      if self.ca2_control() == 4 {
+        self.ca2 = true;
+      }
+    }
+  }
+
+  /// Drives the CA2 line. Ignored while CA2 is an output.
+  pub fn set_ca2(&mut self, level: bool) {
+    let control = self.ca2_control();
+    if control >= 4 || level == self.ca2 {
+      return;
+    }
+    self.ca2 = level;
+    if level == (control & 0x02 != 0) {
+      trace!("VIA CA2 active edge");
+      self.ifr |= IRQ_CA2;
+    }
+  }
+
+  /// Drives the CB1 line. CB1 also clocks the shift register when the ACR
+  /// selects an external clock.
+  pub fn set_cb1(&mut self, level: bool) {
+    if level == self.cb1 {
+      return;
+    }
+    self.cb1 = level;
+    match self.shift_mode() {
+      3 if level => self.shift_bit(),
+      7 if !level => self.shift_bit(),
+      _ => (),
+    }
+    if level == (self.pcr & 0x10 != 0) {
+      trace!("VIA CB1 active edge");
+      self.ifr |= IRQ_CB1;
+      if self.acr & 0x02 != 0 {
+        self.input_b = self.ports.get_pins(Port::B);
+      }
+      if self.cb2_control() == 4 {
+        self.cb2 = true;
+      }
+    }
+  }
+
+  /// Drives the CB2 line, which is also the data input when shifting in.
+  /// Ignored while CB2 is an output.
+  pub fn set_cb2(&mut self, level: bool) {
+    let control = self.cb2_control();
+    if control >= 4 || level == self.cb2 {
+      return;
+    }
+    self.cb2 = level;
+    if level == (control & 0x02 != 0) {
+      trace!("VIA CB2 active edge");
+      self.ifr |= IRQ_CB2;
+    }
+  }
+
+  /// The CA2 mode from bits 1-3 of the PCR.
+  fn ca2_control(&self) -> u8 {
+    (self.pcr >> 1) & 0x07
+  }
+
+  /// The CB2 mode from bits 5-7 of the PCR.
+  fn cb2_control(&self) -> u8 {
+    (self.pcr >> 5) & 0x07
+  }
+
+  /// The shift register mode from bits 2-4 of the ACR.
+  fn shift_mode(&self) -> u8 {
+    (self.acr >> 2) & 0x07
+  }
+
+  /// Cycles between shifts when the shift register runs from timer 2 or the
+  /// system clock. Each bit takes two edges of the shift clock.
+  fn shift_period(&self) -> u16 {
+    match self.shift_mode() {
+      2 | 6 => 2,
+      _ => 2 * (self.t2_latch as u16 + 2),
+    }
+  }
+
+  /// Clears the CA1 and CA2 flags on an access to ORA, leaving CA2 alone in
+  /// its independent interrupt modes, and runs the CA2 handshake.
+  fn access_port_a(&mut self) {
+    self.ifr &= !IRQ_CA1;
+    let control = self.ca2_control();
+    if control != 1 && control != 3 {
+      self.ifr &= !IRQ_CA2;
+    }
+    match control {
+      4 => self.ca2 = false,
+      5 => {
+        self.ca2 = false;
+        self.ca2_pulse = true;
+      }
+      _ => (),
+    }
+  }
+
+  /// Clears the CB1 and CB2 flags on an access to ORB, leaving CB2 alone in
+  /// its independent interrupt modes.
+  fn access_port_b(&mut self) {
+    self.ifr &= !IRQ_CB1;
+    let control = self.cb2_control();
+    if control != 1 && control != 3 {
+      self.ifr &= !IRQ_CB2;
+    }
+  }
+
+  /// Runs the CB2 handshake, which only writes to ORB trigger.
+  fn handshake_port_b(&mut self) {
+    match self.cb2_control() {
+      4 => self.cb2 = false,
+      5 => {
+        self.cb2 = false;
+        self.cb2_pulse = true;
+      }
+      _ => (),
+    }
+  }
+
+  /// Updates CA2 and CB2 after the PCR changes. Outputs idle high, except in
+  /// the manual low modes.
+  fn update_control_outputs(&mut self) {
+    match self.ca2_control() {
+      6 => self.ca2 = false,
+      4 | 5 | 7 => self.ca2 = true,
+      _ => (),
+    }
+    match self.cb2_control() {
+      6 => self.cb2 = false,
+      4 | 5 | 7 => self.cb2 = true,
+      _ => (),
+    }
+  }
+
+  /// Starts shifting a byte, as any access to the shift register does.
+  fn start_shift(&mut self) {
+    self.ifr &= !IRQ_SR;
+    self.shift_count = 0;
+    self.shift_timer = self.shift_period();
+    self.shifting = self.shift_mode() != 0;
+  }
+
+  /// Shifts one bit in from CB2 or out onto CB2. Bytes shifted out
+  /// recirculate, so the shift register holds the same byte afterwards.
+  fn shift_bit(&mut self) {
+    let mode = self.shift_mode();
+    if !self.shifting && mode != 4 {
+      return;
+    }
+    match mode & 0x04 != 0 {
+      true => {
+        let bit = self.shift >> 7;
+        self.shift = (self.shift << 1) | bit;
+        self.cb2 = bit != 0;
+      }
+      false => self.shift = (self.shift << 1) | self.cb2 as u8,
+    }
+    self.shift_count += 1;
+    if self.shift_count == 8 {
+      self.shift_count = 0;
+      if mode != 4 {
+        trace!("VIA shift register done");
+        self.shifting = false;
+        self.ifr |= IRQ_SR;
+      }
+    }
+  }
+
+  /// Counts timer 1 down, reloading it in free running mode.
+  fn tick_timer_1(&mut self) {
+    if self.t1_reload {
+      self.t1_reload = false;
+      self.t1_counter = self.t1_latch;
+      return;
+    }
+    self.t1_counter = self.t1_counter.wrapping_sub(1);
+    if self.t1_counter != 0xFFFF {
+      return;
+    }
+    let free_running = self.acr & 0x40 != 0;
+    if self.t1_armed {
+      trace!("VIA timer 1 timed out");
+      self.ifr |= IRQ_T1;
+      self.pb7 = match free_running {
+        true => !self.pb7,
+        false => true,
+      };
+      self.t1_armed = free_running;
+    }
+    self.t1_reload = free_running;
+  }
+
+  /// Counts timer 2 down, either every cycle or on falling edges of PB6.
+  fn tick_timer_2(&mut self) {
+    if self.acr & 0x20 == 0 {
+      self.t2_counter = self.t2_counter.wrapping_sub(1);
+      if self.t2_counter == 0xFFFF && self.t2_armed {
+        trace!("VIA timer 2 timed out");
+        self.t2_armed = false;
+        self.ifr |= IRQ_T2;
+      }
+      return;
+    }
+    let pb6 = self.ports.get_pins(Port::B) & 0x40 != 0;
+    if self.pb6 && !pb6 {
+      self.t2_counter = self.t2_counter.wrapping_sub(1);
+      if self.t2_counter == 0 && self.t2_armed {
+        trace!("VIA timer 2 counted down");
+        self.t2_armed = false;
+        self.ifr |= IRQ_T2;
+      }
+    }
+    self.pb6 = pb6;
+  }
+
+  /// Runs the shift register when it is clocked internally.
+  fn tick_shift_register(&mut self) {
+    let mode = self.shift_mode();
+    if mode == 0 || mode == 3 || mode == 7 || (!self.shifting && mode != 4) {
+      return;
+    }
+    self.shift_timer = self.shift_timer.saturating_sub(1);
+    if self.shift_timer == 0 {
+      self.shift_timer = self.shift_period();
+      self.shift_bit();
+    }
+  }
+}
+
+impl Device for Via {
+  fn name(&self) -> &str {
+    "6522 VIA"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    let value = self.peek(register);
+    match register & 0x0F {
+      ORB => self.access_port_b(),
+      ORA => self.access_port_a(),
+      T1C_L => self.ifr &= !IRQ_T1,
+      T2C_L => self.ifr &= !IRQ_T2,
+      SR => self.start_shift(),
+      _ => (),
+    }
+    value
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    match register & 0x0F {
+      ORB => {
+        let direction = self.ports.get_direction(Port::B);
+        let input = match self.acr & 0x02 != 0 {
+          true => self.input_b,
+          false => self.get_port_b(),
+        };
+        (self.ports.get_output(Port::B) & direction) | (input & !direction)
+      }
+      ORA | ORA_NO_HANDSHAKE => match self.acr & 0x01 != 0 {
+        true => self.input_a,
+        false => self.get_port_a(),
+      },
+      DDRB => self.ports.get_direction(Port::B),
+      DDRA => self.ports.get_direction(Port::A),
+      T1C_L => self.t1_counter as u8,
+      T1C_H => (self.t1_counter >> 8) as u8,
+      T1L_L => self.t1_latch as u8,
+      T1L_H => (self.t1_latch >> 8) as u8,
+      T2C_L => self.t2_counter as u8,
+      T2C_H => (self.t2_counter >> 8) as u8,
+      SR => self.shift,
+      ACR => self.acr,
+      PCR => self.pcr,
+      IFR => self.ifr | ((self.irq() as u8) << 7),
+      IER => self.ier | 0x80,
+      _ => unreachable!(),
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    match register & 0x0F {
+      ORB => {
+        self.access_port_b();
+        self.handshake_port_b();
+        self.ports.set_output(Port::B, value);
+      }
+      ORA => {
+        self.access_port_a();
+        self.ports.set_output(Port::A, value);
+      }
+      ORA_NO_HANDSHAKE => self.ports.set_output(Port::A, value),
+      DDRB => self.ports.set_direction(Port::B, value),
+      DDRA => self.ports.set_direction(Port::A, value),
+      T1C_L | T1L_L => self.t1_latch = (self.t1_latch & 0xFF00) | value as u16,
+      T1C_H => {
+        self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+        self.t1_counter = self.t1_latch;
+        self.t1_armed = true;
+        self.t1_reload = false;
+        self.ifr &= !IRQ_T1;
+        if self.acr & 0x80 != 0 {
+          self.pb7 = false;
+        }
+      }
+      T1L_H => {
+        self.t1_latch = (self.t1_latch & 0x00FF) | (value as u16) << 8;
+        self.ifr &= !IRQ_T1;
+      }
+      T2C_L => self.t2_latch = value,
+      T2C_H => {
+        self.t2_counter = u16::from_le_bytes([self.t2_latch, value]);
+        self.t2_armed = true;
+        self.ifr &= !IRQ_T2;
+      }
+      SR => {
+        self.shift = value;
+        self.start_shift();
+      }
+      ACR => {
+        debug!("VIA ACR set to {:X}", value);
+        self.acr = value;
+      }
+      PCR => {
+        debug!("VIA PCR set to {:X}", value);
+        self.pcr = value;
+        self.update_control_outputs();
+      }
+      IFR => self.ifr &= !value,
+      IER => match value & 0x80 != 0 {
+        true => self.ier |= value & 0x7F,
+        false => self.ier &= !value,
+      },
+      _ => unreachable!(),
+    }
+  }
+
+  fn tick(&mut self) {
+    if self.ca2_pulse {
+      self.ca2_pulse = false;
+      self.ca2 = true;
+    }
+    if self.cb2_pulse {
+      self.cb2_pulse = false;
+      self.cb2 = true;
+    }
+    self.tick_timer_1();
+    self.tick_timer_2();
+    self.tick_shift_register();
+  }
+
+  fn irq(&self) -> bool {
+    self.ifr & self.ier & 0x7F != 0
+  }
+
+  /// Clears every register but the timers and the shift register, which the
+  /// reset line does not reach.
+  fn reset(&mut self) {
+    debug!("Resetting 6522 VIA");
+    self.ports.reset();
+    self.acr = 0;
+    self.pcr = 0;
+    self.ifr = 0;
+    self.ier = 0;
+    self.t1_armed = false;
+    self.t2_armed = false;
+    self.shifting = false;
+    self.update_control_outputs();
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+impl Default for Via {
+  fn default() -> Self {
+    Via::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+  use test_case::test_case;
+
+  fn ticks(via: &mut Via, count: usize) {
+    for _ in 0..count {
+      via.tick();
+    }
+  }
+
+  #[test]
+  fn ports_read_outputs_and_inputs() {
+    let mut via = Via::new();
+    via.get_ports_mut().set_input(Port::B, 0x0F);
+    via.write(DDRB, 0xF0);
+    via.write(ORB, 0x5A);
+    assert_eq!(via.read(ORB), 0x5F);
+    assert_eq!(via.get_port_b(), 0x5F);
+  }
+
+  #[test]
+  fn registers_are_mirrored() {
+    let mut via = Via::new();
+    let value = random();
+    via.write(0x1FF3, value);
+    assert_eq!(via.read(DDRA), value);
+  }
+
+  #[test]
+  fn timer_1_one_shot() {
+    let mut via = Via::new();
+    via.write(IER, 0x80 | IRQ_T1);
+    via.write(T1C_L, 0x10);
+    via.write(T1C_H, 0x00);
+    ticks(&mut via, 0x10);
+    assert!(!via.irq());
+    via.tick();
+    assert!(via.irq());
+    assert_eq!(via.read(IFR), 0x80 | IRQ_T1);
+    via.read(T1C_L);
+    assert!(!via.irq());
+    ticks(&mut via, 0x20000);
+    assert!(!via.irq());
+  }
+
+  #[test]
+  fn timer_1_free_running_toggles_pb7() {
+    let mut via = Via::new();
+    via.write(ACR, 0xC0);
+    via.write(T1C_L, 0x08);
+    via.write(T1C_H, 0x00);
+    assert_eq!(via.get_port_b() & 0x80, 0);
+    ticks(&mut via, 9);
+    assert_eq!(via.get_port_b() & 0x80, 0x80);
+    via.write(IFR, IRQ_T1);
+    ticks(&mut via, 10);
+    assert_eq!(via.get_interrupt_flags(), IRQ_T1);
+    assert_eq!(via.get_port_b() & 0x80, 0);
+  }
+
+  #[test]
+  fn timer_1_latch_write_keeps_counting() {
+    let mut via = Via::new();
+    via.write(ACR, 0x40);
+    via.write(T1C_L, 0x04);
+    via.write(T1C_H, 0x00);
+    via.write(T1L_L, 0x10);
+    via.write(T1L_H, 0x00);
+    ticks(&mut via, 5);
+    assert_eq!(via.get_interrupt_flags(), IRQ_T1);
+    via.tick();
+    assert_eq!(via.peek(T1C_L), 0x10);
+  }
+
+  #[test]
+  fn timer_2_one_shot() {
+    let mut via = Via::new();
+    via.write(IER, 0x80 | IRQ_T2);
+    via.write(T2C_L, 0x03);
+    via.write(T2C_H, 0x01);
+    ticks(&mut via, 0x103);
+    assert!(!via.irq());
+    via.tick();
+    assert!(via.irq());
+    via.read(T2C_L);
+    assert!(!via.irq());
+  }
+
+  #[test]
+  fn timer_2_counts_pulses() {
+    let mut via = Via::new();
+    via.write(ACR, 0x20);
+    via.write(T2C_L, 0x03);
+    via.write(T2C_H, 0x00);
+    for _ in 0..3 {
+      via.get_ports_mut().set_input(Port::B, 0xBF);
+      via.tick();
+      via.get_ports_mut().set_input(Port::B, 0xFF);
+      ticks(&mut via, 5);
+    }
+    assert_eq!(via.get_interrupt_flags(), IRQ_T2);
+  }
+
+  #[test]
+  fn shift_out_under_system_clock() {
+    let mut via = Via::new();
+    via.write(ACR, 0x18);
+    via.write(SR, 0x81);
+    let mut bits = vec![];
+    for _ in 0..8 {
+      ticks(&mut via, 2);
+      bits.push(via.get_cb2());
+    }
+    assert_eq!(bits, [true, false, false, false, false, false, false, true]);
+    assert_eq!(via.get_interrupt_flags(), IRQ_SR);
+    assert_eq!(via.peek(SR), 0x81);
+  }
+
+  #[test]
+  fn shift_in_under_external_clock() {
+    let mut via = Via::new();
+    via.write(ACR, 0x0C);
+    via.read(SR);
+    for bit in [true, false, true, true, false, false, true, false] {
+      via.set_cb2(bit);
+      via.set_cb1(false);
+      via.set_cb1(true);
+    }
+    assert_eq!(via.read(SR), 0xB2);
+  }
+
+  #[test]
+  fn shift_in_under_timer_2() {
+    let mut via = Via::new();
+    via.write(T2C_L, 0x02);
+    via.write(ACR, 0x04);
+    via.set_cb2(false);
+    via.write(SR, 0xFF);
+    ticks(&mut via, 8 * 8 - 1);
+    assert_eq!(via.get_interrupt_flags() & IRQ_SR, 0);
+    via.tick();
+    assert_eq!(via.get_interrupt_flags() & IRQ_SR, IRQ_SR);
+    assert_eq!(via.peek(SR), 0x00);
+  }
+
+  #[test_case(0x00, IRQ_CA1, IRQ_CA1; "Negative edge")]
+  #[test_case(0x01, 0, IRQ_CA1; "Positive edge")]
+  fn ca1_edges(pcr: u8, after_falling: u8, after_rising: u8) {
+    let mut via = Via::new();
+    via.write(PCR, pcr);
+    via.set_ca1(false);
+    assert_eq!(via.get_interrupt_flags(), after_falling);
+    via.set_ca1(true);
+    assert_eq!(via.get_interrupt_flags(), after_rising);
+  }
+
+  #[test]
+  fn reading_port_a_clears_ca_flags() {
+    let mut via = Via::new();
+    via.set_ca1(false);
+    via.set_ca2(false);
+    assert_eq!(via.get_interrupt_flags(), IRQ_CA1 | IRQ_CA2);
+    via.read(ORA_NO_HANDSHAKE);
+    assert_eq!(via.get_interrupt_flags(), IRQ_CA1 | IRQ_CA2);
+    via.read(ORA);
+    assert_eq!(via.get_interrupt_flags(), 0);
+  }
+
+  #[test]
+  fn independent_ca2_is_not_cleared_by_port_a() {
+    let mut via = Via::new();
+    via.write(PCR, 0x02);
+    via.set_ca2(false);
+    via.read(ORA);
+    assert_eq!(via.get_interrupt_flags(), IRQ_CA2);
+  }
+
+  #[test]
+  fn port_a_latches_on_ca1() {
+    let mut via = Via::new();
+    via.write(ACR, 0x01);
+    via.get_ports_mut().set_input(Port::A, 0x42);
+    via.set_ca1(false);
+    via.get_ports_mut().set_input(Port::A, 0x00);
+    assert_eq!(via.read(ORA), 0x42);
+  }
+
+  #[test]
+  fn ca2_handshake() {
+    let mut via = Via::new();
+    via.write(PCR, 0x08);
+    assert!(via.get_ca2());
+    via.read(ORA);
+    assert!(!via.get_ca2());
+    via.set_ca1(false);
+    assert!(via.get_ca2());
+  }
+
+  #[test]
+  fn cb2_pulse() {
+    let mut via = Via::new();
+    via.write(PCR, 0xA0);
+    via.read(ORB);
+    assert!(via.get_cb2());
+    via.write(ORB, random());
+    assert!(!via.get_cb2());
+    via.tick();
+    assert!(via.get_cb2());
+  }
+
+  #[test_case(0x0C, false; "Manual low")]
+  #[test_case(0x0E, true; "Manual high")]
+  fn ca2_manual_output(pcr: u8, expected: bool) {
+    let mut via = Via::new();
+    via.write(PCR, pcr);
+    assert_eq!(via.get_ca2(), expected);
+  }
+
+  #[test]
+  fn interrupt_enable_register() {
+    let mut via = Via::new();
+    via.write(IER, 0x80 | IRQ_T1 | IRQ_CA1);
+    assert_eq!(via.read(IER), 0x80 | IRQ_T1 | IRQ_CA1);
+    via.write(IER, IRQ_T1);
+    assert_eq!(via.read(IER), 0x80 | IRQ_CA1);
+    via.set_ca1(false);
+    assert!(via.irq());
+    via.write(IFR, IRQ_CA1);
+    assert!(!via.irq());
+  }
+
+  #[test]
+  fn reset_keeps_timers() {
+    let mut via = Via::new();
+    via.write(T1C_L, 0x34);
+    via.write(T1C_H, 0x12);
+    via.write(DDRA, 0xFF);
+    via.write(IER, 0xFF);
+    via.reset();
+    assert_eq!(via.peek(T1C_H), 0x12);
+    assert_eq!(via.peek(DDRA), 0);
+    assert_eq!(via.peek(IER), 0x80);
+  }
+}
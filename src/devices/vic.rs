@@ -0,0 +1,182 @@
+use crate::devices::Device;
+use crate::prelude::*;
+use core::any::Any;
+
+/// Control register 1, with bit 8 of the raster line in bit 7.
+const CONTROL_1: usize = 0x11;
+/// The raster line, low 8 bits.
+const RASTER: usize = 0x12;
+/// Sprite to sprite collisions, cleared on read.
+const SPRITE_COLLISIONS: usize = 0x1E;
+/// Sprite to background collisions, cleared on read.
+const DATA_COLLISIONS: usize = 0x1F;
+/// Interrupt flags.
+const IRQ_FLAGS: usize = 0x19;
+/// Interrupt enables.
+const IRQ_ENABLE: usize = 0x1A;
+/// Registers past the last one read back as 0xFF.
+const REGISTERS: usize = 0x2F;
+/// Interrupt flag for the raster reaching the compare line.
+const IRQ_RASTER: u8 = 0x01;
+/// Cycles per raster line on a PAL machine.
+const LINE_CYCLES: u16 = 63;
+/// Raster lines per frame on a PAL machine.
+const LINES: u16 = 312;
+
+/// The register file of a PAL VIC-II, without any video.
+///
+/// Registers read back what was last written, mirrored every 64 bytes. The
+/// raster counter runs as on a PAL 6569, 63 cycles per line and 312 lines
+/// per frame, so programs that wait for a raster line or use the raster
+/// interrupt keep time. Nothing is drawn, and the collision registers
+/// always read clear.
+#[derive(Clone)]
+pub struct VicII {
+  registers: [u8; REGISTERS],
+  raster: u16,
+  compare: u16,
+  cycle: u16,
+}
+
+impl VicII {
+  /// Creates a VIC-II with cleared registers at the top of a frame.
+  pub fn new() -> VicII {
+    debug!("Initializing VIC-II register file");
+    VicII {
+      registers: [0; REGISTERS],
+      raster: 0,
+      compare: 0,
+      cycle: 0,
+    }
+  }
+
+  /// Gets the raster line being drawn.
+  pub fn get_raster(&self) -> u16 {
+    self.raster
+  }
+}
+
+impl Device for VicII {
+  fn name(&self) -> &str {
+    "VIC-II"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    self.peek(register)
+  }
+
+  fn peek(&self, register: u16) -> u8 {
+    let register = register as usize & 0x3F;
+    match register {
+      CONTROL_1 => (self.registers[CONTROL_1] & 0x7F) | (((self.raster >> 1) & 0x80) as u8),
+      RASTER => self.raster as u8,
+      SPRITE_COLLISIONS | DATA_COLLISIONS => 0,
+      IRQ_FLAGS => self.registers[IRQ_FLAGS] | 0x70 | ((self.irq() as u8) << 7),
+      IRQ_ENABLE => self.registers[IRQ_ENABLE] | 0xF0,
+      0x20..=0x2E => self.registers[register] | 0xF0,
+      REGISTERS..=0x3F => 0xFF,
+      _ => self.registers[register],
+    }
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    let register = register as usize & 0x3F;
+    match register {
+      CONTROL_1 => {
+        self.compare = (self.compare & 0xFF) | (((value & 0x80) as u16) << 1);
+        self.registers[CONTROL_1] = value;
+      }
+      RASTER => self.compare = (self.compare & 0x100) | value as u16,
+      IRQ_FLAGS => self.registers[IRQ_FLAGS] &= !value & 0x0F,
+      IRQ_ENABLE => self.registers[IRQ_ENABLE] = value & 0x0F,
+      REGISTERS..=0x3F => (),
+      _ => self.registers[register] = value,
+    }
+  }
+
+  fn tick(&mut self) {
+    self.cycle += 1;
+    if self.cycle < LINE_CYCLES {
+      return;
+    }
+    self.cycle = 0;
+    self.raster = (self.raster + 1) % LINES;
+    if self.raster == self.compare {
+      trace!("VIC-II raster interrupt at line {}", self.raster);
+      self.registers[IRQ_FLAGS] |= IRQ_RASTER;
+    }
+  }
+
+  fn irq(&self) -> bool {
+    self.registers[IRQ_FLAGS] & self.registers[IRQ_ENABLE] != 0
+  }
+
+  fn reset(&mut self) {
+    debug!("Resetting VIC-II register file");
+    self.registers = [0; REGISTERS];
+    self.compare = 0;
+  }
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+impl Default for VicII {
+  fn default() -> Self {
+    VicII::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+
+  fn lines(vic: &mut VicII, count: u16) {
+    for _ in 0..count * LINE_CYCLES {
+      vic.tick();
+    }
+  }
+
+  #[test]
+  fn registers_mirror() {
+    let mut vic = VicII::new();
+    let value = random();
+    vic.write(0x20 + 0x40, value);
+    assert_eq!(vic.read(0x20), value | 0xF0);
+    assert_eq!(vic.read(0x30), 0xFF);
+  }
+
+  #[test]
+  fn raster_counts() {
+    let mut vic = VicII::new();
+    lines(&mut vic, 300);
+    assert_eq!(vic.read(RASTER as u16), 300u16 as u8);
+    assert_eq!(vic.read(CONTROL_1 as u16) & 0x80, 0x80);
+    lines(&mut vic, 12);
+    assert_eq!(vic.get_raster(), 0);
+  }
+
+  #[test]
+  fn raster_interrupt() {
+    let mut vic = VicII::new();
+    vic.write(RASTER as u16, 0x10);
+    vic.write(IRQ_ENABLE as u16, IRQ_RASTER);
+    lines(&mut vic, 0x0F);
+    assert!(!vic.irq());
+    lines(&mut vic, 1);
+    assert!(vic.irq());
+    assert_eq!(vic.read(IRQ_FLAGS as u16), 0xF1);
+    vic.write(IRQ_FLAGS as u16, IRQ_RASTER);
+    assert!(!vic.irq());
+  }
+}
@@ -0,0 +1,322 @@
+use crate::prelude::*;
+use crate::symbols::SymbolTable;
+use crate::Memory;
+use core::fmt::{Display, Formatter};
+
+/// The ways an instruction can find its operand.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+  Implied,
+  Accumulator,
+  Immediate,
+  ZeroPage,
+  ZeroPageX,
+  ZeroPageY,
+  Absolute,
+  AbsoluteX,
+  AbsoluteY,
+  Indirect,
+  IndexedX,
+  IndexedY,
+  Relative,
+}
+
+impl Mode {
+  /// Gets the number of bytes an instruction in this mode takes, including
+  /// the opcode.
+  pub fn size(self) -> u16 {
+    match self {
+      Mode::Implied | Mode::Accumulator => 1,
+      Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Indirect => 3,
+      _ => 2,
+    }
+  }
+}
+
+const IMP: Mode = Mode::Implied;
+const ACC: Mode = Mode::Accumulator;
+const IMM: Mode = Mode::Immediate;
+const ZP: Mode = Mode::ZeroPage;
+const ZPX: Mode = Mode::ZeroPageX;
+const ZPY: Mode = Mode::ZeroPageY;
+const ABS: Mode = Mode::Absolute;
+const ABX: Mode = Mode::AbsoluteX;
+const ABY: Mode = Mode::AbsoluteY;
+const IND: Mode = Mode::Indirect;
+const IZX: Mode = Mode::IndexedX;
+const IZY: Mode = Mode::IndexedY;
+const REL: Mode = Mode::Relative;
+
+/// Mnemonic and addressing mode of every opcode, using the same names for the
+/// undocumented opcodes as the CPU does.
+#[rustfmt::skip]
+pub const OPCODES: [(&str, Mode); 256] = [
+  ("BRK", IMP), ("ORA", IZX), ("KIL", IMP), ("SLO", IZX), ("DOP", ZP),  ("ORA", ZP),  ("ASL", ZP),  ("SLO", ZP),
+  ("PHP", IMP), ("ORA", IMM), ("ASL", ACC), ("AAC", IMM), ("TOP", ABS), ("ORA", ABS), ("ASL", ABS), ("SLO", ABS),
+  ("BPL", REL), ("ORA", IZY), ("KIL", IMP), ("SLO", IZY), ("DOP", ZPX), ("ORA", ZPX), ("ASL", ZPX), ("SLO", ZPX),
+  ("CLC", IMP), ("ORA", ABY), ("NOP", IMP), ("SLO", ABY), ("TOP", ABX), ("ORA", ABX), ("ASL", ABX), ("SLO", ABX),
+  ("JSR", ABS), ("AND", IZX), ("KIL", IMP), ("RLA", IZX), ("BIT", ZP),  ("AND", ZP),  ("ROL", ZP),  ("RLA", ZP),
+  ("PLP", IMP), ("AND", IMM), ("ROL", ACC), ("AAC", IMM), ("BIT", ABS), ("AND", ABS), ("ROL", ABS), ("RLA", ABS),
+  ("BMI", REL), ("AND", IZY), ("KIL", IMP), ("RLA", IZY), ("DOP", ZPX), ("AND", ZPX), ("ROL", ZPX), ("RLA", ZPX),
+  ("SEC", IMP), ("AND", ABY), ("NOP", IMP), ("RLA", ABY), ("TOP", ABX), ("AND", ABX), ("ROL", ABX), ("RLA", ABX),
+  ("RTI", IMP), ("EOR", IZX), ("KIL", IMP), ("SRE", IZX), ("DOP", ZP),  ("EOR", ZP),  ("LSR", ZP),  ("SRE", ZP),
+  ("PHA", IMP), ("EOR", IMM), ("LSR", ACC), ("ASR", IMM), ("JMP", ABS), ("EOR", ABS), ("LSR", ABS), ("SRE", ABS),
+  ("BVC", REL), ("EOR", IZY), ("KIL", IMP), ("SRE", IZY), ("DOP", ZPX), ("EOR", ZPX), ("LSR", ZPX), ("SRE", ZPX),
+  ("CLI", IMP), ("EOR", ABY), ("NOP", IMP), ("SRE", ABY), ("TOP", ABX), ("EOR", ABX), ("LSR", ABX), ("SRE", ABX),
+  ("RTS", IMP), ("ADC", IZX), ("KIL", IMP), ("RRA", IZX), ("DOP", ZP),  ("ADC", ZP),  ("ROR", ZP),  ("RRA", ZP),
+  ("PLA", IMP), ("ADC", IMM), ("ROR", ACC), ("ARR", IMM), ("JMP", IND), ("ADC", ABS), ("ROR", ABS), ("RRA", ABS),
+  ("BVS", REL), ("ADC", IZY), ("KIL", IMP), ("RRA", IZY), ("DOP", ZPX), ("ADC", ZPX), ("ROR", ZPX), ("RRA", ZPX),
+  ("SEI", IMP), ("ADC", ABY), ("NOP", IMP), ("RRA", ABY), ("TOP", ABX), ("ADC", ABX), ("ROR", ABX), ("RRA", ABX),
+  ("DOP", IMM), ("STA", IZX), ("DOP", IMM), ("AAX", IZX), ("STY", ZP),  ("STA", ZP),  ("STX", ZP),  ("AAX", ZP),
+  ("DEY", IMP), ("DOP", IMM), ("TXA", IMP), ("XAA", IMM), ("STY", ABS), ("STA", ABS), ("STX", ABS), ("AAX", ABS),
+  ("BCC", REL), ("STA", IZY), ("KIL", IMP), ("AXA", IZY), ("STY", ZPX), ("STA", ZPX), ("STX", ZPY), ("AAX", ZPY),
+  ("TYA", IMP), ("STA", ABY), ("TXS", IMP), ("XAS", ABY), ("SYA", ABX), ("STA", ABX), ("SXA", ABY), ("AXA", ABY),
+  ("LDY", IMM), ("LDA", IZX), ("LDX", IMM), ("LAX", IZX), ("LDY", ZP),  ("LDA", ZP),  ("LDX", ZP),  ("LAX", ZP),
+  ("TAY", IMP), ("LDA", IMM), ("TAX", IMP), ("ATX", IMM), ("LDY", ABS), ("LDA", ABS), ("LDX", ABS), ("LAX", ABS),
+  ("BCS", REL), ("LDA", IZY), ("KIL", IMP), ("LAX", IZY), ("LDY", ZPX), ("LDA", ZPX), ("LDX", ZPY), ("LAX", ZPY),
+  ("CLV", IMP), ("LDA", ABY), ("TSX", IMP), ("LAR", ABY), ("LDY", ABX), ("LDA", ABX), ("LDX", ABY), ("LAX", ABY),
+  ("CPY", IMM), ("CMP", IZX), ("DOP", IMM), ("DCP", IZX), ("CPY", ZP),  ("CMP", ZP),  ("DEC", ZP),  ("DCP", ZP),
+  ("INY", IMP), ("CMP", IMM), ("DEX", IMP), ("AXS", IMM), ("CPY", ABS), ("CMP", ABS), ("DEC", ABS), ("DCP", ABS),
+  ("BNE", REL), ("CMP", IZY), ("KIL", IMP), ("DCP", IZY), ("DOP", ZPX), ("CMP", ZPX), ("DEC", ZPX), ("DCP", ZPX),
+  ("CLD", IMP), ("CMP", ABY), ("NOP", IMP), ("DCP", ABY), ("TOP", ABX), ("CMP", ABX), ("DEC", ABX), ("DCP", ABX),
+  ("CPX", IMM), ("SBC", IZX), ("DOP", IMM), ("ISC", IZX), ("CPX", ZP),  ("SBC", ZP),  ("INC", ZP),  ("ISC", ZP),
+  ("INX", IMP), ("SBC", IMM), ("NOP", IMP), ("SBC", IMM), ("CPX", ABS), ("SBC", ABS), ("INC", ABS), ("ISC", ABS),
+  ("BEQ", REL), ("SBC", IZY), ("KIL", IMP), ("ISC", IZY), ("DOP", ZPX), ("SBC", ZPX), ("INC", ZPX), ("ISC", ZPX),
+  ("SED", IMP), ("SBC", ABY), ("NOP", IMP), ("ISC", ABY), ("TOP", ABX), ("SBC", ABX), ("INC", ABX), ("ISC", ABX),
+];
+
+/// Mnemonics of the documented instructions.
+const DOCUMENTED: [&str; 56] = [
+  "ADC", "AND", "ASL", "BCC", "BCS", "BEQ", "BIT", "BMI", "BNE", "BPL", "BRK", "BVC", "BVS", "CLC",
+  "CLD", "CLI", "CLV", "CMP", "CPX", "CPY", "DEC", "DEX", "DEY", "EOR", "INC", "INX", "INY", "JMP",
+  "JSR", "LDA", "LDX", "LDY", "LSR", "NOP", "ORA", "PHA", "PHP", "PLA", "PLP", "ROL", "ROR", "RTI",
+  "RTS", "SBC", "SEC", "SED", "SEI", "STA", "STX", "STY", "TAX", "TAY", "TSX", "TXA", "TXS", "TYA",
+];
+
+/// Returns true for opcodes missing from the 6502's documentation. These
+/// include the single byte NOPs other than 0xEA and the SBC at 0xEB, which
+/// share a documented instruction's mnemonic.
+pub fn is_undocumented(opcode: u8) -> bool {
+  matches!(opcode, 0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA | 0xEB)
+    || !DOCUMENTED.contains(&OPCODES[opcode as usize].0)
+}
+
+/// A single decoded instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Instruction {
+  pub address: u16,
+  pub opcode: u8,
+  pub mnemonic: &'static str,
+  pub mode: Mode,
+  /// The operand bytes as a little endian value. Zero if there are none.
+  pub operand: u16,
+}
+
+impl Instruction {
+  /// Decodes the instruction at an address. Takes the three bytes starting at
+  /// the address, of which only as many as the opcode needs are used.
+  pub fn decode(address: u16, bytes: [u8; 3]) -> Instruction {
+    let (mnemonic, mode) = OPCODES[bytes[0] as usize];
+    let operand = match mode.size() {
+      1 => 0,
+      2 => bytes[1] as u16,
+      _ => u16::from_le_bytes([bytes[1], bytes[2]]),
+    };
+    Instruction {
+      address,
+      opcode: bytes[0],
+      mnemonic,
+      mode,
+      operand,
+    }
+  }
+
+  /// Decodes the instruction at an address in memory, without any of the
+  /// side effects of a real read.
+  pub fn read(memory: &Memory, address: u16) -> Instruction {
+    let bytes = [
+      memory.peek(address),
+      memory.peek(address.wrapping_add(1)),
+      memory.peek(address.wrapping_add(2)),
+    ];
+    Instruction::decode(address, bytes)
+  }
+
+  /// Gets the number of bytes the instruction takes.
+  pub fn size(&self) -> u16 {
+    self.mode.size()
+  }
+
+  /// Gets the bytes that make up the instruction.
+  pub fn get_bytes(&self) -> Vec<u8> {
+    let [lo, hi] = self.operand.to_le_bytes();
+    [self.opcode, lo, hi][..self.size() as usize].to_vec()
+  }
+
+  /// Gets the address a branch or jump goes to, if the instruction has a
+  /// fixed target.
+  pub fn get_target(&self) -> Option<u16> {
+    match (self.mode, self.mnemonic) {
+      (Mode::Relative, _) => {
+        let next = self.address.wrapping_add(2);
+        Some(next.wrapping_add(self.operand as u8 as i8 as u16))
+      }
+      (Mode::Absolute, "JMP") | (Mode::Absolute, "JSR") => Some(self.operand),
+      _ => None,
+    }
+  }
+
+  /// Formats the instruction in assembler syntax. If a symbol table is given,
+  /// addresses with a symbol are printed by name.
+  pub fn format(&self, symbols: Option<&SymbolTable>) -> String {
+    let name = |address: u16, width: usize| -> String {
+      let symbol = symbols.and_then(|s| s.get_symbol(address));
+      match symbol {
+        Some(symbol) => symbol.name.clone(),
+        None => format!("${:0width$X}", address, width = width),
+      }
+    };
+    let operand = match self.mode {
+      Mode::Implied => String::new(),
+      Mode::Accumulator => " A".to_string(),
+      Mode::Immediate => format!(" #${:02X}", self.operand),
+      Mode::ZeroPage => format!(" {}", name(self.operand, 2)),
+      Mode::ZeroPageX => format!(" {},X", name(self.operand, 2)),
+      Mode::ZeroPageY => format!(" {},Y", name(self.operand, 2)),
+      Mode::Absolute => match (self.get_target(), symbols) {
+        (Some(target), Some(symbols)) => format!(" {}", symbols.format_address(target)),
+        _ => format!(" {}", name(self.operand, 4)),
+      },
+      Mode::AbsoluteX => format!(" {},X", name(self.operand, 4)),
+      Mode::AbsoluteY => format!(" {},Y", name(self.operand, 4)),
+      Mode::Indirect => format!(" ({})", name(self.operand, 4)),
+      Mode::IndexedX => format!(" ({},X)", name(self.operand, 2)),
+      Mode::IndexedY => format!(" ({}),Y", name(self.operand, 2)),
+      Mode::Relative => {
+        let target = self.get_target().unwrap_or_default();
+        match symbols {
+          Some(symbols) => format!(" {}", symbols.format_address(target)),
+          None => format!(" ${:04X}", target),
+        }
+      }
+    };
+    format!("{}{}", self.mnemonic, operand)
+  }
+}
+
+impl Display for Instruction {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    write!(f, "{}", self.format(None))
+  }
+}
+
+/// Decodes a number of instructions in a row, starting at an address.
+pub fn disassemble(memory: &Memory, start: u16, count: usize) -> Vec<Instruction> {
+  let mut address = start;
+  let mut instructions = Vec::with_capacity(count);
+  for _ in 0..count {
+    let instruction = Instruction::read(memory, address);
+    address = address.wrapping_add(instruction.size());
+    instructions.push(instruction);
+  }
+  instructions
+}
+
+/// Builds a listing of a number of instructions, one per line, with the
+/// address and raw bytes of each. With a symbol table, labels are printed
+/// above the addresses they name and source lines are added as comments.
+pub fn listing(memory: &Memory, start: u16, count: usize, symbols: Option<&SymbolTable>) -> String {
+  let mut text = String::new();
+  for instruction in disassemble(memory, start, count) {
+    if let Some(symbol) = symbols.and_then(|s| s.get_symbol(instruction.address)) {
+      text.push_str(&format!("{}:\n", symbol.name));
+    }
+    let bytes: Vec<String> = instruction
+      .get_bytes()
+      .iter()
+      .map(|b| format!("{:02X}", b))
+      .collect();
+    let mut line = format!(
+      "{:04X}  {:<8}  {}",
+      instruction.address,
+      bytes.join(" "),
+      instruction.format(symbols)
+    );
+    if let Some(source) = symbols.and_then(|s| s.get_line(instruction.address)) {
+      line = format!("{:<40}; {}", line, source);
+    }
+    text.push_str(line.trim_end());
+    text.push('\n');
+  }
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn memory_with(start: u16, program: &[u8]) -> Memory {
+    let mut memory = Memory::new();
+    for (i, byte) in program.iter().enumerate() {
+      memory.set(start + i as u16, *byte);
+    }
+    memory
+  }
+
+  #[test]
+  fn documented_opcodes() {
+    let documented = (0..=255).filter(|opcode| !is_undocumented(*opcode)).count();
+    assert_eq!(documented, 151);
+    assert!(is_undocumented(0xEB));
+    assert!(is_undocumented(0x02));
+    assert!(!is_undocumented(0xE9));
+  }
+
+  #[test_case([0xEA, 0, 0], "NOP"; "Implied")]
+  #[test_case([0x0A, 0, 0], "ASL A"; "Accumulator")]
+  #[test_case([0xA9, 0x10, 0], "LDA #$10"; "Immediate")]
+  #[test_case([0xA5, 0x10, 0], "LDA $10"; "Zero page")]
+  #[test_case([0xB6, 0x10, 0], "LDX $10,Y"; "Zero page y")]
+  #[test_case([0xAD, 0x34, 0x12], "LDA $1234"; "Absolute")]
+  #[test_case([0x7D, 0x34, 0x12], "ADC $1234,X"; "Absolute x")]
+  #[test_case([0x6C, 0x34, 0x12], "JMP ($1234)"; "Indirect")]
+  #[test_case([0xA1, 0x10, 0], "LDA ($10,X)"; "Indexed x")]
+  #[test_case([0xB1, 0x10, 0], "LDA ($10),Y"; "Indexed y")]
+  #[test_case([0xD0, 0xFE, 0], "BNE $8000"; "Branch backward")]
+  #[test_case([0xD0, 0x10, 0], "BNE $8012"; "Branch forward")]
+  fn decode(bytes: [u8; 3], expected: &str) {
+    let instruction = Instruction::decode(0x8000, bytes);
+    assert_eq!(instruction.to_string(), expected);
+    assert_eq!(
+      instruction.get_bytes(),
+      bytes[..instruction.size() as usize]
+    );
+  }
+
+  #[test]
+  fn disassemble_sequence() {
+    let memory = memory_with(0x8000, &[0xA9, 0x10, 0x8D, 0x00, 0x02, 0xEA]);
+    let instructions = disassemble(&memory, 0x8000, 3);
+    let addresses: Vec<u16> = instructions.iter().map(|i| i.address).collect();
+    assert_eq!(addresses, vec![0x8000, 0x8002, 0x8005]);
+  }
+
+  #[test]
+  fn symbolic_listing() {
+    let memory = memory_with(0x8000, &[0x20, 0x05, 0x80, 0xD0, 0xFB, 0x8D, 0x00, 0x02]);
+    let mut symbols = SymbolTable::new();
+    symbols.add("main", 0x8000, None);
+    symbols.add("print", 0x8005, None);
+    symbols.add("screen", 0x0200, None);
+    symbols.add_line("main.s", 7, 0x8000, 3);
+    let text = listing(&memory, 0x8000, 3, Some(&symbols));
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "main:");
+    assert!(lines[1].starts_with("8000  20 05 80  JSR print"));
+    assert!(lines[1].ends_with("; main.s:7"));
+    assert_eq!(lines[2], "8003  D0 FB     BNE main");
+    assert_eq!(lines[3], "print:");
+    assert_eq!(lines[4], "8005  8D 00 02  STA screen");
+  }
+}
@@ -0,0 +1,626 @@
+use crate::{Registers, CPU};
+use std::collections::BTreeSet;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+/// Number of instructions run between checks for an interrupt from the
+/// debugger while continuing.
+const POLL_INTERVAL: u64 = 1024;
+/// Signal reported when the CPU stops after a step or at a breakpoint.
+const SIGTRAP: u8 = 5;
+/// Signal reported when the debugger interrupts a running program.
+const SIGINT: u8 = 2;
+/// Signal reported when the CPU reaches a KIL opcode.
+const SIGILL: u8 = 4;
+/// Largest packet the stub accepts or sends, as advertised in qSupported.
+const PACKET_SIZE: usize = 0x4000;
+/// The byte the debugger sends to interrupt a running program.
+const INTERRUPT: u8 = 0x03;
+
+/// The kinds of breakpoint a debugger can set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+  /// Set with Z0. Kept by the stub rather than patched into memory, so they
+  /// work in ROM and never show up in memory reads.
+  Software,
+  /// Set with Z1.
+  Hardware,
+}
+
+/// What the stub should do after handling a packet.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+  Reply(String),
+  Resume(bool),
+  Detach,
+  Kill,
+}
+
+/// A GDB remote serial protocol server driving a CPU.
+///
+/// Registers are exposed in the order A, X, Y, SP, P, PC. All but the program
+/// counter are a single byte; the program counter is two bytes, little endian.
+/// Memory reads and writes go through the same memory and mapper the CPU uses,
+/// so bank switched cartridges look the way they do to the program.
+///
+/// The CPU is put into free running mode, so it no longer needs a clock.
+/// "monitor backtrace" prints the CPU's shadow call stack.
+pub struct GdbStub {
+  cpu: CPU,
+  software: BTreeSet<u16>,
+  hardware: BTreeSet<u16>,
+  no_ack: bool,
+}
+
+impl GdbStub {
+  /// Creates a stub driving a CPU.
+  pub fn new(mut cpu: CPU) -> GdbStub {
+    cpu.set_free_running(true);
+    GdbStub {
+      cpu,
+      software: BTreeSet::new(),
+      hardware: BTreeSet::new(),
+      no_ack: false,
+    }
+  }
+
+  /// Gets the CPU being debugged.
+  pub fn get_cpu(&self) -> &CPU {
+    &self.cpu
+  }
+
+  /// Gets the CPU being debugged for modification.
+  pub fn get_cpu_mut(&mut self) -> &mut CPU {
+    &mut self.cpu
+  }
+
+  /// Stops debugging and hands back the CPU.
+  pub fn into_cpu(self) -> CPU {
+    self.cpu
+  }
+
+  /// Adds a breakpoint.
+  pub fn add_breakpoint(&mut self, kind: Breakpoint, address: u16) {
+    debug!("Adding {:?} breakpoint at {:X}", kind, address);
+    match kind {
+      Breakpoint::Software => self.software.insert(address),
+      Breakpoint::Hardware => self.hardware.insert(address),
+    };
+  }
+
+  /// Removes a breakpoint. Returns false if there was no such breakpoint.
+  pub fn remove_breakpoint(&mut self, kind: Breakpoint, address: u16) -> bool {
+    debug!("Removing {:?} breakpoint at {:X}", kind, address);
+    match kind {
+      Breakpoint::Software => self.software.remove(&address),
+      Breakpoint::Hardware => self.hardware.remove(&address),
+    }
+  }
+
+  /// Returns true if there is a breakpoint of any kind at an address.
+  pub fn is_breakpoint(&self, address: u16) -> bool {
+    self.software.contains(&address) || self.hardware.contains(&address)
+  }
+
+  /// Waits for a single debugger to connect to an address and serves it until
+  /// it detaches or kills the session.
+  pub fn listen<A: ToSocketAddrs>(&mut self, address: A) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    debug!("Waiting for debugger on {}", listener.local_addr()?);
+    let (stream, peer) = listener.accept()?;
+    debug!("Debugger connected from {}", peer);
+    self.serve(stream)
+  }
+
+  /// Serves a connected debugger until it detaches or kills the session, or
+  /// the connection closes.
+  pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    while let Some(packet) = self.read_packet(&mut stream)? {
+      trace!("GDB packet: {}", packet);
+      match self.handle(&packet) {
+        Action::Reply(reply) => self.write_packet(&mut stream, &reply)?,
+        Action::Resume(step) => {
+          let stop = self.resume(step, &mut || interrupted(&mut stream));
+          self.write_packet(&mut stream, &stop)?;
+        }
+        Action::Detach => {
+          self.write_packet(&mut stream, "OK")?;
+          break;
+        }
+        Action::Kill => break,
+      }
+    }
+    debug!("Debugger session ended");
+    Ok(())
+  }
+
+  /// Runs the CPU until it hits a breakpoint, halts, exits through a trap or
+  /// the debugger interrupts it, or for a single instruction if stepping.
+  /// Returns the stop reply to send.
+  fn resume(&mut self, step: bool, interrupted: &mut dyn FnMut() -> bool) -> String {
+    let mut count: u64 = 0;
+    loop {
+      if let Some(code) = self.cpu.get_exit_code() {
+        return exited(code);
+      }
+      if self.cpu.is_halted() {
+        warn!("CPU locked at {:X}", self.cpu.get_registers().program_counter);
+        return signal(SIGILL);
+      }
+      self.cpu.step();
+      if let Some(code) = self.cpu.get_exit_code() {
+        return exited(code);
+      }
+      if step {
+        return signal(SIGTRAP);
+      }
+      if self.is_breakpoint(self.cpu.get_registers().program_counter) {
+        return signal(SIGTRAP);
+      }
+      count += 1;
+      if count.is_multiple_of(POLL_INTERVAL) && interrupted() {
+        return signal(SIGINT);
+      }
+    }
+  }
+
+  /// Works out the response to a packet.
+  fn handle(&mut self, packet: &str) -> Action {
+    let reply = |s: &str| Action::Reply(s.to_string());
+    let command = packet.get(..1).unwrap_or("");
+    let args = packet.get(1..).unwrap_or("");
+    match command {
+      "?" => match self.cpu.get_exit_code() {
+        Some(code) => Action::Reply(exited(code)),
+        None => Action::Reply(signal(SIGTRAP)),
+      },
+      "g" => Action::Reply(encode_registers(self.cpu.get_registers())),
+      "G" => match decode_registers(args) {
+        Some(registers) => {
+          self.cpu.set_registers(registers);
+          reply("OK")
+        }
+        None => reply("E01"),
+      },
+      "p" => match self.read_register(args) {
+        Some(value) => Action::Reply(value),
+        None => reply("E01"),
+      },
+      "P" => match self.write_register(args) {
+        Some(()) => reply("OK"),
+        None => reply("E01"),
+      },
+      "m" => match self.read_memory(args) {
+        Some(value) => Action::Reply(value),
+        None => reply("E01"),
+      },
+      "M" => match self.write_memory(args) {
+        Some(()) => reply("OK"),
+        None => reply("E01"),
+      },
+      "c" | "s" => match self.jump(args) {
+        Some(()) => Action::Resume(command == "s"),
+        None => reply("E01"),
+      },
+      "Z" | "z" => match self.breakpoint(command == "Z", args) {
+        Some(true) => reply("OK"),
+        Some(false) => reply(""),
+        None => reply("E01"),
+      },
+      "H" | "T" => reply("OK"),
+      "D" => Action::Detach,
+      "k" => Action::Kill,
+      _ => self.handle_query(packet),
+    }
+  }
+
+  /// Works out the response to a query or v packet.
+  fn handle_query(&mut self, packet: &str) -> Action {
+    let reply = |s: &str| Action::Reply(s.to_string());
+    match packet {
+      p if p.starts_with("qSupported") => {
+        reply(&format!("PacketSize={:x};QStartNoAckMode+", PACKET_SIZE))
+      }
+      "QStartNoAckMode" => {
+        self.no_ack = true;
+        reply("OK")
+      }
+      "qAttached" => reply("1"),
+      "qC" => reply("QC1"),
+      "qfThreadInfo" => reply("m1"),
+      "qsThreadInfo" => reply("l"),
+      "vCont?" => reply("vCont;c;s"),
+      p if p.starts_with("qRcmd,") => {
+        let command = decode(&p[6..]).map(|c| String::from_utf8_lossy(&c).into_owned());
+        let output = match command.as_deref().map(str::trim) {
+          Some("bt") | Some("backtrace") => self.cpu.backtrace(),
+          _ => "Unknown monitor command. Try \"monitor backtrace\".\n".to_string(),
+        };
+        Action::Reply(encode(output.as_bytes()))
+      }
+      p if p.starts_with("vCont;c") => Action::Resume(false),
+      p if p.starts_with("vCont;s") => Action::Resume(true),
+      _ => reply(""),
+    }
+  }
+
+  /// Reads a single register, numbered as in the g packet.
+  fn read_register(&self, args: &str) -> Option<String> {
+    let registers = self.cpu.get_registers();
+    let value = match usize::from_str_radix(args, 16).ok()? {
+      0 => registers.accumulator,
+      1 => registers.x,
+      2 => registers.y,
+      3 => registers.stack_pointer,
+      4 => registers.status,
+      5 => return Some(encode(&registers.program_counter.to_le_bytes())),
+      _ => return None,
+    };
+    Some(encode(&[value]))
+  }
+
+  /// Writes a single register from an n=value argument.
+  fn write_register(&mut self, args: &str) -> Option<()> {
+    let (number, value) = args.split_once('=')?;
+    let bytes = decode(value)?;
+    let mut registers = self.cpu.get_registers();
+    let byte = *bytes.first()?;
+    match usize::from_str_radix(number, 16).ok()? {
+      0 => registers.accumulator = byte,
+      1 => registers.x = byte,
+      2 => registers.y = byte,
+      3 => registers.stack_pointer = byte,
+      4 => registers.status = byte,
+      5 => registers.program_counter = u16::from_le_bytes([byte, *bytes.get(1)?]),
+      _ => return None,
+    }
+    self.cpu.set_registers(registers);
+    Some(())
+  }
+
+  /// Reads memory from an address,length argument. Lengths that would not fit
+  /// in a reply packet are refused.
+  fn read_memory(&self, args: &str) -> Option<String> {
+    let (address, length) = parse_range(args)?;
+    if length > PACKET_SIZE / 2 {
+      return None;
+    }
+    let memory = self.cpu.get_memory();
+    let bytes: Vec<u8> = (0..length)
+      .map(|i| memory.peek(address.wrapping_add(i as u16)))
+      .collect();
+    Some(encode(&bytes))
+  }
+
+  /// Writes memory from an address,length:data argument.
+  fn write_memory(&mut self, args: &str) -> Option<()> {
+    let (range, data) = args.split_once(':')?;
+    let (address, length) = parse_range(range)?;
+    let bytes = decode(data)?;
+    if bytes.len() != length {
+      return None;
+    }
+    let memory = self.cpu.get_memory_mut();
+    for (i, byte) in bytes.iter().enumerate() {
+      memory.poke(address.wrapping_add(i as u16), *byte);
+    }
+    Some(())
+  }
+
+  /// Moves the program counter if a continue or step packet gave an address.
+  fn jump(&mut self, args: &str) -> Option<()> {
+    if !args.is_empty() {
+      let mut registers = self.cpu.get_registers();
+      registers.program_counter = u16::from_str_radix(args, 16).ok()?;
+      self.cpu.set_registers(registers);
+    }
+    Some(())
+  }
+
+  /// Inserts or removes a breakpoint from a type,address,kind argument.
+  /// Returns false for watchpoints, which are not supported.
+  fn breakpoint(&mut self, insert: bool, args: &str) -> Option<bool> {
+    let mut parts = args.split(',');
+    let kind = match parts.next()? {
+      "0" => Breakpoint::Software,
+      "1" => Breakpoint::Hardware,
+      _ => return Some(false),
+    };
+    let address = u16::from_str_radix(parts.next()?, 16).ok()?;
+    match insert {
+      true => self.add_breakpoint(kind, address),
+      false => {
+        self.remove_breakpoint(kind, address);
+      }
+    }
+    Some(true)
+  }
+
+  /// Reads the next packet, acknowledging it unless acks have been turned
+  /// off. Returns None when the connection closes.
+  fn read_packet(&mut self, stream: &mut TcpStream) -> io::Result<Option<String>> {
+    loop {
+      match read_byte(stream)? {
+        None => return Ok(None),
+        Some(b'$') => (),
+        Some(_) => continue,
+      }
+      let mut data = vec![];
+      loop {
+        match read_byte(stream)? {
+          None => return Ok(None),
+          Some(b'#') => break,
+          Some(byte) => data.push(byte),
+        }
+      }
+      let mut checksum = [0; 2];
+      stream.read_exact(&mut checksum)?;
+      let expected = std::str::from_utf8(&checksum)
+        .ok()
+        .and_then(|c| u8::from_str_radix(c, 16).ok());
+      if expected != Some(sum(&data)) && !self.no_ack {
+        warn!("Bad checksum on GDB packet, asking for it again");
+        stream.write_all(b"-")?;
+        continue;
+      }
+      if !self.no_ack {
+        stream.write_all(b"+")?;
+      }
+      return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+    }
+  }
+
+  /// Sends a packet.
+  fn write_packet(&mut self, stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    trace!("GDB reply: {}", data);
+    let packet = format!("${}#{:02x}", data, sum(data.as_bytes()));
+    stream.write_all(packet.as_bytes())
+  }
+}
+
+/// Reads a single byte, or None if the connection closed.
+fn read_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+  let mut byte = [0];
+  match stream.read(&mut byte)? {
+    0 => Ok(None),
+    _ => Ok(Some(byte[0])),
+  }
+}
+
+/// Checks, without blocking, whether the debugger has sent an interrupt.
+fn interrupted(stream: &mut TcpStream) -> bool {
+  if stream.set_nonblocking(true).is_err() {
+    return false;
+  }
+  let mut byte = [0];
+  let result = match stream.read(&mut byte) {
+    Ok(1) => byte[0] == INTERRUPT,
+    Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+    _ => false,
+  };
+  let _ = stream.set_nonblocking(false);
+  result
+}
+
+/// The modulo 256 sum of the packet data.
+/// The stop reply for a signal.
+fn signal(number: u8) -> String {
+  format!("S{:02x}", number)
+}
+
+/// The stop reply for a program that has exited.
+fn exited(code: u8) -> String {
+  format!("W{:02x}", code)
+}
+
+fn sum(data: &[u8]) -> u8 {
+  data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+/// Encodes bytes as lower case hex.
+fn encode(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decodes a string of hex pairs.
+fn decode(text: &str) -> Option<Vec<u8>> {
+  if !text.len().is_multiple_of(2) {
+    return None;
+  }
+  (0..text.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+    .collect()
+}
+
+/// Parses an address,length argument.
+fn parse_range(args: &str) -> Option<(u16, usize)> {
+  let (address, length) = args.split_once(',')?;
+  let address = u16::from_str_radix(address, 16).ok()?;
+  let length = usize::from_str_radix(length, 16).ok()?;
+  Some((address, length))
+}
+
+/// Encodes the registers in the order the g packet uses.
+fn encode_registers(registers: Registers) -> String {
+  let pc = registers.program_counter.to_le_bytes();
+  encode(&[
+    registers.accumulator,
+    registers.x,
+    registers.y,
+    registers.stack_pointer,
+    registers.status,
+    pc[0],
+    pc[1],
+  ])
+}
+
+/// Decodes the registers from a G packet.
+fn decode_registers(text: &str) -> Option<Registers> {
+  let bytes = decode(text)?;
+  if bytes.len() != 7 {
+    return None;
+  }
+  Some(Registers {
+    accumulator: bytes[0],
+    x: bytes[1],
+    y: bytes[2],
+    stack_pointer: bytes[3],
+    status: bytes[4],
+    program_counter: u16::from_le_bytes([bytes[5], bytes[6]]),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::traps::{TrapResult, TrapSite};
+  use crate::STARTING_MEMORY_BLOCK;
+  use std::sync::mpsc;
+  use std::thread;
+  use test_case::test_case;
+
+  fn stub(program: &[u8]) -> GdbStub {
+    let (_, rx) = mpsc::channel();
+    let mut stub = GdbStub::new(CPU::new(rx));
+    let memory = stub.cpu.get_memory_mut();
+    for (i, byte) in program.iter().enumerate() {
+      memory.poke(STARTING_MEMORY_BLOCK + i as u16, *byte);
+    }
+    let mut registers = stub.cpu.get_registers();
+    registers.program_counter = STARTING_MEMORY_BLOCK;
+    stub.cpu.set_registers(registers);
+    stub
+  }
+
+  fn reply(stub: &mut GdbStub, packet: &str) -> String {
+    match stub.handle(packet) {
+      Action::Reply(reply) => reply,
+      other => panic!("Expected a reply, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn registers() {
+    let mut stub = stub(&[]);
+    assert_eq!(reply(&mut stub, "G01020304050080"), "OK");
+    assert_eq!(reply(&mut stub, "g"), "01020304050080");
+    assert_eq!(reply(&mut stub, "p5"), "0080");
+    assert_eq!(reply(&mut stub, "P1=ff"), "OK");
+    assert_eq!(reply(&mut stub, "p1"), "ff");
+    assert_eq!(reply(&mut stub, "p6"), "E01");
+    assert_eq!(reply(&mut stub, "G0102"), "E01");
+  }
+
+  #[test]
+  fn memory() {
+    let mut stub = stub(&[0xA9, 0x10]);
+    assert_eq!(reply(&mut stub, "m8000,2"), "a910");
+    assert_eq!(reply(&mut stub, "M0200,3:010203"), "OK");
+    assert_eq!(reply(&mut stub, "m0200,3"), "010203");
+    assert_eq!(reply(&mut stub, "M0200,2:01"), "E01");
+    assert_eq!(reply(&mut stub, "m0000,2000").len(), 0x4000);
+    assert_eq!(reply(&mut stub, "m0000,2001"), "E01");
+    assert_eq!(reply(&mut stub, "m0000,ffffffffffffffff"), "E01");
+  }
+
+  #[test_case("Z0,8004,1", "OK"; "Software")]
+  #[test_case("Z1,8004,1", "OK"; "Hardware")]
+  #[test_case("Z2,8004,1", ""; "Watchpoint")]
+  fn insert_breakpoint(packet: &str, expected: &str) {
+    let mut stub = stub(&[]);
+    assert_eq!(reply(&mut stub, packet), expected);
+  }
+
+  #[test]
+  fn continue_to_breakpoint() {
+    let mut stub = stub(&[0xA9, 0x10, 0xAA, 0xE8, 0xEA]);
+    reply(&mut stub, "Z0,8004,1");
+    assert_eq!(stub.handle("c"), Action::Resume(false));
+    assert_eq!(stub.resume(false, &mut || false), "S05");
+    let registers = stub.cpu.get_registers();
+    assert_eq!(registers.program_counter, 0x8004);
+    assert_eq!(registers.x, 0x11);
+    reply(&mut stub, "z0,8004,1");
+    assert!(!stub.is_breakpoint(0x8004));
+  }
+
+  #[test]
+  fn step() {
+    let mut stub = stub(&[0xA9, 0x10, 0x02]);
+    assert_eq!(stub.resume(true, &mut || false), "S05");
+    assert_eq!(stub.cpu.get_registers().accumulator, 0x10);
+    assert_eq!(stub.resume(false, &mut || false), "S04");
+  }
+
+  #[test]
+  fn exit_trap() {
+    // LDA #$10; JSR $FFF0
+    let mut stub = stub(&[0xA9, 0x10, 0x20, 0xF0, 0xFF]);
+    stub.cpu.add_trap(
+      TrapSite::Address(0xFFF0),
+      Box::new(|_: &mut CPU| TrapResult::Exit(3)),
+    );
+    assert_eq!(stub.resume(false, &mut || false), "W03");
+    assert_eq!(stub.resume(true, &mut || false), "W03");
+    assert_eq!(reply(&mut stub, "?"), "W03");
+  }
+
+  #[test]
+  fn monitor_backtrace() {
+    let mut stub = stub(&[0x20, 0x10, 0x80]);
+    stub.resume(true, &mut || false);
+    let output = decode(&reply(&mut stub, &format!("qRcmd,{}", encode(b"bt")))).unwrap();
+    assert_eq!(
+      String::from_utf8(output).unwrap(),
+      "#0  $8010\n#1  $8000 (called $8010)\n"
+    );
+  }
+
+  #[test]
+  fn interrupt() {
+    let mut stub = stub(&[0x4C, 0x00, 0x80]);
+    assert_eq!(stub.resume(false, &mut || true), "S02");
+  }
+
+  #[test]
+  fn session() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+      let mut stub = stub(&[0xA2, 0x42, 0xEA]);
+      let (stream, _) = listener.accept().unwrap();
+      stub.serve(stream).unwrap();
+      stub.into_cpu().get_registers()
+    });
+    let mut client = TcpStream::connect(address).unwrap();
+    let mut exchange = |packet: &str| -> String {
+      client
+        .write_all(format!("${}#{:02x}", packet, sum(packet.as_bytes())).as_bytes())
+        .unwrap();
+      let mut response = vec![];
+      let mut byte = [0];
+      while byte[0] != b'#' {
+        client.read_exact(&mut byte).unwrap();
+        response.push(byte[0]);
+      }
+      let mut checksum = [0; 2];
+      client.read_exact(&mut checksum).unwrap();
+      let text = String::from_utf8(response).unwrap();
+      text
+        .trim_start_matches('+')
+        .trim_start_matches('$')
+        .trim_end_matches('#')
+        .to_string()
+    };
+    assert_eq!(
+      exchange("qSupported:swbreak+"),
+      "PacketSize=4000;QStartNoAckMode+"
+    );
+    assert_eq!(exchange("s"), "S05");
+    assert_eq!(exchange("p1"), "42");
+    assert_eq!(exchange("D"), "OK");
+    assert_eq!(server.join().unwrap().program_counter, 0x8002);
+  }
+}
@@ -0,0 +1,162 @@
+use crate::prelude::*;
+/// The interrupts the CPU can take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interrupt {
+  Reset,
+  Nmi,
+  Irq,
+  Break,
+}
+
+/// The kinds of access the CPU reports to hooks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessKind {
+  /// A data read.
+  Read,
+  /// A data write.
+  Write,
+  /// The first byte of an instruction.
+  OpcodeFetch,
+  /// An operand byte of an instruction.
+  OperandFetch,
+  /// A byte pushed onto the stack.
+  Push,
+  /// A byte pulled off the stack.
+  Pop,
+  /// An interrupt was taken. The address is the handler and the value is the
+  /// status register that was pushed.
+  Interrupt(Interrupt),
+}
+
+/// A single access made by the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryEvent {
+  pub kind: AccessKind,
+  pub address: u16,
+  pub value: u8,
+  /// The machine cycle the access happened in.
+  pub cycle: u64,
+  /// The address of the instruction that made the access.
+  pub program_counter: u16,
+}
+
+/// Identifies a registered hook so it can be removed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct HookId(usize);
+
+/// A function called with every access the CPU makes.
+pub type Hook = Box<dyn FnMut(&MemoryEvent) + Send>;
+
+/// The hooks registered on a CPU.
+///
+/// The CPU checks `is_empty` before building an event, so having no hooks
+/// registered costs a single branch per access.
+#[derive(Default)]
+pub struct Hooks {
+  hooks: Vec<(HookId, Hook)>,
+  next: usize,
+}
+
+impl Hooks {
+  /// Creates an empty set of hooks.
+  pub fn new() -> Hooks {
+    Hooks::default()
+  }
+
+  /// Returns true if no hooks are registered.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.hooks.is_empty()
+  }
+
+  /// Gets the number of hooks registered.
+  pub fn len(&self) -> usize {
+    self.hooks.len()
+  }
+
+  /// Registers a hook.
+  pub fn add(&mut self, hook: Hook) -> HookId {
+    let id = HookId(self.next);
+    self.next += 1;
+    self.hooks.push((id, hook));
+    id
+  }
+
+  /// Removes a hook. Returns false if it was not registered.
+  pub fn remove(&mut self, id: HookId) -> bool {
+    let before = self.hooks.len();
+    self.hooks.retain(|(i, _)| *i != id);
+    self.hooks.len() != before
+  }
+
+  /// Removes every hook.
+  pub fn clear(&mut self) {
+    self.hooks.clear();
+  }
+
+  /// Calls every hook with an event, in the order they were registered.
+  pub fn notify(&mut self, event: &MemoryEvent) {
+    for (_, hook) in self.hooks.iter_mut() {
+      hook(event);
+    }
+  }
+}
+
+/// Builds a hook that logs every access at trace level.
+pub fn trace_logger() -> Hook {
+  Box::new(|event: &MemoryEvent| {
+    trace!(
+      "{:>10} {:04X}: {:?} {:04X} = {:02X}",
+      event.cycle,
+      event.program_counter,
+      event.kind,
+      event.address,
+      event.value
+    )
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::{Arc, Mutex};
+
+  fn event(address: u16) -> MemoryEvent {
+    MemoryEvent {
+      kind: AccessKind::Read,
+      address,
+      value: 0,
+      cycle: 0,
+      program_counter: 0,
+    }
+  }
+
+  #[test]
+  fn add_and_remove() {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let mut hooks = Hooks::new();
+    assert!(hooks.is_empty());
+    let first = {
+      let seen = seen.clone();
+      hooks.add(Box::new(move |e| {
+        seen.lock().unwrap().push(("first", e.address))
+      }))
+    };
+    {
+      let seen = seen.clone();
+      hooks.add(Box::new(move |e| {
+        seen.lock().unwrap().push(("second", e.address))
+      }));
+    }
+    hooks.notify(&event(1));
+    assert!(hooks.remove(first));
+    assert!(!hooks.remove(first));
+    hooks.notify(&event(2));
+    assert_eq!(
+      *seen.lock().unwrap(),
+      vec![("first", 1), ("second", 1), ("second", 2)]
+    );
+    hooks.clear();
+    assert_eq!(hooks.len(), 0);
+  }
+}
@@ -0,0 +1,324 @@
+use crate::mappers::{Cnrom, Mapper, Mirroring, Mmc1, Nrom, Uxrom, BANK_16K, BANK_8K};
+use crate::prelude::*;
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// Every iNES file starts with "NES" and an MS-DOS end of file.
+const MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+/// Size of the header at the start of the file.
+const HEADER_SIZE: usize = 16;
+/// Size of the optional trainer that sits between the header and PRG ROM.
+const TRAINER_SIZE: usize = 512;
+/// Where the trainer is loaded in the CPU address space.
+const TRAINER_ADDRESS: u16 = 0x7000;
+
+/// Things that can go wrong while reading a cartridge.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+  /// The file does not start with the iNES magic number.
+  BadMagic,
+  /// The file is shorter than its header says it should be.
+  TooShort,
+  /// We don't have an implementation of the cartridge's mapper.
+  UnsupportedMapper(u16),
+}
+
+impl Display for CartridgeError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      CartridgeError::BadMagic => write!(f, "not an iNES file"),
+      CartridgeError::TooShort => write!(f, "file is shorter than its header says"),
+      CartridgeError::UnsupportedMapper(mapper) => write!(
+        f,
+        "mapper {} is not supported. Supported mappers are 0 (NROM), 1 (MMC1), 2 (UxROM) and 3 (CNROM)",
+        mapper
+      ),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for CartridgeError {}
+
+/// The decoded contents of an iNES or NES 2.0 header.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Header {
+  /// True if the header is in the NES 2.0 format.
+  pub nes2: bool,
+  /// Size of PRG ROM in bytes.
+  pub prg_size: usize,
+  /// Size of CHR ROM in bytes. Zero means the board uses CHR RAM.
+  pub chr_size: usize,
+  /// Size of PRG RAM in bytes.
+  pub prg_ram_size: usize,
+  /// The mapper number.
+  pub mapper: u16,
+  /// The submapper number. Always zero for iNES headers.
+  pub submapper: u8,
+  /// Hard wired nametable mirroring.
+  pub mirroring: Mirroring,
+  /// True if the cartridge has battery backed memory.
+  pub battery: bool,
+  /// True if a 512 byte trainer sits between the header and PRG ROM.
+  pub trainer: bool,
+}
+
+impl Header {
+  /// Decodes a header.
+  pub fn parse(data: &[u8]) -> Result<Header, CartridgeError> {
+    if data.len() < HEADER_SIZE {
+      return Err(CartridgeError::TooShort);
+    }
+    if data[0..4] != MAGIC {
+      return Err(CartridgeError::BadMagic);
+    }
+    let flags_6 = data[6];
+    let nes2 = data[7] & 0x0C == 0x08;
+    // Old dumping tools wrote their name into the end of the header, which
+    // leaves garbage in the upper mapper nibble.
+    let flags_7 = match !nes2 && data[12..16].iter().any(|b| *b != 0) {
+      true => 0,
+      false => data[7],
+    };
+    let mirroring = match (flags_6 & 0x08 == 0x08, flags_6 & 0x01 == 0x01) {
+      (true, _) => Mirroring::FourScreen,
+      (false, true) => Mirroring::Vertical,
+      (false, false) => Mirroring::Horizontal,
+    };
+    let mut mapper = ((flags_7 & 0xF0) | (flags_6 >> 4)) as u16;
+    let (prg_size, chr_size, prg_ram_size, submapper) = match nes2 {
+      true => {
+        mapper |= ((data[8] & 0x0F) as u16) << 8;
+        let prg = nes2_size(data[4], data[9] & 0x0F, BANK_16K);
+        let chr = nes2_size(data[5], data[9] >> 4, BANK_8K);
+        let ram = match data[10] & 0x0F {
+          0 => 0,
+          shift => 64 << shift,
+        };
+        (prg, chr, ram, data[8] >> 4)
+      }
+      false => {
+        let ram = (data[8].max(1) as usize) * BANK_8K;
+        (
+          data[4] as usize * BANK_16K,
+          data[5] as usize * BANK_8K,
+          ram,
+          0,
+        )
+      }
+    };
+    Ok(Header {
+      nes2,
+      prg_size,
+      chr_size,
+      prg_ram_size,
+      mapper,
+      submapper,
+      mirroring,
+      battery: flags_6 & 0x02 == 0x02,
+      trainer: flags_6 & 0x04 == 0x04,
+    })
+  }
+}
+
+/// Works out a NES 2.0 ROM size. If the most significant nibble is 0xF the low
+/// byte holds an exponent and multiplier instead of a count of units.
+fn nes2_size(lsb: u8, msb: u8, unit: usize) -> usize {
+  match msb {
+    0x0F => {
+      let exponent = (lsb >> 2) as u32;
+      let multiplier = (lsb & 0x3) as usize * 2 + 1;
+      2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    }
+    _ => (((msb as usize) << 8) | lsb as usize) * unit,
+  }
+}
+
+/// A cartridge read from an iNES or NES 2.0 file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cartridge {
+  header: Header,
+  trainer: Option<Vec<u8>>,
+  prg: Vec<u8>,
+  chr: Vec<u8>,
+}
+
+impl Cartridge {
+  /// Reads a cartridge from the contents of a .nes file.
+  pub fn parse(data: &[u8]) -> Result<Cartridge, CartridgeError> {
+    let header = Header::parse(data)?;
+    debug!("Parsed iNES header: {:?}", header);
+    let mut offset = HEADER_SIZE;
+    let mut take = |size: usize| {
+      let end = offset.checked_add(size).ok_or(CartridgeError::TooShort)?;
+      let section = data.get(offset..end).ok_or(CartridgeError::TooShort)?;
+      offset = end;
+      Ok(section.to_vec())
+    };
+    let trainer = match header.trainer {
+      true => Some(take(TRAINER_SIZE)?),
+      false => None,
+    };
+    let prg = take(header.prg_size)?;
+    let chr = take(header.chr_size)?;
+    Ok(Cartridge {
+      header,
+      trainer,
+      prg,
+      chr,
+    })
+  }
+
+  /// Gets the decoded header.
+  pub fn get_header(&self) -> &Header {
+    &self.header
+  }
+
+  /// Gets the PRG ROM image.
+  pub fn get_prg(&self) -> &[u8] {
+    &self.prg
+  }
+
+  /// Gets the CHR ROM image. Empty if the board uses CHR RAM.
+  pub fn get_chr(&self) -> &[u8] {
+    &self.chr
+  }
+
+  /// Gets the trainer, if the cartridge has one.
+  pub fn get_trainer(&self) -> Option<&[u8]> {
+    self.trainer.as_deref()
+  }
+
+  /// Gets the address the trainer should be loaded at.
+  pub fn get_trainer_address(&self) -> u16 {
+    TRAINER_ADDRESS
+  }
+
+  /// Builds the mapper the cartridge uses, loaded with its PRG ROM.
+  pub fn create_mapper(&self) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let prg = self.prg.clone();
+    let mapper: Box<dyn Mapper> = match self.header.mapper {
+      0 => Box::new(Nrom::new(prg)),
+      1 => Box::new(Mmc1::new(prg)),
+      2 => Box::new(Uxrom::new(prg)),
+      3 => Box::new(Cnrom::new(prg, self.chr.len() / BANK_8K)),
+      other => return Err(CartridgeError::UnsupportedMapper(other)),
+    };
+    debug!("Cartridge uses {} mapper", mapper.name());
+    Ok(mapper)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn header(prg: u8, chr: u8, flags_6: u8, flags_7: u8) -> Vec<u8> {
+    let mut data = MAGIC.to_vec();
+    data.extend_from_slice(&[prg, chr, flags_6, flags_7, 0, 0, 0, 0, 0, 0, 0, 0]);
+    data
+  }
+
+  fn image(prg: u8, chr: u8, flags_6: u8) -> Vec<u8> {
+    let mut data = header(prg, chr, flags_6, 0);
+    if flags_6 & 0x04 == 0x04 {
+      data.extend(vec![0x7E; TRAINER_SIZE]);
+    }
+    data.extend(vec![0x42; prg as usize * BANK_16K]);
+    data.extend(vec![0x24; chr as usize * BANK_8K]);
+    data
+  }
+
+  #[test]
+  fn ines_header() {
+    let header = Header::parse(&header(2, 1, 0x13, 0x40)).unwrap();
+    assert!(!header.nes2);
+    assert_eq!(header.prg_size, 2 * BANK_16K);
+    assert_eq!(header.chr_size, BANK_8K);
+    assert_eq!(header.prg_ram_size, BANK_8K);
+    assert_eq!(header.mapper, 0x41);
+    assert_eq!(header.mirroring, Mirroring::Vertical);
+    assert!(header.battery);
+    assert!(!header.trainer);
+  }
+
+  #[test]
+  fn ines_header_with_garbage() {
+    let mut data = header(1, 0, 0x10, 0x40);
+    data[12..16].copy_from_slice(b"Dude");
+    assert_eq!(Header::parse(&data).unwrap().mapper, 1);
+  }
+
+  #[test]
+  fn nes2_header() {
+    let mut data = header(2, 0, 0x08, 0x08);
+    data[8] = 0x31;
+    data[9] = 0x01;
+    data[10] = 0x07;
+    let header = Header::parse(&data).unwrap();
+    assert!(header.nes2);
+    assert_eq!(header.mapper, 0x100);
+    assert_eq!(header.submapper, 3);
+    assert_eq!(header.prg_size, 0x102 * BANK_16K);
+    assert_eq!(header.prg_ram_size, 8192);
+    assert_eq!(header.mirroring, Mirroring::FourScreen);
+  }
+
+  #[test_case(0x09, 2usize.pow(2) * 3; "Exponent and multiplier")]
+  #[test_case(0x00, 1; "Exponent zero")]
+  fn nes2_exponent_size(lsb: u8, expected: usize) {
+    assert_eq!(nes2_size(lsb, 0x0F, BANK_16K), expected);
+  }
+
+  #[test]
+  fn bad_magic() {
+    let mut data = header(1, 0, 0, 0);
+    data[0] = 0;
+    assert_eq!(Cartridge::parse(&data), Err(CartridgeError::BadMagic));
+  }
+
+  #[test]
+  fn too_short() {
+    let mut data = image(2, 1, 0);
+    data.pop();
+    assert_eq!(Cartridge::parse(&data), Err(CartridgeError::TooShort));
+  }
+
+  #[test_case(0x09, Ok(12); "Exponent and multiplier")]
+  #[test_case(0xFF, Err(CartridgeError::TooShort); "Saturated exponent")]
+  fn nes2_exponent_prg(prg: u8, expected: Result<usize, CartridgeError>) {
+    let mut data = header(prg, 0, 0, 0x08);
+    data[9] = 0x0F;
+    data.extend(vec![0x42; BANK_16K]);
+    assert_eq!(Cartridge::parse(&data).map(|cart| cart.get_prg().len()), expected);
+  }
+
+  #[test]
+  fn sections() {
+    let cart = Cartridge::parse(&image(1, 1, 0x04)).unwrap();
+    assert_eq!(cart.get_trainer().unwrap()[0], 0x7E);
+    assert_eq!(cart.get_prg().len(), BANK_16K);
+    assert_eq!(cart.get_prg()[0], 0x42);
+    assert_eq!(cart.get_chr()[0], 0x24);
+  }
+
+  #[test_case(0x00, "NROM")]
+  #[test_case(0x10, "MMC1")]
+  #[test_case(0x20, "UxROM")]
+  #[test_case(0x30, "CNROM")]
+  fn create_mapper(flags_6: u8, name: &str) {
+    let cart = Cartridge::parse(&image(2, 1, flags_6)).unwrap();
+    assert_eq!(cart.create_mapper().unwrap().name(), name);
+  }
+
+  #[test]
+  fn unsupported_mapper() {
+    let cart = Cartridge::parse(&image(2, 1, 0x40)).unwrap();
+    match cart.create_mapper() {
+      Err(CartridgeError::UnsupportedMapper(4)) => (),
+      _ => panic!("Expected mapper 4 to be unsupported"),
+    }
+  }
+}
@@ -1,11 +1,49 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[macro_use]
+mod logging;
+mod prelude;
+
+pub mod assembler;
+pub mod call_stack;
+pub mod clock;
+pub mod coverage;
+pub mod devices;
+pub mod disassembler;
+#[cfg(feature = "std")]
+pub mod gdb;
+pub mod hooks;
+pub mod ines;
+pub mod loader;
+pub mod machines;
+pub mod mappers;
 mod memory;
+pub mod profiler;
 mod registers;
-
-use log::{debug, trace, warn};
-use memory::Memory;
+pub mod rewind;
+pub mod runner;
+pub mod symbols;
+pub mod traps;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+use call_stack::{CallStack, Frame, FrameKind, StackError};
+use clock::Clock;
+use core::fmt::{Display, Formatter};
+use coverage::Coverage;
+use devices::{Device, DeviceId};
+use disassembler::{is_undocumented, Instruction, OPCODES};
+use hooks::{AccessKind, Hook, HookId, Hooks, Interrupt, MemoryEvent};
+use ines::{Cartridge, CartridgeError};
+use loader::Image;
+use mappers::Mapper;
+pub use memory::Memory;
+use prelude::*;
 use registers::{GeneralRegister, ProgramCounter, StackPointer, StatusBit, StatusRegister};
-use std::fmt::{Display, Formatter, Result};
-use std::sync::mpsc::Receiver;
+use symbols::SymbolTable;
+use traps::{Trap, TrapId, TrapResult, TrapSite, Traps};
 
 /// A semi-arbitrary choice for where to start program execution. This is what the NES uses
 /// so I figured its as good a place as any to begin.
@@ -29,24 +67,80 @@ pub struct CPU {
   reset_pin: bool,
   nmi_pin: bool,
   irq_pin: bool,
-  clock_pin: Receiver<bool>,
+  device_irq: bool,
+  clock_pin: Box<dyn Clock + Send>,
+  free_running: bool,
+  cycles: u64,
+  symbols: Option<SymbolTable>,
+  coverage: Option<Coverage>,
+  call_stack: CallStack,
+  instruction_start: u16,
+  hooks: Hooks,
+  traps: Traps,
+  exit_code: Option<u8>,
+  undocumented: bool,
+}
+
+/// A copy of the programmer visible registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Registers {
+  pub accumulator: u8,
+  pub x: u8,
+  pub y: u8,
+  pub stack_pointer: u8,
+  pub status: u8,
+  pub program_counter: u16,
+}
+
+/// The parts of the CPU's state besides registers, cycles and memory, which
+/// rewinding has to put back as well.
+#[derive(Clone)]
+pub(crate) struct ExecutionState {
+  reset_pin: bool,
+  nmi_pin: bool,
+  irq_pin: bool,
+  device_irq: bool,
+  call_stack: CallStack,
+  exit_code: Option<u8>,
 }
 
 impl CPU {
   /// Initializes a new CPU instance. Sets all values to their associated defaults.
-  pub fn new(r: Receiver<bool>) -> CPU {
+  ///
+  /// The CPU waits on the clock at the end of every machine cycle. Pass the
+  /// receiving end of a channel to drive it from another thread, or
+  /// `clock::FreeRunning` to run it as fast as possible.
+  pub fn new<C: Clock + Send + 'static>(clock: C) -> CPU {
+    CPU::with_memory(clock, Memory::new())
+  }
+
+  /// Initializes a new CPU instance around existing memory, such as memory
+  /// backed by a buffer the caller supplied.
+  pub fn with_memory<C: Clock + Send + 'static>(clock: C, memory: Memory) -> CPU {
     debug!("Initializing CPU");
+    let free_running = clock.is_free_running();
     CPU {
       program_counter: ProgramCounter::new(),
       accumulator: GeneralRegister::new(),
       x_register: GeneralRegister::new(),
       y_register: GeneralRegister::new(),
       status_register: StatusRegister::new(),
-      memory: Memory::new(),
-      clock_pin: r,
+      memory,
+      clock_pin: Box::new(clock),
       reset_pin: false,
       irq_pin: false,
+      device_irq: false,
       nmi_pin: false,
+      free_running,
+      cycles: 0,
+      symbols: None,
+      coverage: None,
+      call_stack: CallStack::new(),
+      instruction_start: STARTING_MEMORY_BLOCK,
+      hooks: Hooks::new(),
+      traps: Traps::new(),
+      exit_code: None,
+      undocumented: true,
     }
   }
 
@@ -58,42 +152,359 @@ impl CPU {
     self.y_register.reset();
     self.status_register.reset();
     self.memory.reset();
+    self.call_stack.clear();
     self.reset_pin = false;
     self.irq_pin = false;
+    self.device_irq = false;
     self.nmi_pin = false;
+    self.exit_code = None;
+  }
+
+  /// Installs a bank switching mapper in front of memory. The mapper survives
+  /// a reset, but is returned to its power on bank selection.
+  pub fn set_mapper(&mut self, mapper: Box<dyn Mapper>) {
+    self.memory.set_mapper(mapper);
+  }
+
+  /// Maps a peripheral chip into the addresses from start to end, inclusive.
+  /// The device is clocked every machine cycle and its interrupt output is
+  /// wired to the IRQ pin, alongside `set_irq`.
+  pub fn map_device(&mut self, start: u16, end: u16, device: Box<dyn Device>) -> DeviceId {
+    self.memory.map_device(start, end, device)
+  }
+
+  /// Maps another range of addresses to a device that is already mapped,
+  /// with the first address as the given register.
+  pub fn mirror_device(&mut self, id: DeviceId, start: u16, end: u16, register: u16) {
+    self.memory.mirror_device(id, start, end, register);
+  }
+
+  /// Leaves a device's interrupt output unconnected from the IRQ pin.
+  pub fn disconnect_irq(&mut self, id: DeviceId) {
+    self.memory.disconnect_irq(id);
+  }
+
+  /// Gets the CPU's memory.
+  pub fn get_memory(&self) -> &Memory {
+    &self.memory
+  }
+
+  /// Gets the CPU's memory for modification, to load data or take and restore
+  /// snapshots for example. Accesses made this way do not cost cycles.
+  pub fn get_memory_mut(&mut self) -> &mut Memory {
+    &mut self.memory
+  }
+
+  /// Sets whether the CPU runs without waiting for the clock pin. A free
+  /// running CPU executes as fast as the host allows, which is what debuggers
+  /// and other tools driving the CPU one step at a time want.
+  pub fn set_free_running(&mut self, free_running: bool) {
+    debug!("Free running clock set to {}", free_running);
+    self.free_running = free_running;
+  }
+
+  /// Gets the number of machine cycles completed since the CPU was created.
+  pub fn get_cycles(&self) -> u64 {
+    self.cycles
+  }
+
+  /// Sets the cycle counter. Used by tools that move execution back in time.
+  pub fn set_cycles(&mut self, cycles: u64) {
+    self.cycles = cycles;
+  }
+
+  /// Gets a copy of the registers.
+  pub fn get_registers(&self) -> Registers {
+    Registers {
+      accumulator: self.accumulator.get(),
+      x: self.x_register.get(),
+      y: self.y_register.get(),
+      stack_pointer: self.memory.get_stack_pointer().get(),
+      status: self.status_register.get_register(),
+      program_counter: self.program_counter.get() as u16,
+    }
+  }
+
+  /// Sets every register at once.
+  pub fn set_registers(&mut self, registers: Registers) {
+    self.accumulator.set(registers.accumulator);
+    self.x_register.set(registers.x);
+    self.y_register.set(registers.y);
+    // Setting the stack pointer warns, so leave it be unless it moved.
+    if registers.stack_pointer != self.memory.get_stack_pointer().get() {
+      self.memory.set_stack_pointer(registers.stack_pointer);
+    }
+    self.status_register.set(registers.status);
+    self.program_counter.jump(registers.program_counter);
+  }
+
+  /// Copies the pins, the shadow call stack and the exit code.
+  pub(crate) fn get_execution_state(&self) -> ExecutionState {
+    ExecutionState {
+      reset_pin: self.reset_pin,
+      nmi_pin: self.nmi_pin,
+      irq_pin: self.irq_pin,
+      device_irq: self.device_irq,
+      call_stack: self.call_stack.clone(),
+      exit_code: self.exit_code,
+    }
+  }
+
+  /// Puts back state copied with `get_execution_state`.
+  pub(crate) fn set_execution_state(&mut self, state: &ExecutionState) {
+    self.reset_pin = state.reset_pin;
+    self.nmi_pin = state.nmi_pin;
+    self.irq_pin = state.irq_pin;
+    self.device_irq = state.device_irq;
+    self.call_stack = state.call_stack.clone();
+    self.exit_code = state.exit_code;
+  }
+
+  /// Sets up a call to a subroutine as if a JSR at the program counter had just
+  /// run, so the subroutine's RTS comes back to the program counter. Takes no
+  /// cycles. Used by tools that call routines directly, such as unit tests.
+  pub fn call_subroutine(&mut self, address: u16) {
+    let return_address = self.program_counter.get() as u16;
+    debug!("Calling {:X}, returning to {:X}", address, return_address);
+    self.call_stack.enter(Frame {
+      kind: FrameKind::Subroutine,
+      target: address,
+      call_site: return_address,
+      return_address,
+      stack_pointer: self.memory.get_stack_pointer().get(),
+    });
+    let [lo, hi] = return_address.wrapping_sub(1).to_le_bytes();
+    self.push_without_sync(lo);
+    self.push_without_sync(hi);
+    self.program_counter.jump(address);
+  }
+
+  /// Sets the symbol table used to name addresses in traces, disassembly and
+  /// the CPU's display.
+  pub fn set_symbols(&mut self, symbols: SymbolTable) {
+    debug!("Using symbol table with {} symbols", symbols.len());
+    self.symbols = Some(symbols);
+  }
+
+  /// Gets the symbol table, if one has been set.
+  pub fn get_symbols(&self) -> Option<&SymbolTable> {
+    self.symbols.as_ref()
+  }
+
+  /// Formats an address using the symbol table if there is one.
+  pub fn format_address(&self, address: u16) -> String {
+    match &self.symbols {
+      Some(symbols) => symbols.format_address(address),
+      None => format!("${:04X}", address),
+    }
+  }
+
+  /// Disassembles a number of instructions starting at an address, naming
+  /// addresses from the symbol table if there is one.
+  pub fn disassemble(&self, start: u16, count: usize) -> String {
+    disassembler::listing(&self.memory, start, count, self.symbols.as_ref())
+  }
+
+  /// Turns coverage tracking on or off. Turning it on starts from empty
+  /// coverage; turning it off discards what was recorded.
+  pub fn set_coverage(&mut self, enabled: bool) {
+    debug!("Coverage tracking set to {}", enabled);
+    self.coverage = match enabled {
+      true => Some(Coverage::new()),
+      false => None,
+    };
+  }
+
+  /// Gets the coverage recorded so far, if tracking is on.
+  pub fn get_coverage(&self) -> Option<&Coverage> {
+    self.coverage.as_ref()
+  }
+
+  /// Gets the shadow call stack.
+  pub fn get_call_stack(&self) -> &CallStack {
+    &self.call_stack
+  }
+
+  /// Gets the shadow call stack for modification, to take its errors for
+  /// example.
+  pub fn get_call_stack_mut(&mut self) -> &mut CallStack {
+    &mut self.call_stack
+  }
+
+  /// Allows or forbids the undocumented opcodes, which are allowed by
+  /// default. Forbidden ones jam the processor as KIL does, for checking that
+  /// a program sticks to the documented instruction set.
+  pub fn set_undocumented_opcodes(&mut self, allowed: bool) {
+    debug!("Undocumented opcodes allowed: {}", allowed);
+    self.undocumented = allowed;
+  }
+
+  /// Returns true if the program has exited through a trap, or if the next
+  /// instruction is a KIL opcode, or a forbidden undocumented one, which
+  /// would halt the processor.
+  pub fn is_halted(&self) -> bool {
+    if self.exit_code.is_some() {
+      return true;
+    }
+    let address = self.program_counter.get() as u16;
+    if self.has_trap(TrapSite::Address(address)) {
+      return false;
+    }
+    let opcode = self.memory.peek(address);
+    Some(opcode) != self.traps.get_opcode() && self.jams(opcode)
+  }
+
+  /// Returns true if an opcode locks up the processor.
+  fn jams(&self, opcode: u8) -> bool {
+    OPCODES[opcode as usize].0 == "KIL" || (!self.undocumented && is_undocumented(opcode))
+  }
+
+  /// Gets the exit code the program stopped with through a trap, if it has.
+  pub fn get_exit_code(&self) -> Option<u8> {
+    self.exit_code
+  }
+
+  /// Builds a backtrace of the shadow call stack, naming addresses from the
+  /// symbol table if there is one.
+  pub fn backtrace(&self) -> String {
+    self
+      .call_stack
+      .backtrace(self.program_counter.get() as u16, self.symbols.as_ref())
+  }
+
+  /// Registers a hook called with every memory access, stack operation and
+  /// interrupt the CPU makes. Hooks are called in the order they were added.
+  pub fn add_hook(&mut self, hook: Hook) -> HookId {
+    debug!("Adding memory hook");
+    self.hooks.add(hook)
+  }
+
+  /// Removes a hook. Returns false if it was not registered.
+  pub fn remove_hook(&mut self, id: HookId) -> bool {
+    self.hooks.remove(id)
+  }
+
+  /// Removes every hook.
+  pub fn clear_hooks(&mut self) {
+    self.hooks.clear();
+  }
+
+  /// Registers a trap: a host function run when the program counter reaches
+  /// an address, or when the trap opcode is followed by a number. Replaces
+  /// any trap already registered there.
+  pub fn add_trap(&mut self, site: TrapSite, trap: Trap) -> TrapId {
+    debug!("Adding trap at {:?}", site);
+    self.traps.add(site, trap)
+  }
+
+  /// Returns true if a trap is registered at a site.
+  pub fn has_trap(&self, site: TrapSite) -> bool {
+    self.traps.contains(site)
+  }
+
+  /// Removes a trap. Returns false if it was not registered.
+  pub fn remove_trap(&mut self, id: TrapId) -> bool {
+    self.traps.remove(id)
+  }
+
+  /// Removes every trap.
+  pub fn clear_traps(&mut self) {
+    self.traps.clear();
+  }
+
+  /// Reserves an opcode for traps, or frees it again with `None`. The byte
+  /// after the opcode picks the trap to run.
+  pub fn set_trap_opcode(&mut self, opcode: Option<u8>) {
+    debug!("Trap opcode set to {:?}", opcode);
+    self.traps.set_opcode(opcode);
+  }
+
+  /// Runs the trap registered at a site, if there is one, and stops the
+  /// program if it asks to exit.
+  fn run_trap(&mut self, site: TrapSite) -> Option<TrapResult> {
+    let (id, mut trap) = self.traps.take(site)?;
+    trace!("Running trap at {:?}", site);
+    let result = trap(self);
+    self.traps.restore(id, trap);
+    if let TrapResult::Exit(code) = result {
+      debug!("Program exited with {}", code);
+      self.exit_code = Some(code);
+    }
+    Some(result)
+  }
+
+  /// Runs the trap at the program counter, if there is one, and returns to
+  /// its caller. Returns true if a trap ran.
+  fn address_trap(&mut self) -> bool {
+    let address = self.program_counter.get() as u16;
+    self.instruction_start = address;
+    match self.run_trap(TrapSite::Address(address)) {
+      Some(TrapResult::Return) => self.rts(),
+      Some(TrapResult::Exit(_)) => (),
+      None => return false,
+    }
+    true
+  }
+
+  /// Runs the trap numbered by the byte after the trap opcode.
+  fn opcode_trap(&mut self) {
+    let number = self.fetch(AccessKind::OperandFetch);
+    if self.run_trap(TrapSite::Opcode(number)).is_none() {
+      warn!(
+        "No trap {:X} at {}",
+        number,
+        self.format_address(self.instruction_start)
+      );
+    }
+  }
+
+  /// Tells the hooks about an access. Building the event is skipped entirely
+  /// when there are no hooks.
+  #[inline]
+  fn notify(&mut self, kind: AccessKind, address: u16, value: u8) {
+    if self.hooks.is_empty() {
+      return;
+    }
+    self.hooks.notify(&MemoryEvent {
+      kind,
+      address,
+      value,
+      cycle: self.cycles,
+      program_counter: self.instruction_start,
+    });
   }
 
   /// Loads the program into memory.
   ///
   /// # Panics
   /// Panics if the program is too big for the allocated memory space.
-  fn load_program_into_memory(&mut self, program: &Vec<u8>, block: u16) {
+  fn load_program_into_memory(&mut self, program: &[u8], block: u16) {
     debug!("Loading program into memory starting at: {}", block);
-    let mut memory_address = block;
     if program.len() + block as usize > 0xFFFF {
       panic!("Program is too large for allocated memory space. Maybe you didn't set a custom starting block?");
     }
-    for byte in program.iter() {
-      self.memory.set(memory_address, *byte);
-      memory_address += 1;
+    for (offset, byte) in program.iter().enumerate() {
+      self.memory.set(block + offset as u16, *byte);
     }
   }
 
-  /// Waits for a timing signal to be available at the clock pin. Checks interrupt
-  /// pins in the meanwhile. This simulates that the cpu will finish its current
-  /// instruction when an interrupt comes in, then go off and handle the interrupt.
+  /// Waits for a timing signal to be available at the clock pin. Interrupts
+  /// are not taken here: the pins are checked between instructions by `step`,
+  /// so the current instruction always finishes first.
   fn sync(&mut self) {
     let mut count: u32 = 0;
     trace!("Completed machine cycle");
-    // let b = self.clock_pin.recv().unwrap();
-    while !self.clock_pin.try_recv().is_ok() {
-      self.check_pins();
+    self.cycles += 1;
+    self.device_irq = self.memory.tick_devices();
+    if self.free_running {
+      return;
+    }
+    while !self.clock_pin.tick() {
       if count > u32::MAX / 150 {
         panic!("Processor deadlock! Restart your processor!");
       }
       count += 1;
     }
-    // self.check_pins();
     trace!("Starting machine cycle");
   }
 
@@ -104,29 +515,38 @@ impl CPU {
   }
 
   /// Sets the NMI pin to allow for a Non-Maskable interrupt. Non-maskable interrupts
-  /// are run regardless of the setting of the interrupt bit.
+  /// are run regardless of the setting of the interrupt bit. The NMI is edge
+  /// triggered, so it is taken once and the pin is then released.
   pub fn set_nmi(&mut self) {
     trace!("NMI pin set. Interrupt should be handled");
     self.nmi_pin = true;
   }
 
   /// Sets the IRQ pin to allow for a Maskable interrupt. Maskable interrupts are run
-  /// only when the interrupt bit is unset.
+  /// only when the interrupt bit is unset. The IRQ is level triggered, so it is
+  /// taken again after every RTI until the device releases it with `clear_irq`.
   pub fn set_irq(&mut self) {
     trace!("IRQ pin set. Interrupt may be handled");
     self.irq_pin = true;
   }
 
-  /// Checks to see if we have an interrupt at the pins. Check is in priority order,
-  /// that being Reset, NMI, IRQ.
+  /// Releases the IRQ pin.
+  pub fn clear_irq(&mut self) {
+    trace!("IRQ pin cleared");
+    self.irq_pin = false;
+  }
+
+  /// Checks to see if we have an interrupt at the pins and takes the first one
+  /// found, in priority order: Reset, NMI, IRQ.
   fn check_pins(&mut self) {
     if self.reset_pin {
       self.reset_interrupt();
-    }
-    if self.nmi_pin {
+    } else if self.nmi_pin {
+      self.nmi_pin = false;
       self.nmi_interrupt();
-    }
-    if self.irq_pin && !self.status_register.is_flag_set(StatusBit::Interrupt) {
+    } else if (self.irq_pin || self.device_irq)
+      && !self.status_register.is_flag_set(StatusBit::Interrupt)
+    {
       self.irq_interrupt();
     }
   }
@@ -134,30 +554,51 @@ impl CPU {
   /// Pushes a value to the stack. Memory operations cost machine cycles so this
   /// waits for a cycle.
   fn push_to_stack(&mut self, value: u8) {
-    trace!("Push to stack wrapper called");
-    self.memory.push_to_stack(value);
+    self.push_without_sync(value);
     // writing to memory
     self.sync();
   }
 
+  /// Pushes a value to the stack without waiting for a cycle, for instructions
+  /// that account for the cycle themselves.
+  fn push_without_sync(&mut self, value: u8) {
+    self.check_stack_bounds(true);
+    let address = 0x100 | self.memory.get_stack_pointer().get() as u16;
+    self.notify(AccessKind::Push, address, value);
+    self.memory.push_to_stack(value);
+  }
+
   /// Pops(pulls) a value from the stack. Memory operations cost machine cycles
   /// so this waits for a cycle. Pop operations (poperations?) also cost a
   /// machine cycle so we account for that as well.
   fn pop_from_stack(&mut self) -> u8 {
-    trace!("Pop from stack wrapper called");
     // incrementing the pointer
     self.sync();
+    self.check_stack_bounds(false);
     let val = self.memory.pop_from_stack();
+    let address = 0x100 | self.memory.get_stack_pointer().get() as u16;
+    self.notify(AccessKind::Pop, address, val);
     // reading from memory
     self.sync();
     val
   }
 
+  /// Reports to the call stack if a push or pull is about to wrap the stack
+  /// pointer out of the stack page.
+  fn check_stack_bounds(&mut self, pushing: bool) {
+    let address = self.instruction_start;
+    match (pushing, self.memory.get_stack_pointer().get()) {
+      (true, 0x00) => self.call_stack.report(StackError::Overflow { address }),
+      (false, 0xFF) => self.call_stack.report(StackError::Underflow { address }),
+      _ => (),
+    }
+  }
+
   /// Wrapper around getting a 16 bit memory value. We wrap this because memory
   /// operations cost machine cycles so this waits for a cycle.
   fn get_u16(&mut self, index: u16) -> u8 {
-    trace!("Get u16 wrapper called");
     let val = self.memory.get_u16(index);
+    self.notify(AccessKind::Read, index, val);
     self.sync();
     val
   }
@@ -165,7 +606,7 @@ impl CPU {
   /// Wrapper around setting a 16 bit memory value. We wrap this because memory
   /// operations cost machine cycles so this waits for a cycle.
   fn set_u16(&mut self, index: u16, value: u8) {
-    trace!("Set u16 wrapper called");
+    self.notify(AccessKind::Write, index, value);
     self.memory.set(index, value);
     self.sync();
   }
@@ -173,8 +614,8 @@ impl CPU {
   /// Wrapper around setting a zero page value. We wrap this because memory operations
   /// cost machine cycles so this waits for a cycle.
   fn get_zero_page(&mut self, index: u8) -> u8 {
-    trace!("Get zero page wrapper called");
     let val = self.memory.get_zero_page(index);
+    self.notify(AccessKind::Read, index as u16, val);
     self.sync();
     val
   }
@@ -182,24 +623,34 @@ impl CPU {
   /// Wrapper around getting a zero page value. We wrap this because memory operations
   /// cost machine cycles so this waits for a cycle.
   fn set_zero_page(&mut self, index: u8, value: u8) {
-    trace!("Set zero page wrapper called");
+    self.notify(AccessKind::Write, index as u16, value);
     self.memory.set_zero_page(index, value);
     self.sync();
   }
 
-  /// Gets a byte from the program under execution. This increments the program counter,
-  /// returns the value in memory at the address returned by the counter, and waits for
-  /// a cycle.
+  /// Gets a byte from the program under execution. This returns the value in memory at
+  /// the program counter, increments the counter past it, and waits for a cycle.
   fn get_single_operand(&mut self) -> u8 {
-    let op = self.memory.get_u16(self.program_counter.get_and_increase());
+    let op = self.fetch(AccessKind::OperandFetch);
     debug!("Getting an operand with value: {:X}", op);
-    self.sync();
     op
   }
 
-  /// Gets two bytes from the program under execution. This increments the program counter,
-  /// returns the value in memory at the address returned by the counter, and waits for
-  /// a cycle, twice.
+  /// Fetches the byte at the program counter, increments the counter past it,
+  /// and waits for a cycle.
+  fn fetch(&mut self, kind: AccessKind) -> u8 {
+    let index = self.program_counter.get_and_increase();
+    if let Some(coverage) = self.coverage.as_mut() {
+      coverage.record_fetch(index);
+    }
+    let value = self.memory.get_u16(index);
+    self.notify(kind, index, value);
+    self.sync();
+    value
+  }
+
+  /// Gets two bytes from the program under execution. This returns the value in memory at
+  /// the program counter, increments the counter past it, and waits for a cycle, twice.
   fn get_two_operands(&mut self) -> [u8; 2] {
     let lo = self.get_single_operand();
     let hi = self.get_single_operand();
@@ -222,8 +673,8 @@ impl CPU {
   /// providing a starting block for where the program should live in memory. This will load
   /// the program into memory starting at the block specified.
   ///
-  /// Once the program is loaded, enters a loop that gets the next opcode and matches its number
-  /// to the master opcode map, calling the explicit opcode function.
+  /// Once the program is loaded, execution starts at the starting block and steps through
+  /// instructions forever.
   ///
   /// # Panics
   /// This will panic if the program is larger than the remaining difference between 0xFFFF and
@@ -240,268 +691,353 @@ impl CPU {
       None => STARTING_MEMORY_BLOCK,
     };
     self.load_program_into_memory(&program, block);
+    self.program_counter.jump(block);
     debug!("Program loaded. Beginning run loop");
     loop {
-      let opcode = self.get_single_operand();
-      match opcode {
-        0x00 => self.brk(),
-        0x01 => self.indexed_x_cb("ORA", &mut Self::ora),
-        0x02 => self.kil(),
-        0x03 => self.indexed_x_cb("SLO", &mut Self::slo),
-        0x04 => self.zero_page_cb("DOP", &mut Self::dop),
-        0x05 => self.zero_page_cb("ORA", &mut Self::ora),
-        0x06 => self.asl_zero_page(),
-        0x07 => self.zero_page_cb("SLO", &mut Self::slo),
-        0x08 => self.php(),
-        0x09 => self.immediate_cb("ORA", &mut Self::ora),
-        0x0A => self.asl_accumulator(),
-        0x0B => self.immediate_cb("AAC", &mut Self::aac),
-        0x0C => self.absolute_cb("TOP", &mut Self::top),
-        0x0D => self.absolute_cb("ORA", &mut Self::ora),
-        0x0E => self.asl_absolute(),
-        0x0F => self.absolute_cb("SLO", &mut Self::slo),
-        0x10 => self.bpl(),
-        0x11 => self.indexed_y_cb("ORA", &mut Self::ora),
-        0x12 => self.kil(),
-        0x13 => self.indexed_y_cb("SLO", &mut Self::slo),
-        0x14 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
-        0x15 => self.zp_reg_cb("ORA", self.x_register.get(), &mut Self::ora),
-        0x16 => self.asl_zero_page_x(),
-        0x17 => self.zp_reg_cb("SLO", self.x_register.get(), &mut Self::slo),
-        0x18 => self.clc(),
-        0x19 => self.absolute_y_cb("ORA", &mut Self::ora),
-        0x1A => self.nop(),
-        0x1B => self.absolute_y_cb("SLO", &mut Self::slo),
-        0x1C => self.absolute_x_cb("TOP", &mut Self::top),
-        0x1D => self.absolute_x_cb("ORA", &mut Self::ora),
-        0x1E => self.asl_absolute_x(),
-        0x1F => self.absolute_x_cb("SLO", &mut Self::slo),
-        0x20 => self.jsr(),
-        0x21 => self.indexed_x_cb("AND", &mut Self::and),
-        0x22 => self.kil(),
-        0x23 => self.indexed_x_cb("RLA", &mut Self::rla),
-        0x24 => self.zero_page_cb("BIT", &mut Self::bit),
-        0x25 => self.zero_page_cb("AND", &mut Self::and),
-        0x26 => self.rol_zero_page(),
-        0x27 => self.zero_page_cb("RLA", &mut Self::rla),
-        0x28 => self.plp(),
-        0x29 => self.immediate_cb("AND", &mut Self::and),
-        0x2A => self.rol_accumulator(),
-        0x2B => self.immediate_cb("AAC", &mut Self::aac),
-        0x2C => self.absolute_cb("BIT", &mut Self::bit),
-        0x2D => self.absolute_cb("AND", &mut Self::and),
-        0x2E => self.rol_absolute(),
-        0x2F => self.absolute_cb("RLA", &mut Self::rla),
-        0x30 => self.bmi(),
-        0x31 => self.indexed_y_cb("AND", &mut Self::and),
-        0x32 => self.kil(),
-        0x33 => self.indexed_y_cb("RLA", &mut Self::rla),
-        0x34 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
-        0x35 => self.zp_reg_cb("AND", self.x_register.get(), &mut Self::and),
-        0x36 => self.rol_zero_page_x(),
-        0x37 => self.zp_reg_cb("RLA", self.x_register.get(), &mut Self::rla),
-        0x38 => self.sec(),
-        0x39 => self.absolute_y_cb("AND", &mut Self::and),
-        0x3A => self.nop(),
-        0x3B => self.absolute_y_cb("RLA", &mut Self::rla),
-        0x3C => self.absolute_x_cb("TOP", &mut Self::top),
-        0x3D => self.absolute_x_cb("AND", &mut Self::and),
-        0x3E => self.rol_absolute_x(),
-        0x3F => self.absolute_x_cb("RLA", &mut Self::rla),
-        0x40 => self.rti(),
-        0x41 => self.indexed_x_cb("EOR", &mut Self::eor),
-        0x42 => self.kil(),
-        0x43 => self.indexed_x_cb("SRE", &mut Self::sre),
-        0x44 => self.zero_page_cb("DOP", &mut Self::dop),
-        0x45 => self.zero_page_cb("EOR", &mut Self::eor),
-        0x46 => self.lsr_zero_page(),
-        0x47 => self.zero_page_cb("SRE", &mut Self::sre),
-        0x48 => self.pha(),
-        0x49 => self.immediate_cb("EOR", &mut Self::eor),
-        0x4A => self.lsr_accumulator(),
-        0x4B => self.immediate_cb("ASR", &mut Self::asr),
-        0x4C => self.jmp_absolute(),
-        0x4D => self.absolute_cb("EOR", &mut Self::eor),
-        0x4E => self.lsr_absolute(),
-        0x4F => self.absolute_cb("SRE", &mut Self::sre),
-        0x50 => self.bvc(),
-        0x51 => self.indexed_y_cb("EOR", &mut Self::eor),
-        0x52 => self.kil(),
-        0x53 => self.indexed_y_cb("SRE", &mut Self::sre),
-        0x54 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
-        0x55 => self.zp_reg_cb("EOR", self.x_register.get(), &mut Self::eor),
-        0x56 => self.lsr_zero_page_x(),
-        0x57 => self.zp_reg_cb("SRE", self.x_register.get(), &mut Self::sre),
-        0x58 => self.cli(),
-        0x59 => self.absolute_y_cb("EOR", &mut Self::eor),
-        0x5A => self.nop(),
-        0x5B => self.absolute_y_cb("SRE", &mut Self::sre),
-        0x5C => self.absolute_x_cb("TOP", &mut Self::top),
-        0x5D => self.absolute_x_cb("EOR", &mut Self::eor),
-        0x5E => self.lsr_absolute_x(),
-        0x5F => self.absolute_x_cb("SRE", &mut Self::sre),
-        0x60 => self.rts(),
-        0x61 => self.indexed_x_cb("ADC", &mut Self::adc),
-        0x62 => self.kil(),
-        0x63 => self.indexed_x_cb("RRA", &mut Self::rra),
-        0x64 => self.zero_page_cb("DOP", &mut Self::dop),
-        0x65 => self.zero_page_cb("ADC", &mut Self::adc),
-        0x66 => self.ror_zero_page(),
-        0x67 => self.zero_page_cb("RRA", &mut Self::rra),
-        0x68 => self.pla(),
-        0x69 => self.immediate_cb("ADC", &mut Self::adc),
-        0x6A => self.ror_accumulator(),
-        0x6B => self.immediate_cb("ARR", &mut Self::arr),
-        0x6C => self.jmp_indirect(),
-        0x6D => self.absolute_cb("ADC", &mut Self::adc),
-        0x6E => self.ror_absolute(),
-        0x6F => self.absolute_cb("RRA", &mut Self::rra),
-        0x70 => self.bvs(),
-        0x71 => self.indexed_y_cb("ADC", &mut Self::adc),
-        0x72 => self.kil(),
-        0x73 => self.indexed_y_cb("RRA", &mut Self::rra),
-        0x74 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
-        0x75 => self.zp_reg_cb("ADC", self.x_register.get(), &mut Self::adc),
-        0x76 => self.ror_zero_page_x(),
-        0x77 => self.zp_reg_cb("RRA", self.x_register.get(), &mut Self::rra),
-        0x78 => self.sei(),
-        0x79 => self.absolute_x_cb("ADC", &mut Self::adc),
-        0x7A => self.nop(),
-        0x7B => self.absolute_y_cb("RRA", &mut Self::rra),
-        0x7C => self.absolute_x_cb("TOP", &mut Self::top),
-        0x7D => self.absolute_y_cb("ADC", &mut Self::adc),
-        0x7E => self.ror_absolute_x(),
-        0x7F => self.absolute_x_cb("RRA", &mut Self::rra),
-        0x80 => self.immediate_cb("DOP", &mut Self::dop),
-        0x81 => self.sta_indexed_x(),
-        0x82 => self.immediate_cb("DOP", &mut Self::dop),
-        0x83 => self.aax_indirect_x(),
-        0x84 => self.sty_zero_page(),
-        0x85 => self.sta_zero_page(),
-        0x86 => self.stx_zero_page(),
-        0x87 => self.aax_zero_page(),
-        0x88 => self.dey(),
-        0x89 => self.immediate_cb("DOP", &mut Self::dop),
-        0x8A => self.txa(),
-        0x8B => self.xaa(),
-        0x8C => self.sty_absolute(),
-        0x8D => self.sta_absolute(),
-        0x8E => self.stx_absolute(),
-        0x8F => self.aax_absolute(),
-        0x90 => self.bcc(),
-        0x91 => self.sta_indexed_y(),
-        0x92 => self.kil(),
-        0x93 => self.axa_indirect(),
-        0x94 => self.sty_zero_page_x(),
-        0x95 => self.sta_zero_page_x(),
-        0x96 => self.stx_zero_page_y(),
-        0x97 => self.aax_zero_page_y(),
-        0x98 => self.tya(),
-        0x99 => self.sta_absolute_y(),
-        0x9A => self.txs(),
-        0x9B => self.xas(),
-        0x9C => self.sya(),
-        0x9D => self.sta_absolute_x(),
-        0x9E => self.sxa(),
-        0x9F => self.axa_absolute_y(),
-        0xA0 => self.immediate_cb("LDY", &mut Self::ldy),
-        0xA1 => self.indexed_x_cb("LDA", &mut Self::lda),
-        0xA2 => self.immediate_cb("LDX", &mut Self::ldx),
-        0xA3 => self.indexed_x_cb("LAX", &mut Self::lax),
-        0xA4 => self.zero_page_cb("LDY", &mut Self::ldx),
-        0xA5 => self.zero_page_cb("LDA", &mut Self::lda),
-        0xA7 => self.zero_page_cb("LAX", &mut Self::lax),
-        0xA6 => self.zero_page_cb("LDX", &mut Self::ldx),
-        0xA8 => self.tay(),
-        0xA9 => self.immediate_cb("LDA", &mut Self::lda),
-        0xAA => self.tax(),
-        0xAB => self.immediate_cb("ATX", &mut Self::atx),
-        0xAC => self.absolute_cb("LDY", &mut Self::ldy),
-        0xAD => self.absolute_cb("LDA", &mut Self::lda),
-        0xAE => self.absolute_cb("LDX", &mut Self::ldx),
-        0xAF => self.absolute_cb("LAX", &mut Self::lax),
-        0xB0 => self.bcs(),
-        0xB1 => self.indexed_y_cb("LDA", &mut Self::lda),
-        0xB2 => self.kil(),
-        0xB3 => self.indexed_y_cb("LAX", &mut Self::lax),
-        0xB8 => self.clv(),
-        0xB4 => self.zp_reg_cb("LDY", self.x_register.get(), &mut Self::ldy),
-        0xB5 => self.zp_reg_cb("LDA", self.x_register.get(), &mut Self::lda),
-        0xB6 => self.zp_reg_cb("LDX", self.y_register.get(), &mut Self::ldx),
-        0xB7 => self.zp_reg_cb("LAX", self.y_register.get(), &mut Self::lax),
-        0xB9 => self.absolute_y_cb("LDA", &mut Self::lda),
-        0xBA => self.tsx(),
-        0xBB => self.absolute_y_cb("LAR", &mut Self::lar),
-        0xBC => self.absolute_x_cb("LDY", &mut Self::ldy),
-        0xBD => self.absolute_x_cb("LDA", &mut Self::lda),
-        0xBE => self.absolute_y_cb("LDX", &mut Self::ldx),
-        0xBF => self.absolute_y_cb("LAX", &mut Self::lax),
-        0xC0 => self.immediate_cb("CPY", &mut Self::cpy),
-        0xC1 => self.indexed_x_cb("CMP", &mut Self::cmp),
-        0xC2 => self.immediate_cb("DOP", &mut Self::dop),
-        0xC3 => self.dcp_indexed_x(),
-        0xC4 => self.zero_page_cb("CPY", &mut Self::cpy),
-        0xC5 => self.zero_page_cb("CMP", &mut Self::cmp),
-        0xC6 => self.dec_zp(),
-        0xC7 => self.dcp_zp(),
-        0xC8 => self.iny(),
-        0xC9 => self.immediate_cb("CMP", &mut Self::cmp),
-        0xCA => self.dex(),
-        0xCB => self.immediate_cb("AXS", &mut Self::axs),
-        0xCC => self.absolute_cb("CPY", &mut Self::cpy),
-        0xCD => self.absolute_cb("CMP", &mut Self::cmp),
-        0xCE => self.dec_abs(),
-        0xCF => self.dcp_absolute(),
-        0xD0 => self.bne(),
-        0xD1 => self.indexed_y_cb("CMP", &mut Self::cmp),
-        0xD2 => self.kil(),
-        0xD3 => self.dcp_indexed_y(),
-        0xD4 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
-        0xD5 => self.zp_reg_cb("CMP", self.x_register.get(), &mut Self::cmp),
-        0xD6 => self.dec_zp_reg(),
-        0xD7 => self.dcp_zp_reg(),
-        0xD8 => self.cld(),
-        0xD9 => self.absolute_y_cb("CMP", &mut Self::cmp),
-        0xDB => self.dcp_abs_y(),
-        0xDA => self.nop(),
-        0xDC => self.absolute_x_cb("TOP", &mut Self::top),
-        0xDD => self.absolute_x_cb("CMP", &mut Self::cmp),
-        0xDE => self.dec_abs_x(),
-        0xDF => self.dcp_abs_x(),
-        0xE0 => self.immediate_cb("CPX", &mut Self::cpx),
-        0xE1 => self.indexed_x_cb("SBC", &mut Self::sbc),
-        0xE2 => self.immediate_cb("DOP", &mut Self::dop),
-        0xE3 => self.indexed_x_cb("ISC", &mut Self::isc),
-        0xE4 => self.zero_page_cb("CPX", &mut Self::cpx),
-        0xE5 => self.zero_page_cb("SBC", &mut Self::sbc),
-        0xE6 => self.inc_zp(),
-        0xE7 => self.zero_page_cb("ISC", &mut Self::isc),
-        0xE8 => self.inx(),
-        0xE9 => self.immediate_cb("SBC", &mut Self::sbc),
-        0xEA => self.nop(),
-        0xEB => self.immediate_cb("SBC", &mut Self::sbc),
-        0xEC => self.absolute_cb("CPX", &mut Self::cpx),
-        0xED => self.absolute_cb("SBC", &mut Self::sbc),
-        0xEE => self.inc_abs(),
-        0xEF => self.absolute_cb("ISC", &mut Self::isc),
-        0xF0 => self.beq(),
-        0xF1 => self.indexed_y_cb("SBC", &mut Self::sbc),
-        0xF2 => self.kil(),
-        0xF3 => self.indexed_y_cb("ISC", &mut Self::isc),
-        0xF4 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
-        0xF5 => self.zp_reg_cb("SBC", self.x_register.get(), &mut Self::sbc),
-        0xF6 => self.inc_zp_reg(),
-        0xF7 => self.zp_reg_cb("ISC", self.x_register.get(), &mut Self::isc),
-        0xF8 => self.sed(),
-        0xF9 => self.absolute_y_cb("SBC", &mut Self::sbc),
-        0xFA => self.nop(),
-        0xFB => self.absolute_y_cb("ISC", &mut Self::isc),
-        0xFC => self.absolute_x_cb("TOP", &mut Self::top),
-        0xFD => self.absolute_x_cb("SBC", &mut Self::sbc),
-        0xFE => self.inc_abs_x(),
-        0xFF => self.absolute_x_cb("ISC", &mut Self::isc),
+      self.step();
+    }
+  }
+
+  /// Loads an image into memory and points the program counter at its entry point.
+  /// Returns the entry point.
+  pub fn load_image(&mut self, image: &Image) -> u16 {
+    let entry = image.load(&mut self.memory);
+    debug!("Image loaded. Entry point at: {:X}", entry);
+    self.program_counter.jump(entry);
+    entry
+  }
+
+  /// Installs a cartridge's mapper, loads its trainer if it has one, and points the
+  /// program counter at the reset vector. Returns the reset vector.
+  ///
+  /// Fails if we don't have an implementation of the cartridge's mapper.
+  pub fn load_cartridge(&mut self, cartridge: &Cartridge) -> Result<u16, CartridgeError> {
+    self.memory.set_mapper(cartridge.create_mapper()?);
+    if let Some(trainer) = cartridge.get_trainer() {
+      let address = cartridge.get_trainer_address();
+      for (offset, byte) in trainer.iter().enumerate() {
+        self.memory.set(address + offset as u16, *byte);
       }
     }
+    Ok(self.jump_to_reset_vector())
+  }
+
+  /// Points the program counter at the address held in the reset vector, as the
+  /// processor does at power on. Returns the address.
+  pub fn jump_to_reset_vector(&mut self) -> u16 {
+    let lo = self.memory.get_u16(0xFFFC);
+    let hi = self.memory.get_u16(0xFFFD);
+    let index = u16::from_le_bytes([lo, hi]);
+    debug!("Jumping to reset vector at: {:X}", index);
+    self.program_counter.jump(index);
+    index
+  }
+
+  /// Loads an image and begins running it from its entry point.
+  pub fn run_image(&mut self, image: &Image) {
+    self.load_image(image);
+    loop {
+      self.step();
+    }
+  }
+
+  /// Executes a single instruction, first taking any interrupt waiting at the
+  /// pins. A trap at the program counter, or the trap opcode, runs its host
+  /// function in place of an instruction. Does nothing once the program has
+  /// exited through a trap.
+  ///
+  /// Gets the opcode at the program counter and matches its number to the master opcode
+  /// map, calling the explicit opcode function.
+  pub fn step(&mut self) {
+    if self.exit_code.is_some() {
+      return;
+    }
+    if self.reset_pin || self.nmi_pin || self.irq_pin || self.device_irq {
+      self.check_pins();
+    }
+    if !self.traps.is_empty() && self.address_trap() {
+      return;
+    }
+    if trace_enabled!() {
+      let address = self.program_counter.get() as u16;
+      let instruction = Instruction::read(&self.memory, address);
+      trace!(
+        "{}: {}",
+        self.format_address(address),
+        instruction.format(self.symbols.as_ref())
+      );
+    }
+    let address = self.program_counter.get() as u16;
+    self.instruction_start = address;
+    let opcode = self.fetch(AccessKind::OpcodeFetch);
+    if let Some(coverage) = self.coverage.as_mut() {
+      coverage.record_instruction(address);
+    }
+    if Some(opcode) == self.traps.get_opcode() {
+      self.opcode_trap();
+      return;
+    }
+    if !self.undocumented && is_undocumented(opcode) {
+      self.kil();
+    }
+    match opcode {
+      0x00 => self.brk(),
+      0x01 => self.indexed_x_cb("ORA", &mut Self::ora),
+      0x02 => self.kil(),
+      0x03 => self.indexed_x_cb("SLO", &mut Self::slo),
+      0x04 => self.zero_page_cb("DOP", &mut Self::dop),
+      0x05 => self.zero_page_cb("ORA", &mut Self::ora),
+      0x06 => self.asl_zero_page(),
+      0x07 => self.zero_page_cb("SLO", &mut Self::slo),
+      0x08 => self.php(),
+      0x09 => self.immediate_cb("ORA", &mut Self::ora),
+      0x0A => self.asl_accumulator(),
+      0x0B => self.immediate_cb("AAC", &mut Self::aac),
+      0x0C => self.absolute_cb("TOP", &mut Self::top),
+      0x0D => self.absolute_cb("ORA", &mut Self::ora),
+      0x0E => self.asl_absolute(),
+      0x0F => self.absolute_cb("SLO", &mut Self::slo),
+      0x10 => self.bpl(),
+      0x11 => self.indexed_y_cb("ORA", &mut Self::ora),
+      0x12 => self.kil(),
+      0x13 => self.indexed_y_cb("SLO", &mut Self::slo),
+      0x14 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
+      0x15 => self.zp_reg_cb("ORA", self.x_register.get(), &mut Self::ora),
+      0x16 => self.asl_zero_page_x(),
+      0x17 => self.zp_reg_cb("SLO", self.x_register.get(), &mut Self::slo),
+      0x18 => self.clc(),
+      0x19 => self.absolute_y_cb("ORA", &mut Self::ora),
+      0x1A => self.nop(),
+      0x1B => self.absolute_y_cb("SLO", &mut Self::slo),
+      0x1C => self.absolute_x_cb("TOP", &mut Self::top),
+      0x1D => self.absolute_x_cb("ORA", &mut Self::ora),
+      0x1E => self.asl_absolute_x(),
+      0x1F => self.absolute_x_cb("SLO", &mut Self::slo),
+      0x20 => self.jsr(),
+      0x21 => self.indexed_x_cb("AND", &mut Self::and),
+      0x22 => self.kil(),
+      0x23 => self.indexed_x_cb("RLA", &mut Self::rla),
+      0x24 => self.zero_page_cb("BIT", &mut Self::bit),
+      0x25 => self.zero_page_cb("AND", &mut Self::and),
+      0x26 => self.rol_zero_page(),
+      0x27 => self.zero_page_cb("RLA", &mut Self::rla),
+      0x28 => self.plp(),
+      0x29 => self.immediate_cb("AND", &mut Self::and),
+      0x2A => self.rol_accumulator(),
+      0x2B => self.immediate_cb("AAC", &mut Self::aac),
+      0x2C => self.absolute_cb("BIT", &mut Self::bit),
+      0x2D => self.absolute_cb("AND", &mut Self::and),
+      0x2E => self.rol_absolute(),
+      0x2F => self.absolute_cb("RLA", &mut Self::rla),
+      0x30 => self.bmi(),
+      0x31 => self.indexed_y_cb("AND", &mut Self::and),
+      0x32 => self.kil(),
+      0x33 => self.indexed_y_cb("RLA", &mut Self::rla),
+      0x34 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
+      0x35 => self.zp_reg_cb("AND", self.x_register.get(), &mut Self::and),
+      0x36 => self.rol_zero_page_x(),
+      0x37 => self.zp_reg_cb("RLA", self.x_register.get(), &mut Self::rla),
+      0x38 => self.sec(),
+      0x39 => self.absolute_y_cb("AND", &mut Self::and),
+      0x3A => self.nop(),
+      0x3B => self.absolute_y_cb("RLA", &mut Self::rla),
+      0x3C => self.absolute_x_cb("TOP", &mut Self::top),
+      0x3D => self.absolute_x_cb("AND", &mut Self::and),
+      0x3E => self.rol_absolute_x(),
+      0x3F => self.absolute_x_cb("RLA", &mut Self::rla),
+      0x40 => self.rti(),
+      0x41 => self.indexed_x_cb("EOR", &mut Self::eor),
+      0x42 => self.kil(),
+      0x43 => self.indexed_x_cb("SRE", &mut Self::sre),
+      0x44 => self.zero_page_cb("DOP", &mut Self::dop),
+      0x45 => self.zero_page_cb("EOR", &mut Self::eor),
+      0x46 => self.lsr_zero_page(),
+      0x47 => self.zero_page_cb("SRE", &mut Self::sre),
+      0x48 => self.pha(),
+      0x49 => self.immediate_cb("EOR", &mut Self::eor),
+      0x4A => self.lsr_accumulator(),
+      0x4B => self.immediate_cb("ASR", &mut Self::asr),
+      0x4C => self.jmp_absolute(),
+      0x4D => self.absolute_cb("EOR", &mut Self::eor),
+      0x4E => self.lsr_absolute(),
+      0x4F => self.absolute_cb("SRE", &mut Self::sre),
+      0x50 => self.bvc(),
+      0x51 => self.indexed_y_cb("EOR", &mut Self::eor),
+      0x52 => self.kil(),
+      0x53 => self.indexed_y_cb("SRE", &mut Self::sre),
+      0x54 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
+      0x55 => self.zp_reg_cb("EOR", self.x_register.get(), &mut Self::eor),
+      0x56 => self.lsr_zero_page_x(),
+      0x57 => self.zp_reg_cb("SRE", self.x_register.get(), &mut Self::sre),
+      0x58 => self.cli(),
+      0x59 => self.absolute_y_cb("EOR", &mut Self::eor),
+      0x5A => self.nop(),
+      0x5B => self.absolute_y_cb("SRE", &mut Self::sre),
+      0x5C => self.absolute_x_cb("TOP", &mut Self::top),
+      0x5D => self.absolute_x_cb("EOR", &mut Self::eor),
+      0x5E => self.lsr_absolute_x(),
+      0x5F => self.absolute_x_cb("SRE", &mut Self::sre),
+      0x60 => self.rts(),
+      0x61 => self.indexed_x_cb("ADC", &mut Self::adc),
+      0x62 => self.kil(),
+      0x63 => self.indexed_x_cb("RRA", &mut Self::rra),
+      0x64 => self.zero_page_cb("DOP", &mut Self::dop),
+      0x65 => self.zero_page_cb("ADC", &mut Self::adc),
+      0x66 => self.ror_zero_page(),
+      0x67 => self.zero_page_cb("RRA", &mut Self::rra),
+      0x68 => self.pla(),
+      0x69 => self.immediate_cb("ADC", &mut Self::adc),
+      0x6A => self.ror_accumulator(),
+      0x6B => self.immediate_cb("ARR", &mut Self::arr),
+      0x6C => self.jmp_indirect(),
+      0x6D => self.absolute_cb("ADC", &mut Self::adc),
+      0x6E => self.ror_absolute(),
+      0x6F => self.absolute_cb("RRA", &mut Self::rra),
+      0x70 => self.bvs(),
+      0x71 => self.indexed_y_cb("ADC", &mut Self::adc),
+      0x72 => self.kil(),
+      0x73 => self.indexed_y_cb("RRA", &mut Self::rra),
+      0x74 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
+      0x75 => self.zp_reg_cb("ADC", self.x_register.get(), &mut Self::adc),
+      0x76 => self.ror_zero_page_x(),
+      0x77 => self.zp_reg_cb("RRA", self.x_register.get(), &mut Self::rra),
+      0x78 => self.sei(),
+      0x79 => self.absolute_x_cb("ADC", &mut Self::adc),
+      0x7A => self.nop(),
+      0x7B => self.absolute_y_cb("RRA", &mut Self::rra),
+      0x7C => self.absolute_x_cb("TOP", &mut Self::top),
+      0x7D => self.absolute_y_cb("ADC", &mut Self::adc),
+      0x7E => self.ror_absolute_x(),
+      0x7F => self.absolute_x_cb("RRA", &mut Self::rra),
+      0x80 => self.immediate_cb("DOP", &mut Self::dop),
+      0x81 => self.sta_indexed_x(),
+      0x82 => self.immediate_cb("DOP", &mut Self::dop),
+      0x83 => self.aax_indirect_x(),
+      0x84 => self.sty_zero_page(),
+      0x85 => self.sta_zero_page(),
+      0x86 => self.stx_zero_page(),
+      0x87 => self.aax_zero_page(),
+      0x88 => self.dey(),
+      0x89 => self.immediate_cb("DOP", &mut Self::dop),
+      0x8A => self.txa(),
+      0x8B => self.xaa(),
+      0x8C => self.sty_absolute(),
+      0x8D => self.sta_absolute(),
+      0x8E => self.stx_absolute(),
+      0x8F => self.aax_absolute(),
+      0x90 => self.bcc(),
+      0x91 => self.sta_indexed_y(),
+      0x92 => self.kil(),
+      0x93 => self.axa_indirect(),
+      0x94 => self.sty_zero_page_x(),
+      0x95 => self.sta_zero_page_x(),
+      0x96 => self.stx_zero_page_y(),
+      0x97 => self.aax_zero_page_y(),
+      0x98 => self.tya(),
+      0x99 => self.sta_absolute_y(),
+      0x9A => self.txs(),
+      0x9B => self.xas(),
+      0x9C => self.sya(),
+      0x9D => self.sta_absolute_x(),
+      0x9E => self.sxa(),
+      0x9F => self.axa_absolute_y(),
+      0xA0 => self.immediate_cb("LDY", &mut Self::ldy),
+      0xA1 => self.indexed_x_cb("LDA", &mut Self::lda),
+      0xA2 => self.immediate_cb("LDX", &mut Self::ldx),
+      0xA3 => self.indexed_x_cb("LAX", &mut Self::lax),
+      0xA4 => self.zero_page_cb("LDY", &mut Self::ldx),
+      0xA5 => self.zero_page_cb("LDA", &mut Self::lda),
+      0xA7 => self.zero_page_cb("LAX", &mut Self::lax),
+      0xA6 => self.zero_page_cb("LDX", &mut Self::ldx),
+      0xA8 => self.tay(),
+      0xA9 => self.immediate_cb("LDA", &mut Self::lda),
+      0xAA => self.tax(),
+      0xAB => self.immediate_cb("ATX", &mut Self::atx),
+      0xAC => self.absolute_cb("LDY", &mut Self::ldy),
+      0xAD => self.absolute_cb("LDA", &mut Self::lda),
+      0xAE => self.absolute_cb("LDX", &mut Self::ldx),
+      0xAF => self.absolute_cb("LAX", &mut Self::lax),
+      0xB0 => self.bcs(),
+      0xB1 => self.indexed_y_cb("LDA", &mut Self::lda),
+      0xB2 => self.kil(),
+      0xB3 => self.indexed_y_cb("LAX", &mut Self::lax),
+      0xB8 => self.clv(),
+      0xB4 => self.zp_reg_cb("LDY", self.x_register.get(), &mut Self::ldy),
+      0xB5 => self.zp_reg_cb("LDA", self.x_register.get(), &mut Self::lda),
+      0xB6 => self.zp_reg_cb("LDX", self.y_register.get(), &mut Self::ldx),
+      0xB7 => self.zp_reg_cb("LAX", self.y_register.get(), &mut Self::lax),
+      0xB9 => self.absolute_y_cb("LDA", &mut Self::lda),
+      0xBA => self.tsx(),
+      0xBB => self.absolute_y_cb("LAR", &mut Self::lar),
+      0xBC => self.absolute_x_cb("LDY", &mut Self::ldy),
+      0xBD => self.absolute_x_cb("LDA", &mut Self::lda),
+      0xBE => self.absolute_y_cb("LDX", &mut Self::ldx),
+      0xBF => self.absolute_y_cb("LAX", &mut Self::lax),
+      0xC0 => self.immediate_cb("CPY", &mut Self::cpy),
+      0xC1 => self.indexed_x_cb("CMP", &mut Self::cmp),
+      0xC2 => self.immediate_cb("DOP", &mut Self::dop),
+      0xC3 => self.dcp_indexed_x(),
+      0xC4 => self.zero_page_cb("CPY", &mut Self::cpy),
+      0xC5 => self.zero_page_cb("CMP", &mut Self::cmp),
+      0xC6 => self.dec_zp(),
+      0xC7 => self.dcp_zp(),
+      0xC8 => self.iny(),
+      0xC9 => self.immediate_cb("CMP", &mut Self::cmp),
+      0xCA => self.dex(),
+      0xCB => self.immediate_cb("AXS", &mut Self::axs),
+      0xCC => self.absolute_cb("CPY", &mut Self::cpy),
+      0xCD => self.absolute_cb("CMP", &mut Self::cmp),
+      0xCE => self.dec_abs(),
+      0xCF => self.dcp_absolute(),
+      0xD0 => self.bne(),
+      0xD1 => self.indexed_y_cb("CMP", &mut Self::cmp),
+      0xD2 => self.kil(),
+      0xD3 => self.dcp_indexed_y(),
+      0xD4 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
+      0xD5 => self.zp_reg_cb("CMP", self.x_register.get(), &mut Self::cmp),
+      0xD6 => self.dec_zp_reg(),
+      0xD7 => self.dcp_zp_reg(),
+      0xD8 => self.cld(),
+      0xD9 => self.absolute_y_cb("CMP", &mut Self::cmp),
+      0xDB => self.dcp_abs_y(),
+      0xDA => self.nop(),
+      0xDC => self.absolute_x_cb("TOP", &mut Self::top),
+      0xDD => self.absolute_x_cb("CMP", &mut Self::cmp),
+      0xDE => self.dec_abs_x(),
+      0xDF => self.dcp_abs_x(),
+      0xE0 => self.immediate_cb("CPX", &mut Self::cpx),
+      0xE1 => self.indexed_x_cb("SBC", &mut Self::sbc),
+      0xE2 => self.immediate_cb("DOP", &mut Self::dop),
+      0xE3 => self.indexed_x_cb("ISC", &mut Self::isc),
+      0xE4 => self.zero_page_cb("CPX", &mut Self::cpx),
+      0xE5 => self.zero_page_cb("SBC", &mut Self::sbc),
+      0xE6 => self.inc_zp(),
+      0xE7 => self.zero_page_cb("ISC", &mut Self::isc),
+      0xE8 => self.inx(),
+      0xE9 => self.immediate_cb("SBC", &mut Self::sbc),
+      0xEA => self.nop(),
+      0xEB => self.immediate_cb("SBC", &mut Self::sbc),
+      0xEC => self.absolute_cb("CPX", &mut Self::cpx),
+      0xED => self.absolute_cb("SBC", &mut Self::sbc),
+      0xEE => self.inc_abs(),
+      0xEF => self.absolute_cb("ISC", &mut Self::isc),
+      0xF0 => self.beq(),
+      0xF1 => self.indexed_y_cb("SBC", &mut Self::sbc),
+      0xF2 => self.kil(),
+      0xF3 => self.indexed_y_cb("ISC", &mut Self::isc),
+      0xF4 => self.zp_reg_cb("DOP", self.x_register.get(), &mut Self::dop),
+      0xF5 => self.zp_reg_cb("SBC", self.x_register.get(), &mut Self::sbc),
+      0xF6 => self.inc_zp_reg(),
+      0xF7 => self.zp_reg_cb("ISC", self.x_register.get(), &mut Self::isc),
+      0xF8 => self.sed(),
+      0xF9 => self.absolute_y_cb("SBC", &mut Self::sbc),
+      0xFA => self.nop(),
+      0xFB => self.absolute_y_cb("ISC", &mut Self::isc),
+      0xFC => self.absolute_x_cb("TOP", &mut Self::top),
+      0xFD => self.absolute_x_cb("SBC", &mut Self::sbc),
+      0xFE => self.inc_abs_x(),
+      0xFF => self.absolute_x_cb("ISC", &mut Self::isc),
+    }
   }
 
   /*
@@ -658,10 +1194,15 @@ impl CPU {
   /// If the operand is greater than 0x7F we assume it is negative and handle
   /// it as two's complement.
   fn branch(&mut self, condition: bool, op: u8) {
+    if let Some(coverage) = self.coverage.as_mut() {
+      // The opcode and operand have already been fetched.
+      let address = (self.program_counter.get() as u16).wrapping_sub(2);
+      coverage.record_branch(address, condition);
+    }
     if condition {
       let overflow = match op > 0x7F {
         // Funky syntax is two's complement. Cannot have negative unsigned.
-        true => self.program_counter.decrease(!(op + 1)),
+        true => self.program_counter.decrease((!op).wrapping_add(1)),
         false => self.program_counter.increase(op),
       };
       if overflow {
@@ -683,17 +1224,10 @@ impl CPU {
   /// greater than or equal to the test_value, the carry is set.
   fn generic_compare(&mut self, test_value: u8, reg_value: u8) {
     trace!("Comparing values");
-    let (result, carry) = reg_value.overflowing_sub(test_value);
-    if result == 0 {
-      self.status_register.set_flag(StatusBit::Zero);
-    }
-    if !carry {
-      self.status_register.set_flag(StatusBit::Carry);
-    }
-    // Check if bit 7 is set
-    if (result & 0x80) > 0 {
-      self.status_register.set_flag(StatusBit::Negative);
-    }
+    let (result, borrow) = reg_value.overflowing_sub(test_value);
+    self.status_register.handle_c_flag("Compare", !borrow);
+    self.status_register.handle_z_flag(result, "Compare");
+    self.status_register.handle_n_flag(result, "Compare");
   }
 
   /// Generic register operation, such as transfer accumulator to x register.
@@ -800,7 +1334,7 @@ impl CPU {
       false => temp,
     };
     // Get the hi nibble of both values and add them to our lo nibble calc
-    let result = (match_hi(val) + match_hi(acc_val)) as u16 + temp;
+    let result = (match_hi(val) + match_hi(acc_val)) + temp;
     // Handle N and V flags here BEFORE we proceed
     self.status_register.handle_n_flag(result as u8, message);
     self
@@ -822,10 +1356,9 @@ impl CPU {
     trace!("Decimal subtraction. Hope this works!");
     let message = "D SBC";
     // Setup some closures to make life less painful
-    let mod_result_acc = |v| (v & 0xFF) as u8;
     let mod_result_car = |v| !(v > acc_val && v > val);
     let match_lo = |v| v & 0x0F;
-    let match_hi = |v| (v & 0xF0);
+    let match_hi = |v| v & 0xF0;
     // Get the low nibble of both values and subtract them with modifier
     let (temp, over1) = match_lo(acc_val).overflowing_sub(match_lo(val));
     let (temp, over2) = temp.overflowing_sub(modifier);
@@ -838,16 +1371,16 @@ impl CPU {
     let (result, over1) = match_hi(acc_val).overflowing_sub(match_hi(val));
     let result = result.wrapping_add(temp);
     // Handle N and V flags here BEFORE we proceed
-    self.status_register.handle_n_flag(result as u8, message);
+    self.status_register.handle_n_flag(result, message);
     self
       .status_register
-      .handle_v_flag(mod_result_acc(result), message, mod_result_car(result));
+      .handle_v_flag(result, message, mod_result_car(result));
     // If our hi nibble is above the last valid decimal value, additional modification
     let result = match over1 {
       true => result - 0x60,
       false => result,
     };
-    let (result, carry) = (mod_result_acc(result), mod_result_car(result));
+    let carry = mod_result_car(result);
     self.status_register.handle_z_flag(result, message);
     self.status_register.handle_c_flag(message, carry);
     result
@@ -908,6 +1441,7 @@ impl CPU {
   /// the program was in the execution cycle
   fn reset_interrupt(&mut self) {
     let index = self.interrupt(0xFFFC, 0xFFFD);
+    self.notify_interrupt(Interrupt::Reset, index);
     self.program_counter.jump(index);
     debug!("Reset interrupt called");
     self.reset();
@@ -915,18 +1449,61 @@ impl CPU {
 
   /// Calls a non-maskable interrupt.
   fn nmi_interrupt(&mut self) {
+    let frame = self.interrupt_frame(FrameKind::Nmi);
     let index = self.interrupt(0xFFFA, 0xFFFB);
     debug!("NMI interrupt called");
+    self.notify_interrupt(Interrupt::Nmi, index);
+    self.call_stack.enter(Frame {
+      target: index,
+      ..frame
+    });
     self.program_counter.jump(index);
   }
 
   /// Calls a regular interrupt.
   fn irq_interrupt(&mut self) {
+    let kind = match self.status_register.is_flag_set(StatusBit::Break) {
+      true => FrameKind::Break,
+      false => FrameKind::Irq,
+    };
+    let frame = self.interrupt_frame(kind);
     let index = self.interrupt(0xFFFE, 0xFFFF);
     debug!("IRQ interrupt called");
+    let interrupt = match kind {
+      FrameKind::Break => Interrupt::Break,
+      _ => Interrupt::Irq,
+    };
+    self.notify_interrupt(interrupt, index);
+    self.call_stack.enter(Frame {
+      target: index,
+      ..frame
+    });
     self.program_counter.jump(index);
   }
 
+  /// Tells the hooks an interrupt was taken, with the handler address and the
+  /// status register that was pushed.
+  fn notify_interrupt(&mut self, interrupt: Interrupt, handler: u16) {
+    let status = self.status_register.get_register();
+    self.notify(AccessKind::Interrupt(interrupt), handler, status);
+  }
+
+  /// Builds the call stack frame for an interrupt about to start. The target
+  /// is filled in once the vector has been read.
+  fn interrupt_frame(&self, kind: FrameKind) -> Frame {
+    let return_address = self.program_counter.get() as u16;
+    Frame {
+      kind,
+      target: 0,
+      call_site: match kind {
+        FrameKind::Break => self.instruction_start,
+        _ => return_address,
+      },
+      return_address,
+      stack_pointer: self.memory.get_stack_pointer().get(),
+    }
+  }
+
   /*
   ============================================================================================
                                   Opcodes
@@ -1069,7 +1646,7 @@ impl CPU {
   fn asl(&mut self, value: u8) -> u8 {
     let message = "ASL";
     debug!("{} called with value: 0x{:X}", message, value);
-    let (result, carry) = value.overflowing_shl(1);
+    let (result, carry) = (value << 1, value & 0x80 != 0);
     // extra cycle for modification
     self.sync();
     self.status_register.handle_n_flag(result, message);
@@ -1444,7 +2021,7 @@ impl CPU {
   pub fn dec_abs(&mut self) {
     trace!("DEC absolute called");
     let (index, value) = self.absolute("DEC");
-    self.dec(index as u16, value);
+    self.dec(index, value);
   }
 
   /// Absolute x variant of DEC
@@ -1507,14 +2084,14 @@ impl CPU {
   pub fn inc_abs(&mut self) {
     trace!("INC absolute called");
     let (index, value) = self.absolute("INC");
-    self.inc(index as u16, value);
+    self.inc(index, value);
   }
 
   /// INC absolute x variant
   pub fn inc_abs_x(&mut self) {
     trace!("INC absolute x called");
     let (index, value) = self.absolute_reg("INC", self.x_register.get());
-    self.inc(index as u16, value);
+    self.inc(index, value);
     // extra cycle. do not know why
     self.sync();
   }
@@ -1538,7 +2115,7 @@ impl CPU {
   /// Illegal opcode.
   /// Locks the system, so we simulate by panic
   pub fn kil(&self) {
-    panic!("KIL called. CPU is locked.");
+    panic!("KIL called. CPU is locked.\n{}", self.backtrace());
   }
 
   /// JuMP
@@ -1573,11 +2150,18 @@ impl CPU {
   /// counter to the stack to allow for returns.
   pub fn jsr(&mut self) {
     let ops = self.get_two_operands();
+    let index = u16::from_le_bytes(ops);
+    self.call_stack.enter(Frame {
+      kind: FrameKind::Subroutine,
+      target: index,
+      call_site: self.instruction_start,
+      return_address: self.program_counter.get() as u16,
+      stack_pointer: self.memory.get_stack_pointer().get(),
+    });
     self.program_counter.decrease(1);
     let pc_ops = self.program_counter.get().to_le_bytes();
-    self.memory.push_to_stack(pc_ops[0]);
-    self.memory.push_to_stack(pc_ops[1]);
-    let index = u16::from_le_bytes(ops);
+    self.push_without_sync(pc_ops[0]);
+    self.push_without_sync(pc_ops[1]);
     debug!("JSR to index: {:X}, PC stored on stack", index,);
     // extra cycle needed due the return address
     self.sync();
@@ -1659,7 +2243,7 @@ impl CPU {
   /// Affects flags N Z C
   fn lsr(&mut self, value: u8) -> u8 {
     debug!("LSR called on {:X}", value);
-    let (result, carry) = value.overflowing_shr(1);
+    let (result, carry) = (value >> 1, value & 0x01 != 0);
     // extra cycle for modification
     self.sync();
     self.status_register.handle_n_flag(result, "LSR");
@@ -1940,6 +2524,8 @@ impl CPU {
   pub fn rti(&mut self) {
     debug!("RTI called");
     self.return_from_interrupt();
+    let target = self.program_counter.get() as u16;
+    self.call_stack.leave(self.instruction_start, target, true);
   }
 
   /// ReTurn from Subroutine
@@ -1950,7 +2536,8 @@ impl CPU {
     debug!("RTS called");
     let hi = self.pop_from_stack();
     let lo = self.pop_from_stack();
-    let index = u16::from_le_bytes([lo, hi]) + 1;
+    let index = u16::from_le_bytes([lo, hi]).wrapping_add(1);
+    self.call_stack.leave(self.instruction_start, index, false);
     // extra cycle to increment the index
     self.sync();
     self.program_counter.jump(index);
@@ -2137,6 +2724,9 @@ impl CPU {
   pub fn txs(&mut self) {
     debug!("TXS called");
     self.memory.set_stack_pointer(self.x_register.get());
+    self
+      .call_stack
+      .stack_pointer_set(self.instruction_start, self.x_register.get());
     // extra instruction byte always happens
     self.sync();
   }
@@ -2283,17 +2873,17 @@ impl PartialEq for CPU {
     let b4 = self.y_register == other.y_register;
     let b5 = self.accumulator == other.accumulator;
     let b6 = self.status_register == other.status_register;
-    return b1 && b2 && b3 && b4 && b5 && b6;
+    b1 && b2 && b3 && b4 && b5 && b6
   }
 }
 
 /// Prints pretty output about the status of the CPU.
 impl Display for CPU {
-  fn fmt(&self, f: &mut Formatter<'_>) -> Result {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
     write!(
       f,
-      "program_counter: 0x{:X}\nstack_pointer: 0x{:X}\naccumulator: 0x{:X}\nstatus_register: {}\nx_register: 0x{:X}\ny_register: 0x{:X}\n",
-      self.program_counter.get(), self.memory.get_stack_pointer().get(), self.accumulator.get(), self.status_register, self.x_register.get(), self.y_register.get()
+      "program_counter: 0x{:X} ({})\nstack_pointer: 0x{:X}\naccumulator: 0x{:X}\nstatus_register: {}\nx_register: 0x{:X}\ny_register: 0x{:X}\n",
+      self.program_counter.get(), self.format_address(self.program_counter.get() as u16), self.memory.get_stack_pointer().get(), self.accumulator.get(), self.status_register, self.x_register.get(), self.y_register.get()
     )
   }
 }
@@ -2317,12 +2907,9 @@ mod tests {
     std::thread::spawn(move || {
       for _ in 0..count {
         let result = tx.send(true);
-        match result {
-          Err(err) => {
-            println!("{}", err);
-            tx.send(true).unwrap();
-          }
-          Ok(_) => (),
+        if let Err(err) = result {
+          println!("{}", err);
+          tx.send(true).unwrap();
         }
       }
     });
@@ -2358,10 +2945,10 @@ mod tests {
     assert_eq!(cpu.x_register.get(), 0);
     assert_eq!(cpu.y_register.get(), 0);
     assert_eq!(cpu.status_register.get_register(), 0);
-    assert_eq!(cpu.memory.get_u16(random()), 0);
-    assert_eq!(cpu.reset_pin, false);
-    assert_eq!(cpu.irq_pin, false);
-    assert_eq!(cpu.nmi_pin, false);
+    assert_eq!(cpu.memory.peek(random()), 0);
+    assert!(!cpu.reset_pin);
+    assert!(!cpu.irq_pin);
+    assert!(!cpu.nmi_pin);
   }
 
   #[test]
@@ -2383,9 +2970,128 @@ mod tests {
     assert_eq!(cpu.y_register.get(), 0);
     assert_eq!(cpu.status_register.get_register(), 0);
     assert_eq!(cpu.memory.get_u16(random()), 0);
-    assert_eq!(cpu.reset_pin, false);
-    assert_eq!(cpu.irq_pin, false);
-    assert_eq!(cpu.nmi_pin, false);
+    assert!(!cpu.reset_pin);
+    assert!(!cpu.irq_pin);
+    assert!(!cpu.nmi_pin);
+  }
+
+  fn interrupt_cpu(vector: u16) -> CPU {
+    let mut cpu = new_cpu();
+    cpu.set_free_running(true);
+    // main: NOP; NOP
+    cpu.load_program_into_memory(&[0xEA, 0xEA], STARTING_MEMORY_BLOCK);
+    // handler: INX; RTI
+    cpu.load_program_into_memory(&[0xE8, 0x40], 0x9000);
+    cpu.memory.set(vector, 0x00);
+    cpu.memory.set(vector + 1, 0x90);
+    cpu
+  }
+
+  #[test]
+  fn irq_is_taken_until_cleared() {
+    let mut cpu = interrupt_cpu(0xFFFE);
+    cpu.set_irq();
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x9001);
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x8000);
+    cpu.step();
+    assert_eq!(cpu.x_register.get(), 2);
+    cpu.clear_irq();
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x8001);
+    assert_eq!(cpu.x_register.get(), 2);
+  }
+
+  #[test]
+  fn irq_is_masked_by_interrupt_flag() {
+    let mut cpu = interrupt_cpu(0xFFFE);
+    cpu.status_register.set_flag(StatusBit::Interrupt);
+    cpu.set_irq();
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x8001);
+    assert_eq!(cpu.x_register.get(), 0);
+  }
+
+  #[test]
+  fn device_drives_irq() {
+    let mut cpu = new_cpu();
+    cpu.set_free_running(true);
+    cpu.map_device(0x6000, 0x600F, Box::new(devices::Via::new()));
+    let source = "
+              LDA #$C0
+              STA $600E
+              LDA #$20
+              STA $6004
+              LDA #0
+              STA $6005
+              CLI
+      loop:   JMP loop
+              .org $9000
+      handler:
+              INX
+              LDA $6004
+              RTI
+              .org $FFFE
+              .word handler";
+    let assembly = assembler::assemble(source, "via.s", 0x8000).unwrap();
+    cpu.load_image(&assembly.image);
+    cpu.program_counter.jump(0x8000);
+    for _ in 0..100 {
+      cpu.step();
+    }
+    assert_eq!(cpu.x_register.get(), 1);
+  }
+
+  #[test]
+  fn nmi_is_taken_once() {
+    let mut cpu = interrupt_cpu(0xFFFA);
+    cpu.status_register.set_flag(StatusBit::Interrupt);
+    cpu.set_nmi();
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x9001);
+    assert!(!cpu.nmi_pin);
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x8001);
+    assert_eq!(cpu.x_register.get(), 1);
+  }
+
+  #[test]
+  fn cpu_is_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<CPU>();
+  }
+
+  #[test]
+  fn call_subroutine_returns_to_program_counter() {
+    let mut cpu = new_cpu();
+    cpu.set_free_running(true);
+    // sub: INX; RTS
+    cpu.load_program_into_memory(&[0xE8, 0x60], 0x9000);
+    cpu.program_counter.jump(0x0300);
+    cpu.call_subroutine(0x9000);
+    assert_eq!(cpu.program_counter.get(), 0x9000);
+    assert_eq!(cpu.call_stack.get_frames().len(), 1);
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x0300);
+    assert_eq!(cpu.x_register.get(), 1);
+    assert_eq!(cpu.memory.get_stack_pointer().get(), 0xFF);
+    assert_eq!(cpu.call_stack.get_frames().len(), 0);
+  }
+
+  #[test]
+  fn call_subroutine_checks_stack_bounds() {
+    let mut cpu = new_cpu();
+    cpu.program_counter.jump(0x0300);
+    cpu.memory.set_stack_pointer(0x00);
+    cpu.call_subroutine(0x9000);
+    assert!(matches!(
+      cpu.call_stack.get_errors(),
+      [StackError::Overflow { .. }]
+    ));
   }
 
   #[test]
@@ -2396,7 +3102,7 @@ mod tests {
       vector.push(wrapping_u8());
     }
     cpu.load_program_into_memory(&vector, 0);
-    assert_eq!(cpu.memory.get_zero_page(0x92) > 0, true);
+    assert!(cpu.memory.get_zero_page(0x92) > 0);
   }
 
   #[test]
@@ -2410,12 +3116,186 @@ mod tests {
     cpu.load_program_into_memory(&vector, 0xFFFF);
   }
 
+  #[test]
+  fn load_image() {
+    let mut cpu = new_cpu();
+    let image = Image::from_binary(&[0xA9, 0x42], 0x0200).unwrap();
+    assert_eq!(cpu.load_image(&image), 0x0200);
+    assert_eq!(cpu.program_counter.get(), 0x0200);
+    assert_eq!(cpu.memory.get_u16(0x0201), 0x42);
+  }
+
+  fn nrom_cartridge(flags_6: u8) -> Vec<u8> {
+    let mut data = vec![
+      0x4E, 0x45, 0x53, 0x1A, 1, 0, flags_6, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    ];
+    let mut prg = vec![0; 0x4000];
+    prg[0x3FFC] = 0x34;
+    prg[0x3FFD] = 0xC2;
+    data.extend(prg);
+    data
+  }
+
+  #[test]
+  fn load_cartridge() {
+    let mut cpu = new_cpu();
+    let cartridge = Cartridge::parse(&nrom_cartridge(0)).unwrap();
+    assert_eq!(cpu.load_cartridge(&cartridge), Ok(0xC234));
+    assert_eq!(cpu.program_counter.get(), 0xC234);
+  }
+
+  #[test]
+  fn load_cartridge_unsupported_mapper() {
+    let mut cpu = new_cpu();
+    let cartridge = Cartridge::parse(&nrom_cartridge(0xF0)).unwrap();
+    assert_eq!(
+      cpu.load_cartridge(&cartridge),
+      Err(CartridgeError::UnsupportedMapper(15))
+    );
+  }
+
+  #[test]
+  fn hooks() {
+    let mut cpu = new_cpu();
+    cpu.set_free_running(true);
+    // main: JSR sub
+    cpu.load_program_into_memory(&[0x20, 0x10, 0x80], STARTING_MEMORY_BLOCK);
+    // sub: LDA $10; STA $0300; BRK
+    cpu.load_program_into_memory(&[0xA5, 0x10, 0x8D, 0x00, 0x03, 0x00], 0x8010);
+    cpu.memory.set(0x10, 0x42);
+    cpu.memory.set(0xFFFE, 0x00);
+    cpu.memory.set(0xFFFF, 0x90);
+    let events = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let id = {
+      let events = events.clone();
+      cpu.add_hook(Box::new(move |e: &MemoryEvent| {
+        events.lock().unwrap().push(*e)
+      }))
+    };
+    for _ in 0..3 {
+      cpu.step();
+    }
+    let seen: Vec<(AccessKind, u16, u8)> = events
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|e| (e.kind, e.address, e.value))
+      .collect();
+    assert_eq!(
+      seen,
+      vec![
+        (AccessKind::OpcodeFetch, 0x8000, 0x20),
+        (AccessKind::OperandFetch, 0x8001, 0x10),
+        (AccessKind::OperandFetch, 0x8002, 0x80),
+        (AccessKind::Push, 0x01FF, 0x02),
+        (AccessKind::Push, 0x01FE, 0x80),
+        (AccessKind::OpcodeFetch, 0x8010, 0xA5),
+        (AccessKind::OperandFetch, 0x8011, 0x10),
+        (AccessKind::Read, 0x0010, 0x42),
+        (AccessKind::OpcodeFetch, 0x8012, 0x8D),
+        (AccessKind::OperandFetch, 0x8013, 0x00),
+        (AccessKind::OperandFetch, 0x8014, 0x03),
+        (AccessKind::Write, 0x0300, 0x42),
+      ]
+    );
+    let read = events.lock().unwrap()[7];
+    assert_eq!(read.program_counter, 0x8010);
+    assert!(events
+      .lock()
+      .unwrap()
+      .windows(2)
+      .all(|w| w[0].cycle <= w[1].cycle));
+    events.lock().unwrap().clear();
+    cpu.step();
+    let last = *events.lock().unwrap().last().unwrap();
+    assert_eq!(last.kind, AccessKind::Interrupt(Interrupt::Break));
+    assert_eq!(last.address, 0x9000);
+    assert!(cpu.remove_hook(id));
+    events.lock().unwrap().clear();
+    cpu.step();
+    assert!(events.lock().unwrap().is_empty());
+  }
+
+  #[test]
+  fn call_stack() {
+    let mut cpu = new_cpu();
+    cpu.set_free_running(true);
+    // main: JSR sub; NOP
+    cpu.load_program_into_memory(&[0x20, 0x10, 0x80, 0xEA], STARTING_MEMORY_BLOCK);
+    // sub: BRK; .byte 0; RTS
+    cpu.load_program_into_memory(&[0x00, 0x00, 0x60], 0x8010);
+    // handler: RTI
+    cpu.load_program_into_memory(&[0x40], 0x9000);
+    cpu.memory.set(0xFFFE, 0x00);
+    cpu.memory.set(0xFFFF, 0x90);
+    let mut symbols = SymbolTable::new();
+    symbols.add("main", 0x8000, None);
+    symbols.add("sub", 0x8010, None);
+    symbols.add("handler", 0x9000, None);
+    cpu.set_symbols(symbols);
+    cpu.step();
+    cpu.step();
+    assert_eq!(
+      cpu.backtrace(),
+      "#0  $9000 handler\n\
+       #1  $8010 sub (interrupted by Break, handler handler)\n\
+       #2  $8000 main (called sub)\n"
+    );
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x8012);
+    cpu.step();
+    assert_eq!(cpu.program_counter.get(), 0x8003);
+    assert!(cpu.get_call_stack().get_frames().is_empty());
+    assert!(cpu.get_call_stack().get_errors().is_empty());
+    cpu.program_counter.jump(0x8012);
+    cpu.step();
+    assert_eq!(
+      cpu.get_call_stack_mut().take_errors(),
+      vec![
+        StackError::Underflow { address: 0x8012 },
+        StackError::UnmatchedReturn {
+          address: 0x8012,
+          target: 0x0001
+        }
+      ]
+    );
+  }
+
+  #[test]
+  fn symbols() {
+    let mut cpu = new_cpu();
+    cpu.load_program_into_memory(&[0xA9, 0x10, 0x4C, 0x00, 0x80], STARTING_MEMORY_BLOCK);
+    cpu.program_counter.jump(STARTING_MEMORY_BLOCK + 2);
+    assert!(cpu
+      .to_string()
+      .starts_with("program_counter: 0x8002 ($8002)"));
+    let mut symbols = SymbolTable::new();
+    symbols.add("main", STARTING_MEMORY_BLOCK, None);
+    cpu.set_symbols(symbols);
+    assert!(cpu
+      .to_string()
+      .starts_with("program_counter: 0x8002 (main+2)"));
+    assert_eq!(
+      cpu.disassemble(STARTING_MEMORY_BLOCK, 2),
+      "main:\n8000  A9 10     LDA #$10\n8002  4C 00 80  JMP main\n"
+    );
+  }
+
+  #[test]
+  fn step() {
+    let mut cpu = setup_sync(2);
+    let image = Image::from_binary(&[0xA9, 0x42], 0x0200).unwrap();
+    cpu.load_image(&image);
+    cpu.step();
+    assert_eq!(cpu.accumulator.get(), 0x42);
+    assert_eq!(cpu.program_counter.get(), 0x0202);
+  }
+
   #[test]
   fn sync_proceeds_when_clock_signal_received() {
     let mut cpu = setup_sync(1);
     cpu.sync();
     // if we got here, things are working
-    assert_eq!(true, true);
   }
 
   #[test]
@@ -2457,7 +3337,7 @@ mod tests {
   #[test_case(random())]
   fn get_single_operand(value: u8) {
     let mut cpu = setup_sync(1);
-    let pc = cpu.program_counter.get() + 1;
+    let pc = cpu.program_counter.get();
     cpu.memory.set(pc as u16, value);
     let op = cpu.get_single_operand();
     assert_eq!(op, value);
@@ -2466,7 +3346,7 @@ mod tests {
   #[test_case(random(), random())]
   fn get_two_operands(v1: u8, v2: u8) {
     let mut cpu = setup_sync(2);
-    let pc = cpu.program_counter.get() + 1;
+    let pc = cpu.program_counter.get();
     cpu.memory.set(pc as u16, v1);
     cpu.memory.set((pc + 1) as u16, v2);
     let ops = cpu.get_two_operands();
@@ -2480,7 +3360,6 @@ mod tests {
     let mut cpu = setup_sync(sync_count);
     cpu.test_for_overflow(v1, v2);
     // if we're here, things worked
-    assert_eq!(true, true);
   }
 
   // NOTES FOR SYNC COUNTS IN THIS SECTION
@@ -2491,7 +3370,7 @@ mod tests {
   fn immediate(value: u8) {
     let mut cpu = setup_sync(1);
     let pc = cpu.program_counter.get();
-    cpu.memory.set((pc + 1) as u16, value);
+    cpu.memory.set(pc as u16, value);
     let result = cpu.immediate("Test");
     assert_eq!(value, result);
   }
@@ -2500,7 +3379,7 @@ mod tests {
   fn zero_page(value: u8, index: u8) {
     let mut cpu = setup_sync(2);
     let pc = cpu.program_counter.get();
-    cpu.memory.set((pc + 1) as u16, index);
+    cpu.memory.set(pc as u16, index);
     cpu.memory.set_zero_page(index, value);
     let (i_result, v_result) = cpu.zero_page("Test");
     assert_eq!(value, v_result);
@@ -2512,7 +3391,7 @@ mod tests {
   fn zero_page_reg(value: u8, index: u8, reg: u8) {
     let mut cpu = setup_sync(3);
     let pc = cpu.program_counter.get();
-    cpu.memory.set((pc + 1) as u16, index);
+    cpu.memory.set(pc as u16, index);
     let index = index.wrapping_add(reg);
     cpu.memory.set_zero_page(index, value);
     let (i_result, v_result) = cpu.zp_reg("Test", reg);
@@ -2525,8 +3404,8 @@ mod tests {
     let mut cpu = setup_sync(3);
     let pc = cpu.program_counter.get();
     let ops = index.to_le_bytes();
-    cpu.memory.set((pc + 1) as u16, ops[0]);
-    cpu.memory.set((pc + 2) as u16, ops[1]);
+    cpu.memory.set(pc as u16, ops[0]);
+    cpu.memory.set((pc + 1) as u16, ops[1]);
     cpu.memory.set(index, value);
     let (i_result, v_result) = cpu.absolute("Test");
     assert_eq!(value, v_result);
@@ -2539,8 +3418,8 @@ mod tests {
     let mut cpu = setup_sync(sync_count);
     let pc = cpu.program_counter.get();
     let ops = index.to_le_bytes();
-    cpu.memory.set((pc + 1) as u16, ops[0]);
-    cpu.memory.set((pc + 2) as u16, ops[1]);
+    cpu.memory.set(pc as u16, ops[0]);
+    cpu.memory.set((pc + 1) as u16, ops[1]);
     let index = index.wrapping_add(reg as u16);
     cpu.memory.set(index, value);
     let (i_result, v_result) = cpu.absolute_reg("Test", reg);
@@ -2554,7 +3433,7 @@ mod tests {
     let mut cpu = setup_sync(sync_count);
     let pc = cpu.program_counter.get();
     cpu.x_register.set(reg);
-    cpu.memory.set((pc + 1) as u16, op);
+    cpu.memory.set(pc as u16, op);
     let mod_op = op.wrapping_add(reg);
     let ops = index.to_le_bytes();
     cpu.memory.set_zero_page(mod_op, ops[0]);
@@ -2571,7 +3450,7 @@ mod tests {
     let mut cpu = setup_sync(sync_count);
     let pc = cpu.program_counter.get();
     cpu.y_register.set(reg);
-    cpu.memory.set((pc + 1) as u16, op);
+    cpu.memory.set(pc as u16, op);
     let ops = index.to_le_bytes();
     cpu.memory.set_zero_page(op, ops[0]);
     cpu.memory.set_zero_page(op.wrapping_add(1), ops[1]);
@@ -2589,7 +3468,7 @@ mod tests {
     let pc = cpu.program_counter.get();
     cpu.branch(condition, op);
     let result = match op > 0x7F {
-      true => pc - !(op + 1) as usize,
+      true => pc - ((!op) as usize + 1),
       false => pc + op as usize,
     };
     assert_eq!(result, cpu.program_counter.get());
@@ -2623,6 +3502,18 @@ mod tests {
     );
   }
 
+  #[test]
+  fn generic_compare_clears_flags() {
+    let mut cpu = setup_sync(0);
+    cpu.status_register.set(0xFF);
+    cpu.generic_compare(0x10, 0x20);
+    assert!(cpu.status_register.is_flag_set(StatusBit::Carry));
+    assert!(!cpu.status_register.is_flag_set(StatusBit::Zero));
+    assert!(!cpu.status_register.is_flag_set(StatusBit::Negative));
+    cpu.generic_compare(0x20, 0x10);
+    assert!(!cpu.status_register.is_flag_set(StatusBit::Carry));
+  }
+
   #[test_case(0; "Zero")]
   #[test_case(0xAA; "Negative")]
   #[test_case(0x12; "Positive")]
@@ -2707,7 +3598,7 @@ mod tests {
   fn set_flag(flag: StatusBit) {
     let mut cpu = setup_sync(1);
     cpu.set_flag(flag);
-    assert_eq!(cpu.status_register.is_flag_set(flag), true);
+    assert!(cpu.status_register.is_flag_set(flag));
   }
 
   #[test_case(StatusBit::Carry; "Clear carry")]
@@ -2721,7 +3612,7 @@ mod tests {
     let mut cpu = setup_sync(1);
     cpu.status_register.set_flag(flag);
     cpu.clear_flag(flag);
-    assert_eq!(cpu.status_register.is_flag_set(flag), false);
+    assert!(!cpu.status_register.is_flag_set(flag));
   }
 
   #[test_case(0xFFFA, 0xFFFB, random(), random(), random(), random(); "interrupt values")]
@@ -2740,7 +3631,7 @@ mod tests {
       cpu.memory.get_u16(0x1FD),
       cpu.status_register.get_register()
     );
-    assert_eq!(cpu.status_register.is_flag_set(StatusBit::Interrupt), true);
+    assert!(cpu.status_register.is_flag_set(StatusBit::Interrupt));
   }
 
   // 0xCF is all flags except break and unused set
@@ -2756,7 +3647,7 @@ mod tests {
     cpu.return_from_interrupt();
     assert_eq!(cpu.program_counter.get(), pc as usize);
     assert_eq!(cpu.status_register.get_register(), 0xCB);
-    assert_eq!(cpu.status_register.is_flag_set(StatusBit::Interrupt), false);
+    assert!(!cpu.status_register.is_flag_set(StatusBit::Interrupt));
   }
 
   #[test_case(0x58, 0x46, 1, 0x05, true)]
@@ -2862,7 +3753,7 @@ mod tests {
   fn asl_zero_page(index: u8, value: u8) {
     let mut cpu = setup_sync(4);
     cpu.memory.set_zero_page(index, value);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, index);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, index);
     cpu.asl_zero_page();
     let result = cpu.memory.get_zero_page(index);
     assert_eq!(result, value.wrapping_shl(1));
@@ -2874,7 +3765,7 @@ mod tests {
     let mod_index = index.wrapping_add(x);
     cpu.x_register.set(x);
     cpu.memory.set_zero_page(mod_index, val);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, index);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, index);
     cpu.asl_zero_page_x();
     let result = cpu.memory.get_zero_page(mod_index);
     assert_eq!(result, val.wrapping_shl(1));
@@ -2884,8 +3775,8 @@ mod tests {
   fn asl_absolute(index: u16, val: u8) {
     let mut cpu = setup_sync(6);
     let ops = index.to_le_bytes();
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     cpu.memory.set(index, val);
     cpu.asl_absolute();
     let result = cpu.memory.get_u16(index);
@@ -2899,8 +3790,8 @@ mod tests {
     let ops = index.to_le_bytes();
     cpu.x_register.set(x);
     cpu.memory.set(mod_index, val);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     cpu.asl_absolute_x();
     let result = cpu.memory.get_u16(mod_index);
     assert_eq!(result, val.wrapping_shl(1));
@@ -2946,8 +3837,8 @@ mod tests {
     cpu.x_register.set(x);
     cpu.y_register.set(y);
     let ops = index.to_le_bytes();
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     let mod_index = index.wrapping_add(y as u16);
     cpu.axa_absolute_y();
     let result = x & acc;
@@ -2961,7 +3852,7 @@ mod tests {
     let mut cpu = setup_sync(4);
     cpu.accumulator.set(acc);
     cpu.x_register.set(x);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     let ops = index.to_le_bytes();
     let op = op.wrapping_add(x);
     cpu.memory.set_zero_page(op, ops[0]);
@@ -3009,13 +3900,13 @@ mod tests {
   fn bpl(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     match take {
       true => pc_start += op as u16,
       false => cpu.status_register.set_flag(StatusBit::Negative),
     };
     cpu.bpl();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3023,13 +3914,13 @@ mod tests {
   fn bmi(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     if take {
       pc_start += op as u16;
       cpu.status_register.set_flag(StatusBit::Negative);
     }
     cpu.bmi();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3037,13 +3928,13 @@ mod tests {
   fn bvc(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     match take {
       true => pc_start += op as u16,
       false => cpu.status_register.set_flag(StatusBit::Overflow),
     };
     cpu.bvc();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3051,13 +3942,13 @@ mod tests {
   fn bvs(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     if take {
       pc_start += op as u16;
       cpu.status_register.set_flag(StatusBit::Overflow);
     }
     cpu.bvs();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3065,13 +3956,13 @@ mod tests {
   fn bcc(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     match take {
       true => pc_start += op as u16,
       false => cpu.status_register.set_flag(StatusBit::Carry),
     };
     cpu.bcc();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3079,13 +3970,13 @@ mod tests {
   fn bcs(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     if take {
       pc_start += op as u16;
       cpu.status_register.set_flag(StatusBit::Carry);
     }
     cpu.bcs();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3093,13 +3984,13 @@ mod tests {
   fn bne(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     match take {
       true => pc_start += op as u16,
       false => cpu.status_register.set_flag(StatusBit::Zero),
     };
     cpu.bne();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(true, 0x05; "Take branch")]
@@ -3107,13 +3998,13 @@ mod tests {
   fn beq(take: bool, op: u8) {
     let mut cpu = setup_sync(3);
     let mut pc_start = STARTING_MEMORY_BLOCK + 1;
-    cpu.memory.set(pc_start, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     if take {
       pc_start += op as u16;
       cpu.status_register.set_flag(StatusBit::Zero);
     }
     cpu.beq();
-    assert_eq!(cpu.program_counter.get(), (pc_start as u16) as usize);
+    assert_eq!(cpu.program_counter.get(), pc_start as usize);
   }
 
   #[test_case(random())]
@@ -3191,7 +4082,7 @@ mod tests {
     let mut cpu = setup_sync(1);
     cpu.status_register.set_flag(flag);
     f(&mut cpu);
-    assert_eq!(cpu.status_register.is_flag_set(StatusBit::Carry), false);
+    assert!(!cpu.status_register.is_flag_set(StatusBit::Carry));
   }
 
   #[test_case(random(), random())]
@@ -3214,7 +4105,7 @@ mod tests {
     let base_cpu = setup_sync(1);
     cpu.dop(0);
     let result = cpu == base_cpu;
-    assert_eq!(result, true);
+    assert!(result);
   }
 
   #[test_case(random(), random())]
@@ -3248,12 +4139,21 @@ mod tests {
     cpu.kil();
   }
 
+  #[test]
+  #[should_panic]
+  fn forbidden_undocumented_opcode() {
+    let mut cpu = setup_sync(1);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, 0xA7);
+    cpu.set_undocumented_opcodes(false);
+    cpu.step();
+  }
+
   #[test_case(random())]
   fn jmp_absolute(index: u16) {
     let mut cpu = setup_sync(2);
     let ops = index.to_le_bytes();
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     cpu.jmp_absolute();
     assert_eq!(cpu.program_counter.get(), index as usize);
   }
@@ -3262,8 +4162,8 @@ mod tests {
   fn jmp_indirect(index: u16, dest_index: u16) {
     let mut cpu = setup_sync(4);
     let ops = index.to_le_bytes();
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     let dest_ops = dest_index.to_le_bytes();
     cpu.memory.set(index, dest_ops[0]);
     cpu.memory.set(index.wrapping_add(1), dest_ops[1]);
@@ -3275,8 +4175,8 @@ mod tests {
   fn jsr(index: u16) {
     let mut cpu = setup_sync(5);
     let ops = index.to_le_bytes();
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     cpu.jsr();
     assert_eq!(cpu.program_counter.get(), index as usize);
     assert_ne!(cpu.memory.get_stack_pointer().get(), 0xFF);
@@ -3328,13 +4228,29 @@ mod tests {
     assert_eq!(result, val.wrapping_shr(1));
   }
 
+  #[test_case(0x81, 0x02, true; "Carry out")]
+  #[test_case(0x40, 0x80, false; "No carry")]
+  fn asl_sets_carry(value: u8, expected: u8, carry: bool) {
+    let mut cpu = setup_sync(1);
+    assert_eq!(cpu.asl(value), expected);
+    assert_eq!(cpu.status_register.is_flag_set(StatusBit::Carry), carry);
+  }
+
+  #[test_case(0x81, 0x40, true; "Carry out")]
+  #[test_case(0x02, 0x01, false; "No carry")]
+  fn lsr_sets_carry(value: u8, expected: u8, carry: bool) {
+    let mut cpu = setup_sync(1);
+    assert_eq!(cpu.lsr(value), expected);
+    assert_eq!(cpu.status_register.is_flag_set(StatusBit::Carry), carry);
+  }
+
   #[test]
   fn nop() {
     let mut cpu = setup_sync(1);
     let base_cpu = setup_sync(1);
     cpu.nop();
     let result = cpu == base_cpu;
-    assert_eq!(result, true);
+    assert!(result);
   }
 
   #[test_case(random(), random())]
@@ -3495,7 +4411,7 @@ mod tests {
   fn set_flags<F: FnMut(&mut CPU)>(flag: StatusBit, f: &mut F) {
     let mut cpu = setup_sync(1);
     f(&mut cpu);
-    assert_eq!(cpu.status_register.is_flag_set(flag), true);
+    assert!(cpu.status_register.is_flag_set(flag));
   }
 
   #[test_case(random(), random())]
@@ -3522,7 +4438,7 @@ mod tests {
   fn sta_zero_page(index: u8, val: u8) {
     let mut cpu = setup_sync(2);
     cpu.accumulator.set(val);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, index);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, index);
     cpu.sta_zero_page();
     assert_eq!(cpu.memory.get_zero_page(index), val);
   }
@@ -3533,7 +4449,7 @@ mod tests {
     let cpu2 = setup_sync(0);
     cpu.top(0);
     let result = cpu == cpu2;
-    assert_eq!(result, true);
+    assert!(result);
   }
 
   #[test_case(random(), random())]
@@ -3591,7 +4507,7 @@ mod tests {
     let mut cpu = setup_sync(3);
     cpu.y_register.set(y);
     cpu.x_register.set(x);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, op);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, op);
     let index = op.wrapping_add(y);
     cpu.memory.set_zero_page(op.wrapping_add(y), index);
     cpu.stx_zero_page_y();
@@ -3603,8 +4519,8 @@ mod tests {
     let mut cpu = setup_sync(3);
     cpu.y_register.set(y);
     let ops = index.to_le_bytes();
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[0]);
-    cpu.memory.set(STARTING_MEMORY_BLOCK + 2, ops[1]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK, ops[0]);
+    cpu.memory.set(STARTING_MEMORY_BLOCK + 1, ops[1]);
     cpu.sty_absolute();
     assert_eq!(cpu.memory.get_u16(index), y);
   }
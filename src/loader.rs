@@ -0,0 +1,552 @@
+use crate::prelude::*;
+use crate::Memory;
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// The file formats we know how to load.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+  /// Intel HEX, as produced by most assemblers with a hex output option.
+  IntelHex,
+  /// Motorola S-record, any of S19, S28 or S37.
+  SRecord,
+  /// A raw binary that needs a load address supplied.
+  Binary,
+  /// A Commodore PRG file. The first two bytes are the load address.
+  Prg,
+}
+
+impl Format {
+  /// Guesses the format from a file extension. Anything we don't recognise is
+  /// assumed to be a raw binary.
+  pub fn from_extension(extension: &str) -> Format {
+    match extension.to_ascii_lowercase().as_str() {
+      "hex" | "ihex" | "ihx" => Format::IntelHex,
+      "s19" | "s28" | "s37" | "srec" | "mot" => Format::SRecord,
+      "prg" => Format::Prg,
+      _ => Format::Binary,
+    }
+  }
+}
+
+/// Things that can go wrong while parsing an image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadError {
+  /// A record could not be parsed. Holds the line number and what was wrong.
+  Syntax(usize, String),
+  /// A record's checksum did not match its contents. Holds the line number.
+  Checksum(usize),
+  /// A record or segment lands outside of the 16 bit address space. Holds the
+  /// offending address.
+  OutOfRange(u32),
+  /// The file is too short to hold what its format requires.
+  TooShort,
+}
+
+impl Display for LoadError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      LoadError::Syntax(line, message) => write!(f, "syntax error on line {}: {}", line, message),
+      LoadError::Checksum(line) => write!(f, "checksum mismatch on line {}", line),
+      LoadError::OutOfRange(address) => {
+        write!(f, "address 0x{:X} is outside of the address space", address)
+      }
+      LoadError::TooShort => write!(f, "file is too short"),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for LoadError {}
+
+/// A run of bytes to be placed at an address.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Segment {
+  pub address: u16,
+  pub data: Vec<u8>,
+}
+
+/// A parsed program, made up of the segments to place in memory and an
+/// optional entry point.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Image {
+  segments: Vec<Segment>,
+  entry: Option<u16>,
+}
+
+impl Image {
+  /// Parses an image in the given format. The address is only used for raw
+  /// binaries, and defaults to the starting memory block.
+  pub fn parse(data: &[u8], format: Format, address: Option<u16>) -> Result<Image, LoadError> {
+    match format {
+      Format::IntelHex => Image::from_intel_hex(&String::from_utf8_lossy(data)),
+      Format::SRecord => Image::from_srecord(&String::from_utf8_lossy(data)),
+      Format::Binary => Image::from_binary(data, address.unwrap_or(crate::STARTING_MEMORY_BLOCK)),
+      Format::Prg => Image::from_prg(data),
+    }
+  }
+
+  /// Creates an image from a raw binary loaded at an address. The entry point
+  /// is the load address.
+  pub fn from_binary(data: &[u8], address: u16) -> Result<Image, LoadError> {
+    debug!("Loading {} byte binary at {:X}", data.len(), address);
+    let mut image = Image::default();
+    image.add_segment(address as u32, data.to_vec())?;
+    Ok(image)
+  }
+
+  /// Creates an image from a Commodore PRG file, where the first two bytes are
+  /// the little endian load address.
+  pub fn from_prg(data: &[u8]) -> Result<Image, LoadError> {
+    if data.len() < 2 {
+      return Err(LoadError::TooShort);
+    }
+    let address = u16::from_le_bytes([data[0], data[1]]);
+    debug!("Loading PRG at {:X}", address);
+    Image::from_binary(&data[2..], address)
+  }
+
+  /// Parses Intel HEX. Supports data, end of file, extended segment and
+  /// extended linear address records, and takes the entry point from start
+  /// segment or start linear address records.
+  pub fn from_intel_hex(text: &str) -> Result<Image, LoadError> {
+    let mut image = Image::default();
+    let mut base: u32 = 0;
+    for (number, line) in text.lines().enumerate() {
+      let number = number + 1;
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+      if !line.starts_with(':') {
+        return Err(LoadError::Syntax(number, "missing start code".to_string()));
+      }
+      let bytes = decode_hex(&line[1..], number)?;
+      if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err(LoadError::Syntax(number, "bad record length".to_string()));
+      }
+      let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+      if sum != 0 {
+        return Err(LoadError::Checksum(number));
+      }
+      let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+      let data = &bytes[4..bytes.len() - 1];
+      trace!("Intel HEX record type {:X} at {:X}", bytes[3], offset);
+      match bytes[3] {
+        0x00 => image.add_segment(base + offset, data.to_vec())?,
+        0x01 => break,
+        0x02 => base = read_exact(data, 2, number)? << 4,
+        0x03 => {
+          if data.len() != 4 {
+            return Err(LoadError::Syntax(number, "bad address length".to_string()));
+          }
+          // CS:IP, where CS is a paragraph number.
+          let segment = u16::from_be_bytes([data[0], data[1]]) as u32;
+          let offset = u16::from_be_bytes([data[2], data[3]]) as u32;
+          image.entry = Some(range_check((segment << 4) + offset)?)
+        }
+        0x04 => base = read_exact(data, 2, number)? << 16,
+        0x05 => image.entry = Some(range_check(read_exact(data, 4, number)?)?),
+        other => {
+          return Err(LoadError::Syntax(
+            number,
+            format!("unknown record type {:X}", other),
+          ))
+        }
+      }
+    }
+    Ok(image)
+  }
+
+  /// Parses Motorola S-records. Data can come from S1, S2 or S3 records and
+  /// the entry point from S7, S8 or S9 records. Header and count records are
+  /// checked and skipped.
+  pub fn from_srecord(text: &str) -> Result<Image, LoadError> {
+    let mut image = Image::default();
+    for (number, line) in text.lines().enumerate() {
+      let number = number + 1;
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+      if !line.is_ascii() {
+        return Err(LoadError::Syntax(number, "non-ASCII characters".to_string()));
+      }
+      if line.len() < 2 || !line.starts_with('S') {
+        return Err(LoadError::Syntax(number, "missing record type".to_string()));
+      }
+      let kind = line.as_bytes()[1];
+      let bytes = decode_hex(&line[2..], number)?;
+      if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+        return Err(LoadError::Syntax(number, "bad record length".to_string()));
+      }
+      let sum = bytes[..bytes.len() - 1]
+        .iter()
+        .fold(0u8, |acc, b| acc.wrapping_add(*b));
+      if !sum != bytes[bytes.len() - 1] {
+        return Err(LoadError::Checksum(number));
+      }
+      let address_size = match kind {
+        b'0' | b'1' | b'5' | b'9' => 2,
+        b'2' | b'6' | b'8' => 3,
+        b'3' | b'7' => 4,
+        _ => {
+          return Err(LoadError::Syntax(
+            number,
+            format!("unknown record type S{}", kind as char),
+          ))
+        }
+      };
+      if bytes.len() < address_size + 2 {
+        return Err(LoadError::Syntax(number, "record too short".to_string()));
+      }
+      let address = read_be(&bytes[1..1 + address_size], number)?;
+      let data = &bytes[1 + address_size..bytes.len() - 1];
+      trace!("S-record type S{} at {:X}", kind as char, address);
+      match kind {
+        b'1' | b'2' | b'3' => image.add_segment(address, data.to_vec())?,
+        b'7' | b'8' | b'9' => image.entry = Some(range_check(address)?),
+        _ => (),
+      }
+    }
+    Ok(image)
+  }
+
+  /// Adds a segment, checking it fits in the address space.
+  pub(crate) fn add_segment(&mut self, address: u32, data: Vec<u8>) -> Result<(), LoadError> {
+    if data.is_empty() {
+      return Ok(());
+    }
+    // Only an address above the address space can overflow.
+    let last = address
+      .checked_add(data.len() as u32 - 1)
+      .ok_or(LoadError::OutOfRange(address))?;
+    range_check(last)?;
+    self.segments.push(Segment {
+      address: address as u16,
+      data,
+    });
+    Ok(())
+  }
+
+  /// Gets the segments that make up the image.
+  pub fn get_segments(&self) -> &[Segment] {
+    &self.segments
+  }
+
+  /// Gets the entry point. If the file didn't specify one this is the address
+  /// of the first segment, or the starting memory block for an empty image.
+  pub fn get_entry(&self) -> u16 {
+    match (self.entry, self.segments.first()) {
+      (Some(entry), _) => entry,
+      (None, Some(segment)) => segment.address,
+      (None, None) => crate::STARTING_MEMORY_BLOCK,
+    }
+  }
+
+  /// Places every segment into memory and returns the entry point.
+  pub fn load(&self, memory: &mut Memory) -> u16 {
+    for segment in self.segments.iter() {
+      debug!(
+        "Placing {} bytes at {:X}",
+        segment.data.len(),
+        segment.address
+      );
+      for (offset, byte) in segment.data.iter().enumerate() {
+        memory.set(segment.address + offset as u16, *byte);
+      }
+    }
+    self.get_entry()
+  }
+
+  /// Writes the image in the given format. Binaries and PRG files hold a
+  /// single run of bytes, so gaps between segments are filled with zeros.
+  pub fn write(&self, format: Format) -> Vec<u8> {
+    match format {
+      Format::IntelHex => self.to_intel_hex().into_bytes(),
+      Format::SRecord => self.to_srecord().into_bytes(),
+      Format::Binary => self.to_binary().1,
+      Format::Prg => {
+        let (address, data) = self.to_binary();
+        let mut prg = address.to_le_bytes().to_vec();
+        prg.extend(data);
+        prg
+      }
+    }
+  }
+
+  /// Flattens the segments into one run of bytes, returning its address.
+  pub fn to_binary(&self) -> (u16, Vec<u8>) {
+    let start = match self.segments.iter().map(|s| s.address).min() {
+      Some(start) => start,
+      None => return (crate::STARTING_MEMORY_BLOCK, vec![]),
+    };
+    let mut data = vec![];
+    for segment in self.segments.iter() {
+      let offset = (segment.address - start) as usize;
+      let end = offset + segment.data.len();
+      if data.len() < end {
+        data.resize(end, 0);
+      }
+      data[offset..end].copy_from_slice(&segment.data);
+    }
+    (start, data)
+  }
+
+  /// Writes Intel HEX, 16 bytes to a data record, with the entry point in a
+  /// start linear address record.
+  pub fn to_intel_hex(&self) -> String {
+    let mut text = String::new();
+    let mut record = |kind: u8, address: u16, data: &[u8]| {
+      let mut bytes = vec![data.len() as u8];
+      bytes.extend(address.to_be_bytes());
+      bytes.push(kind);
+      bytes.extend(data);
+      let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+      bytes.push(sum.wrapping_neg());
+      text += &format!(":{}\n", encode_hex(&bytes));
+    };
+    for segment in self.segments.iter() {
+      for (index, chunk) in segment.data.chunks(16).enumerate() {
+        record(0x00, segment.address + index as u16 * 16, chunk);
+      }
+    }
+    record(0x05, 0, &(self.get_entry() as u32).to_be_bytes());
+    record(0x01, 0, &[]);
+    text
+  }
+
+  /// Writes Motorola S-records, 16 bytes to an S1 record, with the entry
+  /// point in an S9 record.
+  pub fn to_srecord(&self) -> String {
+    let mut text = String::new();
+    let mut record = |kind: char, address: u16, data: &[u8]| {
+      let mut bytes = vec![data.len() as u8 + 3];
+      bytes.extend(address.to_be_bytes());
+      bytes.extend(data);
+      let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+      bytes.push(!sum);
+      text += &format!("S{}{}\n", kind, encode_hex(&bytes));
+    };
+    for segment in self.segments.iter() {
+      for (index, chunk) in segment.data.chunks(16).enumerate() {
+        record('1', segment.address + index as u16 * 16, chunk);
+      }
+    }
+    record('9', self.get_entry(), &[]);
+    text
+  }
+}
+
+/// Encodes bytes as a string of upper case hex digit pairs.
+fn encode_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// Decodes a string of hex digit pairs into bytes.
+fn decode_hex(text: &str, line: usize) -> Result<Vec<u8>, LoadError> {
+  if !text.len().is_multiple_of(2) || !text.is_ascii() {
+    return Err(LoadError::Syntax(line, "odd number of digits".to_string()));
+  }
+  (0..text.len())
+    .step_by(2)
+    .map(|i| {
+      u8::from_str_radix(&text[i..i + 2], 16)
+        .map_err(|_| LoadError::Syntax(line, format!("bad hex digits {}", &text[i..i + 2])))
+    })
+    .collect()
+}
+
+/// Reads up to four big endian bytes as a number.
+fn read_be(bytes: &[u8], line: usize) -> Result<u32, LoadError> {
+  if bytes.is_empty() || bytes.len() > 4 {
+    return Err(LoadError::Syntax(line, "bad address length".to_string()));
+  }
+  Ok(bytes.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// Reads a big endian value from a record's data, which must be exactly
+/// `length` bytes long.
+fn read_exact(bytes: &[u8], length: usize, line: usize) -> Result<u32, LoadError> {
+  if bytes.len() != length {
+    return Err(LoadError::Syntax(line, "bad address length".to_string()));
+  }
+  read_be(bytes, line)
+}
+
+/// Checks an address fits in 16 bits.
+fn range_check(address: u32) -> Result<u16, LoadError> {
+  match address > 0xFFFF {
+    true => Err(LoadError::OutOfRange(address)),
+    false => Ok(address as u16),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::random;
+  use test_case::test_case;
+
+  #[test]
+  fn intel_hex() {
+    let text = ":0380000001020377\n:040000050000800077\n:00000001FF\n";
+    let image = Image::from_intel_hex(text).unwrap();
+    assert_eq!(
+      image.get_segments(),
+      &[Segment {
+        address: 0x8000,
+        data: vec![1, 2, 3]
+      }]
+    );
+    assert_eq!(image.get_entry(), 0x8000);
+  }
+
+  #[test_case(":020000040001F9\n:0100000042BD\n:00000001FF\n", 0x10000; "Above 64K")]
+  #[test_case(":02000004FFFFFC\n:02FFFF00AABB9B\n", 0xFFFFFFFF; "Top of linear space")]
+  fn intel_hex_extended_address(text: &str, address: u32) {
+    assert_eq!(
+      Image::from_intel_hex(text),
+      Err(LoadError::OutOfRange(address))
+    );
+  }
+
+  #[test]
+  fn intel_hex_start_segment() {
+    let image = Image::from_intel_hex(":0400000308000010E1\n:00000001FF\n").unwrap();
+    assert_eq!(image.get_entry(), 0x8010);
+    assert_eq!(
+      Image::from_intel_hex(":04000003F0000010F9\n"),
+      Err(LoadError::OutOfRange(0xF0010))
+    );
+  }
+
+  #[test_case(":0380000001020378"; "Bad checksum")]
+  fn intel_hex_checksum(text: &str) {
+    assert_eq!(Image::from_intel_hex(text), Err(LoadError::Checksum(1)));
+  }
+
+  #[test_case("0380000001020377"; "Missing colon")]
+  #[test_case(":038000000102"; "Short record")]
+  #[test_case(":03800000010203ZZ"; "Bad digits")]
+  #[test_case(":04000002FFFFFFFFFE"; "Long segment address")]
+  #[test_case(":0400000400000000F8"; "Long linear address")]
+  #[test_case(":020000050000F9"; "Short start address")]
+  fn intel_hex_syntax(text: &str) {
+    match Image::from_intel_hex(text) {
+      Err(LoadError::Syntax(1, _)) => (),
+      other => panic!("Expected a syntax error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn srecord() {
+    let text = "S00600004844521B\nS1060400010203EF\nS5030001FB\nS9030400F8\n";
+    let image = Image::from_srecord(text).unwrap();
+    assert_eq!(
+      image.get_segments(),
+      &[Segment {
+        address: 0x0400,
+        data: vec![1, 2, 3]
+      }]
+    );
+    assert_eq!(image.get_entry(), 0x0400);
+  }
+
+  #[test]
+  fn srecord_s28() {
+    let text = "S207000400010203EE\nS804000400F7\n";
+    let image = Image::from_srecord(text).unwrap();
+    assert_eq!(image.get_segments()[0].address, 0x0400);
+    assert_eq!(image.get_entry(), 0x0400);
+  }
+
+  #[test]
+  fn srecord_checksum() {
+    assert_eq!(
+      Image::from_srecord("S1060400010203EE"),
+      Err(LoadError::Checksum(1))
+    );
+  }
+
+  #[test_case("1060400010203EF"; "Missing S")]
+  #[test_case("Sé"; "Multi-byte record type")]
+  #[test_case("S1é060400010203EF"; "Multi-byte digits")]
+  fn srecord_syntax(text: &str) {
+    match Image::from_srecord(text) {
+      Err(LoadError::Syntax(1, _)) => (),
+      other => panic!("Expected a syntax error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn prg() {
+    let image = Image::from_prg(&[0x01, 0x08, 0xA9, 0x00]).unwrap();
+    assert_eq!(image.get_entry(), 0x0801);
+    assert_eq!(image.get_segments()[0].data, vec![0xA9, 0x00]);
+    assert_eq!(Image::from_prg(&[0x01]), Err(LoadError::TooShort));
+  }
+
+  #[test]
+  fn srecord_out_of_range() {
+    assert_eq!(
+      Image::from_srecord("S307FFFFFFFFAABB97"),
+      Err(LoadError::OutOfRange(0xFFFFFFFF))
+    );
+  }
+
+  #[test]
+  fn binary_out_of_range() {
+    assert_eq!(
+      Image::from_binary(&[0; 4], 0xFFFE),
+      Err(LoadError::OutOfRange(0x10001))
+    );
+  }
+
+  #[test]
+  fn load() {
+    let image = Image::from_binary(&[0xA9, 0x10], 0x0200).unwrap();
+    let mut memory = Memory::new();
+    let entry = image.load(&mut memory);
+    assert_eq!(entry, 0x0200);
+    assert_eq!(memory.get_u16(0x0200), 0xA9);
+    assert_eq!(memory.get_u16(0x0201), 0x10);
+  }
+
+  #[test]
+  fn to_binary_fills_gaps() {
+    let mut image = Image::from_binary(&[1, 2], 0x0200).unwrap();
+    image.add_segment(0x0204, vec![3]).unwrap();
+    assert_eq!(image.to_binary(), (0x0200, vec![1, 2, 0, 0, 3]));
+    assert_eq!(image.write(Format::Prg), vec![0x00, 0x02, 1, 2, 0, 0, 3]);
+  }
+
+  #[test]
+  fn intel_hex_output() {
+    let image = Image::from_binary(&[1, 2, 3], 0x8000).unwrap();
+    assert_eq!(
+      image.to_intel_hex(),
+      ":0380000001020377\n:040000050000800077\n:00000001FF\n"
+    );
+  }
+
+  #[test_case(Format::IntelHex; "Intel HEX")]
+  #[test_case(Format::SRecord; "S-record")]
+  fn round_trip(format: Format) {
+    let data: Vec<u8> = (0..40).map(|_| random()).collect();
+    let mut image = Image::from_binary(&data, 0x1234).unwrap();
+    image.add_segment(0xC000, vec![random()]).unwrap();
+    let parsed = Image::parse(&image.write(format), format, None).unwrap();
+    assert_eq!(parsed.to_binary(), image.to_binary());
+    assert_eq!(parsed.get_entry(), 0x1234);
+  }
+
+  #[test_case("HEX", Format::IntelHex)]
+  #[test_case("s19", Format::SRecord)]
+  #[test_case("prg", Format::Prg)]
+  #[test_case("bin", Format::Binary)]
+  fn from_extension(extension: &str, expected: Format) {
+    assert_eq!(Format::from_extension(extension), expected);
+  }
+}
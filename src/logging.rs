@@ -0,0 +1,68 @@
+//! Logging macros used throughout the emulator core.
+//!
+//! With the `logging` feature turned on these forward to the `log` crate.
+//! With it turned off they compile to nothing, so the per-access logging in
+//! the hot paths costs nothing at all. The arguments are still type checked
+//! either way, so code does not rot while the feature is off.
+
+#[cfg(feature = "logging")]
+macro_rules! error {
+  ($($arg:tt)*) => { log::error!($($arg)*) };
+}
+
+#[cfg(feature = "logging")]
+macro_rules! warn {
+  ($($arg:tt)*) => { log::warn!($($arg)*) };
+}
+
+#[cfg(feature = "logging")]
+macro_rules! debug {
+  ($($arg:tt)*) => { log::debug!($($arg)*) };
+}
+
+#[cfg(feature = "logging")]
+macro_rules! trace {
+  ($($arg:tt)*) => { log::trace!($($arg)*) };
+}
+
+/// Returns true if trace logging is turned on, for work that is only done to
+/// build a trace message.
+#[cfg(feature = "logging")]
+macro_rules! trace_enabled {
+  () => {
+    log::log_enabled!(log::Level::Trace)
+  };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! error {
+  ($($arg:tt)*) => { if false { $crate::logging::discard(format_args!($($arg)*)) } };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! warn {
+  ($($arg:tt)*) => { if false { $crate::logging::discard(format_args!($($arg)*)) } };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! debug {
+  ($($arg:tt)*) => { if false { $crate::logging::discard(format_args!($($arg)*)) } };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! trace {
+  ($($arg:tt)*) => { if false { $crate::logging::discard(format_args!($($arg)*)) } };
+}
+
+#[cfg(not(feature = "logging"))]
+macro_rules! trace_enabled {
+  () => {
+    false
+  };
+}
+
+/// Swallows a log message so its arguments are still type checked when
+/// logging is compiled out.
+#[cfg(not(feature = "logging"))]
+#[inline(always)]
+pub(crate) fn discard(_: core::fmt::Arguments) {}
@@ -0,0 +1,252 @@
+use crate::clock::FreeRunning;
+use crate::devices::serial::Serial;
+use crate::devices::{Device, DeviceId, Pia, Port, Rom};
+use crate::machines::MachineError;
+use crate::prelude::*;
+use crate::CPU;
+use core::any::Any;
+
+/// Size of the monitor ROM at the top of memory.
+pub const MONITOR_SIZE: usize = 0x100;
+/// Control register A, whose top bit is set while a key is waiting.
+const KBDCR: u16 = 1;
+/// Where the open bus starts, just above the bottom 4K of RAM.
+const OPEN_BUS_START: u16 = 0x1000;
+
+/// Addresses with nothing behind them. Nothing drives the data bus, so reads
+/// see the last byte the CPU put on it, which for absolute addressing is the
+/// high byte of the address. Writes are lost.
+#[derive(Clone)]
+struct OpenBus;
+
+impl Device for OpenBus {
+  fn name(&self) -> &str {
+    "open bus"
+  }
+
+  fn read(&mut self, register: u16) -> u8 {
+    self.peek(register)
+  }
+
+  /// Registers are offsets from `OPEN_BUS_START`, however the range is
+  /// mirrored.
+  fn peek(&self, register: u16) -> u8 {
+    (register.wrapping_add(OPEN_BUS_START) >> 8) as u8
+  }
+
+  fn write(&mut self, register: u16, value: u8) {
+    trace!(
+      "Ignoring write of {:X} to open bus at {:X}",
+      value,
+      register.wrapping_add(OPEN_BUS_START)
+    );
+  }
+
+  fn reset(&mut self) {}
+
+  fn box_clone(&self) -> Box<dyn Device> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+/// The Apple-1.
+///
+/// - 4K of RAM at 0x0000, and optionally another 4K at 0xE000 where Integer
+///   BASIC was loaded
+/// - a 6821 PIA at 0xD010: port A and CA1 take the keyboard, port B and CB2
+///   send characters to the display, and PB7 reads the display's busy line
+/// - a 256 byte monitor ROM, normally the Woz Monitor, at 0xFF00
+/// - nothing anywhere else: reads see the open bus and writes are lost
+///
+/// The keyboard and display are a serial line: bytes received are typed,
+/// with lowercase folded to uppercase and backspace mapped to the underscore
+/// WozMon uses to rub out, and characters the display shows are
+/// transmitted, with a CR at each end of line. The PIA's interrupt outputs
+/// are left unconnected, as on the real board.
+pub struct Apple1 {
+  cpu: CPU,
+  pia: DeviceId,
+  terminal: Box<dyn Serial>,
+}
+
+impl Apple1 {
+  /// Builds the machine around a 256 byte monitor ROM and points the CPU at
+  /// the reset vector in it. Fits the 4K of RAM at 0xE000 if `basic_ram` is
+  /// set.
+  pub fn new<S: Serial + 'static>(
+    monitor: Vec<u8>,
+    terminal: S,
+    basic_ram: bool,
+  ) -> Result<Apple1, MachineError> {
+    if monitor.len() != MONITOR_SIZE {
+      return Err(MachineError::RomSize {
+        expected: MONITOR_SIZE,
+        actual: monitor.len(),
+      });
+    }
+    let mut cpu = CPU::new(FreeRunning);
+    let mut pia = Pia::new();
+    // The display is never busy, so PB7 always reads low.
+    pia.get_ports_mut().set_input(Port::B, 0x00);
+    let pia = cpu.map_device(0xD010, 0xD013, Box::new(pia));
+    cpu.disconnect_irq(pia);
+    cpu.map_device(0xFF00, 0xFFFF, Box::new(Rom::new(monitor)));
+    let open_bus = cpu.map_device(OPEN_BUS_START, 0xDFFF, Box::new(OpenBus));
+    let (start, end) = if basic_ram { (0xF000, 0xFEFF) } else { (0xE000, 0xFEFF) };
+    cpu.mirror_device(open_bus, start, end, start - OPEN_BUS_START);
+    cpu.jump_to_reset_vector();
+    Ok(Apple1 {
+      cpu,
+      pia,
+      terminal: Box::new(terminal),
+    })
+  }
+
+  /// Gets the CPU.
+  pub fn get_cpu(&self) -> &CPU {
+    &self.cpu
+  }
+
+  /// Gets the CPU, to load programs or inspect memory.
+  pub fn get_cpu_mut(&mut self) -> &mut CPU {
+    &mut self.cpu
+  }
+
+  /// Gets the PIA.
+  pub fn get_pia(&self) -> &Pia {
+    self.cpu.get_memory().get_device(self.pia).unwrap()
+  }
+
+  /// Runs one instruction, then services the keyboard and display.
+  pub fn step(&mut self) {
+    self.cpu.step();
+    let pia: &mut Pia = self.cpu.get_memory_mut().get_device_mut(self.pia).unwrap();
+    if !pia.get_cb2() {
+      let character = pia.get_ports().get_output(Port::B) & 0x7F;
+      match character {
+        b'\r' => self.terminal.transmit(b'\r'),
+        0x20..=0x5F => self.terminal.transmit(character),
+        0x60..=0x7F => self.terminal.transmit(character - 0x20),
+        _ => trace!("Display ignoring {:X}", character),
+      }
+      pia.set_cb1(false);
+      pia.set_cb1(true);
+    }
+    if pia.peek(KBDCR) & 0x80 == 0 {
+      if let Some(key) = self.terminal.receive() {
+        let key = match key {
+          b'\n' => b'\r',
+          0x08 | 0x7F => b'_',
+          _ => key.to_ascii_uppercase() & 0x7F,
+        };
+        pia.get_ports_mut().set_input(Port::A, key | 0x80);
+        pia.set_ca1(false);
+        pia.set_ca1(true);
+      }
+    }
+  }
+
+  /// Runs for at least the given number of cycles. Stops early and returns
+  /// false if the CPU halts.
+  pub fn run(&mut self, cycles: u64) -> bool {
+    let end = self.cpu.get_cycles() + cycles;
+    while self.cpu.get_cycles() < end {
+      if self.cpu.is_halted() {
+        return false;
+      }
+      self.step();
+    }
+    true
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+  use crate::devices::serial::Buffer;
+  use test_case::test_case;
+
+  /// A monitor that sets up the PIA as WozMon does, interrupts included,
+  /// and echoes every key.
+  const ECHO: &str = "
+KBD = $D010
+KBDCR = $D011
+DSP = $D012
+DSPCR = $D013
+
+        .org $FF00
+reset:  CLD
+        CLI
+        LDY #$7F
+        STY DSP
+        LDA #$A7
+        STA KBDCR
+        STA DSPCR
+next:   LDA KBDCR
+        BPL next
+        LDA KBD
+        JSR echo
+        JMP next
+echo:   BIT DSP
+        BMI echo
+        STA DSP
+        RTS
+
+        .org $FFFA
+        .word $0000, reset, $0000
+";
+
+  fn monitor(source: &str) -> Vec<u8> {
+    let assembly = assemble(source, "monitor.s", 0xFF00).unwrap();
+    let mut monitor = vec![0; MONITOR_SIZE];
+    for segment in assembly.image.get_segments() {
+      let start = segment.address as usize - 0xFF00;
+      monitor[start..start + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    monitor
+  }
+
+  #[test]
+  fn rejects_wrong_monitor_size() {
+    let error = Apple1::new(vec![0; 0x1000], Buffer::new(), false).err();
+    assert_eq!(
+      error,
+      Some(MachineError::RomSize {
+        expected: MONITOR_SIZE,
+        actual: 0x1000
+      })
+    );
+  }
+
+  #[test]
+  fn echoes_keys() {
+    let terminal = Buffer::new();
+    let mut machine = Apple1::new(monitor(ECHO), terminal.clone(), false).unwrap();
+    assert_eq!(machine.get_cpu().get_registers().program_counter, 0xFF00);
+    terminal.push_input(b"hello\x08\n");
+    assert!(machine.run(5_000));
+    assert_eq!(terminal.take_output(), b"HELLO_\r");
+  }
+
+  #[test_case(false, 0xE000, 0xE0; "Without BASIC RAM")]
+  #[test_case(true, 0xE000, 0x55; "With BASIC RAM")]
+  #[test_case(false, 0x0FFF, 0x55; "Bottom RAM")]
+  #[test_case(false, 0x1000, 0x10; "Above bottom RAM")]
+  #[test_case(true, 0xF000, 0xF0; "Above BASIC RAM")]
+  #[test_case(true, 0xFF00, 0xD8; "Monitor ROM")]
+  fn memory_map(basic_ram: bool, address: u16, expected: u8) {
+    let mut machine = Apple1::new(monitor(ECHO), Buffer::new(), basic_ram).unwrap();
+    let memory = machine.get_cpu_mut().get_memory_mut();
+    memory.poke(address, 0x55);
+    assert_eq!(memory.peek(address), expected);
+  }
+}
@@ -0,0 +1,283 @@
+use crate::clock::FreeRunning;
+use crate::devices::{DeviceId, Hd44780, Peripheral, Port, Rom, Via};
+use crate::machines::MachineError;
+use crate::prelude::*;
+use crate::CPU;
+use core::any::Any;
+
+/// Size of the ROM, which fills the top half of the address space.
+pub const ROM_SIZE: usize = 0x8000;
+/// Port A line wired to the LCD's E pin.
+const LCD_E: u8 = 0x80;
+/// Port A line wired to the LCD's RW pin.
+const LCD_RW: u8 = 0x40;
+/// Port A line wired to the LCD's RS pin.
+const LCD_RS: u8 = 0x20;
+
+/// The 6502 breadboard computer from Ben Eater's video series.
+///
+/// - RAM from 0x0000, filling the bottom half of the address space around
+///   the VIA
+/// - a 6522 VIA at 0x6000, mirrored up to 0x7FFF
+/// - a 32K ROM at 0x8000
+/// - a 16x2 HD44780 LCD with its data bus on port B, and E, RW and RS on
+///   PA7, PA6 and PA5
+///
+/// The VIA's IRQ output is wired to the CPU, so programs can use its timers
+/// and handshake lines as interrupt sources.
+pub struct BenEater {
+  cpu: CPU,
+  via: DeviceId,
+}
+
+impl BenEater {
+  /// Builds the machine around a 32K ROM image and points the CPU at the
+  /// reset vector in it.
+  pub fn new(rom: Vec<u8>) -> Result<BenEater, MachineError> {
+    if rom.len() != ROM_SIZE {
+      return Err(MachineError::RomSize {
+        expected: ROM_SIZE,
+        actual: rom.len(),
+      });
+    }
+    let mut cpu = CPU::new(FreeRunning);
+    let mut via = Via::new();
+    via.set_peripheral(Box::new(LcdWiring::new()));
+    let via = cpu.map_device(0x6000, 0x7FFF, Box::new(via));
+    cpu.map_device(0x8000, 0xFFFF, Box::new(Rom::new(rom)));
+    cpu.jump_to_reset_vector();
+    Ok(BenEater { cpu, via })
+  }
+
+  /// Gets the CPU.
+  pub fn get_cpu(&self) -> &CPU {
+    &self.cpu
+  }
+
+  /// Gets the CPU, to step it or inspect memory.
+  pub fn get_cpu_mut(&mut self) -> &mut CPU {
+    &mut self.cpu
+  }
+
+  /// Gets the VIA.
+  pub fn get_via(&self) -> &Via {
+    self.cpu.get_memory().get_device(self.via).unwrap()
+  }
+
+  /// Gets the LCD controller.
+  pub fn get_lcd(&self) -> &Hd44780 {
+    let wiring: &LcdWiring = self.get_via().get_ports().get_peripheral().unwrap();
+    &wiring.lcd
+  }
+
+  /// Renders the LCD as text framed in a box.
+  pub fn render_lcd(&self) -> String {
+    let text = self.get_lcd().render();
+    let width = text.lines().next().map_or(0, |l| l.chars().count());
+    let border = format!("+{}+", "-".repeat(width));
+    let mut lines = vec![border.clone()];
+    lines.extend(text.lines().map(|line| format!("|{}|", line)));
+    lines.push(border);
+    lines.join("\n")
+  }
+
+  /// Runs for at least the given number of cycles. Stops early and returns
+  /// false if the CPU halts.
+  pub fn run(&mut self, cycles: u64) -> bool {
+    let end = self.cpu.get_cycles() + cycles;
+    while self.cpu.get_cycles() < end {
+      if self.cpu.is_halted() {
+        return false;
+      }
+      self.cpu.step();
+    }
+    true
+  }
+}
+
+/// Connects the LCD to the VIA's ports: port B to the data bus and the top
+/// three lines of port A to the control pins.
+#[derive(Clone)]
+struct LcdWiring {
+  lcd: Hd44780,
+  control: u8,
+  data: u8,
+}
+
+impl LcdWiring {
+  fn new() -> LcdWiring {
+    LcdWiring {
+      lcd: Hd44780::new(16, 2),
+      control: 0,
+      data: 0,
+    }
+  }
+}
+
+impl Peripheral for LcdWiring {
+  fn output(&mut self, port: Port, value: u8, driven: u8) {
+    match port {
+      Port::A => self.control = value & driven,
+      Port::B => self.data = value & driven,
+    }
+    self.lcd.set_pins(
+      self.control & LCD_RS != 0,
+      self.control & LCD_RW != 0,
+      self.control & LCD_E != 0,
+      self.data,
+    );
+  }
+
+  fn input(&self, port: Port) -> u8 {
+    match port {
+      Port::A => 0xFF,
+      Port::B => self.lcd.get_output().unwrap_or(0xFF),
+    }
+  }
+
+  fn box_clone(&self) -> Box<dyn Peripheral> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+
+  /// The "hello world" program from the series, polling the busy flag
+  /// before each transfer.
+  const HELLO: &str = "
+PORTB = $6000
+PORTA = $6001
+DDRB = $6002
+DDRA = $6003
+
+E  = %10000000
+RW = %01000000
+RS = %00100000
+
+        .org $8000
+reset:  LDX #$FF
+        TXS
+        LDA #%11111111
+        STA DDRB
+        LDA #%11100000
+        STA DDRA
+
+        LDA #%00111000
+        JSR lcd_instruction
+        LDA #%00001110
+        JSR lcd_instruction
+        LDA #%00000110
+        JSR lcd_instruction
+        LDA #%00000001
+        JSR lcd_instruction
+
+        LDX #0
+print:  LDA message,X
+        BEQ loop
+        JSR print_char
+        INX
+        JMP print
+
+loop:   JMP loop
+
+message: .byte \"Hello, world!\", 0
+
+lcd_wait:
+        PHA
+        LDA #%00000000
+        STA DDRB
+lcdbusy:
+        LDA #RW
+        STA PORTA
+        LDA #RW + E
+        STA PORTA
+        LDA PORTB
+        AND #%10000000
+        BNE lcdbusy
+        LDA #RW
+        STA PORTA
+        LDA #%11111111
+        STA DDRB
+        PLA
+        RTS
+
+lcd_instruction:
+        JSR lcd_wait
+        STA PORTB
+        LDA #0
+        STA PORTA
+        LDA #E
+        STA PORTA
+        LDA #0
+        STA PORTA
+        RTS
+
+print_char:
+        JSR lcd_wait
+        STA PORTB
+        LDA #RS
+        STA PORTA
+        LDA #RS + E
+        STA PORTA
+        LDA #RS
+        STA PORTA
+        RTS
+
+        .org $FFFC
+        .word reset
+        .word $0000
+";
+
+  fn rom(source: &str) -> Vec<u8> {
+    let assembly = assemble(source, "rom.s", 0x8000).unwrap();
+    let mut rom = vec![0xEA; ROM_SIZE];
+    for segment in assembly.image.get_segments() {
+      let start = segment.address as usize - 0x8000;
+      rom[start..start + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    rom
+  }
+
+  #[test]
+  fn rejects_wrong_rom_size() {
+    let error = BenEater::new(vec![0; 0x2000]).err();
+    assert_eq!(
+      error,
+      Some(MachineError::RomSize {
+        expected: ROM_SIZE,
+        actual: 0x2000
+      })
+    );
+  }
+
+  #[test]
+  fn hello_world() {
+    let mut machine = BenEater::new(rom(HELLO)).unwrap();
+    assert!(machine.run(20_000));
+    assert_eq!(
+      machine.render_lcd(),
+      "+----------------+\n|Hello, world!   |\n|                |\n+----------------+"
+    );
+  }
+
+  #[test]
+  fn stops_when_halted() {
+    let mut machine = BenEater::new(rom(
+      ".org $8000\nreset: NOP\n.byte $02\n.org $FFFC\n.word reset",
+    ))
+    .unwrap();
+    assert!(!machine.run(1_000));
+    assert_eq!(machine.get_cpu().get_registers().program_counter, 0x8001);
+  }
+}
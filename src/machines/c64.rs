@@ -0,0 +1,389 @@
+use crate::clock::FreeRunning;
+use crate::devices::serial::Serial;
+use crate::devices::{Cia, Device, DeviceId, Sid, VicII};
+use crate::machines::MachineError;
+use crate::mappers::Mapper;
+use crate::prelude::*;
+use crate::CPU;
+
+/// Size of the BASIC ROM at 0xA000.
+pub const BASIC_SIZE: usize = 0x2000;
+/// Size of the KERNAL ROM at 0xE000.
+pub const KERNAL_SIZE: usize = 0x2000;
+/// Size of the character ROM at 0xD000.
+pub const CHARACTERS_SIZE: usize = 0x1000;
+/// The PAL system clock, in Hz.
+const CLOCK_RATE: u64 = 985_248;
+/// The mains frequency feeding the CIAs' time of day clocks on a PAL
+/// machine, in Hz.
+const MAINS_FREQUENCY: u64 = 50;
+/// The KERNAL's CHROUT entry in its jump table.
+const CHROUT: u16 = 0xFFD2;
+/// 6510 port line that banks in BASIC.
+const LORAM: u8 = 0x01;
+/// 6510 port line that banks in the KERNAL.
+const HIRAM: u8 = 0x02;
+/// 6510 port line that picks I/O over the character ROM.
+const CHAREN: u8 = 0x04;
+/// 6510 port lines pulled high while they're inputs: the three banking
+/// lines and the cassette switch sense.
+const PORT_PULL_UPS: u8 = 0x17;
+
+/// The 6510's on chip I/O port and the PLA that banks memory from its
+/// lines, with no cartridge fitted.
+///
+/// - 0xA000-0xBFFF: BASIC with LORAM and HIRAM set, otherwise RAM
+/// - 0xD000-0xDFFF: RAM with LORAM and HIRAM clear, otherwise I/O with
+///   CHAREN set and the character ROM with it clear
+/// - 0xE000-0xFFFF: the KERNAL with HIRAM set, otherwise RAM
+///
+/// Writes to ROM land in the RAM beneath it. The RAM beneath I/O is held
+/// here, as the flat memory at 0xD000 holds the colour RAM and the rest of
+/// I/O goes to devices.
+#[derive(Clone)]
+struct Pla {
+  basic: Vec<u8>,
+  kernal: Vec<u8>,
+  characters: Vec<u8>,
+  ram: Vec<u8>,
+  direction: u8,
+  output: u8,
+}
+
+impl Pla {
+  fn new(basic: Vec<u8>, kernal: Vec<u8>, characters: Vec<u8>) -> Pla {
+    Pla {
+      basic,
+      kernal,
+      characters,
+      ram: vec![0; 0x1000],
+      direction: 0,
+      output: 0,
+    }
+  }
+
+  /// The levels on the port's lines.
+  fn lines(&self) -> u8 {
+    (self.output & self.direction) | (PORT_PULL_UPS & !self.direction)
+  }
+
+  fn is_io_ram(&self) -> bool {
+    self.lines() & (LORAM | HIRAM) == 0
+  }
+}
+
+impl Mapper for Pla {
+  fn name(&self) -> &str {
+    "C64 PLA"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    let lines = self.lines();
+    match index {
+      0x0000 => Some(self.direction),
+      0x0001 => Some(lines),
+      0xA000..=0xBFFF if lines & (LORAM | HIRAM) == (LORAM | HIRAM) => {
+        Some(self.basic[index as usize - 0xA000])
+      }
+      0xD000..=0xDFFF if self.is_io_ram() => Some(self.ram[index as usize - 0xD000]),
+      0xD000..=0xDFFF if lines & CHAREN == 0 => Some(self.characters[index as usize - 0xD000]),
+      0xE000..=0xFFFF if lines & HIRAM != 0 => Some(self.kernal[index as usize - 0xE000]),
+      _ => None,
+    }
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    match index {
+      0x0000 => self.direction = value,
+      0x0001 => self.output = value,
+      0xD000..=0xDFFF if self.is_io_ram() || self.lines() & CHAREN == 0 => {
+        self.ram[index as usize - 0xD000] = value
+      }
+      _ => return false,
+    }
+    true
+  }
+
+  fn reset(&mut self) {
+    self.direction = 0;
+    self.output = 0;
+  }
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(self.clone())
+  }
+}
+
+/// The CPU side of a PAL Commodore 64, for running machine language
+/// programs without a screen.
+///
+/// - a 6510, whose port at 0x0000 and 0x0001 banks BASIC, the KERNAL, the
+///   character ROM and I/O in and out of 64K of RAM as the PLA does
+/// - a VIC-II register file at 0xD000 with a running raster counter, wired
+///   to IRQ
+/// - a SID register file at 0xD400
+/// - colour RAM at 0xD800
+/// - CIA 1 at 0xDC00, wired to IRQ, and CIA 2 at 0xDD00, wired to NMI
+///
+/// Nothing is drawn, played or read from a keyboard. Instead calls to the
+/// KERNAL's CHROUT are caught while the KERNAL is banked in, and each
+/// printable character, and the carriage return, is sent to a serial line.
+pub struct C64 {
+  cpu: CPU,
+  vic: DeviceId,
+  cia1: DeviceId,
+  cia2: DeviceId,
+  terminal: Box<dyn Serial>,
+  nmi: bool,
+}
+
+impl C64 {
+  /// Builds the machine around images of the BASIC, KERNAL and character
+  /// ROMs, and points the CPU at the KERNAL's reset vector.
+  pub fn new<S: Serial + 'static>(
+    basic: Vec<u8>,
+    kernal: Vec<u8>,
+    characters: Vec<u8>,
+    terminal: S,
+  ) -> Result<C64, MachineError> {
+    for (rom, expected) in [
+      (&basic, BASIC_SIZE),
+      (&kernal, KERNAL_SIZE),
+      (&characters, CHARACTERS_SIZE),
+    ] {
+      if rom.len() != expected {
+        return Err(MachineError::RomSize {
+          expected,
+          actual: rom.len(),
+        });
+      }
+    }
+    let mut cpu = CPU::new(FreeRunning);
+    cpu.set_mapper(Box::new(Pla::new(basic, kernal, characters)));
+    let vic = cpu.map_device(0xD000, 0xD3FF, Box::new(VicII::new()));
+    cpu.map_device(0xD400, 0xD7FF, Box::new(Sid::new()));
+    let cia1 = cpu.map_device(0xDC00, 0xDCFF, Box::new(pal_cia()));
+    let cia2 = cpu.map_device(0xDD00, 0xDDFF, Box::new(pal_cia()));
+    cpu.disconnect_irq(cia2);
+    cpu.jump_to_reset_vector();
+    Ok(C64 {
+      cpu,
+      vic,
+      cia1,
+      cia2,
+      terminal: Box::new(terminal),
+      nmi: false,
+    })
+  }
+
+  /// Gets the CPU.
+  pub fn get_cpu(&self) -> &CPU {
+    &self.cpu
+  }
+
+  /// Gets the CPU, to load programs or inspect memory.
+  pub fn get_cpu_mut(&mut self) -> &mut CPU {
+    &mut self.cpu
+  }
+
+  /// Gets the VIC-II.
+  pub fn get_vic(&self) -> &VicII {
+    self.cpu.get_memory().get_device(self.vic).unwrap()
+  }
+
+  /// Gets CIA 1, which scans the keyboard and joysticks.
+  pub fn get_cia1(&self) -> &Cia {
+    self.cpu.get_memory().get_device(self.cia1).unwrap()
+  }
+
+  /// Gets CIA 2, which drives the serial bus and the user port.
+  pub fn get_cia2(&self) -> &Cia {
+    self.cpu.get_memory().get_device(self.cia2).unwrap()
+  }
+
+  /// Runs one instruction, or one call to CHROUT.
+  pub fn step(&mut self) {
+    match self.is_at_chrout() {
+      true => self.chrout(),
+      false => self.cpu.step(),
+    }
+    let nmi = self.get_cia2().irq();
+    if nmi && !self.nmi {
+      self.cpu.set_nmi();
+    }
+    self.nmi = nmi;
+  }
+
+  /// Runs for at least the given number of cycles. Stops early and returns
+  /// false if the CPU halts.
+  pub fn run(&mut self, cycles: u64) -> bool {
+    let end = self.cpu.get_cycles() + cycles;
+    while self.cpu.get_cycles() < end {
+      if !self.is_at_chrout() && self.cpu.is_halted() {
+        return false;
+      }
+      self.step();
+    }
+    true
+  }
+
+  /// Returns true if the next instruction is the KERNAL's CHROUT.
+  fn is_at_chrout(&self) -> bool {
+    let registers = self.cpu.get_registers();
+    registers.program_counter == CHROUT && self.cpu.get_memory().peek(0x0001) & HIRAM != 0
+  }
+
+  /// Sends the character in the accumulator to the terminal and returns
+  /// with carry clear, as CHROUT does on success.
+  fn chrout(&mut self) {
+    let character = self.cpu.get_registers().accumulator;
+    match character {
+      0x0D | 0x20..=0x5F => self.terminal.transmit(character),
+      _ => trace!("CHROUT ignoring {:X}", character),
+    }
+    self.cpu.rts();
+    let mut registers = self.cpu.get_registers();
+    registers.status &= !0x01;
+    self.cpu.set_registers(registers);
+  }
+}
+
+/// A CIA clocked as on a PAL machine.
+fn pal_cia() -> Cia {
+  let mut cia = Cia::new();
+  cia.set_clock_rate(CLOCK_RATE);
+  cia.set_mains_frequency(MAINS_FREQUENCY);
+  cia
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+  use crate::devices::serial::Buffer;
+  use test_case::test_case;
+
+  /// Builds the three ROMs, with a KERNAL assembled from a program that
+  /// starts at `reset` and the rest of each ROM filled with a marker. The
+  /// KERNAL jump table is left as KIL opcodes.
+  fn roms(program: &str) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let source = format!(
+      "        .org $E100\n{}\n        .org $FFFA\n        .word nmi, reset, irq\n",
+      program
+    );
+    let assembly = assemble(&source, "kernal.s", 0xE000).unwrap();
+    let mut kernal = vec![0x02; KERNAL_SIZE];
+    kernal[0] = 0xEE;
+    for segment in assembly.image.get_segments() {
+      let start = segment.address as usize - 0xE000;
+      kernal[start..start + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    (vec![0xBA; BASIC_SIZE], kernal, vec![0xCC; CHARACTERS_SIZE])
+  }
+
+  fn c64(program: &str, terminal: Buffer) -> C64 {
+    let (basic, kernal, characters) = roms(program);
+    C64::new(basic, kernal, characters, terminal).unwrap()
+  }
+
+  const IDLE: &str = "
+reset:  JMP reset
+nmi:
+irq:    RTI";
+
+  #[test]
+  fn rejects_wrong_rom_size() {
+    let (basic, kernal, _) = roms(IDLE);
+    let error = C64::new(basic, kernal, vec![0; 0x800], Buffer::new()).err();
+    assert_eq!(
+      error,
+      Some(MachineError::RomSize {
+        expected: CHARACTERS_SIZE,
+        actual: 0x800
+      })
+    );
+  }
+
+  #[test_case(0x07, 0xBA, 0x00, 0xEE; "Standard")]
+  #[test_case(0x06, 0x11, 0x00, 0xEE; "BASIC out")]
+  #[test_case(0x03, 0xBA, 0xCC, 0xEE; "Character ROM")]
+  #[test_case(0x05, 0x11, 0x00, 0x11; "KERNAL out")]
+  #[test_case(0x01, 0x11, 0xCC, 0x11; "Character ROM only")]
+  #[test_case(0x00, 0x11, 0x11, 0x11; "All RAM")]
+  fn banking(lines: u8, basic: u8, io: u8, kernal: u8) {
+    let mut machine = c64(IDLE, Buffer::new());
+    let memory = machine.get_cpu_mut().get_memory_mut();
+    assert_eq!(memory.peek(0x0001), PORT_PULL_UPS);
+    memory.set(0x0000, 0x07);
+    memory.set(0x0001, 0x00);
+    for address in [0xA000, 0xD000, 0xE000] {
+      memory.set(address, 0x11);
+    }
+    memory.set(0x0001, lines);
+    assert_eq!(memory.peek(0xA000), basic);
+    assert_eq!(memory.peek(0xD000), io);
+    assert_eq!(memory.peek(0xE000), kernal);
+  }
+
+  #[test]
+  fn writes_under_rom_reach_ram() {
+    let mut machine = c64(IDLE, Buffer::new());
+    let memory = machine.get_cpu_mut().get_memory_mut();
+    memory.set(0xA000, 0x42);
+    assert_eq!(memory.peek(0xA000), 0xBA);
+    memory.set(0x0000, 0x07);
+    memory.set(0x0001, 0x06);
+    assert_eq!(memory.peek(0xA000), 0x42);
+  }
+
+  #[test]
+  fn chrout() {
+    let program = "
+reset:  LDX #0
+loop:   LDA message,X
+        BEQ done
+        JSR $FFD2
+        BCS done
+        INX
+        BNE loop
+done:   .byte $02
+message: .byte \"HELLO, WORLD!\", 13, $93, 0
+nmi:
+irq:    RTI";
+    let terminal = Buffer::new();
+    let mut machine = c64(program, terminal.clone());
+    assert!(!machine.run(10_000));
+    assert_eq!(terminal.take_output(), b"HELLO, WORLD!\r");
+  }
+
+  #[test_case("DC"; "CIA 1 on IRQ")]
+  #[test_case("DD"; "CIA 2 on NMI")]
+  fn cia_interrupts(cia: &str) {
+    let program = "
+reset:  LDA #$81
+        STA $CC0D
+        LDA #$40
+        STA $CC04
+        LDA #$00
+        STA $CC05
+        LDA #$01
+        STA $CC0E
+        CLI
+loop:   JMP loop
+nmi:
+irq:    INC $02
+        LDA $CC0D
+        RTI";
+    let mut machine = c64(&program.replace("CC", cia), Buffer::new());
+    machine.run(0x41 * 10);
+    assert_eq!(machine.get_cpu().get_memory().peek(0x02), 9);
+  }
+
+  #[test]
+  fn raster_runs() {
+    let mut machine = c64(IDLE, Buffer::new());
+    machine.run(63 * 100);
+    assert_eq!(machine.get_vic().get_raster(), 100);
+    assert_eq!(machine.get_cpu().get_memory().peek(0xD012), 100);
+  }
+}
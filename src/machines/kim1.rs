@@ -0,0 +1,681 @@
+use crate::clock::FreeRunning;
+use crate::devices::serial::Serial;
+use crate::devices::{DeviceId, Peripheral, Port, Rriot};
+use crate::machines::MachineError;
+use crate::prelude::*;
+use crate::CPU;
+use core::any::Any;
+
+/// Size of the ROM image, the two 6530 ROMs from 0x1800 to 0x1FFF.
+pub const ROM_SIZE: usize = 0x800;
+/// Size of each 6530's ROM.
+const RRIOT_ROM_SIZE: usize = 0x400;
+/// Cycles per bit on the TTY line, at 1200 baud with the 1 MHz clock. The
+/// monitor measures the rate from the RUBOUT it is sent at reset.
+const BIT_CYCLES: u64 = 1_000_000 / 1200;
+/// Bits in a TTY frame: a start bit, 8 data bits and 2 stop bits.
+const FRAME_BITS: u64 = 11;
+/// Cycles a key is held down for, and then left up for, when typed.
+const KEY_CYCLES: u64 = 50_000;
+/// The decoder output that reads the TTY jumper on PA0.
+const TTY_ROW: u8 = 3;
+/// The decoder output that lights the first LED digit.
+const FIRST_DIGIT: u8 = 4;
+
+/// Something typed on the host, as it reaches the KIM-1's keypad.
+enum Input {
+  /// A key in the matrix, numbered by row and then column: 0-F, then AD,
+  /// DA, +, GO and PC.
+  Key(u8),
+  /// The ST key, wired to NMI.
+  Stop,
+  /// The RS key, wired to RESET.
+  Reset,
+}
+
+/// Maps a byte from the host to the keypad.
+fn keypad_input(byte: u8) -> Option<Input> {
+  match byte.to_ascii_lowercase() {
+    digit @ b'0'..=b'9' => Some(Input::Key(digit - b'0')),
+    digit @ b'a'..=b'f' => Some(Input::Key(digit - b'a' + 0x0A)),
+    b'@' => Some(Input::Key(0x10)),
+    b'=' => Some(Input::Key(0x11)),
+    b'+' => Some(Input::Key(0x12)),
+    b'g' => Some(Input::Key(0x13)),
+    b'p' => Some(Input::Key(0x14)),
+    b's' => Some(Input::Stop),
+    b'r' => Some(Input::Reset),
+    _ => None,
+  }
+}
+
+/// The keypad, LED display and TTY interface, wired to the system 6530's
+/// ports through a 74145 decoder on PB1-PB4.
+#[derive(Clone)]
+struct Panel {
+  tty: bool,
+  key: Option<u8>,
+  serial_in: bool,
+  serial_out: bool,
+  select: u8,
+  segments: u8,
+  lit: u8,
+  digits: [u8; 6],
+}
+
+impl Panel {
+  fn new(tty: bool) -> Panel {
+    Panel {
+      tty,
+      key: None,
+      serial_in: true,
+      serial_out: true,
+      select: 0x0F,
+      segments: 0,
+      lit: 0,
+      digits: [0; 6],
+    }
+  }
+
+  /// The LED digit the decoder is lighting, if any.
+  fn digit(&self) -> Option<usize> {
+    match self.select {
+      FIRST_DIGIT..=9 => Some((self.select - FIRST_DIGIT) as usize),
+      _ => None,
+    }
+  }
+}
+
+impl Peripheral for Panel {
+  /// Tracks the segments driven while each digit is selected. The monitor
+  /// blanks the segments before moving on to the next digit, so a digit
+  /// shows every segment lit while it was selected.
+  fn output(&mut self, port: Port, value: u8, driven: u8) {
+    match port {
+      Port::A => self.segments = value & driven & 0x7F,
+      Port::B => {
+        self.serial_out = value & 0x01 != 0 || driven & 0x01 == 0;
+        let select = ((value & driven) >> 1) & 0x0F;
+        if select != self.select {
+          if let Some(digit) = self.digit() {
+            self.digits[digit] = self.lit;
+          }
+          self.select = select;
+          self.lit = 0;
+        }
+      }
+    }
+    if self.digit().is_some() {
+      self.lit |= self.segments;
+    }
+  }
+
+  fn input(&self, port: Port) -> u8 {
+    if port == Port::B {
+      return 0xFF;
+    }
+    let mut columns = 0x7F;
+    if let Some(key) = self.key {
+      if key / 7 == self.select {
+        columns &= !(1 << (key % 7));
+      }
+    }
+    if self.tty && self.select == TTY_ROW {
+      columns &= !0x01;
+    }
+    columns | ((self.serial_in as u8) << 7)
+  }
+
+  fn box_clone(&self) -> Box<dyn Peripheral> {
+    Box::new(self.clone())
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+/// Frames bytes from the host onto PA7.
+struct SerialIn {
+  start: u64,
+  frame: u16,
+  busy_until: u64,
+}
+
+impl SerialIn {
+  fn new() -> SerialIn {
+    SerialIn {
+      start: 0,
+      frame: 0x7FF,
+      busy_until: 0,
+    }
+  }
+
+  /// Starts sending a byte, and holds off the next one for a frame after it
+  /// so the monitor has time to echo it.
+  fn send(&mut self, now: u64, byte: u8) {
+    self.start = now;
+    self.frame = 0x600 | ((byte as u16) << 1);
+    self.busy_until = now + 2 * FRAME_BITS * BIT_CYCLES;
+  }
+
+  /// The level on the line at a cycle.
+  fn level(&self, now: u64) -> bool {
+    let bit = (now - self.start) / BIT_CYCLES;
+    bit >= FRAME_BITS || self.frame & (1 << bit) != 0
+  }
+}
+
+/// Decodes the frames the monitor bit bangs on PB0.
+struct SerialOut {
+  level: bool,
+  last_edge: u64,
+  start: Option<u64>,
+  count: u64,
+  byte: u8,
+}
+
+impl SerialOut {
+  fn new() -> SerialOut {
+    SerialOut {
+      level: true,
+      last_edge: 0,
+      start: None,
+      count: 0,
+      byte: 0,
+    }
+  }
+
+  /// Samples the line, returning a byte once its last data bit is in.
+  fn sample(&mut self, now: u64, level: bool) -> Option<u8> {
+    if level != self.level {
+      self.level = level;
+      self.last_edge = now;
+      if !level && self.start.is_none() {
+        self.start = Some(now);
+        self.count = 0;
+        self.byte = 0;
+      }
+    }
+    let start = self.start?;
+    while self.count < 8 && now >= start + (3 * BIT_CYCLES) / 2 + self.count * BIT_CYCLES {
+      self.byte |= (level as u8) << self.count;
+      self.count += 1;
+    }
+    if self.count == 8 && now >= start + (19 * BIT_CYCLES) / 2 {
+      self.start = None;
+      return Some(self.byte);
+    }
+    None
+  }
+
+  /// Returns true if the line has been quiet for a few bits.
+  fn is_quiet(&self, now: u64) -> bool {
+    self.start.is_none() && now >= self.last_edge + 4 * BIT_CYCLES
+  }
+}
+
+/// The MOS KIM-1.
+///
+/// - 1K of RAM from 0x0000, with the rest of memory free for expansion
+/// - the application 6530-003 with I/O at 0x1700, RAM at 0x1780 and the
+///   tape routines in ROM at 0x1800
+/// - the system 6530-002 with I/O at 0x1740, RAM at 0x17C0 and the monitor
+///   in ROM at 0x1C00, mirrored at 0xFC00 for the vectors as the KIM-1 does
+///   not decode the top address lines
+/// - the six digit LED display and the 23 key keypad, scanned by the
+///   monitor through the system 6530's ports
+/// - the TTY interface, bit banged on PA7 and PB0 at 1200 baud
+///
+/// The host is connected through a serial line. With the TTY jumper fitted
+/// it is the teletype: bytes are typed with lowercase folded to uppercase,
+/// and a RUBOUT is sent first for the monitor to measure the baud rate
+/// from. Otherwise bytes press keys: 0-9 and A-F, `@` for AD, `=` for DA,
+/// `+`, `G` for GO, `P` for PC, `S` for ST and `R` for RS. The 6530s'
+/// interrupt outputs are left unconnected.
+pub struct Kim1 {
+  cpu: CPU,
+  system: DeviceId,
+  terminal: Box<dyn Serial>,
+  tty: bool,
+  pending: Option<u8>,
+  serial_in: SerialIn,
+  serial_out: SerialOut,
+  key_release: Option<u64>,
+  next_key: u64,
+}
+
+impl Kim1 {
+  /// Builds the machine around a 2K image of the two 6530 ROMs, 003 then
+  /// 002, and points the CPU at the reset vector. With `tty` set the TTY
+  /// jumper is fitted and the monitor talks over the serial line rather
+  /// than the keypad and display.
+  pub fn new<S: Serial + 'static>(
+    rom: Vec<u8>,
+    terminal: S,
+    tty: bool,
+  ) -> Result<Kim1, MachineError> {
+    if rom.len() != ROM_SIZE {
+      return Err(MachineError::RomSize {
+        expected: ROM_SIZE,
+        actual: rom.len(),
+      });
+    }
+    let mut cpu = CPU::new(FreeRunning);
+    let application = Rriot::new(rom[..RRIOT_ROM_SIZE].to_vec());
+    let application = cpu.map_device(0x1700, 0x173F, Box::new(application));
+    cpu.mirror_device(application, 0x1780, 0x17BF, 0x040);
+    cpu.mirror_device(application, 0x1800, 0x1BFF, 0x400);
+    cpu.disconnect_irq(application);
+    let mut system = Rriot::new(rom[RRIOT_ROM_SIZE..].to_vec());
+    system.set_peripheral(Box::new(Panel::new(tty)));
+    let system = cpu.map_device(0x1740, 0x177F, Box::new(system));
+    cpu.mirror_device(system, 0x17C0, 0x17FF, 0x040);
+    cpu.mirror_device(system, 0x1C00, 0x1FFF, 0x400);
+    cpu.mirror_device(system, 0xFC00, 0xFFFF, 0x400);
+    cpu.disconnect_irq(system);
+    cpu.jump_to_reset_vector();
+    Ok(Kim1 {
+      cpu,
+      system,
+      terminal: Box::new(terminal),
+      tty,
+      pending: if tty { Some(0x7F) } else { None },
+      serial_in: SerialIn::new(),
+      serial_out: SerialOut::new(),
+      key_release: None,
+      next_key: 0,
+    })
+  }
+
+  /// Gets the CPU.
+  pub fn get_cpu(&self) -> &CPU {
+    &self.cpu
+  }
+
+  /// Gets the CPU, to load programs or inspect memory.
+  pub fn get_cpu_mut(&mut self) -> &mut CPU {
+    &mut self.cpu
+  }
+
+  /// Gets the segments last lit on each LED digit, segment a in bit 0
+  /// through segment g in bit 6.
+  pub fn get_display(&self) -> [u8; 6] {
+    self.panel().digits
+  }
+
+  /// Renders the LED display as text, with a space between the address and
+  /// data digits. Patterns that aren't a letter or digit render as `?`.
+  pub fn render_display(&self) -> String {
+    let digits: Vec<char> = self.get_display().iter().map(|d| to_char(*d)).collect();
+    format!(
+      "{} {}",
+      digits[..4].iter().collect::<String>(),
+      digits[4..].iter().collect::<String>()
+    )
+  }
+
+  /// Runs one instruction, then services the keypad or the TTY.
+  pub fn step(&mut self) {
+    self.cpu.step();
+    let now = self.cpu.get_cycles();
+    match self.tty {
+      true => self.service_tty(now),
+      false => self.service_keypad(now),
+    }
+  }
+
+  /// Runs for at least the given number of cycles. Stops early and returns
+  /// false if the CPU halts.
+  pub fn run(&mut self, cycles: u64) -> bool {
+    let end = self.cpu.get_cycles() + cycles;
+    while self.cpu.get_cycles() < end {
+      if self.cpu.is_halted() {
+        return false;
+      }
+      self.step();
+    }
+    true
+  }
+
+  fn panel(&self) -> &Panel {
+    let system: &Rriot = self.cpu.get_memory().get_device(self.system).unwrap();
+    system.get_ports().get_peripheral().unwrap()
+  }
+
+  fn panel_mut(&mut self) -> &mut Panel {
+    let memory = self.cpu.get_memory_mut();
+    let system: &mut Rriot = memory.get_device_mut(self.system).unwrap();
+    system.get_ports_mut().get_peripheral_mut().unwrap()
+  }
+
+  /// Holds each typed key down and then up for long enough for the monitor
+  /// to debounce it.
+  fn service_keypad(&mut self, now: u64) {
+    if let Some(release) = self.key_release {
+      if now >= release {
+        self.panel_mut().key = None;
+        self.key_release = None;
+        self.next_key = now + KEY_CYCLES;
+      }
+      return;
+    }
+    if now < self.next_key {
+      return;
+    }
+    match self.terminal.receive().and_then(keypad_input) {
+      Some(Input::Key(key)) => {
+        trace!("KIM-1 key {:X} down", key);
+        self.panel_mut().key = Some(key);
+        self.key_release = Some(now + KEY_CYCLES);
+      }
+      Some(Input::Stop) => self.cpu.set_nmi(),
+      Some(Input::Reset) => self.cpu.set_reset(),
+      None => (),
+    }
+  }
+
+  /// Passes bytes between the host and the bit banged TTY lines, sending
+  /// only while the monitor is quiet so it isn't talked over.
+  fn service_tty(&mut self, now: u64) {
+    let level = self.panel().serial_out;
+    if let Some(byte) = self.serial_out.sample(now, level) {
+      match byte & 0x7F {
+        0x00 => (),
+        byte => self.terminal.transmit(byte),
+      }
+    }
+    if now >= self.serial_in.busy_until && self.serial_out.is_quiet(now) {
+      let byte = match self.pending.take() {
+        Some(byte) => Some(byte),
+        None => self.terminal.receive(),
+      };
+      if let Some(byte) = byte {
+        let byte = match byte {
+          b'\n' => b'\r',
+          _ => byte.to_ascii_uppercase(),
+        };
+        self.serial_in.send(now, byte);
+      }
+    }
+    let level = self.serial_in.level(now);
+    self.panel_mut().serial_in = level;
+  }
+}
+
+/// Reads a seven segment pattern as the letter or digit it shows.
+fn to_char(segments: u8) -> char {
+  match segments & 0x7F {
+    0x00 => ' ',
+    0x3F => '0',
+    0x06 => '1',
+    0x5B => '2',
+    0x4F => '3',
+    0x66 => '4',
+    0x6D => '5',
+    0x7D => '6',
+    0x07 => '7',
+    0x7F => '8',
+    0x6F => '9',
+    0x77 => 'A',
+    0x7C => 'b',
+    0x39 => 'C',
+    0x5E => 'd',
+    0x79 => 'E',
+    0x71 => 'F',
+    0x3D => 'G',
+    0x76 => 'H',
+    0x74 => 'h',
+    0x1E => 'J',
+    0x38 => 'L',
+    0x54 => 'n',
+    0x5C => 'o',
+    0x73 => 'P',
+    0x50 => 'r',
+    0x78 => 't',
+    0x3E => 'U',
+    0x1C => 'u',
+    0x6E => 'y',
+    0x40 => '-',
+    0x08 => '_',
+    _ => '?',
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+  use crate::devices::serial::Buffer;
+
+  const PORTS: &str = "
+SAD = $1740
+PADD = $1741
+SBD = $1742
+PBDD = $1743
+";
+
+  /// Builds a ROM image from a program at 0x1C00 that starts at `reset`.
+  fn rom(program: &str) -> Vec<u8> {
+    let source = format!(
+      "{}\n        .org $1C00\n{}\n        .org $1FFC\n        .word reset\n",
+      PORTS, program
+    );
+    let assembly = assemble(&source, "kim.s", 0x1C00).unwrap();
+    let mut rom = vec![0; ROM_SIZE];
+    for segment in assembly.image.get_segments() {
+      let start = segment.address as usize - 0x1800;
+      rom[start..start + segment.data.len()].copy_from_slice(&segment.data);
+    }
+    rom
+  }
+
+  /// A program that bit banges "HI" at 1200 baud after noting the TTY
+  /// jumper in 0x00.
+  const TRANSMIT: &str = "
+reset:  CLD
+        LDX #$FF
+        TXS
+        LDA #$1F
+        STA PBDD
+        LDA #$07
+        STA SBD
+        LDA #$00
+        STA PADD
+        LDA SAD
+        AND #$01
+        STA $00
+        LDA #$48
+        JSR outch
+        LDA #$49
+        JSR outch
+        .byte $02
+outch:  STA $01
+        LDA #$06
+        STA SBD
+        JSR delay
+        LDX #8
+obit:   LSR $01
+        LDA #$06
+        ADC #$00
+        STA SBD
+        JSR delay
+        DEX
+        BNE obit
+        LDA #$07
+        STA SBD
+        JSR delay
+        JSR delay
+        RTS
+delay:  LDY #160
+dloop:  DEY
+        BNE dloop
+        RTS
+";
+
+  /// A program that receives two bytes at 1200 baud into 0x10.
+  const RECEIVE: &str = "
+reset:  CLD
+        LDX #$FF
+        TXS
+        LDA #$1F
+        STA PBDD
+        LDA #$07
+        STA SBD
+        LDA #$00
+        STA PADD
+        LDX #0
+next:   JSR getch
+        STA $10,X
+        INX
+        CPX #2
+        BNE next
+        .byte $02
+getch:  BIT SAD
+        BMI getch
+        LDY #80
+half:   DEY
+        BNE half
+        LDA #8
+        STA $02
+gbit:   JSR delay
+        LDA SAD
+        ASL
+        ROR $03
+        DEC $02
+        BNE gbit
+        JSR delay
+        LDA $03
+        RTS
+delay:  LDY #160
+dloop:  DEY
+        BNE dloop
+        RTS
+";
+
+  #[test]
+  fn rejects_wrong_rom_size() {
+    let error = Kim1::new(vec![0; 0x400], Buffer::new(), false).err();
+    assert_eq!(
+      error,
+      Some(MachineError::RomSize {
+        expected: ROM_SIZE,
+        actual: 0x400
+      })
+    );
+  }
+
+  #[test]
+  fn memory_map() {
+    let mut rom = rom("reset: JMP reset");
+    rom[0x10] = 0x42;
+    let machine = Kim1::new(rom, Buffer::new(), false).unwrap();
+    let memory = machine.get_cpu().get_memory();
+    assert_eq!(memory.peek(0x1810), 0x42);
+    assert_eq!(memory.peek(0xFFFD), 0x1C);
+    assert_eq!(machine.get_cpu().get_registers().program_counter, 0x1C00);
+  }
+
+  #[test]
+  fn led_display() {
+    let program = "
+reset:  LDA #$7F
+        STA PADD
+        LDA #$1F
+        STA PBDD
+scan:   LDY #0
+        LDX #$09
+digit:  LDA #0
+        STA SAD
+        STX SBD
+        LDA table,Y
+        STA SAD
+        INX
+        INX
+        INY
+        CPY #6
+        BNE digit
+        JMP scan
+table:  .byte $06, $39, $5B, $5B, $79, $77";
+    let mut machine = Kim1::new(rom(program), Buffer::new(), false).unwrap();
+    machine.run(1_000);
+    assert_eq!(machine.render_display(), "1C22 EA");
+  }
+
+  #[test]
+  fn keypad() {
+    let program = "
+reset:  LDX #$FF
+        TXS
+        LDA #$1F
+        STA PBDD
+        LDA #$00
+        STA PADD
+poll:   LDX #0
+row:    TXA
+        ASL
+        ORA #$01
+        STA SBD
+        LDA SAD
+        EOR #$FF
+        AND #$7F
+        BNE found
+        INX
+        CPX #3
+        BNE row
+        JMP poll
+found:  STA $01
+        STX $02
+        TXA
+        ASL
+        ASL
+        ASL
+        SEC
+        SBC $02
+        TAY
+bit:    LSR $01
+        BCS done
+        INY
+        JMP bit
+done:   STY $00
+        JMP poll";
+    let terminal = Buffer::new();
+    let mut machine = Kim1::new(rom(program), terminal.clone(), false).unwrap();
+    terminal.push_input(b"xd@");
+    machine.run(KEY_CYCLES);
+    assert_eq!(machine.get_cpu().get_memory().peek(0x00), 0x0D);
+    machine.run(3 * KEY_CYCLES);
+    assert_eq!(machine.get_cpu().get_memory().peek(0x00), 0x10);
+  }
+
+  #[test]
+  fn tty_transmit() {
+    let terminal = Buffer::new();
+    let mut machine = Kim1::new(rom(TRANSMIT), terminal.clone(), true).unwrap();
+    assert!(!machine.run(100_000));
+    assert_eq!(terminal.take_output(), b"HI");
+    assert_eq!(machine.get_cpu().get_memory().peek(0x00), 0x00);
+  }
+
+  #[test]
+  fn keypad_mode_jumper() {
+    let mut machine = Kim1::new(rom(TRANSMIT), Buffer::new(), false).unwrap();
+    assert!(!machine.run(100_000));
+    assert_eq!(machine.get_cpu().get_memory().peek(0x00), 0x01);
+  }
+
+  #[test]
+  fn tty_receive() {
+    let terminal = Buffer::new();
+    terminal.push_input(b"a");
+    let mut machine = Kim1::new(rom(RECEIVE), terminal, true).unwrap();
+    assert!(!machine.run(100_000));
+    let memory = machine.get_cpu().get_memory();
+    assert_eq!(memory.peek(0x10), 0x7F);
+    assert_eq!(memory.peek(0x11), b'A');
+  }
+}
@@ -0,0 +1,35 @@
+mod apple1;
+mod ben_eater;
+mod c64;
+mod kim1;
+
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::error::Error;
+
+pub use apple1::Apple1;
+pub use ben_eater::BenEater;
+pub use c64::C64;
+pub use kim1::Kim1;
+
+/// Things that can go wrong while building a machine.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MachineError {
+  /// A ROM image is not the size of the socket it goes in.
+  RomSize { expected: usize, actual: usize },
+}
+
+impl Display for MachineError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      MachineError::RomSize { expected, actual } => write!(
+        f,
+        "ROM image is {} bytes, expected {} bytes",
+        actual, expected
+      ),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for MachineError {}
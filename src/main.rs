@@ -1,31 +1,370 @@
+mod cli;
+
+use cli::{Options, Start, USAGE};
 use flexi_logger::{detailed_format, Logger};
 use log::debug;
+use rust6502lib::assembler::assemble;
+use rust6502lib::clock::FreeRunning;
+use rust6502lib::devices::serial::Terminal;
+use rust6502lib::disassembler::{listing, Instruction};
+use rust6502lib::gdb::GdbStub;
+use rust6502lib::loader::{Format, Image};
+use rust6502lib::machines::{Apple1, BenEater, Kim1, C64};
+use rust6502lib::runner::{dump_memory, trace_until, StopReason};
+use rust6502lib::traps::{Host, HOST_BASE};
 use rust6502lib::*;
-use std::sync::mpsc;
+use std::path::Path;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Cycles a machine runs between checks on its display and the clock.
+const MACHINE_SLICE: u64 = 10_000;
 
 fn main() {
-  Logger::with_env_or_str("trace")
-    .log_to_file()
-    .directory("log_files")
-    .duplicate_to_stdout(flexi_logger::Duplicate::All)
+  let args: Vec<String> = std::env::args().skip(1).collect();
+  let options = match Options::parse(&args) {
+    Ok(options) => options,
+    Err(error) => {
+      eprintln!("{}\n\n{}", error, USAGE);
+      std::process::exit(1);
+    }
+  };
+  if let Err(error) = start_logging(&options) {
+    eprintln!("{}", error);
+    std::process::exit(1);
+  }
+  debug!("Initialized in {} mode", options.command);
+  let result = match options.command.as_str() {
+    "help" => {
+      println!("{}", USAGE);
+      Ok(0)
+    }
+    "run" | "trace" | "test" => run(&options),
+    "asm" => assemble_file(&options),
+    "disasm" => disassemble_file(&options),
+    "debug" => debug(&options),
+    _ => boot(&options),
+  };
+  match result {
+    Ok(code) => std::process::exit(code),
+    Err(error) => {
+      eprintln!("{}", error);
+      std::process::exit(1);
+    }
+  }
+}
+
+/// Starts logging at the level asked for, or from RUST_LOG, to stderr or to
+/// a file in the log directory.
+fn start_logging(options: &Options) -> Result<(), String> {
+  let logger = match &options.log_level {
+    Some(level) => Logger::with_str(level)
+      .check_parser_error()
+      .map_err(|error| format!("bad log level {}: {}", level, error))?,
+    None => Logger::with_env_or_str("warn"),
+  };
+  let logger = match &options.log_dir {
+    Some(directory) => logger.log_to_file().directory(directory.clone()),
+    None => logger,
+  };
+  logger
     .format(detailed_format)
     .start()
-    .unwrap();
-  let pattern = std::env::args().nth(1).expect("no pattern given");
-  if &pattern == "parser" {
-    debug!("Initialized in parser mode");
-    debug!("Disabled for the moment");
+    .map(|_| ())
+    .map_err(|error| format!("could not start logging: {}", error))
+}
+
+/// Keeps the emulator to a clock speed by sleeping whenever it gets ahead of
+/// the wall clock.
+struct Pacer {
+  hz: f64,
+  started: Instant,
+  cycles: u64,
+}
+
+impl Pacer {
+  fn new(hz: f64, cycles: u64) -> Pacer {
+    Pacer {
+      hz,
+      started: Instant::now(),
+      cycles,
+    }
+  }
+
+  /// Waits until the wall clock catches up with the cycles run so far.
+  /// Short waits are saved up, as sleeping for them costs more than they
+  /// are worth.
+  fn wait(&self, cycles: u64) {
+    let due = Duration::from_secs_f64((cycles - self.cycles) as f64 / self.hz);
+    let ahead = due.saturating_sub(self.started.elapsed());
+    if ahead >= Duration::from_millis(1) {
+      thread::sleep(ahead);
+    }
+  }
+}
+
+/// Reads a file, naming it in the error.
+fn read(path: &str) -> Result<Vec<u8>, String> {
+  std::fs::read(path).map_err(|error| format!("could not read {}: {}", path, error))
+}
+
+/// Gets the program file the command works on.
+fn get_program(options: &Options) -> Result<&str, String> {
+  options
+    .program
+    .as_deref()
+    .ok_or_else(|| format!("{} needs a program\n\n{}", options.command, USAGE))
+}
+
+/// Gets the format for a file from the options or the file's extension.
+fn get_format(options: &Options, path: &str) -> Format {
+  options.format.unwrap_or_else(|| {
+    let extension = Path::new(path).extension().and_then(|e| e.to_str());
+    Format::from_extension(extension.unwrap_or(""))
+  })
+}
+
+/// Reads the program into an image.
+fn load(options: &Options) -> Result<Image, String> {
+  let path = get_program(options)?;
+  let data = read(path)?;
+  Image::parse(&data, get_format(options, path), options.load)
+    .map_err(|error| format!("could not load {}: {}", path, error))
+}
+
+/// Builds a CPU with the program loaded, ready to start.
+fn load_cpu(options: &Options) -> Result<CPU, String> {
+  let image = load(options)?;
+  let mut cpu = CPU::new(FreeRunning);
+  cpu.set_undocumented_opcodes(!options.documented_only);
+  cpu.load_image(&image);
+  if options.host {
+    Host::new().install_at(&mut cpu, HOST_BASE);
+  }
+  match options.start {
+    Some(Start::Reset) => {
+      cpu.jump_to_reset_vector();
+    }
+    Some(Start::Address(address)) => {
+      let mut registers = cpu.get_registers();
+      registers.program_counter = address;
+      cpu.set_registers(registers);
+    }
+    None => (),
+  }
+  Ok(cpu)
+}
+
+/// Formats the next instruction and the registers for a trace.
+fn trace_line(cpu: &CPU) -> String {
+  let registers = cpu.get_registers();
+  let line = listing(cpu.get_memory(), registers.program_counter, 1, None);
+  format!(
+    "{:<36}A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} CYC:{}",
+    line.trim_end(),
+    registers.accumulator,
+    registers.x,
+    registers.y,
+    registers.stack_pointer,
+    registers.status,
+    cpu.get_cycles()
+  )
+}
+
+/// Loads a program and runs it until it stops, tracing each instruction to
+/// stdout for `trace`. Prints why it stopped, the registers and any memory
+/// asked for to stderr. `test` passes when the program stops at the success
+/// address, or exits with 0 if none is given. Returns the process exit code.
+fn run(options: &Options) -> Result<i32, String> {
+  let mut cpu = load_cpu(options)?;
+  let mut stops = options.stops.clone();
+  stops.extend(options.success);
+  let pacer = options.clock.map(|hz| Pacer::new(hz, cpu.get_cycles()));
+  let tracing = options.command == "trace";
+  let reason = trace_until(&mut cpu, &stops, options.limit, |cpu| {
+    if tracing {
+      println!("{}", trace_line(cpu));
+    }
+    if let Some(pacer) = &pacer {
+      pacer.wait(cpu.get_cycles());
+    }
+  });
+  let code = if options.command == "test" {
+    let passed = match (options.success, reason) {
+      (Some(success), StopReason::StopAddress(address)) => address == success,
+      (Some(_), _) => false,
+      (None, reason) => reason.exit_code() == 0,
+    };
+    eprintln!("{}", if passed { "PASS" } else { "FAIL" });
+    if passed {
+      0
+    } else {
+      1
+    }
   } else {
-    let (tx, rx) = mpsc::channel();
-    thread::spawn(move || loop {
-      thread::sleep(Duration::from_micros(1790));
-      tx.send(true).unwrap();
-    });
-    debug!("Initialized in program mode");
-    let program = vec![0xA9, 0x10, 0x69, 0x10];
-    let mut cpu = CPU::new(rx);
-    cpu.run(program, None);
+    reason.exit_code()
+  };
+  eprintln!(
+    "Stopped: {} after {} cycles\n{}",
+    reason,
+    cpu.get_cycles(),
+    cpu
+  );
+  for (first, last) in options.dumps.iter() {
+    eprint!("{}", dump_memory(&cpu, *first, *last));
   }
+  Ok(code)
+}
+
+/// Assembles a source file and writes the image out, in the format asked
+/// for or the output file's, Intel HEX by default.
+fn assemble_file(options: &Options) -> Result<i32, String> {
+  let path = get_program(options)?;
+  let source = String::from_utf8(read(path)?).map_err(|_| format!("{} is not text", path))?;
+  let assembly =
+    assemble(&source, path, options.origin.unwrap_or(0x8000)).map_err(|error| error.to_string())?;
+  let output = match &options.output {
+    Some(output) => output.clone(),
+    None => {
+      let extension = match options.format.unwrap_or(Format::IntelHex) {
+        Format::IntelHex => "hex",
+        Format::SRecord => "srec",
+        Format::Binary => "bin",
+        Format::Prg => "prg",
+      };
+      Path::new(path)
+        .with_extension(extension)
+        .to_string_lossy()
+        .into_owned()
+    }
+  };
+  let format = get_format(options, &output);
+  std::fs::write(&output, assembly.image.write(format))
+    .map_err(|error| format!("could not write {}: {}", output, error))?;
+  Ok(0)
+}
+
+/// Prints a listing of a program, from --start for --count instructions or
+/// else each segment of the image in full.
+fn disassemble_file(options: &Options) -> Result<i32, String> {
+  let image = load(options)?;
+  let mut cpu = CPU::new(FreeRunning);
+  cpu.load_image(&image);
+  let start = match options.start {
+    Some(Start::Reset) => {
+      cpu.jump_to_reset_vector();
+      Some(cpu.get_registers().program_counter)
+    }
+    Some(Start::Address(address)) => Some(address),
+    None => None,
+  };
+  let memory = cpu.get_memory();
+  if let Some(start) = start {
+    let count = options.count.unwrap_or(20);
+    print!("{}", listing(memory, start, count, None));
+    return Ok(0);
+  }
+  for segment in image.get_segments() {
+    let end = segment.address as u32 + segment.data.len() as u32;
+    let mut address = segment.address as u32;
+    let mut count = 0;
+    while address < end && options.count.is_none_or(|limit| count < limit) {
+      address += Instruction::read(memory, address as u16).size() as u32;
+      count += 1;
+    }
+    print!("{}", listing(memory, segment.address, count, None));
+  }
+  Ok(0)
+}
+
+/// Loads a program and serves it to GDB.
+fn debug(options: &Options) -> Result<i32, String> {
+  let cpu = load_cpu(options)?;
+  let address = format!("127.0.0.1:{}", options.port.unwrap_or(1234));
+  eprintln!("Waiting for GDB on {}", address);
+  GdbStub::new(cpu)
+    .listen(address.as_str())
+    .map_err(|error| format!("debugger session failed: {}", error))?;
+  Ok(0)
+}
+
+/// Boots a machine and runs it until the CPU halts, at the machine's own
+/// clock speed unless another is asked for.
+fn boot(options: &Options) -> Result<i32, String> {
+  let machine = options
+    .machine
+    .as_deref()
+    .ok_or_else(|| format!("no machine given\n\n{}", USAGE))?;
+  let rom = |name: &str| options.get_rom(name).and_then(read);
+  let error = |error: machines::MachineError| format!("could not build machine: {}", error);
+  match machine {
+    "ben-eater" => {
+      let mut machine = BenEater::new(rom("rom")?).map_err(error)?;
+      let mut screen = String::new();
+      let pacer = Pacer::new(options.clock.unwrap_or(1e6), 0);
+      while machine.run(MACHINE_SLICE) {
+        let lcd = machine.render_lcd();
+        if lcd != screen {
+          println!("{}", lcd);
+          screen = lcd;
+        }
+        pacer.wait(machine.get_cpu().get_cycles());
+      }
+      halted(machine.get_cpu())
+    }
+    "apple1" => {
+      let mut machine = Apple1::new(rom("rom")?, Terminal::new(), true).map_err(error)?;
+      let pacer = Pacer::new(options.clock.unwrap_or(1_022_727.0), 0);
+      while machine.run(MACHINE_SLICE) {
+        pacer.wait(machine.get_cpu().get_cycles());
+      }
+      halted(machine.get_cpu())
+    }
+    "kim1" => {
+      let mut machine = Kim1::new(rom("rom")?, Terminal::new(), options.tty).map_err(error)?;
+      let mut display = String::new();
+      let pacer = Pacer::new(options.clock.unwrap_or(1e6), 0);
+      while machine.run(MACHINE_SLICE) {
+        if !options.tty && machine.render_display() != display {
+          display = machine.render_display();
+          println!("[{}]", display);
+        }
+        pacer.wait(machine.get_cpu().get_cycles());
+      }
+      halted(machine.get_cpu())
+    }
+    "c64" => {
+      let mut machine = C64::new(
+        rom("basic")?,
+        rom("kernal")?,
+        rom("characters")?,
+        Terminal::new(),
+      )
+      .map_err(error)?;
+      if let Some(path) = &options.program {
+        // Let the KERNAL and BASIC finish starting up first.
+        machine.run(3_000_000);
+        let image = Image::parse(&read(path)?, Format::Prg, None)
+          .map_err(|error| format!("could not load {}: {}", path, error))?;
+        let cpu = machine.get_cpu_mut();
+        let start = image.load(cpu.get_memory_mut());
+        let mut registers = cpu.get_registers();
+        registers.program_counter = start;
+        cpu.set_registers(registers);
+      }
+      let cycles = machine.get_cpu().get_cycles();
+      let pacer = Pacer::new(options.clock.unwrap_or(985_248.0), cycles);
+      while machine.run(MACHINE_SLICE) {
+        pacer.wait(machine.get_cpu().get_cycles());
+      }
+      halted(machine.get_cpu())
+    }
+    machine => Err(format!("unknown machine {}", machine)),
+  }
+}
+
+/// Reports where a machine's CPU halted.
+fn halted(cpu: &CPU) -> Result<i32, String> {
+  println!("CPU halted at {:X}", cpu.get_registers().program_counter);
+  Ok(0)
 }
@@ -0,0 +1,87 @@
+use crate::mappers::{Bank, BankSwitcher, Mapper, BANK_16K};
+use crate::prelude::*;
+
+/// Mapper 3. Fixed PRG ROM laid out like NROM, with any write to
+/// 0x8000-0xFFFF selecting an 8K CHR bank.
+///
+/// CHR is only visible to the PPU, so from the CPU side all we do is track the
+/// selected bank.
+#[derive(Clone)]
+pub struct Cnrom {
+  banks: BankSwitcher,
+  chr_banks: usize,
+  chr_bank: usize,
+}
+
+impl Cnrom {
+  /// Creates a new CNROM board from a PRG ROM image and the number of 8K CHR
+  /// banks on the board.
+  pub fn new(prg: Vec<u8>, chr_banks: usize) -> Cnrom {
+    debug!("Initializing CNROM with {} bytes of PRG", prg.len());
+    let mut banks = BankSwitcher::new();
+    let rom = banks.add_bank(Bank::rom("prg_rom", prg, BANK_16K));
+    banks.map(0x8000, rom, 0);
+    banks.map(0xC000, rom, 1);
+    Cnrom {
+      banks,
+      chr_banks: chr_banks.max(1),
+      chr_bank: 0,
+    }
+  }
+
+  /// Gets the currently selected CHR bank.
+  pub fn get_chr_bank(&self) -> usize {
+    self.chr_bank
+  }
+}
+
+impl Mapper for Cnrom {
+  fn name(&self) -> &str {
+    "CNROM"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    self.banks.read(index)
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    if index >= 0x8000 {
+      self.chr_bank = value as usize % self.chr_banks;
+      debug!("CNROM selecting CHR bank {}", self.chr_bank);
+      return true;
+    }
+    false
+  }
+
+  fn reset(&mut self) {
+    self.chr_bank = 0;
+  }
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(self.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case(2, 2; "In range")]
+  #[test_case(5, 1; "Wraps")]
+  fn select_chr(value: u8, expected: usize) {
+    let mut cnrom = Cnrom::new(vec![0; BANK_16K], 4);
+    assert!(cnrom.write(0x8000, value));
+    assert_eq!(cnrom.get_chr_bank(), expected);
+  }
+
+  #[test]
+  fn prg_is_fixed() {
+    let mut prg = vec![0; BANK_16K];
+    prg[0] = 0x42;
+    let mut cnrom = Cnrom::new(prg, 4);
+    cnrom.write(0x8000, 3);
+    assert_eq!(cnrom.read(0x8000), Some(0x42));
+    assert_eq!(cnrom.read(0xC000), Some(0x42));
+  }
+}
@@ -0,0 +1,93 @@
+use crate::mappers::{Bank, BankSwitcher, Mapper};
+use crate::prelude::*;
+
+/// A simple banking latch, the kind found on a lot of homebrew boards.
+///
+/// One window shows a page of a bank, and writing to the latch register
+/// selects which page. Use a bank with 16K pages for a 16K latch or 32K pages
+/// for a 32K latch. The bank can be ROM or RAM.
+#[derive(Clone)]
+pub struct Latch {
+  banks: BankSwitcher,
+  window: usize,
+  register: u16,
+}
+
+impl Latch {
+  /// Creates a new latch that shows the bank at the start index and switches
+  /// pages when the register is written.
+  pub fn new(bank: Bank, start: u16, register: u16) -> Latch {
+    debug!(
+      "Initializing latch for {} at {:X}, register at {:X}",
+      bank.name(),
+      start,
+      register
+    );
+    let mut banks = BankSwitcher::new();
+    let id = banks.add_bank(bank);
+    let window = banks.map(start, id, 0);
+    Latch {
+      banks,
+      window,
+      register,
+    }
+  }
+
+  /// Gets the page currently selected by the latch.
+  pub fn get_page(&self) -> usize {
+    self.banks.get_page(self.window)
+  }
+}
+
+impl Mapper for Latch {
+  fn name(&self) -> &str {
+    "Latch"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    self.banks.read(index)
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    if index == self.register {
+      self.banks.switch(self.window, value as usize);
+      return true;
+    }
+    self.banks.write(index, value)
+  }
+
+  fn reset(&mut self) {
+    self.banks.switch(self.window, 0);
+  }
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(self.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::{BANK_16K, BANK_32K};
+
+  #[test]
+  fn rom_latch_32k() {
+    let image = (0..4).flat_map(|p| vec![p as u8; BANK_32K]).collect();
+    let mut latch = Latch::new(Bank::rom("rom", image, BANK_32K), 0x8000, 0x7000);
+    assert_eq!(latch.read(0xFFFF), Some(0));
+    assert!(latch.write(0x7000, 2));
+    assert_eq!(latch.get_page(), 2);
+    assert_eq!(latch.read(0x8000), Some(2));
+    assert_eq!(latch.read(0x7000), None);
+  }
+
+  #[test]
+  fn ram_latch_16k() {
+    let mut latch = Latch::new(Bank::ram("ram", 4, BANK_16K), 0x4000, 0x0200);
+    latch.write(0x4000, 0x42);
+    latch.write(0x0200, 1);
+    assert_eq!(latch.read(0x4000), Some(0));
+    latch.write(0x0200, 0);
+    assert_eq!(latch.read(0x4000), Some(0x42));
+  }
+}
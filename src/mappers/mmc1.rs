@@ -0,0 +1,236 @@
+use crate::mappers::{Bank, BankSwitcher, Mapper, Mirroring, BANK_16K, BANK_8K};
+use crate::prelude::*;
+
+/// Control register value at power on: last PRG bank fixed at 0xC000.
+const CONTROL_DEFAULT: u8 = 0x0C;
+
+/// Mapper 1. Registers are loaded serially, one bit per write, through a five
+/// bit shift register.
+///
+/// Writing a value with bit 7 set clears the shift register. The fifth write
+/// copies the shift register into whichever register the address of that
+/// write selects:
+/// - 0x8000-0x9FFF control (mirroring, PRG and CHR modes)
+/// - 0xA000-0xBFFF CHR bank 0
+/// - 0xC000-0xDFFF CHR bank 1
+/// - 0xE000-0xFFFF PRG bank, with bit 4 disabling PRG RAM
+#[derive(Clone)]
+pub struct Mmc1 {
+  banks: BankSwitcher,
+  rom: usize,
+  low: usize,
+  high: usize,
+  shift: u8,
+  count: u8,
+  control: u8,
+  chr_0: u8,
+  chr_1: u8,
+  prg: u8,
+}
+
+impl Mmc1 {
+  /// Creates a new MMC1 board from a PRG ROM image, with 8K of PRG RAM at
+  /// 0x6000.
+  pub fn new(prg: Vec<u8>) -> Mmc1 {
+    debug!("Initializing MMC1 with {} bytes of PRG", prg.len());
+    let mut banks = BankSwitcher::new();
+    let ram = banks.add_bank(Bank::ram("prg_ram", 1, BANK_8K));
+    let rom = banks.add_bank(Bank::rom("prg_rom", prg, BANK_16K));
+    banks.map(0x6000, ram, 0);
+    let low = banks.map(0x8000, rom, 0);
+    let high = banks.map(0xC000, rom, 0);
+    let mut mmc1 = Mmc1 {
+      banks,
+      rom,
+      low,
+      high,
+      shift: 0,
+      count: 0,
+      control: CONTROL_DEFAULT,
+      chr_0: 0,
+      chr_1: 0,
+      prg: 0,
+    };
+    mmc1.update_banks();
+    mmc1
+  }
+
+  /// Gets the mirroring currently selected by the control register.
+  pub fn get_mirroring(&self) -> Mirroring {
+    match self.control & 0x3 {
+      0 => Mirroring::SingleScreenLower,
+      1 => Mirroring::SingleScreenUpper,
+      2 => Mirroring::Vertical,
+      _ => Mirroring::Horizontal,
+    }
+  }
+
+  /// Gets the 4K CHR banks currently selected for PPU 0x0000 and 0x1000.
+  pub fn get_chr_banks(&self) -> (usize, usize) {
+    match self.control & 0x10 == 0x10 {
+      true => (self.chr_0 as usize, self.chr_1 as usize),
+      false => {
+        let bank = (self.chr_0 & 0x1E) as usize;
+        (bank, bank + 1)
+      }
+    }
+  }
+
+  /// Returns true if PRG RAM is enabled.
+  pub fn is_ram_enabled(&self) -> bool {
+    self.prg & 0x10 == 0
+  }
+
+  /// Lines the PRG windows up with the control and PRG registers.
+  fn update_banks(&mut self) {
+    let bank = (self.prg & 0x0F) as usize;
+    let last = self.banks.pages(self.rom) - 1;
+    let (low, high) = match (self.control & 0x0C) >> 2 {
+      0 | 1 => (bank & !1, (bank & !1) + 1),
+      2 => (0, bank),
+      _ => (bank, last),
+    };
+    self.banks.switch(self.low, low);
+    self.banks.switch(self.high, high);
+  }
+
+  /// Copies the shift register into the register selected by the index.
+  fn commit(&mut self, index: u16) {
+    let value = self.shift;
+    match index & 0x6000 {
+      0x0000 => self.control = value,
+      0x2000 => self.chr_0 = value,
+      0x4000 => self.chr_1 = value,
+      _ => self.prg = value,
+    }
+    debug!("MMC1 register {:X} loaded with {:X}", index & 0xE000, value);
+    self.update_banks();
+  }
+}
+
+impl Mapper for Mmc1 {
+  fn name(&self) -> &str {
+    "MMC1"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    if (0x6000..0x8000).contains(&index) && !self.is_ram_enabled() {
+      return Some(0);
+    }
+    self.banks.read(index)
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    if index < 0x8000 {
+      if (0x6000..0x8000).contains(&index) && !self.is_ram_enabled() {
+        return true;
+      }
+      return self.banks.write(index, value);
+    }
+    if value & 0x80 == 0x80 {
+      trace!("MMC1 shift register reset");
+      self.shift = 0;
+      self.count = 0;
+      self.control |= CONTROL_DEFAULT;
+      self.update_banks();
+      return true;
+    }
+    self.shift = (self.shift >> 1) | ((value & 0x1) << 4);
+    self.count += 1;
+    if self.count == 5 {
+      self.commit(index);
+      self.shift = 0;
+      self.count = 0;
+    }
+    true
+  }
+
+  fn reset(&mut self) {
+    self.shift = 0;
+    self.count = 0;
+    self.control = CONTROL_DEFAULT;
+    self.chr_0 = 0;
+    self.chr_1 = 0;
+    self.prg = 0;
+    self.update_banks();
+  }
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(self.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::mappers::Mirroring;
+  use test_case::test_case;
+
+  fn prg() -> Vec<u8> {
+    (0..8).flat_map(|p| vec![p as u8; BANK_16K]).collect()
+  }
+
+  fn load(mmc1: &mut Mmc1, index: u16, value: u8) {
+    for bit in 0..5 {
+      mmc1.write(index, (value >> bit) & 0x1);
+    }
+  }
+
+  #[test]
+  fn power_on() {
+    let mmc1 = Mmc1::new(prg());
+    assert_eq!(mmc1.read(0x8000), Some(0));
+    assert_eq!(mmc1.read(0xC000), Some(7));
+  }
+
+  #[test_case(0x0C, 3, 3, 7; "Switch low, fix last")]
+  #[test_case(0x08, 3, 0, 3; "Fix first, switch high")]
+  #[test_case(0x00, 3, 2, 3; "32K mode ignores low bit")]
+  fn prg_modes(control: u8, bank: u8, low: u8, high: u8) {
+    let mut mmc1 = Mmc1::new(prg());
+    load(&mut mmc1, 0x8000, control);
+    load(&mut mmc1, 0xE000, bank);
+    assert_eq!(mmc1.read(0x8000), Some(low));
+    assert_eq!(mmc1.read(0xC000), Some(high));
+  }
+
+  #[test]
+  fn shift_reset() {
+    let mut mmc1 = Mmc1::new(prg());
+    load(&mut mmc1, 0x8000, 0x08);
+    mmc1.write(0xE000, 1);
+    mmc1.write(0xE000, 0x80);
+    assert_eq!(mmc1.count, 0);
+    assert_eq!(mmc1.read(0xC000), Some(7));
+  }
+
+  #[test_case(0x02, Mirroring::Vertical; "Vertical")]
+  #[test_case(0x03, Mirroring::Horizontal; "Horizontal")]
+  #[test_case(0x00, Mirroring::SingleScreenLower; "Single screen")]
+  fn mirroring(control: u8, expected: Mirroring) {
+    let mut mmc1 = Mmc1::new(prg());
+    load(&mut mmc1, 0x8000, control);
+    assert_eq!(mmc1.get_mirroring(), expected);
+  }
+
+  #[test_case(0x10, (5, 2); "4K mode")]
+  #[test_case(0x00, (4, 5); "8K mode")]
+  fn chr_banks(control: u8, expected: (usize, usize)) {
+    let mut mmc1 = Mmc1::new(prg());
+    load(&mut mmc1, 0x8000, control);
+    load(&mut mmc1, 0xA000, 5);
+    load(&mut mmc1, 0xC000, 2);
+    assert_eq!(mmc1.get_chr_banks(), expected);
+  }
+
+  #[test]
+  fn prg_ram_disable() {
+    let mut mmc1 = Mmc1::new(prg());
+    mmc1.write(0x6000, 0x42);
+    assert_eq!(mmc1.read(0x6000), Some(0x42));
+    load(&mut mmc1, 0xE000, 0x10);
+    assert_eq!(mmc1.read(0x6000), Some(0));
+    load(&mut mmc1, 0xE000, 0x00);
+    assert_eq!(mmc1.read(0x6000), Some(0x42));
+  }
+}
@@ -0,0 +1,335 @@
+mod cnrom;
+mod latch;
+mod mmc1;
+mod nrom;
+mod uxrom;
+
+use crate::prelude::*;
+
+pub use cnrom::Cnrom;
+pub use latch::Latch;
+pub use mmc1::Mmc1;
+pub use nrom::Nrom;
+pub use uxrom::Uxrom;
+
+/// Size of a 4K bank, used for CHR banking on MMC1.
+pub const BANK_4K: usize = 0x1000;
+/// Size of an 8K bank, used for PRG RAM and CHR banks.
+pub const BANK_8K: usize = 0x2000;
+/// Size of a 16K bank, the most common PRG bank size.
+pub const BANK_16K: usize = 0x4000;
+/// Size of a 32K bank, a whole cartridge window.
+pub const BANK_32K: usize = 0x8000;
+
+/// A bank switching scheme that sits in front of memory.
+///
+/// Every access to memory is offered to the mapper first. If the mapper claims
+/// the address the access is handled entirely by it, otherwise it falls through
+/// to the flat 64K of regular memory. This lets a mapper page windows of a
+/// larger ROM or RAM image into the address space and watch for writes to its
+/// control registers.
+///
+/// Mappers are `Send` so the CPU that owns them can be moved to another
+/// thread.
+pub trait Mapper: Send {
+  /// A short name for the scheme, used for logging.
+  fn name(&self) -> &str;
+
+  /// Reads the value at an index. Returns None if the mapper does not claim
+  /// the index.
+  fn read(&self, index: u16) -> Option<u8>;
+
+  /// Writes a value to an index. Returns false if the mapper does not claim
+  /// the index. Writes to control registers or to ROM windows are claimed.
+  fn write(&mut self, index: u16, value: u8) -> bool;
+
+  /// Returns the control registers to their power on state. The contents of
+  /// RAM banks are left alone.
+  fn reset(&mut self);
+
+  /// Clones the mapper along with its current bank selection. Used to snapshot
+  /// the mapper together with memory.
+  fn box_clone(&self) -> Box<dyn Mapper>;
+}
+
+impl Clone for Box<dyn Mapper> {
+  fn clone(&self) -> Self {
+    self.box_clone()
+  }
+}
+
+/// Nametable mirroring as selected by the header or the mapper.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Mirroring {
+  Horizontal,
+  Vertical,
+  SingleScreenLower,
+  SingleScreenUpper,
+  FourScreen,
+}
+
+/// A named ROM or RAM image split into equally sized pages.
+///
+/// Page selection wraps, so selecting page 5 of a 4 page bank gives page 1.
+/// This matches how boards with fewer address lines than the latch is wide
+/// behave, and also gives us mirroring of small images for free.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bank {
+  name: String,
+  data: Vec<u8>,
+  page_size: usize,
+  writable: bool,
+}
+
+impl Bank {
+  /// Creates a read only bank from an image. The image is padded with zeroes
+  /// up to a whole number of pages.
+  pub fn rom(name: &str, data: Vec<u8>, page_size: usize) -> Bank {
+    debug!("Creating {} byte ROM bank {}", data.len(), name);
+    Bank::from_image(name, data, page_size, false)
+  }
+
+  /// Creates a zero filled, writable bank with the given number of pages.
+  pub fn ram(name: &str, pages: usize, page_size: usize) -> Bank {
+    debug!("Creating {} page RAM bank {}", pages, name);
+    Bank::from_image(name, vec![0; pages * page_size], page_size, true)
+  }
+
+  fn from_image(name: &str, mut data: Vec<u8>, page_size: usize, writable: bool) -> Bank {
+    let pages = data.len().div_ceil(page_size).max(1);
+    data.resize(pages * page_size, 0);
+    Bank {
+      name: name.to_string(),
+      data,
+      page_size,
+      writable,
+    }
+  }
+
+  /// Gets the name of the bank.
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Gets the number of pages in the bank.
+  pub fn pages(&self) -> usize {
+    self.data.len() / self.page_size
+  }
+
+  /// Gets the size of a single page.
+  pub fn page_size(&self) -> usize {
+    self.page_size
+  }
+
+  /// Returns true if this is a RAM bank.
+  pub fn is_writable(&self) -> bool {
+    self.writable
+  }
+
+  /// Gets the backing image, for saving battery backed RAM for example.
+  pub fn data(&self) -> &[u8] {
+    &self.data
+  }
+
+  fn index(&self, page: usize, offset: usize) -> usize {
+    (page % self.pages()) * self.page_size + offset % self.page_size
+  }
+
+  /// Reads the value at an offset into a page.
+  pub fn read(&self, page: usize, offset: usize) -> u8 {
+    self.data[self.index(page, offset)]
+  }
+
+  /// Writes a value at an offset into a page. Returns false and leaves the
+  /// bank untouched if this is a ROM bank.
+  pub fn write(&mut self, page: usize, offset: usize, value: u8) -> bool {
+    if !self.writable {
+      trace!("Ignoring write to ROM bank {}", self.name);
+      return false;
+    }
+    let index = self.index(page, offset);
+    self.data[index] = value;
+    true
+  }
+}
+
+/// An address range that shows one page of a bank.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Window {
+  start: u16,
+  bank: usize,
+  page: usize,
+}
+
+/// The building block mappers are made out of. Holds a set of banks and the
+/// windows they are currently visible through.
+///
+/// A window is always the size of a page of the bank it shows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BankSwitcher {
+  banks: Vec<Bank>,
+  windows: Vec<Window>,
+}
+
+impl BankSwitcher {
+  /// Creates an empty switcher with no banks or windows.
+  pub fn new() -> BankSwitcher {
+    BankSwitcher::default()
+  }
+
+  /// Adds a bank, returning its id.
+  pub fn add_bank(&mut self, bank: Bank) -> usize {
+    self.banks.push(bank);
+    self.banks.len() - 1
+  }
+
+  /// Looks up a bank by name.
+  pub fn get_bank(&self, name: &str) -> Option<&Bank> {
+    self.banks.iter().find(|b| b.name == name)
+  }
+
+  /// Maps a page of a bank into the address space starting at the given index,
+  /// returning the id of the new window.
+  ///
+  /// # Panics
+  /// Panics if the bank does not exist or the window would run past 0xFFFF.
+  pub fn map(&mut self, start: u16, bank: usize, page: usize) -> usize {
+    let size = self.banks[bank].page_size;
+    if start as usize + size > 0x10000 {
+      panic!("Window at {:X} does not fit in the address space", start);
+    }
+    debug!(
+      "Mapping page {} of bank {} at {:X}",
+      page, self.banks[bank].name, start
+    );
+    self.windows.push(Window { start, bank, page });
+    self.windows.len() - 1
+  }
+
+  /// Switches the page a window shows.
+  pub fn switch(&mut self, window: usize, page: usize) {
+    let w = &mut self.windows[window];
+    debug!("Switching window at {:X} to page {}", w.start, page);
+    w.page = page;
+  }
+
+  /// Gets the page a window is currently showing.
+  pub fn get_page(&self, window: usize) -> usize {
+    self.windows[window].page
+  }
+
+  /// Gets the number of pages in a bank.
+  pub fn pages(&self, bank: usize) -> usize {
+    self.banks[bank].pages()
+  }
+
+  /// Finds the window containing an index, and the offset into it.
+  fn find(&self, index: u16) -> Option<(usize, usize)> {
+    self.windows.iter().enumerate().find_map(|(i, w)| {
+      let offset = (index as usize).wrapping_sub(w.start as usize);
+      match offset < self.banks[w.bank].page_size {
+        true => Some((i, offset)),
+        false => None,
+      }
+    })
+  }
+
+  /// Reads through whichever window contains the index.
+  pub fn read(&self, index: u16) -> Option<u8> {
+    let (window, offset) = self.find(index)?;
+    let w = &self.windows[window];
+    Some(self.banks[w.bank].read(w.page, offset))
+  }
+
+  /// Writes through whichever window contains the index. Returns true if a
+  /// window contains the index, even if it shows ROM and the write is dropped.
+  pub fn write(&mut self, index: u16, value: u8) -> bool {
+    match self.find(index) {
+      Some((window, offset)) => {
+        let w = self.windows[window].clone();
+        self.banks[w.bank].write(w.page, offset, value);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  fn numbered_pages(pages: usize, size: usize) -> Vec<u8> {
+    (0..pages).flat_map(|p| vec![p as u8; size]).collect()
+  }
+
+  #[test]
+  fn mappers_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<Box<dyn Mapper>>();
+  }
+
+  #[test]
+  fn bank_pads_image() {
+    let bank = Bank::rom("prg", vec![1, 2, 3], 0x10);
+    assert_eq!(bank.pages(), 1);
+    assert_eq!(bank.data().len(), 0x10);
+  }
+
+  #[test_case(0, 0; "First page")]
+  #[test_case(3, 3; "Last page")]
+  #[test_case(5, 1; "Wraps")]
+  fn bank_read(page: usize, expected: u8) {
+    let bank = Bank::rom("prg", numbered_pages(4, 0x10), 0x10);
+    assert_eq!(bank.read(page, 0x4), expected);
+  }
+
+  #[test]
+  fn rom_bank_ignores_writes() {
+    let mut bank = Bank::rom("prg", vec![0x42; 0x10], 0x10);
+    assert!(!bank.write(0, 0, 0));
+    assert_eq!(bank.read(0, 0), 0x42);
+  }
+
+  #[test]
+  fn ram_bank_write() {
+    let mut bank = Bank::ram("wram", 2, 0x10);
+    assert!(bank.write(1, 3, 0x42));
+    assert_eq!(bank.read(1, 3), 0x42);
+    assert_eq!(bank.read(0, 3), 0);
+  }
+
+  #[test]
+  fn switcher_read_through_window() {
+    let mut switcher = BankSwitcher::new();
+    let bank = switcher.add_bank(Bank::rom("prg", numbered_pages(4, 0x100), 0x100));
+    let window = switcher.map(0x8000, bank, 0);
+    assert_eq!(switcher.read(0x8010), Some(0));
+    switcher.switch(window, 2);
+    assert_eq!(switcher.read(0x8010), Some(2));
+    assert_eq!(switcher.read(0x8100), None);
+    assert_eq!(switcher.read(0x7FFF), None);
+  }
+
+  #[test]
+  fn switcher_write_through_window() {
+    let mut switcher = BankSwitcher::new();
+    let bank = switcher.add_bank(Bank::ram("wram", 2, 0x100));
+    let window = switcher.map(0x6000, bank, 1);
+    assert!(switcher.write(0x6001, 0x42));
+    switcher.switch(window, 0);
+    assert_eq!(switcher.read(0x6001), Some(0));
+    switcher.switch(window, 1);
+    assert_eq!(switcher.read(0x6001), Some(0x42));
+    assert!(!switcher.write(0x5FFF, 0x42));
+  }
+
+  #[test]
+  #[should_panic]
+  fn switcher_map_past_end() {
+    let mut switcher = BankSwitcher::new();
+    let bank = switcher.add_bank(Bank::ram("wram", 1, 0x100));
+    switcher.map(0xFF80, bank, 0);
+  }
+}
@@ -0,0 +1,76 @@
+use crate::mappers::{Bank, BankSwitcher, Mapper, BANK_16K, BANK_8K};
+use crate::prelude::*;
+
+/// Mapper 0. No bank switching at all.
+///
+/// 16K or 32K of PRG ROM at 0x8000, with 16K images mirrored into 0xC000, and
+/// 8K of PRG RAM at 0x6000.
+#[derive(Clone)]
+pub struct Nrom {
+  banks: BankSwitcher,
+}
+
+impl Nrom {
+  /// Creates a new NROM board from a PRG ROM image.
+  pub fn new(prg: Vec<u8>) -> Nrom {
+    debug!("Initializing NROM with {} bytes of PRG", prg.len());
+    let mut banks = BankSwitcher::new();
+    let ram = banks.add_bank(Bank::ram("prg_ram", 1, BANK_8K));
+    let rom = banks.add_bank(Bank::rom("prg_rom", prg, BANK_16K));
+    banks.map(0x6000, ram, 0);
+    banks.map(0x8000, rom, 0);
+    banks.map(0xC000, rom, 1);
+    Nrom { banks }
+  }
+}
+
+impl Mapper for Nrom {
+  fn name(&self) -> &str {
+    "NROM"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    self.banks.read(index)
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    self.banks.write(index, value)
+  }
+
+  fn reset(&mut self) {}
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(self.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mirrors_16k() {
+    let mut prg = vec![0; BANK_16K];
+    prg[0x10] = 0x42;
+    let nrom = Nrom::new(prg);
+    assert_eq!(nrom.read(0x8010), Some(0x42));
+    assert_eq!(nrom.read(0xC010), Some(0x42));
+  }
+
+  #[test]
+  fn maps_32k() {
+    let mut prg = vec![0; BANK_16K * 2];
+    prg[BANK_16K + 0x10] = 0x42;
+    let nrom = Nrom::new(prg);
+    assert_eq!(nrom.read(0x8010), Some(0));
+    assert_eq!(nrom.read(0xC010), Some(0x42));
+  }
+
+  #[test]
+  fn prg_ram() {
+    let mut nrom = Nrom::new(vec![0; BANK_16K]);
+    assert!(nrom.write(0x6000, 0x42));
+    assert_eq!(nrom.read(0x6000), Some(0x42));
+    assert_eq!(nrom.read(0x5FFF), None);
+  }
+}
@@ -0,0 +1,87 @@
+use crate::mappers::{Bank, BankSwitcher, Mapper, BANK_16K};
+use crate::prelude::*;
+
+/// Mapper 2. A switchable 16K bank at 0x8000 and the last 16K bank fixed at
+/// 0xC000. Any write to 0x8000-0xFFFF selects the bank at 0x8000.
+#[derive(Clone)]
+pub struct Uxrom {
+  banks: BankSwitcher,
+  switchable: usize,
+}
+
+impl Uxrom {
+  /// Creates a new UxROM board from a PRG ROM image.
+  pub fn new(prg: Vec<u8>) -> Uxrom {
+    debug!("Initializing UxROM with {} bytes of PRG", prg.len());
+    let mut banks = BankSwitcher::new();
+    let rom = banks.add_bank(Bank::rom("prg_rom", prg, BANK_16K));
+    let last = banks.pages(rom) - 1;
+    let switchable = banks.map(0x8000, rom, 0);
+    banks.map(0xC000, rom, last);
+    Uxrom { banks, switchable }
+  }
+
+  /// Gets the bank currently mapped at 0x8000.
+  pub fn get_bank(&self) -> usize {
+    self.banks.get_page(self.switchable)
+  }
+}
+
+impl Mapper for Uxrom {
+  fn name(&self) -> &str {
+    "UxROM"
+  }
+
+  fn read(&self, index: u16) -> Option<u8> {
+    self.banks.read(index)
+  }
+
+  fn write(&mut self, index: u16, value: u8) -> bool {
+    if index >= 0x8000 {
+      self.banks.switch(self.switchable, value as usize);
+      return true;
+    }
+    self.banks.write(index, value)
+  }
+
+  fn reset(&mut self) {
+    self.banks.switch(self.switchable, 0);
+  }
+
+  fn box_clone(&self) -> Box<dyn Mapper> {
+    Box::new(self.clone())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn prg() -> Vec<u8> {
+    (0..8).flat_map(|p| vec![p as u8; BANK_16K]).collect()
+  }
+
+  #[test]
+  fn power_on() {
+    let uxrom = Uxrom::new(prg());
+    assert_eq!(uxrom.read(0x8000), Some(0));
+    assert_eq!(uxrom.read(0xC000), Some(7));
+  }
+
+  #[test]
+  fn switch() {
+    let mut uxrom = Uxrom::new(prg());
+    assert!(uxrom.write(0xFFFF, 3));
+    assert_eq!(uxrom.get_bank(), 3);
+    assert_eq!(uxrom.read(0xBFFF), Some(3));
+    assert_eq!(uxrom.read(0xC000), Some(7));
+  }
+
+  #[test]
+  fn reset() {
+    let mut uxrom = Uxrom::new(prg());
+    uxrom.write(0x8000, 5);
+    uxrom.reset();
+    assert_eq!(uxrom.read(0x8000), Some(0));
+  }
+}
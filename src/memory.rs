@@ -1,5 +1,8 @@
+use crate::devices::{Device, DeviceId};
+use crate::mappers::Mapper;
+use crate::prelude::*;
 use crate::StackPointer;
-use log::{debug, error, trace};
+use core::ops::{Deref, DerefMut};
 
 /// 16 bits worth of screaming fast memory.
 const MEMORY_MAX: usize = 0x10000;
@@ -22,9 +25,42 @@ const STACK_MAX: u16 = 0x1FF;
 /// register had 0xFF, we would get a result of 0x13 and 0x33. As 0x13 was
 /// incremented, a page boundary crossing occurred and we must tell the CPU.
 ///
+/// ## Bank switching
+/// A mapper can be installed in front of memory. Every access is offered to
+/// the mapper first, and only falls through to devices and then the flat
+/// array if the mapper does not claim the address. Cloning memory clones the
+/// mapper along with its bank selection, so a clone is a complete snapshot.
+///
+/// ## Devices
+/// Peripheral chips can be mapped into ranges of addresses. Devices are
+/// checked after the mapper, so a mapper can bank them out, and claim every
+/// access in their range. Reads made by the CPU can change a device's state,
+/// so `get_u16` and friends take memory mutably, while `peek` reads without
+/// disturbing anything.
+///
+/// ## Journaling
+/// With journaling turned on every write is recorded, in order, until the
+/// journal is taken. Replaying a journal on top of a snapshot rebuilds memory
+/// and mapper state, as the mapper sees the same writes again. Writes that land
+/// on devices are skipped, since devices also change on reads and as time
+/// passes, so only a snapshot brings a device back to an earlier state.
+///
+/// ## Backing
+/// By default memory allocates its own 64K. A buffer can be supplied instead,
+/// such as a static array on a microcontroller. Buffers smaller than 64K are
+/// mirrored through the address space, as on boards that leave the upper
+/// address lines undecoded. Snapshots always copy into memory of their own,
+/// and restoring one copies back into the supplied buffer.
+#[derive(Clone)]
 pub struct Memory {
-  mem: [u8; MEMORY_MAX],
+  mem: Backing,
+  mask: usize,
   sp: StackPointer,
+  mapper: Option<Box<dyn Mapper>>,
+  devices: Vec<Box<dyn Device>>,
+  mappings: Vec<Mapping>,
+  irq_connected: Vec<bool>,
+  journal: Option<Vec<(u16, u8)>>,
 }
 
 impl Memory {
@@ -32,48 +68,287 @@ impl Memory {
   pub fn new() -> Memory {
     debug!("Initializing new memory");
     Memory {
-      mem: [0; MEMORY_MAX],
+      mem: Backing::Owned(vec![0; MEMORY_MAX].into_boxed_slice()),
+      mask: MEMORY_MAX - 1,
+      sp: StackPointer::new(),
+      mapper: None,
+      devices: vec![],
+      mappings: vec![],
+      irq_connected: vec![],
+      journal: None,
+    }
+  }
+
+  /// Creates memory backed by a buffer supplied by the caller. The buffer is
+  /// used as it is, without clearing it first.
+  ///
+  /// # Panics
+  /// Panics if the buffer's length is not a power of two between 1 and 64K.
+  pub fn with_backing(buffer: &'static mut [u8]) -> Memory {
+    let length = buffer.len();
+    if !length.is_power_of_two() || length > MEMORY_MAX {
+      panic!("Memory backing must be a power of two no larger than 64K");
+    }
+    debug!("Initializing memory backed by {} bytes", length);
+    Memory {
+      mem: Backing::Borrowed(buffer),
+      mask: length - 1,
       sp: StackPointer::new(),
+      mapper: None,
+      devices: vec![],
+      mappings: vec![],
+      irq_connected: vec![],
+      journal: None,
     }
   }
 
-  /// Resets the memory to the base state.
+  /// Resets the memory to the base state. Any mapper stays installed but is
+  /// returned to its power on bank selection.
   pub fn reset(&mut self) {
     debug!("Resetting memory");
-    self.mem = [0; MEMORY_MAX];
+    self.mem.fill(0);
     self.sp.reset();
+    if let Some(mapper) = self.mapper.as_mut() {
+      mapper.reset();
+    }
+    for device in self.devices.iter_mut() {
+      device.reset();
+    }
+  }
+
+  /// Installs a mapper in front of memory, replacing any existing one.
+  pub fn set_mapper(&mut self, mapper: Box<dyn Mapper>) {
+    debug!("Installing {} mapper", mapper.name());
+    self.mapper = Some(mapper);
+  }
+
+  /// Removes the installed mapper, if any, and returns it.
+  pub fn take_mapper(&mut self) -> Option<Box<dyn Mapper>> {
+    self.mapper.take()
+  }
+
+  /// Gets the installed mapper, if any.
+  pub fn get_mapper(&self) -> Option<&dyn Mapper> {
+    self.mapper.as_deref()
+  }
+
+  /// Maps a device into the addresses from start to end, inclusive. The
+  /// first address is the device's register 0. Devices mapped earlier win
+  /// where ranges overlap.
+  pub fn map_device(&mut self, start: u16, end: u16, device: Box<dyn Device>) -> DeviceId {
+    let id = DeviceId(self.devices.len());
+    self.devices.push(device);
+    self.irq_connected.push(true);
+    self.mirror_device(id, start, end, 0);
+    id
+  }
+
+  /// Maps another range of addresses to a device that is already mapped,
+  /// with the first address as the given register. Used for chips whose
+  /// registers are spread over more than one range, or mirrored by partial
+  /// address decoding.
+  ///
+  /// # Panics
+  /// Panics if the device was never mapped.
+  pub fn mirror_device(&mut self, id: DeviceId, start: u16, end: u16, register: u16) {
+    assert!(id.0 < self.devices.len(), "Device {} was never mapped", id.0);
+    debug!(
+      "Mapping {} register {:X} at {:X}-{:X}",
+      self.devices[id.0].name(),
+      register,
+      start,
+      end
+    );
+    self.mappings.push(Mapping {
+      start,
+      end,
+      device: id.0,
+      register,
+    });
+  }
+
+  /// Leaves a device's interrupt output unconnected, for boards that don't
+  /// wire it to the IRQ pin.
+  ///
+  /// # Panics
+  /// Panics if the device was never mapped.
+  pub fn disconnect_irq(&mut self, id: DeviceId) {
+    debug!("Disconnecting {} from IRQ", self.devices[id.0].name());
+    self.irq_connected[id.0] = false;
+  }
+
+  /// Gets a mapped device if it is a `T`.
+  pub fn get_device<T: Device + 'static>(&self, id: DeviceId) -> Option<&T> {
+    self.devices.get(id.0)?.as_any().downcast_ref()
+  }
+
+  /// Gets a mapped device if it is a `T`.
+  pub fn get_device_mut<T: Device + 'static>(&mut self, id: DeviceId) -> Option<&mut T> {
+    self.devices.get_mut(id.0)?.as_any_mut().downcast_mut()
+  }
+
+  /// Advances every device by one cycle. Returns true if any of them is
+  /// holding the IRQ line.
+  pub fn tick_devices(&mut self) -> bool {
+    let mut irq = false;
+    for (device, connected) in self.devices.iter_mut().zip(&self.irq_connected) {
+      device.tick();
+      irq |= *connected && device.irq();
+    }
+    irq
+  }
+
+  /// Finds the device claiming an index, if any, along with the register
+  /// the index selects.
+  fn find_device(&self, index: u16) -> Option<(usize, u16)> {
+    self
+      .mappings
+      .iter()
+      .find(|m| (m.start..=m.end).contains(&index))
+      .map(|m| (m.device, m.register.wrapping_add(index - m.start)))
+  }
+
+  /// Returns true if any devices are mapped.
+  pub fn has_devices(&self) -> bool {
+    !self.mappings.is_empty()
+  }
+
+  /// Takes a snapshot of memory, including the stack pointer and the state of
+  /// the mapper and devices.
+  pub fn snapshot(&self) -> Memory {
+    self.clone()
+  }
+
+  /// Restores memory to a previously taken snapshot. Journaling stays as it
+  /// was before the restore.
+  pub fn restore(&mut self, snapshot: &Memory) {
+    debug!("Restoring memory snapshot");
+    match self.mem.len() == snapshot.mem.len() {
+      true => self.mem.copy_from_slice(&snapshot.mem),
+      false => self.mem = snapshot.mem.clone(),
+    }
+    self.mask = snapshot.mask;
+    self.sp = snapshot.sp.clone();
+    self.mapper = snapshot.mapper.clone();
+    self.devices = snapshot.devices.clone();
+    self.mappings = snapshot.mappings.clone();
+    self.irq_connected = snapshot.irq_connected.clone();
+  }
+
+  /// Turns journaling of writes on or off. Turning it off discards the
+  /// journal.
+  pub fn set_journaling(&mut self, journaling: bool) {
+    self.journal = match journaling {
+      true => self.journal.take().or_else(|| Some(vec![])),
+      false => None,
+    };
+  }
+
+  /// Takes the writes journaled since the last call, oldest first, as pairs
+  /// of index and value.
+  pub fn take_journal(&mut self) -> Vec<(u16, u8)> {
+    self
+      .journal
+      .as_mut()
+      .map(core::mem::take)
+      .unwrap_or_default()
+  }
+
+  /// Replays journaled writes. The mapper sees them as it did the first time,
+  /// but writes to devices are skipped. Replayed writes are not journaled.
+  pub fn replay(&mut self, writes: &[(u16, u8)]) {
+    for (index, value) in writes {
+      if let Some(mapper) = self.mapper.as_mut() {
+        if mapper.write(*index, *value) {
+          continue;
+        }
+      }
+      if self.find_device(*index).is_none() {
+        self.mem[*index as usize & self.mask] = *value;
+      }
+    }
+  }
+
+  /// Reads a value as the CPU does, giving the mapper and then devices the
+  /// first chance to claim the index.
+  fn read(&mut self, index: u16) -> u8 {
+    if let Some(value) = self.mapper.as_ref().and_then(|m| m.read(index)) {
+      return value;
+    }
+    if let Some((device, register)) = self.find_device(index) {
+      return self.devices[device].read(register);
+    }
+    self.mem[index as usize & self.mask]
+  }
+
+  /// Reads a value without side effects on any device.
+  fn inspect(&self, index: u16) -> u8 {
+    if let Some(value) = self.mapper.as_ref().and_then(|m| m.read(index)) {
+      return value;
+    }
+    if let Some((device, register)) = self.find_device(index) {
+      return self.devices[device].peek(register);
+    }
+    self.mem[index as usize & self.mask]
+  }
+
+  /// Writes a value, giving the mapper and then devices the first chance to
+  /// claim the index.
+  fn write(&mut self, index: u16, value: u8) {
+    if let Some(journal) = self.journal.as_mut() {
+      journal.push((index, value));
+    }
+    if let Some(mapper) = self.mapper.as_mut() {
+      if mapper.write(index, value) {
+        return;
+      }
+    }
+    if let Some((device, register)) = self.find_device(index) {
+      self.devices[device].write(register, value);
+      return;
+    }
+    self.mem[index as usize & self.mask] = value;
   }
 
   /// Sets an index to a value. Logs an error if this overwrites the stack.
   pub fn set(&mut self, index: u16, value: u8) {
-    if index <= STACK_MAX && index >= STACK_MIN {
+    if (STACK_MIN..=STACK_MAX).contains(&index) {
       error!("Accessing memory from the stack improperly!");
     }
-    trace!("Setting value at index: {:X} to {:X}", index, value);
-    self.mem[index as usize] = value;
+    self.write(index, value);
   }
 
   /// Sets memory in the zero page. This takes less machine cycles than a normal write
   /// so we have a specific method to preserve cycle timing.
   pub fn set_zero_page(&mut self, index: u8, value: u8) {
-    trace!("Setting zero page at index: {:X} to {:X}", index, value);
-    self.mem[index as usize] = value;
+    self.write(index as u16, value);
   }
 
   /// Gets memory from the zero page. This takes less machine cycles than a normal read
   /// so we have a sepcific method to preserve cycle timing.
-  pub fn get_zero_page(&self, index: u8) -> u8 {
-    trace!("Getting value at index: {:X}", index);
-    self.mem[index as usize]
+  pub fn get_zero_page(&mut self, index: u8) -> u8 {
+    self.read(index as u16)
   }
 
   /// Gets the value at an index. Logs an error if this reads from the stack.
-  pub fn get_u16(&self, index: u16) -> u8 {
-    if index <= STACK_MAX && index >= STACK_MIN {
+  pub fn get_u16(&mut self, index: u16) -> u8 {
+    if (STACK_MIN..=STACK_MAX).contains(&index) {
       error!("Accessing memory from the stack improperly!");
     }
-    trace!("Getting value at index: {:X}", index);
-    self.mem[index as usize]
+    self.read(index)
+  }
+
+  /// Gets the value at an index without logging, and without disturbing any
+  /// device. Used by debugging tools that inspect memory rather than the
+  /// program under execution.
+  pub fn peek(&self, index: u16) -> u8 {
+    self.inspect(index)
+  }
+
+  /// Sets the value at an index without logging. The write still goes through
+  /// any installed mapper, just as a write from the CPU would.
+  pub fn poke(&mut self, index: u16, value: u8) {
+    self.write(index, value);
   }
 
   /// Adds a value to the stack. Takes in a value to be entered and the stack pointer.
@@ -81,17 +356,18 @@ impl Memory {
   /// logical OR the pointer val with 0x100 to get a value between 0x100 & 0x1FF.
   pub fn push_to_stack(&mut self, value: u8) {
     debug!("Pushing {:X} to stack", value);
-    let index = (STACK_MIN | self.sp.push()) as usize;
-    self.mem[index] = value;
+    let index = STACK_MIN | self.sp.push();
+    self.write(index, value);
   }
 
   /// Takes a value from the stack. Returns the value at the current stack pointer.
   /// We store our stack pointer as a u8, and our stack index starts at 0x100. So we
   /// logical OR the pointer val with 0x100 to get a value between 0x100 & 0x1FF.
   pub fn pop_from_stack(&mut self) -> u8 {
-    let index = (STACK_MIN | self.sp.pop()) as usize;
-    debug!("Popping {:X} from stack", self.mem[index]);
-    self.mem[index]
+    let index = STACK_MIN | self.sp.pop();
+    let value = self.read(index);
+    debug!("Popping {:X} from stack", value);
+    value
   }
 
   /// Gets our instance of the stack pointer
@@ -105,6 +381,57 @@ impl Memory {
   }
 }
 
+/// A range of addresses claimed by a device, and the register the first
+/// address selects.
+#[derive(Clone)]
+struct Mapping {
+  start: u16,
+  end: u16,
+  device: usize,
+  register: u16,
+}
+
+/// Where the bytes of memory live.
+enum Backing {
+  /// Allocated by memory itself.
+  Owned(Box<[u8]>),
+  /// Supplied by the caller.
+  Borrowed(&'static mut [u8]),
+}
+
+impl Deref for Backing {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self {
+      Backing::Owned(bytes) => bytes,
+      Backing::Borrowed(bytes) => bytes,
+    }
+  }
+}
+
+impl DerefMut for Backing {
+  fn deref_mut(&mut self) -> &mut [u8] {
+    match self {
+      Backing::Owned(bytes) => bytes,
+      Backing::Borrowed(bytes) => bytes,
+    }
+  }
+}
+
+/// Clones always own their bytes, as a borrowed buffer can't be shared.
+impl Clone for Backing {
+  fn clone(&self) -> Self {
+    Backing::Owned(self.to_vec().into_boxed_slice())
+  }
+}
+
+impl Default for Memory {
+  fn default() -> Self {
+    Memory::new()
+  }
+}
+
 impl Eq for Memory {}
 
 impl PartialEq for Memory {
@@ -113,16 +440,29 @@ impl PartialEq for Memory {
     let i2 = other.mem.iter().map(|x| *x as usize);
     let s1: usize = i1.sum();
     let s2: usize = i2.sum();
-    return self.sp == other.sp && s1 == s2;
+    self.sp == other.sp && s1 == s2
   }
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::devices::Via;
+  use crate::mappers::{Bank, Latch};
   use rand::random;
   use test_case::test_case;
 
+  fn latched_memory() -> Memory {
+    let image = (0..4).flat_map(|p| vec![p as u8; 0x100]).collect();
+    let mut memory = Memory::new();
+    memory.set_mapper(Box::new(Latch::new(
+      Bank::rom("rom", image, 0x100),
+      0x8000,
+      0x7000,
+    )));
+    memory
+  }
+
   #[test]
   fn new() {
     let memory = Memory::new();
@@ -178,4 +518,172 @@ mod tests {
     let result = memory.pop_from_stack();
     assert_eq!(result, value);
   }
+
+  #[test]
+  fn mapper_claims_reads_and_writes() {
+    let mut memory = latched_memory();
+    memory.set(0x7000, 2);
+    assert_eq!(memory.get_u16(0x8000), 2);
+    assert_eq!(memory.mem[0x7000], 0);
+    memory.set(0x9000, 0x42);
+    assert_eq!(memory.get_u16(0x9000), 0x42);
+  }
+
+  /// Memory with a VIA at 0x6000, its timer 1 set to time out after 3 cycles.
+  fn via_memory() -> Memory {
+    let mut memory = Memory::new();
+    memory.map_device(0x6000, 0x600F, Box::new(Via::new()));
+    memory.set(0x600E, 0xC0);
+    memory.set(0x6004, 0x02);
+    memory.set(0x6005, 0x00);
+    memory
+  }
+
+  #[test]
+  fn device_claims_range() {
+    let mut memory = via_memory();
+    let value = random();
+    memory.set(0x6003, value);
+    assert_eq!(memory.get_u16(0x6003), value);
+    assert_eq!(memory.mem[0x6003], 0);
+    memory.set(0x6010, value);
+    assert_eq!(memory.mem[0x6010], value);
+  }
+
+  #[test]
+  fn replay_skips_devices() {
+    let mut memory = via_memory();
+    memory.set_journaling(true);
+    memory.replay(&[(0x6003, 0xFF), (0x6010, 0x42)]);
+    assert_eq!(memory.peek(0x6003), 0);
+    assert_eq!(memory.peek(0x6010), 0x42);
+    assert!(memory.take_journal().is_empty());
+  }
+
+  #[test]
+  fn device_reads_have_side_effects() {
+    let mut memory = via_memory();
+    assert!(!memory.tick_devices());
+    assert!(!memory.tick_devices());
+    assert!(memory.tick_devices());
+    memory.peek(0x6004);
+    assert!(memory.tick_devices());
+    memory.get_u16(0x6004);
+    assert!(!memory.tick_devices());
+  }
+
+  #[test]
+  fn disconnected_irq() {
+    let mut memory = via_memory();
+    memory.disconnect_irq(DeviceId(0));
+    for _ in 0..4 {
+      assert!(!memory.tick_devices());
+    }
+    let via: &Via = memory.get_device(DeviceId(0)).unwrap();
+    assert_ne!(via.get_interrupt_flags(), 0);
+  }
+
+  #[test]
+  fn mirrored_device_registers() {
+    let mut memory = Memory::new();
+    let id = memory.map_device(0x6000, 0x6003, Box::new(Via::new()));
+    memory.mirror_device(id, 0x7000, 0x7001, 0x2);
+    let value = random();
+    memory.set(0x7001, value);
+    assert_eq!(memory.get_u16(0x6003), value);
+    assert_eq!(memory.mem[0x7001], 0);
+  }
+
+  #[test]
+  #[should_panic(expected = "never mapped")]
+  fn mirror_unmapped_device() {
+    let mut memory = Memory::new();
+    memory.mirror_device(DeviceId(0), 0x7000, 0x7001, 0);
+  }
+
+  #[test]
+  fn snapshot_keeps_devices() {
+    let mut memory = via_memory();
+    let snapshot = memory.snapshot();
+    for _ in 0..3 {
+      memory.tick_devices();
+    }
+    memory.restore(&snapshot);
+    assert!(!memory.tick_devices());
+    let via: &Via = memory.get_device(DeviceId(0)).unwrap();
+    assert_eq!(via.get_interrupt_flags(), 0);
+  }
+
+  #[test]
+  fn reset_keeps_mapper() {
+    let mut memory = latched_memory();
+    memory.set(0x7000, 2);
+    memory.reset();
+    assert!(memory.get_mapper().is_some());
+    assert_eq!(memory.get_u16(0x8000), 0);
+  }
+
+  #[test]
+  fn snapshot_includes_mapper() {
+    let mut memory = latched_memory();
+    memory.set(0x7000, 1);
+    memory.set(0x10, 0x42);
+    let snapshot = memory.snapshot();
+    memory.set(0x7000, 3);
+    memory.set(0x10, 0);
+    memory.restore(&snapshot);
+    assert_eq!(memory.get_u16(0x8000), 1);
+    assert_eq!(memory.get_zero_page(0x10), 0x42);
+  }
+
+  #[test]
+  fn journal_replays_onto_snapshot() {
+    let mut memory = latched_memory();
+    let snapshot = memory.snapshot();
+    memory.set_journaling(true);
+    memory.set(0x7000, 2);
+    memory.push_to_stack(0x42);
+    let journal = memory.take_journal();
+    assert_eq!(journal, vec![(0x7000, 2), (STACK_MAX, 0x42)]);
+    let mut replayed = snapshot;
+    for (index, value) in journal {
+      replayed.poke(index, value);
+    }
+    assert_eq!(replayed.get_u16(0x8000), 2);
+    assert_eq!(replayed.get_u16(STACK_MAX), 0x42);
+    assert!(memory.take_journal().is_empty());
+  }
+
+  fn buffer(length: usize) -> &'static mut [u8] {
+    Box::leak(vec![0; length].into_boxed_slice())
+  }
+
+  #[test]
+  fn backing_is_mirrored() {
+    let mut memory = Memory::with_backing(buffer(0x800));
+    memory.poke(0x0010, 0x42);
+    assert_eq!(memory.peek(0x0810), 0x42);
+    assert_eq!(memory.peek(0xF810), 0x42);
+    memory.poke(0xFFFF, 0x24);
+    assert_eq!(memory.peek(0x07FF), 0x24);
+  }
+
+  #[test]
+  fn restore_into_backing() {
+    let mut memory = Memory::with_backing(buffer(0x10000));
+    memory.poke(0x1234, 1);
+    let snapshot = memory.snapshot();
+    memory.poke(0x1234, 2);
+    memory.restore(&snapshot);
+    assert_eq!(memory.peek(0x1234), 1);
+    assert!(matches!(memory.mem, Backing::Borrowed(_)));
+  }
+
+  #[test_case(0; "Empty")]
+  #[test_case(0x3000; "Not a power of two")]
+  #[test_case(0x20000; "Too large")]
+  #[should_panic]
+  fn backing_size(length: usize) {
+    Memory::with_backing(buffer(length));
+  }
 }
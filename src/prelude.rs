@@ -0,0 +1,7 @@
+//! The parts of the standard prelude that live in `alloc`, so modules build
+//! the same with and without the `std` feature.
+
+pub use alloc::boxed::Box;
+pub use alloc::string::{String, ToString};
+pub use alloc::vec::Vec;
+pub use alloc::{format, vec};
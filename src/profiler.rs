@@ -0,0 +1,403 @@
+use crate::call_stack::FrameKind;
+use crate::disassembler::Instruction;
+use crate::prelude::*;
+use crate::symbols::SymbolTable;
+use crate::CPU;
+use alloc::collections::BTreeMap;
+
+/// Jump to SubRoutine.
+const JSR: u8 = 0x20;
+/// ReTurn from Subroutine.
+const RTS: u8 = 0x60;
+/// BReaK, which enters the interrupt handler.
+const BRK: u8 = 0x00;
+/// ReTurn from Interrupt.
+const RTI: u8 = 0x40;
+/// The hardware stack holds at most 128 return addresses, so a deeper shadow
+/// stack means routines were left without returning, as with a JSR whose
+/// return address is pulled off the stack.
+const MAX_DEPTH: usize = 128;
+
+/// What was spent at a single instruction address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AddressProfile {
+  /// How many times the instruction ran.
+  pub count: u64,
+  /// Cycles spent running it.
+  pub cycles: u64,
+}
+
+/// What was spent in a routine, named by its entry address.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoutineProfile {
+  /// How many times the routine was called.
+  pub calls: u64,
+  /// Cycles spent in the routine and everything it called. Recursive calls
+  /// are only counted once.
+  pub inclusive: u64,
+  /// Cycles spent in the routine's own instructions.
+  pub exclusive: u64,
+}
+
+/// A distinct call stack seen while profiling.
+#[derive(Clone, Debug, Default)]
+struct Stack {
+  /// The routines on the stack, root first.
+  frames: Vec<u16>,
+  /// Each routine on the stack once, so recursion is only counted once.
+  routines: Vec<u16>,
+  /// Cycles spent with exactly this stack.
+  cycles: u64,
+}
+
+/// Accumulates where a program spends its time.
+///
+/// Each instruction run through the profiler is charged to its address and
+/// to the routines on a shadow call stack. The stack follows JSR and RTS, and
+/// BRK, IRQ, NMI and RTI for interrupt handlers. The routine running when
+/// profiling started is the root of the stack. Like the hardware stack, the
+/// shadow stack holds at most 128 routines, and the oldest are dropped to
+/// make room for more.
+#[derive(Clone, Debug, Default)]
+pub struct Profiler {
+  addresses: BTreeMap<u16, AddressProfile>,
+  routines: BTreeMap<u16, RoutineProfile>,
+  stacks: Vec<Stack>,
+  stack_ids: BTreeMap<Vec<u16>, usize>,
+  stack: Vec<u16>,
+  stack_id: Option<usize>,
+  instructions: u64,
+  cycles: u64,
+}
+
+impl Profiler {
+  /// Creates an empty profiler.
+  pub fn new() -> Profiler {
+    Profiler::default()
+  }
+
+  /// Executes and profiles a single instruction.
+  pub fn step(&mut self, cpu: &mut CPU) {
+    let mut address = cpu.get_registers().program_counter;
+    let depth = cpu.get_call_stack().get_frames().len();
+    let cycles = cpu.get_cycles();
+    let mut opcode = cpu.get_memory().peek(address);
+    cpu.step();
+    // An IRQ or NMI taken before the instruction leaves a new frame on the
+    // CPU's call stack, and the instruction that ran was the handler's first.
+    if let Some(frame) = cpu.get_call_stack().get_frames().get(depth) {
+      if matches!(frame.kind, FrameKind::Irq | FrameKind::Nmi) && frame.call_site == address {
+        self.interrupt(address, frame.target);
+        address = frame.target;
+        opcode = cpu.get_memory().peek(address);
+      }
+    }
+    let next = cpu.get_registers().program_counter;
+    self.record(address, opcode, cpu.get_cycles() - cycles, next);
+  }
+
+  /// Records an IRQ or NMI taken before the instruction at an address, which
+  /// enters the handler. The handler's RTI leaves it again.
+  pub fn interrupt(&mut self, address: u16, handler: u16) {
+    trace!("Profiling interrupt at {:X} to {:X}", address, handler);
+    if self.stack.is_empty() {
+      self.enter(address);
+    }
+    self.enter(handler);
+  }
+
+  /// Records an instruction that ran at an address, took a number of cycles
+  /// and left the program counter at `next`.
+  pub fn record(&mut self, address: u16, opcode: u8, cycles: u64, next: u16) {
+    trace!("Profiling {:X} for {} cycles", address, cycles);
+    if self.stack.is_empty() {
+      self.enter(address);
+    }
+    let profile = self.addresses.entry(address).or_default();
+    profile.count += 1;
+    profile.cycles += cycles;
+    self.instructions += 1;
+    self.cycles += cycles;
+    let top = *self.stack.last().unwrap_or(&address);
+    self.routines.entry(top).or_default().exclusive += cycles;
+    let id = self.stack_id();
+    let stack = &mut self.stacks[id];
+    stack.cycles += cycles;
+    for routine in &stack.routines {
+      self.routines.entry(*routine).or_default().inclusive += cycles;
+    }
+    match opcode {
+      JSR | BRK => self.enter(next),
+      RTS | RTI if self.stack.len() > 1 => {
+        self.stack.pop();
+        self.stack_id = None;
+      }
+      _ => (),
+    }
+  }
+
+  /// Pushes a routine onto the shadow call stack.
+  fn enter(&mut self, routine: u16) {
+    if self.stack.len() == MAX_DEPTH {
+      self.stack.remove(0);
+    }
+    self.stack.push(routine);
+    self.stack_id = None;
+    self.routines.entry(routine).or_default().calls += 1;
+  }
+
+  /// Gets the index of the current shadow stack in `stacks`, adding it the
+  /// first time it is seen. Looked up again only after the stack changes.
+  fn stack_id(&mut self) -> usize {
+    if let Some(id) = self.stack_id {
+      return id;
+    }
+    let stacks = &mut self.stacks;
+    let id = *self
+      .stack_ids
+      .entry(self.stack.clone())
+      .or_insert_with_key(|frames| {
+        let mut routines = frames.clone();
+        routines.sort_unstable();
+        routines.dedup();
+        stacks.push(Stack {
+          frames: frames.clone(),
+          routines,
+          cycles: 0,
+        });
+        stacks.len() - 1
+      });
+    self.stack_id = Some(id);
+    id
+  }
+
+  /// Throws away everything recorded so far.
+  pub fn reset(&mut self) {
+    debug!("Resetting profiler");
+    *self = Profiler::new();
+  }
+
+  /// Gets the number of instructions profiled.
+  pub fn get_instructions(&self) -> u64 {
+    self.instructions
+  }
+
+  /// Gets the number of cycles profiled.
+  pub fn get_cycles(&self) -> u64 {
+    self.cycles
+  }
+
+  /// Gets the profile of an instruction address.
+  pub fn get_address(&self, address: u16) -> Option<&AddressProfile> {
+    self.addresses.get(&address)
+  }
+
+  /// Gets the profile of a routine by its entry address.
+  pub fn get_routine(&self, address: u16) -> Option<&RoutineProfile> {
+    self.routines.get(&address)
+  }
+
+  /// Builds a text report of routines sorted by inclusive cycles, then
+  /// instruction addresses sorted by cycles. With a symbol table, addresses
+  /// are named and instructions are disassembled from the CPU's memory.
+  pub fn report(&self, cpu: Option<&CPU>, symbols: Option<&SymbolTable>) -> String {
+    let name = |address: u16| match symbols {
+      Some(symbols) => symbols.format_address(address),
+      None => format!("${:04X}", address),
+    };
+    let percent = |cycles: u64| match self.cycles {
+      0 => 0.0,
+      total => cycles as f64 * 100.0 / total as f64,
+    };
+    let mut text = format!(
+      "{} instructions, {} cycles\n\n",
+      self.instructions, self.cycles
+    );
+    text.push_str(&format!(
+      "{:<24} {:>8} {:>12} {:>7} {:>12} {:>7}\n",
+      "routine", "calls", "inclusive", "%", "exclusive", "%"
+    ));
+    let mut routines: Vec<_> = self.routines.iter().collect();
+    routines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+    for (address, routine) in routines {
+      text.push_str(&format!(
+        "{:<24} {:>8} {:>12} {:>6.2}% {:>12} {:>6.2}%\n",
+        name(*address),
+        routine.calls,
+        routine.inclusive,
+        percent(routine.inclusive),
+        routine.exclusive,
+        percent(routine.exclusive)
+      ));
+    }
+    text.push_str(&format!(
+      "\n{:<24} {:>8} {:>12} {:>7}  instruction\n",
+      "address", "count", "cycles", "%"
+    ));
+    let mut addresses: Vec<_> = self.addresses.iter().collect();
+    addresses.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(b.0)));
+    for (address, profile) in addresses {
+      let instruction = cpu
+        .map(|cpu| Instruction::read(cpu.get_memory(), *address).format(symbols))
+        .unwrap_or_default();
+      let line = format!(
+        "{:<24} {:>8} {:>12} {:>6.2}%  {}",
+        name(*address),
+        profile.count,
+        profile.cycles,
+        percent(profile.cycles),
+        instruction
+      );
+      text.push_str(line.trim_end());
+      text.push('\n');
+    }
+    text
+  }
+
+  /// Builds the collapsed stack format read by flamegraph tools. Each line is
+  /// a semicolon separated call stack, root first, followed by the cycles
+  /// spent with exactly that stack.
+  pub fn collapsed(&self, symbols: Option<&SymbolTable>) -> String {
+    let name = |address: &u16| match symbols {
+      Some(symbols) => symbols.format_address(*address),
+      None => format!("${:04X}", address),
+    };
+    let mut lines: Vec<String> = self
+      .stacks
+      .iter()
+      .filter(|stack| stack.cycles > 0)
+      .map(|stack| {
+        let frames: Vec<String> = stack.frames.iter().map(name).collect();
+        format!("{} {}", frames.join(";"), stack.cycles)
+      })
+      .collect();
+    lines.sort();
+    let mut text = lines.join("\n");
+    if !text.is_empty() {
+      text.push('\n');
+    }
+    text
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::mpsc;
+
+  // main: JSR sub; JSR sub; NOP
+  // sub: INX; RTS
+  const PROGRAM: [u8; 9] = [0x20, 0x07, 0x02, 0x20, 0x07, 0x02, 0xEA, 0xE8, 0x60];
+
+  fn profile(steps: usize) -> (CPU, Profiler) {
+    let (_, rx) = mpsc::channel();
+    let mut cpu = CPU::new(rx);
+    cpu.set_free_running(true);
+    for (i, byte) in PROGRAM.iter().enumerate() {
+      cpu.get_memory_mut().poke(0x0200 + i as u16, *byte);
+    }
+    let mut registers = cpu.get_registers();
+    registers.program_counter = 0x0200;
+    cpu.set_registers(registers);
+    let mut profiler = Profiler::new();
+    for _ in 0..steps {
+      profiler.step(&mut cpu);
+    }
+    (cpu, profiler)
+  }
+
+  #[test]
+  fn addresses() {
+    let (cpu, profiler) = profile(7);
+    assert_eq!(profiler.get_instructions(), 7);
+    assert_eq!(profiler.get_cycles(), cpu.get_cycles());
+    let inx = profiler.get_address(0x0207).unwrap();
+    assert_eq!(inx.count, 2);
+    assert!(inx.cycles > 0);
+    assert_eq!(profiler.get_address(0x0300), None);
+  }
+
+  #[test]
+  fn routines() {
+    let (_, profiler) = profile(7);
+    let main = profiler.get_routine(0x0200).unwrap();
+    let sub = profiler.get_routine(0x0207).unwrap();
+    assert_eq!(main.calls, 1);
+    assert_eq!(sub.calls, 2);
+    assert_eq!(main.inclusive, profiler.get_cycles());
+    assert_eq!(main.exclusive + sub.inclusive, main.inclusive);
+    assert_eq!(sub.inclusive, sub.exclusive);
+  }
+
+  #[test]
+  fn irq() {
+    let (mut cpu, mut profiler) = profile(1);
+    // handler: INY; RTI
+    cpu.get_memory_mut().poke(0x0300, 0xC8);
+    cpu.get_memory_mut().poke(0x0301, 0x40);
+    cpu.get_memory_mut().poke(0xFFFE, 0x00);
+    cpu.get_memory_mut().poke(0xFFFF, 0x03);
+    cpu.set_irq();
+    profiler.step(&mut cpu);
+    cpu.clear_irq();
+    for _ in 0..6 {
+      profiler.step(&mut cpu);
+    }
+    let handler = profiler.get_routine(0x0300).unwrap();
+    assert_eq!(handler.calls, 1);
+    assert_eq!(handler.inclusive, handler.exclusive);
+    assert_eq!(profiler.get_address(0x0300).unwrap().count, 1);
+    assert_eq!(profiler.get_routine(0x0207).unwrap().calls, 2);
+    let main = profiler.get_routine(0x0200).unwrap();
+    assert_eq!(main.inclusive, profiler.get_cycles());
+  }
+
+  #[test]
+  fn bounded_stack() {
+    let (_, rx) = mpsc::channel();
+    let mut cpu = CPU::new(rx);
+    cpu.set_free_running(true);
+    // loop: JSR sub; sub: PLA; PLA; JMP loop
+    for (i, byte) in [0x20, 0x03, 0x02, 0x68, 0x68, 0x4C, 0x00, 0x02].iter().enumerate() {
+      cpu.get_memory_mut().poke(0x0200 + i as u16, *byte);
+    }
+    let mut registers = cpu.get_registers();
+    registers.program_counter = 0x0200;
+    cpu.set_registers(registers);
+    let mut profiler = Profiler::new();
+    for _ in 0..2000 {
+      profiler.step(&mut cpu);
+    }
+    assert_eq!(profiler.stack.len(), MAX_DEPTH);
+    assert!(profiler.stacks.len() <= MAX_DEPTH + 1);
+    assert_eq!(profiler.get_routine(0x0203).unwrap().calls, 500);
+  }
+
+  #[test]
+  fn collapsed() {
+    let (_, profiler) = profile(7);
+    let mut symbols = SymbolTable::new();
+    symbols.add("main", 0x0200, None);
+    symbols.add("sub", 0x0207, None);
+    let text = profiler.collapsed(Some(&symbols));
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].starts_with("main "));
+    assert!(lines[1].starts_with("main;sub "));
+    let total: u64 = lines
+      .iter()
+      .map(|l| l.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+      .sum();
+    assert_eq!(total, profiler.get_cycles());
+  }
+
+  #[test]
+  fn report() {
+    let (cpu, profiler) = profile(7);
+    let text = profiler.report(Some(&cpu), None);
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines[0].starts_with("7 instructions"));
+    assert!(lines[3].starts_with("$0200"));
+    assert!(text.contains("INX"));
+  }
+}
@@ -1,4 +1,3 @@
-use log::{debug, trace};
 /// A generic 6502 register, X Y or A
 #[derive(Eq, PartialEq)]
 pub struct GeneralRegister(u8);
@@ -1,5 +1,4 @@
 use crate::STARTING_MEMORY_BLOCK;
-use log::{debug, trace};
 
 /// The Program Counter for the computer. Keeps track of where the computer's
 /// execution is transpiring.
@@ -84,11 +83,12 @@ impl ProgramCounter {
     self.value = index;
   }
 
-  /// Increments the PC and then returns the new value. Used primarily for retrieving
-  /// operands in a manner that preserves processor sync.
+  /// Returns the current value and then increments the PC past it. Used primarily for
+  /// retrieving opcodes and operands in a manner that preserves processor sync.
   pub fn get_and_increase(&mut self) -> u16 {
+    let value = self.value;
     self.increase(1);
-    self.value
+    value
   }
 }
 
@@ -172,6 +172,7 @@ mod tests {
   fn get_and_increase() {
     let mut pc = ProgramCounter::new();
     let op = pc.get_and_increase();
-    assert_eq!(op, STARTING_MEMORY_BLOCK + 1);
+    assert_eq!(op, STARTING_MEMORY_BLOCK);
+    assert_eq!(pc.value, STARTING_MEMORY_BLOCK + 1);
   }
 }
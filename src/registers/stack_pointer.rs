@@ -1,4 +1,3 @@
-use log::{debug, warn};
 /// Stack pointer works top down, so we start at 0xFF
 const START_INDEX: u8 = 0xFF;
 
@@ -9,7 +8,7 @@ const START_INDEX: u8 = 0xFF;
 /// The stack pointer always points at the next free value, so pushing will
 /// return the current value and decrement, while popping will have to
 /// increment before returning the value.
-#[derive(PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct StackPointer(u8);
 
 impl StackPointer {
@@ -27,7 +26,7 @@ impl StackPointer {
 
   /// Gets the current value of the stack pointer without mutating it.
   pub fn get(&self) -> u8 {
-    warn!("Getting stack pointer value. Might be weird behavior");
+    trace!("Getting stack pointer value. Might be weird behavior");
     self.0
   }
 
@@ -1,5 +1,4 @@
-use log::{debug, trace};
-use std::fmt::{Display, Formatter};
+use core::fmt::{Display, Formatter};
 
 /// Hey we're in binary!
 const BASE: u8 = 2;
@@ -128,7 +127,7 @@ impl StatusRegister {
 
 /// Displays a readable set of info about the state of each flag
 impl Display for StatusRegister {
-  fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
     let c = self.is_flag_set(StatusBit::Carry);
     let z = self.is_flag_set(StatusBit::Zero);
     let i = self.is_flag_set(StatusBit::Interrupt);
@@ -176,11 +175,11 @@ mod tests {
   fn bits(bit: StatusBit) {
     let mut reg = StatusRegister::new();
     reg.set_flag(bit);
-    assert_eq!(reg.0 >= 1, true);
-    assert_eq!(reg.is_flag_set(bit), true);
+    assert!(reg.0 >= 1);
+    assert!(reg.is_flag_set(bit));
     reg.clear_flag(bit);
     assert_eq!(reg.0, 0);
-    assert_eq!(reg.is_flag_set(bit), false);
+    assert!(!reg.is_flag_set(bit));
   }
 
   #[test]
@@ -193,13 +192,13 @@ mod tests {
     reg.set_flag(StatusBit::Decimal);
     reg.set_flag(StatusBit::Overflow);
     reg.set_flag(StatusBit::Negative);
-    assert_eq!(reg.is_flag_set(StatusBit::Carry), true);
-    assert_eq!(reg.is_flag_set(StatusBit::Zero), true);
-    assert_eq!(reg.is_flag_set(StatusBit::Interrupt), true);
-    assert_eq!(reg.is_flag_set(StatusBit::Break), true);
-    assert_eq!(reg.is_flag_set(StatusBit::Decimal), true);
-    assert_eq!(reg.is_flag_set(StatusBit::Overflow), true);
-    assert_eq!(reg.is_flag_set(StatusBit::Negative), true);
+    assert!(reg.is_flag_set(StatusBit::Carry));
+    assert!(reg.is_flag_set(StatusBit::Zero));
+    assert!(reg.is_flag_set(StatusBit::Interrupt));
+    assert!(reg.is_flag_set(StatusBit::Break));
+    assert!(reg.is_flag_set(StatusBit::Decimal));
+    assert!(reg.is_flag_set(StatusBit::Overflow));
+    assert!(reg.is_flag_set(StatusBit::Negative));
   }
 
   #[test]
@@ -220,7 +219,7 @@ mod tests {
   fn handle_carry_set() {
     let mut reg = StatusRegister::new();
     reg.handle_c_flag("test", true);
-    assert_eq!(reg.is_flag_set(StatusBit::Carry), true);
+    assert!(reg.is_flag_set(StatusBit::Carry));
   }
 
   #[test]
@@ -228,28 +227,28 @@ mod tests {
     let mut reg = StatusRegister::new();
     reg.set_flag(StatusBit::Carry);
     reg.handle_c_flag("test", false);
-    assert_eq!(reg.is_flag_set(StatusBit::Carry), false);
+    assert!(!reg.is_flag_set(StatusBit::Carry));
   }
 
   #[test]
   fn handle_overflow_set() {
     let mut reg = StatusRegister::new();
     reg.handle_v_flag(0x80, "test", false);
-    assert_eq!(reg.is_flag_set(StatusBit::Overflow), true);
+    assert!(reg.is_flag_set(StatusBit::Overflow));
   }
 
   #[test]
   fn handle_overflow_set_carry() {
     let mut reg = StatusRegister::new();
     reg.handle_v_flag(0x81, "test", true);
-    assert_eq!(reg.is_flag_set(StatusBit::Overflow), true);
+    assert!(reg.is_flag_set(StatusBit::Overflow));
   }
 
   #[test]
   fn handle_zero_set() {
     let mut reg = StatusRegister::new();
     reg.handle_z_flag(0x0, "test");
-    assert_eq!(reg.is_flag_set(StatusBit::Zero), true);
+    assert!(reg.is_flag_set(StatusBit::Zero));
   }
 
   #[test]
@@ -257,14 +256,14 @@ mod tests {
     let mut reg = StatusRegister::new();
     reg.set_flag(StatusBit::Zero);
     reg.handle_z_flag(0x1, "test");
-    assert_eq!(reg.is_flag_set(StatusBit::Zero), false);
+    assert!(!reg.is_flag_set(StatusBit::Zero));
   }
 
   #[test]
   fn handle_negative_set() {
     let mut reg = StatusRegister::new();
     reg.handle_n_flag(0x80, "test");
-    assert_eq!(reg.is_flag_set(StatusBit::Negative), true);
+    assert!(reg.is_flag_set(StatusBit::Negative));
   }
 
   #[test]
@@ -272,6 +271,6 @@ mod tests {
     let mut reg = StatusRegister::new();
     reg.set_flag(StatusBit::Negative);
     reg.handle_n_flag(0x1, "test");
-    assert_eq!(reg.is_flag_set(StatusBit::Negative), false);
+    assert!(!reg.is_flag_set(StatusBit::Negative));
   }
 }
@@ -0,0 +1,414 @@
+use crate::prelude::*;
+use crate::{ExecutionState, Memory, Registers, CPU};
+use alloc::collections::VecDeque;
+
+/// The state before a single instruction, and the writes it made.
+struct Entry {
+  registers: Registers,
+  cycles: u64,
+  state: ExecutionState,
+  writes: Vec<(u16, u8)>,
+}
+
+/// A full copy of the CPU taken before an instruction.
+struct Checkpoint {
+  index: u64,
+  registers: Registers,
+  cycles: u64,
+  state: ExecutionState,
+  memory: Memory,
+}
+
+/// A write found by a "who last wrote this address" query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteRecord {
+  /// The position of the instruction in the history.
+  pub index: u64,
+  /// The address of the instruction that made the write.
+  pub program_counter: u16,
+  /// The value written.
+  pub value: u8,
+}
+
+/// Records execution so it can be stepped backwards.
+///
+/// Every instruction run through the recorder adds a journal entry holding the
+/// registers, pins, call stack and exit code before it ran and the memory
+/// writes it made. Every `interval`
+/// instructions a full checkpoint of the CPU is taken as well. Going back is a
+/// matter of restoring the nearest checkpoint and replaying the journaled
+/// writes up to the target instruction, so mapper state comes back along with
+/// memory.
+///
+/// Replaying writes cannot rebuild the state of devices, which also change on
+/// reads and as cycles pass. While any devices are mapped a checkpoint is
+/// taken before every instruction instead, so stepping back never replays
+/// anything, at the cost of keeping only `capacity` instructions of history.
+///
+/// At most `capacity` checkpoints are kept. When one more is taken the oldest
+/// is dropped along with the journal entries it covered, which bounds memory
+/// use at roughly `capacity` copies of memory plus `interval * capacity`
+/// journal entries.
+pub struct Rewind {
+  interval: u64,
+  capacity: usize,
+  first: u64,
+  entries: VecDeque<Entry>,
+  checkpoints: VecDeque<Checkpoint>,
+}
+
+impl Rewind {
+  /// Creates a recorder that checkpoints every `interval` instructions and
+  /// keeps at most `capacity` checkpoints.
+  ///
+  /// # Panics
+  /// Panics if either value is zero.
+  pub fn new(interval: u64, capacity: usize) -> Rewind {
+    if interval == 0 || capacity == 0 {
+      panic!("Rewind interval and capacity must be greater than zero");
+    }
+    Rewind {
+      interval,
+      capacity,
+      first: 0,
+      entries: VecDeque::new(),
+      checkpoints: VecDeque::new(),
+    }
+  }
+
+  /// Gets the position in the history, which is the number of instructions
+  /// recorded and not stepped back over.
+  pub fn get_position(&self) -> u64 {
+    self.first + self.entries.len() as u64
+  }
+
+  /// Gets the earliest position that can still be stepped back to.
+  pub fn get_earliest(&self) -> u64 {
+    self
+      .checkpoints
+      .front()
+      .map(|c| c.index)
+      .unwrap_or(self.first)
+  }
+
+  /// Executes and records a single instruction.
+  pub fn step(&mut self, cpu: &mut CPU) {
+    let index = self.get_position();
+    if index.is_multiple_of(self.interval)
+      || self.checkpoints.is_empty()
+      || cpu.get_memory().has_devices()
+    {
+      self.checkpoint(cpu, index);
+    }
+    let registers = cpu.get_registers();
+    let cycles = cpu.get_cycles();
+    let state = cpu.get_execution_state();
+    let memory = cpu.get_memory_mut();
+    memory.set_journaling(true);
+    memory.take_journal();
+    cpu.step();
+    let memory = cpu.get_memory_mut();
+    let writes = memory.take_journal();
+    // Left on, the journal would grow with every step made outside the
+    // recorder.
+    memory.set_journaling(false);
+    self.entries.push_back(Entry {
+      registers,
+      cycles,
+      state,
+      writes,
+    });
+  }
+
+  /// Moves the CPU back by one instruction. Returns false if there is no
+  /// recorded history left to go back through.
+  pub fn step_back(&mut self, cpu: &mut CPU) -> bool {
+    let position = self.get_position();
+    if position <= self.get_earliest() {
+      return false;
+    }
+    self.go_to(cpu, position - 1);
+    true
+  }
+
+  /// Moves the CPU back to the most recent point in the history where the
+  /// program counter was at one of the breakpoints. If no breakpoint is found
+  /// the CPU is left at the earliest point recorded and false is returned.
+  pub fn run_back_to(&mut self, cpu: &mut CPU, breakpoints: &[u16]) -> bool {
+    let earliest = self.get_earliest();
+    let position = self.get_position();
+    let found = (earliest..position)
+      .rev()
+      .find(|i| breakpoints.contains(&self.entry(*i).registers.program_counter));
+    match found {
+      Some(index) => self.go_to(cpu, index),
+      None if position > earliest => self.go_to(cpu, earliest),
+      None => (),
+    }
+    found.is_some()
+  }
+
+  /// Finds the most recent recorded write to an address.
+  pub fn last_write(&self, address: u16) -> Option<WriteRecord> {
+    let position = self.get_position();
+    (self.first..position).rev().find_map(|index| {
+      let entry = self.entry(index);
+      entry
+        .writes
+        .iter()
+        .rev()
+        .find(|(a, _)| *a == address)
+        .map(|(_, value)| WriteRecord {
+          index,
+          program_counter: entry.registers.program_counter,
+          value: *value,
+        })
+    })
+  }
+
+  /// Throws away the recorded history.
+  pub fn clear(&mut self) {
+    self.first = self.get_position();
+    self.entries.clear();
+    self.checkpoints.clear();
+  }
+
+  /// Gets the journal entry for a position in the history.
+  fn entry(&self, index: u64) -> &Entry {
+    &self.entries[(index - self.first) as usize]
+  }
+
+  /// Takes a checkpoint, dropping the oldest one if there are too many.
+  fn checkpoint(&mut self, cpu: &CPU, index: u64) {
+    trace!("Taking rewind checkpoint at {}", index);
+    self.checkpoints.push_back(Checkpoint {
+      index,
+      registers: cpu.get_registers(),
+      cycles: cpu.get_cycles(),
+      state: cpu.get_execution_state(),
+      memory: cpu.get_memory().snapshot(),
+    });
+    if self.checkpoints.len() > self.capacity {
+      self.checkpoints.pop_front();
+      let earliest = self.get_earliest();
+      while self.first < earliest {
+        self.entries.pop_front();
+        self.first += 1;
+      }
+    }
+  }
+
+  /// Puts the CPU in the state it was in before the instruction at a position
+  /// ran, and forgets everything after it.
+  fn go_to(&mut self, cpu: &mut CPU, index: u64) {
+    debug!("Rewinding from {} to {}", self.get_position(), index);
+    while self
+      .checkpoints
+      .back()
+      .map(|c| c.index > index)
+      .unwrap_or(false)
+    {
+      self.checkpoints.pop_back();
+    }
+    let checkpoint = match self.checkpoints.back() {
+      Some(checkpoint) => checkpoint,
+      None => return,
+    };
+    let memory = cpu.get_memory_mut();
+    memory.restore(&checkpoint.memory);
+    for i in checkpoint.index..index {
+      memory.replay(&self.entries[(i - self.first) as usize].writes);
+    }
+    let (registers, cycles, state) = match index == checkpoint.index {
+      true => (checkpoint.registers, checkpoint.cycles, &checkpoint.state),
+      false => {
+        let entry = self.entry(index);
+        (entry.registers, entry.cycles, &entry.state)
+      }
+    };
+    cpu.set_registers(registers);
+    cpu.set_cycles(cycles);
+    cpu.set_execution_state(state);
+    self.entries.truncate((index - self.first) as usize);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::devices::Via;
+  use crate::mappers::{Bank, Latch};
+  use crate::traps::{TrapResult, TrapSite};
+  use std::sync::mpsc;
+  use test_case::test_case;
+
+  // INC $10; INX; STX $11; JMP $0200
+  const LOOP: [u8; 9] = [0xE6, 0x10, 0xE8, 0x86, 0x11, 0x4C, 0x00, 0x02, 0x00];
+
+  fn cpu(program: &[u8]) -> CPU {
+    let (_, rx) = mpsc::channel();
+    let mut cpu = CPU::new(rx);
+    cpu.set_free_running(true);
+    for (i, byte) in program.iter().enumerate() {
+      cpu.get_memory_mut().poke(0x0200 + i as u16, *byte);
+    }
+    let mut registers = cpu.get_registers();
+    registers.program_counter = 0x0200;
+    cpu.set_registers(registers);
+    cpu
+  }
+
+  #[test_case(1, 100; "Checkpoint every step")]
+  #[test_case(16, 100; "Sparse checkpoints")]
+  fn step_back(interval: u64, capacity: usize) {
+    let mut cpu = cpu(&LOOP);
+    let mut rewind = Rewind::new(interval, capacity);
+    let mut history = vec![];
+    for _ in 0..40 {
+      history.push((
+        cpu.get_registers(),
+        cpu.get_cycles(),
+        cpu.get_memory().peek(0x10),
+      ));
+      rewind.step(&mut cpu);
+    }
+    for expected in history.iter().rev() {
+      assert!(rewind.step_back(&mut cpu));
+      let state = (
+        cpu.get_registers(),
+        cpu.get_cycles(),
+        cpu.get_memory().peek(0x10),
+      );
+      assert_eq!(&state, expected);
+    }
+    assert!(!rewind.step_back(&mut cpu));
+  }
+
+  #[test]
+  fn step_back_over_call_and_exit() {
+    // JSR sub; JSR $FFF0; BRK; sub: INX; RTS
+    let mut cpu = cpu(&[0x20, 0x07, 0x02, 0x20, 0xF0, 0xFF, 0x00, 0xE8, 0x60]);
+    cpu.add_trap(
+      TrapSite::Address(0xFFF0),
+      Box::new(|_: &mut CPU| TrapResult::Exit(3)),
+    );
+    let mut rewind = Rewind::new(4, 10);
+    let state = |cpu: &CPU| {
+      (
+        cpu.get_registers(),
+        cpu.get_call_stack().get_frames().to_vec(),
+        cpu.get_exit_code(),
+      )
+    };
+    let mut history = vec![];
+    for _ in 0..5 {
+      history.push(state(&cpu));
+      rewind.step(&mut cpu);
+    }
+    assert_eq!(cpu.get_exit_code(), Some(3));
+    for expected in history.iter().rev() {
+      assert!(rewind.step_back(&mut cpu));
+      assert_eq!(&state(&cpu), expected);
+    }
+    rewind.step(&mut cpu);
+    assert_eq!(cpu.get_call_stack().get_frames().len(), 1);
+  }
+
+  #[test]
+  fn stops_journaling() {
+    let mut cpu = cpu(&LOOP);
+    let mut rewind = Rewind::new(4, 10);
+    rewind.step(&mut cpu);
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.get_memory().peek(0x11), 1);
+    assert!(cpu.get_memory_mut().take_journal().is_empty());
+  }
+
+  #[test]
+  fn bounded_history() {
+    let mut cpu = cpu(&LOOP);
+    let mut rewind = Rewind::new(8, 2);
+    for _ in 0..100 {
+      rewind.step(&mut cpu);
+    }
+    assert_eq!(rewind.get_earliest(), 88);
+    assert!(rewind.entries.len() <= 16);
+    let mut steps = 0;
+    while rewind.step_back(&mut cpu) {
+      steps += 1;
+    }
+    assert_eq!(steps, 12);
+    assert_eq!(rewind.get_position(), 88);
+  }
+
+  #[test]
+  fn run_back_to() {
+    let mut cpu = cpu(&LOOP);
+    let mut rewind = Rewind::new(4, 10);
+    for _ in 0..10 {
+      rewind.step(&mut cpu);
+    }
+    assert!(rewind.run_back_to(&mut cpu, &[0x0203]));
+    assert_eq!(cpu.get_registers().program_counter, 0x0203);
+    assert_eq!(rewind.get_position(), 6);
+    assert_eq!(cpu.get_registers().x, 2);
+    assert!(!rewind.run_back_to(&mut cpu, &[0x1234]));
+    assert_eq!(rewind.get_position(), 0);
+  }
+
+  #[test]
+  fn last_write() {
+    let mut cpu = cpu(&LOOP);
+    let mut rewind = Rewind::new(4, 10);
+    for _ in 0..7 {
+      rewind.step(&mut cpu);
+    }
+    let record = rewind.last_write(0x11).unwrap();
+    assert_eq!(record.index, 6);
+    assert_eq!(record.program_counter, 0x0203);
+    assert_eq!(record.value, 2);
+    assert_eq!(rewind.last_write(0x12), None);
+  }
+
+  #[test]
+  fn restores_mapper() {
+    // LDA #1; STA $7000; LDA #2; STA $7000
+    let mut cpu = cpu(&[0xA9, 0x01, 0x8D, 0x00, 0x70, 0xA9, 0x02, 0x8D, 0x00, 0x70]);
+    let image = (0..4).flat_map(|p| vec![p as u8; 0x100]).collect();
+    let bank = Bank::rom("rom", image, 0x100);
+    cpu.set_mapper(Box::new(Latch::new(bank, 0x8000, 0x7000)));
+    let mut rewind = Rewind::new(100, 1);
+    for _ in 0..4 {
+      rewind.step(&mut cpu);
+    }
+    assert_eq!(cpu.get_memory().peek(0x8000), 2);
+    rewind.step_back(&mut cpu);
+    assert_eq!(cpu.get_memory().peek(0x8000), 1);
+  }
+
+  #[test]
+  fn restores_via() {
+    // LDA #8; STA $6004; LDA #0; STA $6005; NOP...
+    let mut program = vec![0xA9, 0x08, 0x8D, 0x04, 0x60, 0xA9, 0x00, 0x8D, 0x05, 0x60];
+    program.extend([0xEA; 20]);
+    let mut cpu = cpu(&program);
+    cpu
+      .get_memory_mut()
+      .map_device(0x6000, 0x600F, Box::new(Via::new()));
+    let mut rewind = Rewind::new(16, 100);
+    let mut history = vec![];
+    for _ in 0..20 {
+      let memory = cpu.get_memory();
+      history.push((cpu.get_cycles(), memory.peek(0x6004), memory.peek(0x600D)));
+      rewind.step(&mut cpu);
+    }
+    for expected in history.iter().rev() {
+      assert!(rewind.step_back(&mut cpu));
+      let memory = cpu.get_memory();
+      let state = (cpu.get_cycles(), memory.peek(0x6004), memory.peek(0x600D));
+      assert_eq!(&state, expected);
+    }
+    assert!(!rewind.step_back(&mut cpu));
+  }
+}
@@ -0,0 +1,256 @@
+use crate::prelude::*;
+use crate::traps::TrapSite;
+use crate::CPU;
+use core::fmt::{Display, Formatter};
+
+/// The BRK opcode.
+const BRK: u8 = 0x00;
+/// Exit code for a jam. Codes from 128 up are kept apart from the ones a
+/// program exits with, which are expected to be below 128.
+const EXIT_JAM: i32 = 130;
+/// Exit code for running out of the limit.
+const EXIT_LIMIT: i32 = 131;
+/// Exit code for getting stuck. Test suites often trap failures in a jump to
+/// itself, so getting stuck is not taken as success.
+const EXIT_STUCK: i32 = 132;
+
+/// How long to let a program run before giving up on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+  /// Stop once this many machine cycles have run.
+  Cycles(u64),
+  /// Stop once this many instructions have run.
+  Instructions(u64),
+}
+
+/// Why a program stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+  /// The next instruction is a BRK, at this address.
+  Break(u16),
+  /// The program counter reached one of the stop addresses.
+  StopAddress(u16),
+  /// The next instruction, at this address, would jam the processor.
+  Jam(u16),
+  /// The program counter stayed at this address, in a jump or branch to
+  /// itself that only an interrupt could leave.
+  Stuck(u16),
+  /// The program exited through a trap with this code.
+  Exit(u8),
+  /// The cycle or instruction limit ran out.
+  Limit,
+}
+
+impl StopReason {
+  /// A process exit code for the stop, for scripts to check: 0 for a BRK or
+  /// a stop address, the program's own code for an exit through a trap, 130
+  /// for a jam, 131 when the limit ran out and 132 for getting stuck.
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      StopReason::Break(_) | StopReason::StopAddress(_) => 0,
+      StopReason::Exit(code) => *code as i32,
+      StopReason::Jam(_) => EXIT_JAM,
+      StopReason::Limit => EXIT_LIMIT,
+      StopReason::Stuck(_) => EXIT_STUCK,
+    }
+  }
+}
+
+impl Display for StopReason {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      StopReason::Break(address) => write!(f, "BRK at ${:04X}", address),
+      StopReason::StopAddress(address) => write!(f, "reached ${:04X}", address),
+      StopReason::Jam(address) => write!(f, "jammed at ${:04X}", address),
+      StopReason::Stuck(address) => write!(f, "stuck at ${:04X}", address),
+      StopReason::Exit(code) => write!(f, "exited with {}", code),
+      StopReason::Limit => write!(f, "limit reached"),
+    }
+  }
+}
+
+/// Runs the CPU until the program stops: on a BRK, at one of the stop
+/// addresses, on a jam, on an exit through a trap, when it gets stuck in a
+/// jump to itself, or when the limit runs out. Without a limit a program
+/// that never stops runs forever.
+///
+/// The BRK, the jam or the instruction at the stop address is not run, so
+/// the registers show the state just before it. Getting stuck ends the run,
+/// so this is for programs that don't wait on interrupts from devices.
+pub fn run_until(cpu: &mut CPU, stop_addresses: &[u16], limit: Option<Limit>) -> StopReason {
+  trace_until(cpu, stop_addresses, limit, |_| ())
+}
+
+/// Runs the CPU as `run_until` does, calling a function with the CPU before
+/// each instruction, to trace or pace the program.
+pub fn trace_until<F: FnMut(&CPU)>(
+  cpu: &mut CPU,
+  stop_addresses: &[u16],
+  limit: Option<Limit>,
+  mut before: F,
+) -> StopReason {
+  let start = cpu.get_cycles();
+  let mut instructions = 0;
+  loop {
+    if let Some(code) = cpu.get_exit_code() {
+      return StopReason::Exit(code);
+    }
+    let address = cpu.get_registers().program_counter;
+    if stop_addresses.contains(&address) {
+      return StopReason::StopAddress(address);
+    }
+    let trap = cpu.has_trap(TrapSite::Address(address));
+    if !trap && cpu.get_memory().peek(address) == BRK {
+      return StopReason::Break(address);
+    }
+    if cpu.is_halted() {
+      return StopReason::Jam(address);
+    }
+    let done = match limit {
+      Some(Limit::Cycles(cycles)) => cpu.get_cycles() - start >= cycles,
+      Some(Limit::Instructions(count)) => instructions >= count,
+      None => false,
+    };
+    if done {
+      return StopReason::Limit;
+    }
+    before(cpu);
+    cpu.step();
+    instructions += 1;
+    if cpu.get_registers().program_counter == address && cpu.get_exit_code().is_none() {
+      return StopReason::Stuck(address);
+    }
+  }
+}
+
+/// Formats memory from start to end, inclusive, as hex and ASCII lines of 16
+/// bytes each.
+pub fn dump_memory(cpu: &CPU, start: u16, end: u16) -> String {
+  let memory = cpu.get_memory();
+  let mut text = String::new();
+  let mut address = start as u32;
+  while address <= end as u32 {
+    let last = (address + 15).min(end as u32);
+    let bytes: Vec<u8> = (address..=last).map(|a| memory.peek(a as u16)).collect();
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+    let ascii: String = bytes
+      .iter()
+      .map(|b| match b {
+        0x20..=0x7E => *b as char,
+        _ => '.',
+      })
+      .collect();
+    text += &format!("{:04X}: {:<47}  {}\n", address, hex.join(" "), ascii);
+    address += 16;
+  }
+  text
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::assembler::assemble;
+  use crate::clock::FreeRunning;
+  use crate::traps::TrapResult;
+  use test_case::test_case;
+
+  fn cpu(program: &str) -> CPU {
+    let source = format!("        .org $0200\n{}", program);
+    let assembly = assemble(&source, "test.s", 0x0200).unwrap();
+    let mut cpu = CPU::new(FreeRunning);
+    cpu.load_image(&assembly.image);
+    cpu
+  }
+
+  #[test]
+  fn stops_on_break() {
+    let mut cpu = cpu("        LDA #1\n        BRK");
+    assert_eq!(run_until(&mut cpu, &[], None), StopReason::Break(0x0202));
+    assert_eq!(cpu.get_registers().accumulator, 1);
+  }
+
+  #[test]
+  fn stops_on_jam() {
+    let mut cpu = cpu("        NOP\n        .byte $02");
+    let reason = run_until(&mut cpu, &[], None);
+    assert_eq!(reason, StopReason::Jam(0x0201));
+    assert_eq!(reason.exit_code(), 130);
+  }
+
+  #[test_case(true, StopReason::Break(0x0203); "Allowed")]
+  #[test_case(false, StopReason::Jam(0x0201); "Forbidden")]
+  fn undocumented_opcodes(allowed: bool, expected: StopReason) {
+    let mut cpu = cpu("        NOP\n        .byte $A7, $10\n        BRK");
+    cpu.set_undocumented_opcodes(allowed);
+    assert_eq!(run_until(&mut cpu, &[], None), expected);
+  }
+
+  #[test]
+  fn stops_at_address() {
+    let mut cpu = cpu("        LDX #3\nloop:   DEX\n        BNE loop\ndone:   JMP done");
+    assert_eq!(
+      run_until(&mut cpu, &[0x0205], Some(Limit::Instructions(100))),
+      StopReason::StopAddress(0x0205)
+    );
+    assert_eq!(cpu.get_registers().x, 0);
+  }
+
+  #[test_case("done:   JMP done"; "Jump")]
+  #[test_case("done:   BVC done"; "Branch")]
+  fn stops_when_stuck(program: &str) {
+    let mut cpu = cpu(program);
+    let reason = run_until(&mut cpu, &[], None);
+    assert_eq!(
+      reason,
+      StopReason::Stuck(cpu.get_registers().program_counter)
+    );
+    assert_eq!(reason.exit_code(), 132);
+  }
+
+  #[test]
+  fn traces_each_instruction() {
+    let mut cpu = cpu("        LDX #3\nloop:   DEX\n        BNE loop\n        BRK");
+    let mut seen = vec![];
+    let reason = trace_until(&mut cpu, &[], None, |cpu| {
+      seen.push(cpu.get_registers().program_counter)
+    });
+    assert_eq!(reason, StopReason::Break(0x0205));
+    assert_eq!(
+      seen,
+      [0x0200, 0x0202, 0x0203, 0x0202, 0x0203, 0x0202, 0x0203]
+    );
+  }
+
+  #[test]
+  fn stops_on_exit() {
+    let mut cpu = cpu("        JSR $FFF0");
+    cpu.add_trap(
+      TrapSite::Address(0xFFF0),
+      Box::new(|_: &mut CPU| TrapResult::Exit(7)),
+    );
+    let reason = run_until(&mut cpu, &[], None);
+    assert_eq!(reason, StopReason::Exit(7));
+    assert_eq!(reason.exit_code(), 7);
+  }
+
+  #[test_case(Limit::Instructions(10), 5, 40; "Instructions")]
+  #[test_case(Limit::Cycles(10), 2, 13; "Cycles")]
+  fn stops_at_limit(limit: Limit, increments: u8, cycles: u64) {
+    let mut cpu = cpu("loop:   INC $10\n        JMP loop");
+    let reason = run_until(&mut cpu, &[], Some(limit));
+    assert_eq!(reason, StopReason::Limit);
+    assert_eq!(reason.exit_code(), 131);
+    assert_eq!(cpu.get_memory().peek(0x10), increments);
+    assert_eq!(cpu.get_cycles(), cycles);
+  }
+
+  #[test]
+  fn dumps_memory() {
+    let cpu = cpu("        .byte \"HI\", 0");
+    assert_eq!(
+      dump_memory(&cpu, 0x0200, 0x0202),
+      format!("0200: 48 49 00{}  HI.\n", " ".repeat(39))
+    );
+    assert_eq!(dump_memory(&cpu, 0x0200, 0x0210).lines().count(), 2);
+  }
+}
@@ -0,0 +1,405 @@
+use crate::prelude::*;
+use alloc::collections::BTreeMap;
+use core::fmt::{Display, Formatter};
+#[cfg(feature = "std")]
+use std::error::Error;
+
+/// Things that can go wrong while reading a symbol file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SymbolError {
+  /// A line could not be parsed. Holds the line number and what was wrong.
+  Syntax(usize, String),
+}
+
+impl Display for SymbolError {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    match self {
+      SymbolError::Syntax(line, message) => write!(f, "syntax error on line {}: {}", line, message),
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl Error for SymbolError {}
+
+/// A named address, such as a routine entry point or a variable.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+  pub name: String,
+  pub address: u16,
+  /// How many bytes the symbol covers, if the file told us.
+  pub size: Option<u16>,
+}
+
+/// A range of addresses generated by a line of source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine {
+  pub file: String,
+  pub line: usize,
+  pub start: u16,
+  pub size: u16,
+}
+
+impl Display for SourceLine {
+  fn fmt(&self, f: &mut Formatter<'_>) -> core::fmt::Result {
+    write!(f, "{}:{}", self.file, self.line)
+  }
+}
+
+/// Maps addresses to symbol names and source lines so execution can be read
+/// in terms of the program's own routine names.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+  by_address: BTreeMap<u16, Symbol>,
+  by_name: BTreeMap<String, u16>,
+  lines: Vec<SourceLine>,
+}
+
+impl SymbolTable {
+  /// Creates an empty symbol table.
+  pub fn new() -> SymbolTable {
+    SymbolTable::default()
+  }
+
+  /// Adds a symbol. If two symbols share an address the first one added is
+  /// the one used for lookups by address.
+  pub fn add(&mut self, name: &str, address: u16, size: Option<u16>) {
+    trace!("Adding symbol {} at {:X}", name, address);
+    self.by_name.insert(name.to_string(), address);
+    self.by_address.entry(address).or_insert(Symbol {
+      name: name.to_string(),
+      address,
+      size,
+    });
+  }
+
+  /// Adds a source line covering a range of addresses.
+  pub fn add_line(&mut self, file: &str, line: usize, start: u16, size: u16) {
+    self.lines.push(SourceLine {
+      file: file.to_string(),
+      line,
+      start,
+      size,
+    });
+  }
+
+  /// Adds every symbol and line from another table.
+  pub fn merge(&mut self, other: SymbolTable) {
+    for symbol in other.by_address.into_values() {
+      self.add(&symbol.name, symbol.address, symbol.size);
+    }
+    for (name, address) in other.by_name {
+      self.by_name.entry(name).or_insert(address);
+    }
+    self.lines.extend(other.lines);
+  }
+
+  /// Gets the number of symbols in the table.
+  pub fn len(&self) -> usize {
+    self.by_name.len()
+  }
+
+  /// Returns true if the table holds no symbols.
+  pub fn is_empty(&self) -> bool {
+    self.by_name.is_empty()
+  }
+
+  /// Gets the address of a symbol by name.
+  pub fn get_address(&self, name: &str) -> Option<u16> {
+    self.by_name.get(name).copied()
+  }
+
+  /// Gets the symbol defined exactly at an address.
+  pub fn get_symbol(&self, address: u16) -> Option<&Symbol> {
+    self.by_address.get(&address)
+  }
+
+  /// Finds the nearest symbol at or below an address, along with the offset
+  /// from it. Symbols with a known size only match addresses inside them.
+  pub fn lookup(&self, address: u16) -> Option<(&Symbol, u16)> {
+    let (start, symbol) = self.by_address.range(..=address).next_back()?;
+    let offset = address - start;
+    match symbol.size {
+      Some(size) if offset >= size.max(1) => None,
+      _ => Some((symbol, offset)),
+    }
+  }
+
+  /// Gets every source line in the table.
+  pub fn get_lines(&self) -> &[SourceLine] {
+    &self.lines
+  }
+
+  /// Finds the source line that generated an address. If several lines cover
+  /// the address the narrowest one wins.
+  pub fn get_line(&self, address: u16) -> Option<&SourceLine> {
+    self
+      .lines
+      .iter()
+      .filter(|l| address >= l.start && (address as u32) < l.start as u32 + l.size as u32)
+      .min_by_key(|l| l.size)
+  }
+
+  /// Formats an address as symbol+offset, falling back to plain hex.
+  pub fn format_address(&self, address: u16) -> String {
+    match self.lookup(address) {
+      Some((symbol, 0)) => symbol.name.clone(),
+      Some((symbol, offset)) => format!("{}+{}", symbol.name, offset),
+      None => format!("${:04X}", address),
+    }
+  }
+
+  /// Reads a simple label file, one "label = $addr" per line. Addresses can
+  /// be written as $hex, 0xhex or decimal. Anything after a ';' is a comment.
+  pub fn from_simple(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut table = SymbolTable::new();
+    for (number, line) in text.lines().enumerate() {
+      let line = line.split(';').next().unwrap_or("").trim();
+      if line.is_empty() {
+        continue;
+      }
+      let mut parts = line.splitn(2, '=');
+      let name = parts.next().unwrap_or("").trim();
+      let value = parts.next().map(|v| v.trim()).unwrap_or("");
+      if name.is_empty() || value.is_empty() {
+        return Err(SymbolError::Syntax(
+          number + 1,
+          "expected label = address".to_string(),
+        ));
+      }
+      table.add(name, parse_number(value, number + 1)?, None);
+    }
+    debug!("Read {} symbols from label file", table.len());
+    Ok(table)
+  }
+
+  /// Reads a VICE label file, as written by ca65's -Ln option or VICE's own
+  /// save_labels. Lines look like "al C:080d .start" or "add_label $080d start".
+  pub fn from_vice(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut table = SymbolTable::new();
+    for (number, line) in text.lines().enumerate() {
+      let parts: Vec<&str> = line.split_whitespace().collect();
+      if parts.is_empty() {
+        continue;
+      }
+      if parts.len() != 3 || (parts[0] != "al" && parts[0] != "add_label") {
+        return Err(SymbolError::Syntax(
+          number + 1,
+          "expected al <address> <label>".to_string(),
+        ));
+      }
+      let address = parts[1].trim_start_matches("C:");
+      let address = match address.starts_with('$') {
+        true => parse_number(address, number + 1)?,
+        false => parse_number(&format!("${}", address), number + 1)?,
+      };
+      table.add(parts[2].trim_start_matches('.'), address, None);
+    }
+    debug!("Read {} symbols from VICE label file", table.len());
+    Ok(table)
+  }
+
+  /// Reads an ld65 debug info file, as written by ld65's --dbgfile option.
+  /// Labels become symbols and line records become source lines.
+  pub fn from_ld65(text: &str) -> Result<SymbolTable, SymbolError> {
+    let mut files: BTreeMap<u32, String> = BTreeMap::new();
+    let mut segments: BTreeMap<u32, u32> = BTreeMap::new();
+    let mut spans: BTreeMap<u32, (u32, u32, u32)> = BTreeMap::new();
+    let mut lines = vec![];
+    let mut symbols = vec![];
+    for (number, line) in text.lines().enumerate() {
+      let number = number + 1;
+      let line = line.trim();
+      if line.is_empty() {
+        continue;
+      }
+      let (kind, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, ""),
+      };
+      let fields = parse_fields(rest, number)?;
+      let get = |key: &str| -> Result<u32, SymbolError> {
+        let value = fields
+          .get(key)
+          .ok_or_else(|| SymbolError::Syntax(number, format!("missing {}", key)))?;
+        parse_number(value, number).map(|v| v as u32).or_else(|_| {
+          value
+            .parse::<u32>()
+            .map_err(|_| SymbolError::Syntax(number, format!("bad number {}", value)))
+        })
+      };
+      match kind {
+        "file" => {
+          let name = fields.get("name").cloned().unwrap_or_default();
+          files.insert(get("id")?, name);
+        }
+        "seg" => {
+          segments.insert(get("id")?, get("start")?);
+        }
+        "span" => {
+          spans.insert(get("id")?, (get("seg")?, get("start")?, get("size")?));
+        }
+        "line" => {
+          if let Some(span_list) = fields.get("span") {
+            lines.push((get("file")?, get("line")? as usize, span_list.clone()));
+          }
+        }
+        "sym" => {
+          let is_label = fields.get("type").map(|t| t == "lab").unwrap_or(false);
+          if is_label {
+            let name = fields.get("name").cloned().unwrap_or_default();
+            let size = match fields.contains_key("size") {
+              true => Some(get("size")? as u16),
+              false => None,
+            };
+            symbols.push((name, get("val")? as u16, size));
+          }
+        }
+        _ => (),
+      }
+    }
+    let mut table = SymbolTable::new();
+    for (name, address, size) in symbols {
+      table.add(&name, address, size);
+    }
+    for (file, line, span_list) in lines {
+      let file_name = files.get(&file).cloned().unwrap_or_default();
+      for id in span_list.split('+').filter_map(|s| s.parse::<u32>().ok()) {
+        if let Some((seg, start, size)) = spans.get(&id) {
+          let base = segments.get(seg).copied().unwrap_or(0);
+          table.add_line(&file_name, line, (base + start) as u16, *size as u16);
+        }
+      }
+    }
+    debug!(
+      "Read {} symbols and {} lines from ld65 debug file",
+      table.len(),
+      table.lines.len()
+    );
+    Ok(table)
+  }
+
+  /// Reads a symbol file, picking the format from the file extension: .dbg
+  /// for ld65 debug info, .lbl or .vs for VICE labels, anything else for
+  /// simple label files.
+  pub fn parse(text: &str, extension: &str) -> Result<SymbolTable, SymbolError> {
+    match extension.to_ascii_lowercase().as_str() {
+      "dbg" => SymbolTable::from_ld65(text),
+      "lbl" | "vs" => SymbolTable::from_vice(text),
+      _ => SymbolTable::from_simple(text),
+    }
+  }
+}
+
+/// Parses a number written as $hex, 0xhex or decimal.
+fn parse_number(value: &str, line: usize) -> Result<u16, SymbolError> {
+  let result = if let Some(hex) = value.strip_prefix('$') {
+    u32::from_str_radix(hex, 16)
+  } else if let Some(hex) = value.strip_prefix("0x") {
+    u32::from_str_radix(hex, 16)
+  } else {
+    value.parse::<u32>()
+  };
+  match result {
+    Ok(v) if v <= 0xFFFF => Ok(v as u16),
+    _ => Err(SymbolError::Syntax(line, format!("bad address {}", value))),
+  }
+}
+
+/// Splits the key=value,key="value" list that makes up an ld65 record.
+fn parse_fields(text: &str, line: usize) -> Result<BTreeMap<String, String>, SymbolError> {
+  let mut fields = BTreeMap::new();
+  let mut chars = text.chars().peekable();
+  while chars.peek().is_some() {
+    let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+    let mut value = String::new();
+    if chars.peek() == Some(&'"') {
+      chars.next();
+      loop {
+        match chars.next() {
+          Some('"') => break,
+          Some(c) => value.push(c),
+          None => return Err(SymbolError::Syntax(line, "unterminated string".to_string())),
+        }
+      }
+      if let Some(c) = chars.next() {
+        if c != ',' {
+          return Err(SymbolError::Syntax(line, "expected ','".to_string()));
+        }
+      }
+    } else {
+      value = chars.by_ref().take_while(|c| *c != ',').collect();
+    }
+    fields.insert(key.trim().to_string(), value);
+  }
+  Ok(fields)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  const LD65: &str = r#"version	major=2,minor=0
+info	csym=0,file=1,lib=0,line=2,mod=1,scope=1,seg=1,span=2,sym=2,type=1
+file	id=0,name="main, loop.s",size=100,mtime=0x5F000000,mod=0
+line	id=0,file=0,line=3,span=0
+line	id=1,file=0,line=4,span=1
+mod	id=0,name="main.o",file=0
+seg	id=0,name="CODE",start=0x008000,size=0x0005,addrsize=absolute,type=ro,oname="a.bin",ooffs=0
+span	id=0,seg=0,start=0,size=2
+span	id=1,seg=0,start=2,size=3
+scope	id=0,name="",mod=0,size=5
+sym	id=0,name="reset",addrsize=absolute,size=5,scope=0,def=0,ref=1,val=0x8000,seg=0,type=lab
+sym	id=1,name="SCREEN",addrsize=absolute,scope=0,def=0,val=0x400,type=equ
+"#;
+
+  #[test]
+  fn ld65() {
+    let table = SymbolTable::from_ld65(LD65).unwrap();
+    assert_eq!(table.get_address("reset"), Some(0x8000));
+    assert_eq!(table.get_address("SCREEN"), None);
+    assert_eq!(table.format_address(0x8003), "reset+3");
+    assert_eq!(table.format_address(0x8005), "$8005");
+    let line = table.get_line(0x8003).unwrap();
+    assert_eq!(line.to_string(), "main, loop.s:4");
+    assert!(table.get_line(0x8005).is_none());
+  }
+
+  #[test]
+  fn vice() {
+    let text = "al C:080d .start\nal 0810 .loop\nadd_label $0820 done\n";
+    let table = SymbolTable::from_vice(text).unwrap();
+    assert_eq!(table.get_address("start"), Some(0x080D));
+    assert_eq!(table.get_address("loop"), Some(0x0810));
+    assert_eq!(table.get_address("done"), Some(0x0820));
+  }
+
+  #[test]
+  fn simple() {
+    let text = "; comment\nreset = $8000\nnmi = 0x8100 ; handler\nzp = 16\n";
+    let table = SymbolTable::from_simple(text).unwrap();
+    assert_eq!(table.len(), 3);
+    assert_eq!(table.get_address("nmi"), Some(0x8100));
+    assert_eq!(table.get_address("zp"), Some(16));
+  }
+
+  #[test_case("reset $8000"; "Missing equals")]
+  #[test_case("reset = $10000"; "Out of range")]
+  fn simple_syntax(text: &str) {
+    assert!(SymbolTable::from_simple(text).is_err());
+  }
+
+  #[test]
+  fn lookup() {
+    let mut table = SymbolTable::new();
+    table.add("main", 0x8000, None);
+    table.add("alias", 0x8000, None);
+    table.add("irq", 0x9000, None);
+    assert_eq!(table.format_address(0x8000), "main");
+    assert_eq!(table.format_address(0x8FFF), "main+4095");
+    assert_eq!(table.format_address(0x9001), "irq+1");
+    assert_eq!(table.format_address(0x7FFF), "$7FFF");
+    assert_eq!(table.get_address("alias"), Some(0x8000));
+  }
+}
@@ -0,0 +1,615 @@
+use crate::prelude::*;
+use crate::CPU;
+#[cfg(feature = "std")]
+use std::fs::{File, OpenOptions};
+#[cfg(feature = "std")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+
+/// Where a trap is triggered from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapSite {
+  /// The program counter reaching an address, normally as the target of a
+  /// JSR. The CPU returns to the caller once the handler has run.
+  Address(u16),
+  /// The reserved trap opcode followed by this number. The CPU carries on
+  /// after the number once the handler has run.
+  Opcode(u8),
+}
+
+/// What the CPU does once a trap's handler has run.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapResult {
+  /// Carry on with the program.
+  Return,
+  /// Stop the program with an exit code.
+  Exit(u8),
+}
+
+/// Identifies a registered trap so it can be removed again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TrapId(usize);
+
+/// A host function run in place of 6502 code, with the CPU to read and set
+/// registers and memory through. Traps are `Send` so the CPU can be moved to
+/// another thread along with them.
+pub type Trap = Box<dyn FnMut(&mut CPU) -> TrapResult + Send>;
+
+/// The traps registered on a CPU, and the opcode reserved for them.
+///
+/// The CPU checks `is_empty` before looking for an address trap, so having
+/// no traps registered costs a single branch per instruction.
+#[derive(Default)]
+pub struct Traps {
+  traps: Vec<(TrapId, TrapSite, Option<Trap>)>,
+  opcode: Option<u8>,
+  next: usize,
+}
+
+impl Traps {
+  /// Creates an empty set of traps with no opcode reserved.
+  pub fn new() -> Traps {
+    Traps::default()
+  }
+
+  /// Returns true if no traps are registered.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.traps.is_empty()
+  }
+
+  /// Gets the number of traps registered.
+  pub fn len(&self) -> usize {
+    self.traps.len()
+  }
+
+  /// Registers a trap, replacing any already registered at the same site.
+  pub fn add(&mut self, site: TrapSite, trap: Trap) -> TrapId {
+    let id = TrapId(self.next);
+    self.next += 1;
+    self.traps.retain(|(_, s, _)| *s != site);
+    self.traps.push((id, site, Some(trap)));
+    id
+  }
+
+  /// Returns true if a trap is registered at a site.
+  pub fn contains(&self, site: TrapSite) -> bool {
+    self.traps.iter().any(|(_, s, _)| *s == site)
+  }
+
+  /// Removes a trap. Returns false if it was not registered.
+  pub fn remove(&mut self, id: TrapId) -> bool {
+    let before = self.traps.len();
+    self.traps.retain(|(i, _, _)| *i != id);
+    self.traps.len() != before
+  }
+
+  /// Removes every trap. The reserved opcode is kept.
+  pub fn clear(&mut self) {
+    self.traps.clear();
+  }
+
+  /// Gets the opcode reserved for traps, if there is one.
+  #[inline]
+  pub fn get_opcode(&self) -> Option<u8> {
+    self.opcode
+  }
+
+  /// Reserves an opcode for traps, or frees it again with `None`.
+  pub fn set_opcode(&mut self, opcode: Option<u8>) {
+    self.opcode = opcode;
+  }
+
+  /// Takes the handler registered at a site out to be run, so it can be
+  /// given the CPU that owns it. Hand it back with `restore`.
+  pub(crate) fn take(&mut self, site: TrapSite) -> Option<(TrapId, Trap)> {
+    self
+      .traps
+      .iter_mut()
+      .find(|(_, s, trap)| *s == site && trap.is_some())
+      .and_then(|(id, _, trap)| trap.take().map(|trap| (*id, trap)))
+  }
+
+  /// Puts back a handler taken with `take`, unless it was removed while it
+  /// ran.
+  pub(crate) fn restore(&mut self, id: TrapId, trap: Trap) {
+    if let Some((_, _, slot)) = self.traps.iter_mut().find(|(i, _, _)| *i == id) {
+      *slot = Some(trap);
+    }
+  }
+}
+
+/// The address the host traps are installed at by default, just below the
+/// vectors.
+pub const HOST_BASE: u16 = 0xFFF0;
+/// Writes the byte in A to the output.
+pub const PUTCHAR: u8 = 0;
+/// Reads a byte from the input into A. Sets carry at the end of the input.
+pub const GETCHAR: u8 = 1;
+/// Stops the program with the exit code in A.
+pub const EXIT: u8 = 2;
+/// Opens the file named by the zero terminated string at A (low) and X
+/// (high), reading with Y = 0, writing with Y = 1 or appending with Y = 2.
+/// Returns the handle in A, or sets carry if the file can't be opened.
+pub const OPEN: u8 = 3;
+/// Closes the handle in A. Sets carry if it wasn't open.
+pub const CLOSE: u8 = 4;
+/// Reads from a handle, with A (low) and X (high) pointing to a block of
+/// the handle, the buffer address and the length. Returns the number of
+/// bytes read in A (low) and X (high), 0 at the end of the file, or sets
+/// carry on an error.
+pub const READ: u8 = 5;
+/// Writes to a handle, with a block as for `READ`. Returns the number of
+/// bytes written in A (low) and X (high), or sets carry on an error.
+pub const WRITE: u8 = 6;
+/// The handle of the input, always open.
+pub const STDIN: u8 = 0;
+/// The handle of the output, always open.
+pub const STDOUT: u8 = 1;
+/// The handle of the host's stderr, always open.
+pub const STDERR: u8 = 2;
+/// The carry bit of the status register.
+#[cfg(feature = "std")]
+const CARRY: u8 = 0x01;
+
+/// A set of traps that give a program the host's console and files, so a
+/// plain 6502 program can run without a machine or a ROM around it, as with
+/// sim65's paravirtualisation.
+///
+/// Arguments go in registers and results come back in them, with carry set
+/// on failure. Each trap is installed at the base address plus its number,
+/// to be called with JSR, or under its number after the trap opcode. See
+/// `PUTCHAR`, `GETCHAR`, `EXIT`, `OPEN`, `CLOSE`, `READ` and `WRITE`.
+#[cfg(feature = "std")]
+pub struct Host {
+  input: Box<dyn Read + Send>,
+  output: Box<dyn Write + Send>,
+  files: Vec<Option<File>>,
+}
+
+#[cfg(feature = "std")]
+impl Host {
+  /// Creates a host on stdin and stdout.
+  pub fn new() -> Host {
+    Host::with_streams(Box::new(io::stdin()), Box::new(io::stdout()))
+  }
+
+  /// Creates a host on the given input and output, for tests and for
+  /// driving a program from code.
+  pub fn with_streams(input: Box<dyn Read + Send>, output: Box<dyn Write + Send>) -> Host {
+    Host {
+      input,
+      output,
+      files: vec![],
+    }
+  }
+
+  /// Installs the traps at the base address plus each trap's number.
+  pub fn install_at(self, cpu: &mut CPU, base: u16) {
+    debug!("Installing host traps at {:X}", base);
+    self.install(cpu, |number| {
+      TrapSite::Address(base.wrapping_add(number as u16))
+    });
+  }
+
+  /// Reserves an opcode for traps and installs the traps under their
+  /// numbers.
+  pub fn install_opcode(self, cpu: &mut CPU, opcode: u8) {
+    debug!("Installing host traps on opcode {:X}", opcode);
+    cpu.set_trap_opcode(Some(opcode));
+    self.install(cpu, TrapSite::Opcode);
+  }
+
+  fn install<F: Fn(u8) -> TrapSite>(self, cpu: &mut CPU, site: F) {
+    let host = Arc::new(Mutex::new(self));
+    for number in PUTCHAR..=WRITE {
+      let host = host.clone();
+      cpu.add_trap(
+        site(number),
+        Box::new(move |cpu: &mut CPU| host.lock().unwrap().call(number, cpu)),
+      );
+    }
+  }
+
+  fn call(&mut self, number: u8, cpu: &mut CPU) -> TrapResult {
+    let mut registers = cpu.get_registers();
+    let pointer = u16::from_le_bytes([registers.accumulator, registers.x]);
+    let result = match number {
+      PUTCHAR => self.putchar(registers.accumulator),
+      GETCHAR => self.getchar(),
+      EXIT => {
+        let _ = self.output.flush();
+        return TrapResult::Exit(registers.accumulator);
+      }
+      OPEN => self.open(cpu, pointer, registers.y),
+      CLOSE => self.close(registers.accumulator),
+      READ | WRITE => self.transfer(cpu, pointer, number == WRITE),
+      _ => unreachable!(),
+    };
+    match result {
+      Ok(value) => {
+        registers.status &= !CARRY;
+        let [lo, hi] = value.to_le_bytes();
+        registers.accumulator = lo;
+        if number == READ || number == WRITE {
+          registers.x = hi;
+        }
+      }
+      Err(error) => {
+        debug!("Host trap {} failed: {}", number, error);
+        registers.status |= CARRY;
+      }
+    }
+    cpu.set_registers(registers);
+    TrapResult::Return
+  }
+
+  fn putchar(&mut self, value: u8) -> io::Result<u16> {
+    self.output.write_all(&[value])?;
+    self.output.flush()?;
+    Ok(value as u16)
+  }
+
+  fn getchar(&mut self) -> io::Result<u16> {
+    let mut byte = [0];
+    match self.input.read(&mut byte)? {
+      0 => Err(io::ErrorKind::UnexpectedEof.into()),
+      _ => Ok(byte[0] as u16),
+    }
+  }
+
+  fn open(&mut self, cpu: &mut CPU, pointer: u16, mode: u8) -> io::Result<u16> {
+    let memory = cpu.get_memory_mut();
+    let name: Vec<u8> = (0..=u8::MAX as u16)
+      .map(|offset| memory.get_u16(pointer.wrapping_add(offset)))
+      .take_while(|byte| *byte != 0)
+      .collect();
+    let name = String::from_utf8_lossy(&name).to_string();
+    let file = match mode {
+      0 => File::open(&name),
+      1 => File::create(&name),
+      2 => OpenOptions::new().append(true).create(true).open(&name),
+      _ => return Err(io::ErrorKind::InvalidInput.into()),
+    }?;
+    debug!("Opened {} with mode {}", name, mode);
+    let index = match self.files.iter().position(Option::is_none) {
+      Some(index) => index,
+      None => {
+        self.files.push(None);
+        self.files.len() - 1
+      }
+    };
+    let handle = index + STDERR as usize + 1;
+    if handle > u8::MAX as usize {
+      self.files.pop();
+      return Err(io::ErrorKind::Other.into());
+    }
+    self.files[index] = Some(file);
+    Ok(handle as u16)
+  }
+
+  fn close(&mut self, handle: u8) -> io::Result<u16> {
+    self
+      .file(handle)?
+      .take()
+      .map(|_| 0)
+      .ok_or_else(|| io::ErrorKind::NotFound.into())
+  }
+
+  fn file(&mut self, handle: u8) -> io::Result<&mut Option<File>> {
+    match handle.checked_sub(STDERR + 1) {
+      Some(index) => self
+        .files
+        .get_mut(index as usize)
+        .filter(|file| file.is_some())
+        .ok_or_else(|| io::ErrorKind::NotFound.into()),
+      None => Err(io::ErrorKind::InvalidInput.into()),
+    }
+  }
+
+  fn transfer(&mut self, cpu: &mut CPU, block: u16, write: bool) -> io::Result<u16> {
+    let memory = cpu.get_memory_mut();
+    let param = |memory: &mut crate::Memory, offset: u16| {
+      u16::from_le_bytes([
+        memory.get_u16(block.wrapping_add(offset)),
+        memory.get_u16(block.wrapping_add(offset + 1)),
+      ])
+    };
+    let handle = memory.get_u16(block);
+    let buffer = param(memory, 1);
+    let length = param(memory, 3) as usize;
+    let mut data: Vec<u8> = (0..length as u16)
+      .map(|offset| memory.get_u16(buffer.wrapping_add(offset)))
+      .collect();
+    let count = match (handle, write) {
+      (STDIN, false) => self.input.read(&mut data)?,
+      (STDOUT, true) => {
+        self.output.write_all(&data)?;
+        self.output.flush()?;
+        data.len()
+      }
+      (STDERR, true) => {
+        io::stderr().write_all(&data)?;
+        data.len()
+      }
+      (STDIN..=STDERR, _) => return Err(io::ErrorKind::InvalidInput.into()),
+      (_, false) => self.file(handle)?.as_mut().unwrap().read(&mut data)?,
+      (_, true) => {
+        self.file(handle)?.as_mut().unwrap().write_all(&data)?;
+        data.len()
+      }
+    };
+    if !write {
+      for (offset, byte) in data[..count].iter().enumerate() {
+        memory.set(buffer.wrapping_add(offset as u16), *byte);
+      }
+    }
+    Ok(count as u16)
+  }
+}
+
+#[cfg(feature = "std")]
+impl Default for Host {
+  fn default() -> Self {
+    Host::new()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[cfg(feature = "std")]
+  use crate::assembler::assemble;
+  use crate::clock::FreeRunning;
+  use rand::random;
+  #[cfg(feature = "std")]
+  use std::sync::{Arc, Mutex};
+  #[cfg(feature = "std")]
+  use test_case::test_case;
+
+  /// An output the test keeps a clone of to see what was written.
+  #[cfg(feature = "std")]
+  #[derive(Clone, Default)]
+  struct Output(Arc<Mutex<Vec<u8>>>);
+
+  #[cfg(feature = "std")]
+  impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  /// Puts a program at 0x0200 and points the program counter at it.
+  fn load(cpu: &mut CPU, program: &[u8]) {
+    for (offset, byte) in program.iter().enumerate() {
+      cpu.get_memory_mut().set(0x0200 + offset as u16, *byte);
+    }
+    let mut registers = cpu.get_registers();
+    registers.program_counter = 0x0200;
+    cpu.set_registers(registers);
+  }
+
+  /// Assembles a program at 0x0200, with the host traps defined as
+  /// symbols, and runs it on a CPU with the host installed at `HOST_BASE`
+  /// until it exits.
+  #[cfg(feature = "std")]
+  fn run(program: &str, input: &[u8]) -> (CPU, Vec<u8>) {
+    let source = format!(
+      "PUTCHAR = $FFF0\nGETCHAR = $FFF1\nEXIT = $FFF2\nOPEN = $FFF3\n\
+       CLOSE = $FFF4\nREAD = $FFF5\nWRITE = $FFF6\n        .org $0200\n{}",
+      program
+    );
+    let assembly = assemble(&source, "test.s", 0x0200).unwrap();
+    let mut cpu = CPU::new(FreeRunning);
+    cpu.load_image(&assembly.image);
+    let output = Output::default();
+    Host::with_streams(
+      Box::new(io::Cursor::new(input.to_vec())),
+      Box::new(output.clone()),
+    )
+    .install_at(&mut cpu, HOST_BASE);
+    for _ in 0..100_000 {
+      if cpu.is_halted() {
+        break;
+      }
+      cpu.step();
+    }
+    let output = output.0.lock().unwrap().clone();
+    (cpu, output)
+  }
+
+  #[test]
+  fn address_trap_returns_to_caller() {
+    let mut cpu = CPU::new(FreeRunning);
+    // JSR $1234, LDX #$01
+    load(&mut cpu, &[0x20, 0x34, 0x12, 0xA2, 0x01]);
+    cpu.add_trap(
+      TrapSite::Address(0x1234),
+      Box::new(|cpu: &mut CPU| {
+        let mut registers = cpu.get_registers();
+        registers.accumulator = 0x42;
+        cpu.set_registers(registers);
+        TrapResult::Return
+      }),
+    );
+    cpu.step();
+    cpu.step();
+    cpu.step();
+    let registers = cpu.get_registers();
+    assert_eq!(registers.accumulator, 0x42);
+    assert_eq!(registers.x, 0x01);
+    assert_eq!(registers.stack_pointer, 0xFF);
+  }
+
+  #[test]
+  fn opcode_trap_continues_after_number() {
+    let mut cpu = CPU::new(FreeRunning);
+    let value: u8 = random();
+    // TRAP 7, LDX #$01
+    load(&mut cpu, &[0x02, 0x07, 0xA2, 0x01]);
+    cpu.set_trap_opcode(Some(0x02));
+    cpu.add_trap(
+      TrapSite::Opcode(0x07),
+      Box::new(move |cpu: &mut CPU| {
+        cpu.get_memory_mut().set(0x10, value);
+        TrapResult::Return
+      }),
+    );
+    assert!(!cpu.is_halted());
+    cpu.step();
+    cpu.step();
+    assert_eq!(cpu.get_memory().peek(0x10), value);
+    assert_eq!(cpu.get_registers().x, 0x01);
+  }
+
+  #[test]
+  fn sites_are_replaced_and_removed() {
+    let mut traps = Traps::new();
+    let id = traps.add(TrapSite::Address(0x10), Box::new(|_| TrapResult::Return));
+    traps.add(TrapSite::Address(0x10), Box::new(|_| TrapResult::Exit(1)));
+    assert_eq!(traps.len(), 1);
+    assert!(traps.contains(TrapSite::Address(0x10)));
+    assert!(!traps.contains(TrapSite::Opcode(0x10)));
+    assert!(!traps.remove(id));
+    let (id, trap) = traps.take(TrapSite::Address(0x10)).unwrap();
+    assert!(traps.take(TrapSite::Address(0x10)).is_none());
+    assert!(traps.remove(id));
+    traps.restore(id, trap);
+    assert!(traps.is_empty());
+  }
+
+  #[cfg(feature = "std")]
+  #[test_case(0; "Zero")]
+  #[test_case(random(); "Random")]
+  fn exit(code: u8) {
+    let program = format!("        LDA #{}\n        JSR EXIT\n        LDA #0\n", code);
+    let (cpu, _) = run(&program, &[]);
+    assert!(cpu.is_halted());
+    assert_eq!(cpu.get_exit_code(), Some(code));
+    assert_eq!(cpu.get_registers().program_counter, HOST_BASE + EXIT as u16);
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn echoes_input() {
+    let program = "
+loop:   JSR GETCHAR
+        BCS done
+        JSR PUTCHAR
+        JMP loop
+done:   LDA #3
+        JSR EXIT";
+    let (cpu, output) = run(program, b"Hello\n");
+    assert_eq!(output, b"Hello\n");
+    assert_eq!(cpu.get_exit_code(), Some(3));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn writes_and_reads_files() {
+    let path = std::env::temp_dir().join(format!("rust6502-traps-{}", random::<u32>()));
+    let program = format!(
+      "
+        LDA #<name
+        LDX #>name
+        LDY #1
+        JSR OPEN
+        BCS fail
+        STA block
+        LDA #<block
+        LDX #>block
+        JSR WRITE
+        BCS fail
+        LDA block
+        JSR CLOSE
+        LDA #<name
+        LDX #>name
+        LDY #0
+        JSR OPEN
+        BCS fail
+        STA block
+        LDA #<buffer
+        STA block + 1
+        LDA #>buffer
+        STA block + 2
+        LDA #<block
+        LDX #>block
+        JSR READ
+        STX $11
+        STA $10
+        LDA #1
+        STA block
+        LDA #<block
+        LDX #>block
+        JSR WRITE
+        LDA #0
+        JSR EXIT
+fail:   LDA #1
+        JSR EXIT
+block:  .byte 0
+        .word message, 5
+message: .byte \"HELLO\"
+buffer: .byte 0, 0, 0, 0, 0
+name:   .byte \"{}\", 0
+",
+      path.display()
+    );
+    let (cpu, output) = run(&program, &[]);
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(cpu.get_exit_code(), Some(0));
+    assert_eq!(cpu.get_memory().peek(0x10), 5);
+    assert_eq!(cpu.get_memory().peek(0x11), 0);
+    assert_eq!(output, b"HELLO");
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn open_missing_file_sets_carry() {
+    let program = "
+        LDA #<name
+        LDX #>name
+        LDY #0
+        JSR OPEN
+        LDA #0
+        ADC #0
+        JSR EXIT
+name:   .byte \"/no/such/file\", 0";
+    let (cpu, _) = run(program, &[]);
+    assert_eq!(cpu.get_exit_code(), Some(1));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn bad_handle_sets_carry() {
+    let program = "
+        LDA #7
+        JSR CLOSE
+        LDA #0
+        ADC #0
+        JSR EXIT";
+    let (cpu, _) = run(program, &[]);
+    assert_eq!(cpu.get_exit_code(), Some(1));
+  }
+
+  #[cfg(feature = "std")]
+  #[test]
+  fn opcode_host() {
+    let mut cpu = CPU::new(FreeRunning);
+    // LDA #'A', TRAP PUTCHAR, LDA #9, TRAP EXIT
+    load(&mut cpu, &[0xA9, b'A', 0x02, PUTCHAR, 0xA9, 9, 0x02, EXIT]);
+    let output = Output::default();
+    Host::with_streams(Box::new(io::empty()), Box::new(output.clone()))
+      .install_opcode(&mut cpu, 0x02);
+    while !cpu.is_halted() {
+      cpu.step();
+    }
+    assert_eq!(cpu.get_exit_code(), Some(9));
+    assert_eq!(*output.0.lock().unwrap(), b"A");
+  }
+}
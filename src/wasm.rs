@@ -0,0 +1,255 @@
+//! JavaScript bindings for running the emulator in a browser.
+//!
+//! Build the `wasm` crate in the workspace with `wasm-pack build wasm` to get
+//! an npm package exposing `Emulator`.
+
+use crate::clock::FreeRunning;
+use crate::loader::{Format, Image};
+use crate::symbols::SymbolTable;
+use crate::CPU;
+use wasm_bindgen::prelude::*;
+
+/// A free running CPU driven from JavaScript.
+///
+/// There are no threads in the browser, so the CPU runs on the caller's
+/// event loop: call `run_for_cycles` from `requestAnimationFrame` or a timer
+/// to run it at a given speed.
+#[wasm_bindgen]
+pub struct Emulator {
+  cpu: CPU,
+}
+
+#[wasm_bindgen]
+impl Emulator {
+  /// Creates an emulator with empty memory and the program counter at $8000.
+  #[wasm_bindgen(constructor)]
+  #[allow(clippy::new_without_default)]
+  pub fn new() -> Emulator {
+    Emulator {
+      cpu: CPU::new(FreeRunning),
+    }
+  }
+
+  /// Copies a program into memory at an address and points the program
+  /// counter at it.
+  pub fn load(&mut self, program: &[u8], address: u16) {
+    let memory = self.cpu.get_memory_mut();
+    for (offset, byte) in program.iter().enumerate() {
+      memory.poke(address.wrapping_add(offset as u16), *byte);
+    }
+    self.set_program_counter(address);
+  }
+
+  /// Parses an image in the format named by a file extension, such as "hex"
+  /// or "prg", loads it and points the program counter at its entry point.
+  /// Raw binaries are loaded at `address`. Returns the entry point.
+  pub fn load_image(&mut self, data: &[u8], extension: &str, address: u16) -> Result<u16, String> {
+    let format = Format::from_extension(extension);
+    let image = Image::parse(data, format, Some(address)).map_err(|e| e.to_string())?;
+    Ok(self.cpu.load_image(&image))
+  }
+
+  /// Loads symbols in the format named by a file extension, such as "sym",
+  /// "lbl" or "dbg", for use in disassembly.
+  pub fn load_symbols(&mut self, text: &str, extension: &str) -> Result<(), String> {
+    let symbols = SymbolTable::parse(text, extension).map_err(|e| e.to_string())?;
+    self.cpu.set_symbols(symbols);
+    Ok(())
+  }
+
+  /// Returns true if the next instruction is a KIL opcode, which would halt
+  /// the processor, or the program has exited through a trap.
+  #[wasm_bindgen(getter)]
+  pub fn halted(&self) -> bool {
+    self.cpu.is_halted()
+  }
+
+  /// Executes a single instruction. Returns the number of cycles it took, or
+  /// zero if the processor is halted.
+  pub fn step(&mut self) -> u32 {
+    if self.halted() {
+      return 0;
+    }
+    let start = self.cpu.get_cycles();
+    self.cpu.step();
+    (self.cpu.get_cycles() - start) as u32
+  }
+
+  /// Executes instructions until at least a number of cycles have passed or
+  /// the processor halts. Returns the number of cycles that passed, which
+  /// can run over by part of an instruction.
+  pub fn run_for_cycles(&mut self, cycles: u32) -> u32 {
+    let mut elapsed = 0;
+    while elapsed < cycles {
+      match self.step() {
+        0 => break,
+        taken => elapsed += taken,
+      }
+    }
+    elapsed
+  }
+
+  /// Gets the number of cycles run since the emulator was created.
+  #[wasm_bindgen(getter)]
+  pub fn cycles(&self) -> f64 {
+    self.cpu.get_cycles() as f64
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn accumulator(&self) -> u8 {
+    self.cpu.get_registers().accumulator
+  }
+
+  #[wasm_bindgen(setter)]
+  pub fn set_accumulator(&mut self, value: u8) {
+    self.update(|r| r.accumulator = value);
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn x(&self) -> u8 {
+    self.cpu.get_registers().x
+  }
+
+  #[wasm_bindgen(setter)]
+  pub fn set_x(&mut self, value: u8) {
+    self.update(|r| r.x = value);
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn y(&self) -> u8 {
+    self.cpu.get_registers().y
+  }
+
+  #[wasm_bindgen(setter)]
+  pub fn set_y(&mut self, value: u8) {
+    self.update(|r| r.y = value);
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn stack_pointer(&self) -> u8 {
+    self.cpu.get_registers().stack_pointer
+  }
+
+  #[wasm_bindgen(setter)]
+  pub fn set_stack_pointer(&mut self, value: u8) {
+    self.update(|r| r.stack_pointer = value);
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn status(&self) -> u8 {
+    self.cpu.get_registers().status
+  }
+
+  #[wasm_bindgen(setter)]
+  pub fn set_status(&mut self, value: u8) {
+    self.update(|r| r.status = value);
+  }
+
+  #[wasm_bindgen(getter)]
+  pub fn program_counter(&self) -> u16 {
+    self.cpu.get_registers().program_counter
+  }
+
+  #[wasm_bindgen(setter)]
+  pub fn set_program_counter(&mut self, value: u16) {
+    self.update(|r| r.program_counter = value);
+  }
+
+  /// Reads a byte of memory without side effects.
+  pub fn read(&self, address: u16) -> u8 {
+    self.cpu.get_memory().peek(address)
+  }
+
+  /// Reads a range of memory, wrapping around at the top of the address
+  /// space.
+  pub fn read_range(&self, start: u16, length: u32) -> Vec<u8> {
+    let memory = self.cpu.get_memory();
+    (0..length.min(0x10000))
+      .map(|i| memory.peek(start.wrapping_add(i as u16)))
+      .collect()
+  }
+
+  /// Writes a byte of memory.
+  pub fn write(&mut self, address: u16, value: u8) {
+    self.cpu.get_memory_mut().poke(address, value);
+  }
+
+  /// Disassembles a number of instructions starting at an address, one per
+  /// line.
+  pub fn disassemble(&self, start: u16, count: usize) -> String {
+    self.cpu.disassemble(start, count)
+  }
+
+  /// Changes a single register.
+  fn update(&mut self, change: impl FnOnce(&mut crate::Registers)) {
+    let mut registers = self.cpu.get_registers();
+    change(&mut registers);
+    self.cpu.set_registers(registers);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  #[cfg(target_arch = "wasm32")]
+  use wasm_bindgen_test::wasm_bindgen_test;
+
+  // LDX #$03; loop: DEX; BNE loop; STX $10; KIL
+  const PROGRAM: [u8; 8] = [0xA2, 0x03, 0xCA, 0xD0, 0xFD, 0x86, 0x10, 0x02];
+
+  #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+  #[cfg_attr(not(target_arch = "wasm32"), test)]
+  fn run_for_cycles() {
+    let mut emulator = Emulator::new();
+    emulator.write(0x10, 0xFF);
+    emulator.load(&PROGRAM, 0x0200);
+    assert_eq!(emulator.program_counter(), 0x0200);
+    assert!(emulator.step() > 0);
+    assert_eq!(emulator.x(), 3);
+    let elapsed = emulator.run_for_cycles(1000);
+    assert!(elapsed < 1000);
+    assert!(emulator.halted());
+    assert_eq!(emulator.step(), 0);
+    assert_eq!(emulator.program_counter(), 0x0207);
+    assert_eq!(emulator.read(0x10), 0);
+    assert_eq!(emulator.cycles(), elapsed as f64 + 2.0);
+  }
+
+  #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+  #[cfg_attr(not(target_arch = "wasm32"), test)]
+  fn registers_and_memory() {
+    let mut emulator = Emulator::new();
+    emulator.set_accumulator(1);
+    emulator.set_x(2);
+    emulator.set_y(3);
+    emulator.set_status(0x81);
+    emulator.set_stack_pointer(0xF0);
+    assert_eq!(
+      (
+        emulator.accumulator(),
+        emulator.x(),
+        emulator.y(),
+        emulator.status(),
+        emulator.stack_pointer()
+      ),
+      (1, 2, 3, 0x81, 0xF0)
+    );
+    emulator.write(0xFFFF, 0xAA);
+    emulator.write(0x0000, 0xBB);
+    assert_eq!(emulator.read_range(0xFFFF, 2), vec![0xAA, 0xBB]);
+  }
+
+  #[cfg_attr(target_arch = "wasm32", wasm_bindgen_test)]
+  #[cfg_attr(not(target_arch = "wasm32"), test)]
+  fn images_and_disassembly() {
+    let mut emulator = Emulator::new();
+    let hex = ":03030000A9018AC6\n:00000001FF\n";
+    assert_eq!(emulator.load_image(hex.as_bytes(), "hex", 0), Ok(0x0300));
+    assert!(emulator.load_image(b":zz\n", "hex", 0).is_err());
+    emulator.load_symbols("start = $0300\n", "sym").unwrap();
+    assert_eq!(
+      emulator.disassemble(0x0300, 1),
+      "start:\n0300  A9 01     LDA #$01\n"
+    );
+  }
+}
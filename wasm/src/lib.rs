@@ -0,0 +1,7 @@
+//! The WebAssembly build of the emulator.
+//!
+//! The bindings themselves live behind the library's `wasm` feature. This
+//! crate only exists to build them as a `cdylib`, which the library can't be
+//! without breaking `no_std` users that depend on it.
+
+pub use rust6502lib::wasm::Emulator;